@@ -0,0 +1,29 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // generate the C header when building the C ABI. it's written to OUT_DIR, and only copied over the checked-in include/pfv.h when PFV_WRITE_HEADER is set
+    #[cfg(feature = "capi")]
+    {
+        let crate_dir = std::path::PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+        let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+
+        println!("cargo:rerun-if-changed=src/capi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        println!("cargo:rerun-if-env-changed=PFV_WRITE_HEADER");
+
+        // only the capi module is exported, so parse just that file rather than the whole crate
+        let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).expect("failed to read cbindgen.toml");
+
+        let bindings = cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(crate_dir.join("src/capi.rs"))
+            .generate()
+            .expect("failed to generate pfv.h");
+
+        bindings.write_to_file(out_dir.join("pfv.h"));
+
+        if std::env::var_os("PFV_WRITE_HEADER").is_some() {
+            bindings.write_to_file(crate_dir.join("include/pfv.h"));
+        }
+    }
+}
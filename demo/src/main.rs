@@ -61,8 +61,9 @@ fn main() {
 
     let desired_spec = {
         AudioSpecDesired {
-            freq: Some(decoder.samplerate() as i32),
-            channels: Some(decoder.channels() as u8),
+            // fall back to SDL's defaults if the video has no audio track
+            freq: if decoder.samplerate() > 0 { Some(decoder.samplerate() as i32) } else { None },
+            channels: if decoder.channels() > 0 { Some(decoder.channels() as u8) } else { None },
             samples: Some(2048)
         }
     };
@@ -101,7 +102,7 @@ fn main() {
         let delta = (new_frametimer - frametimer) as f32 / timer_subsystem.performance_frequency() as f32;
         frametimer = new_frametimer;
 
-        let playing = decoder.advance_delta(delta as f64, &mut |frame| {
+        let playing = decoder.advance_delta_with_audio(delta as f64, &mut |frame| {
             tex.update_yuv(Rect::new(0, 0, frame.width as u32, frame.height as u32), &frame.plane_y.pixels, frame.width,
                 &frame.plane_u.pixels, (frame.width / 2) as usize,
                 &frame.plane_v.pixels, (frame.width / 2) as usize).unwrap();
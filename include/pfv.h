@@ -0,0 +1,298 @@
+#ifndef PFV_H
+#define PFV_H
+
+/* Generated by cbindgen from src/capi.rs - build with the capi feature to regenerate */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define PFV_OK 0
+
+/**
+ * Reading or writing the stream failed
+ */
+#define PFV_ERROR_IO -1
+
+/**
+ * Data isn't a PFV stream, or is truncated or corrupt
+ */
+#define PFV_ERROR_FORMAT -2
+
+/**
+ * Stream was written by an unsupported codec version
+ */
+#define PFV_ERROR_VERSION -3
+
+/**
+ * A null pointer, invalid string, or out of range value was passed in
+ */
+#define PFV_ERROR_INVALID_ARGUMENT -4
+
+/**
+ * The call isn't allowed at this point, for example writing metadata after the first frame or encoding after finishing
+ */
+#define PFV_ERROR_STATE -5
+
+/**
+ * An internal error (a Rust panic) occurred. The handle is left in an unspecified state, and should only be closed
+ */
+#define PFV_ERROR_PANIC -6
+
+typedef enum PfvAudioCodec {
+  PFV_AUDIO_CODEC_PCM,
+  PFV_AUDIO_CODEC_QOA,
+} PfvAudioCodec;
+
+/**
+ * How a frame was coded in the stream
+ */
+typedef enum PfvFrameType {
+  PFV_FRAME_TYPE_INTRA,
+  PFV_FRAME_TYPE_PREDICTED,
+  /**
+   * Nothing changed since the previous frame
+   */
+  PFV_FRAME_TYPE_DROP,
+} PfvFrameType;
+
+/**
+ * Opaque decoder handle
+ */
+typedef struct PfvDecoder PfvDecoder;
+
+/**
+ * Opaque encoder handle
+ */
+typedef struct PfvEncoder PfvEncoder;
+
+/**
+ * Planes of a decoded I420 frame, pointing into the decoder's frame buffer. Valid until the next call on the decoder
+ */
+typedef struct PfvPlanes {
+  uint32_t width;
+  uint32_t height;
+  const uint8_t *y;
+  uint32_t y_stride;
+  const uint8_t *u;
+  const uint8_t *v;
+  /**
+   * Stride of both chroma planes, which are half the width & height of the luma plane
+   */
+  uint32_t uv_stride;
+} PfvPlanes;
+
+/**
+ * A frame returned by pfv_decoder_next_frame
+ */
+typedef struct PfvFrame {
+  /**
+   * Index of this frame in the stream (drop frames count as frames)
+   */
+  uint64_t index;
+  /**
+   * Presentation timestamp in seconds
+   */
+  double pts;
+  enum PfvFrameType frame_type;
+  /**
+   * For drop frames these are unchanged from the previous frame
+   */
+  struct PfvPlanes planes;
+} PfvFrame;
+
+/**
+ * Called by pfv_decoder_advance_delta for every frame which is presented
+ */
+typedef void (*PfvVideoCallback)(const struct PfvPlanes *planes, void *user_data);
+
+/**
+ * Called by pfv_decoder_advance_delta with decoded audio, as `len` interleaved 16-bit samples
+ */
+typedef void (*PfvAudioCallback)(const int16_t *samples, size_t len, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Open a decoder reading from memory. The data is not copied, and must stay valid until the decoder is closed.
+ * num_threads of 0 is treated as 1, and num_threads is ignored if the library was built without the multithreading feature.
+ * Returns null on failure, in which case the error code is written to `error` (which may be null)
+ */
+struct PfvDecoder *pfv_decoder_open_memory(const uint8_t *data,
+                                           size_t len,
+                                           uint32_t num_threads,
+                                           int *error);
+
+/**
+ * Open a decoder reading from a file, given its UTF-8 path. Behaves like pfv_decoder_open_memory otherwise
+ */
+struct PfvDecoder *pfv_decoder_open_file(const char *path,
+                                         uint32_t num_threads,
+                                         int *error);
+
+/**
+ * Release a decoder. Null is ignored
+ */
+void pfv_decoder_close(struct PfvDecoder *decoder);
+
+uint32_t pfv_decoder_width(const struct PfvDecoder *decoder);
+
+uint32_t pfv_decoder_height(const struct PfvDecoder *decoder);
+
+/**
+ * Get the stream's timebase: each tick lasts num / den seconds
+ */
+int pfv_decoder_timebase(const struct PfvDecoder *decoder, uint32_t *num, uint32_t *den);
+
+/**
+ * Total number of frames, or -1 if the stream wasn't finalized
+ */
+int64_t pfv_decoder_frame_count(const struct PfvDecoder *decoder);
+
+/**
+ * Duration in seconds, or -1 if the stream wasn't finalized
+ */
+double pfv_decoder_duration(const struct PfvDecoder *decoder);
+
+/**
+ * Channel count of the selected audio track, or 0 if there is none
+ */
+uint32_t pfv_decoder_audio_channels(const struct PfvDecoder *decoder);
+
+/**
+ * Samplerate of the selected audio track, or 0 if there is none
+ */
+uint32_t pfv_decoder_audio_samplerate(const struct PfvDecoder *decoder);
+
+/**
+ * Select which audio track is handed to the audio callback, or -1 for no audio. Returns PFV_ERROR_INVALID_ARGUMENT if the track is not an audio track
+ */
+int pfv_decoder_select_audio_track(struct PfvDecoder *decoder,
+                                   int track);
+
+/**
+ * Decode the next frame into `frame`. Returns 1 if a frame was decoded, 0 at the end of the stream, or an error code
+ */
+int pfv_decoder_next_frame(struct PfvDecoder *decoder,
+                           struct PfvFrame *frame);
+
+/**
+ * Decode the next frame directly into caller-provided I420 buffers. Each plane must hold stride * rows bytes, and chroma planes are half the width & height of the luma plane.
+ * Returns 1 if a frame was decoded, 0 at the end of the stream, or an error code
+ */
+int pfv_decoder_next_frame_into(struct PfvDecoder *decoder,
+                                uint8_t *y,
+                                uint32_t y_stride,
+                                uint8_t *u,
+                                uint32_t u_stride,
+                                uint8_t *v,
+                                uint32_t v_stride);
+
+/**
+ * Advance the playback clock by `delta` seconds, presenting every frame whose timestamp has been reached. Either callback may be null.
+ * Returns 1 while the stream is still playing, 0 once it has ended, or an error code
+ */
+int pfv_decoder_advance_delta(struct PfvDecoder *decoder,
+                              double delta,
+                              PfvVideoCallback on_video,
+                              PfvAudioCallback on_audio,
+                              void *user_data);
+
+/**
+ * Seek so that the next frame decoded is the given frame, and set the playback clock to its timestamp.
+ * Returns 1 on success, 0 if the stream has fewer frames, or an error code
+ */
+int pfv_decoder_seek(struct PfvDecoder *decoder,
+                     uint64_t frame);
+
+/**
+ * Rewind to the first frame of the stream
+ */
+int pfv_decoder_reset(struct PfvDecoder *decoder);
+
+/**
+ * Open an encoder writing to a file, given its UTF-8 path. Quality ranges from 0 to 10. num_threads of 0 is treated as 1, and num_threads is ignored if the library was built without the multithreading feature.
+ * Returns null on failure, in which case the error code is written to `error` (which may be null)
+ */
+struct PfvEncoder *pfv_encoder_open_file(const char *path,
+                                         uint32_t width,
+                                         uint32_t height,
+                                         uint32_t framerate,
+                                         int quality,
+                                         uint32_t num_threads,
+                                         int *error);
+
+/**
+ * Open an encoder writing to memory, otherwise behaving like pfv_encoder_open_file. Read the result back with pfv_encoder_memory once the encoder has finished
+ */
+struct PfvEncoder *pfv_encoder_open_memory(uint32_t width,
+                                           uint32_t height,
+                                           uint32_t framerate,
+                                           int quality,
+                                           uint32_t num_threads,
+                                           int *error);
+
+/**
+ * Release an encoder. An encoder which hasn't been finished is finished first, but without filling in the header's totals (see pfv_encoder_finish). Null is ignored
+ */
+void pfv_encoder_close(struct PfvEncoder *encoder);
+
+/**
+ * Add a metadata entry (see the KEY_* constants of the Rust metadata module for well-known keys). Must be called before the first frame
+ */
+int pfv_encoder_write_metadata(struct PfvEncoder *encoder,
+                               const char *key,
+                               const char *value);
+
+/**
+ * Give the stream an audio track. Must be called before the first frame
+ */
+int pfv_encoder_set_audio_format(struct PfvEncoder *encoder,
+                                 enum PfvAudioCodec codec,
+                                 uint32_t channels,
+                                 uint32_t samplerate);
+
+/**
+ * Encode `len` interleaved 16-bit samples of audio, continuing on from the previously encoded samples
+ */
+int pfv_encoder_encode_audio(struct PfvEncoder *encoder,
+                             const int16_t *samples,
+                             size_t len);
+
+/**
+ * Encode an I420 frame, choosing between I-frame & P-frame automatically. Each plane must hold stride * rows bytes, and chroma planes are half the width & height of the luma plane
+ */
+int pfv_encoder_encode_frame(struct PfvEncoder *encoder,
+                             const uint8_t *y,
+                             uint32_t y_stride,
+                             const uint8_t *u,
+                             uint32_t u_stride,
+                             const uint8_t *v,
+                             uint32_t v_stride);
+
+/**
+ * Encode a drop frame, which repeats the previous frame
+ */
+int pfv_encoder_encode_dropframe(struct PfvEncoder *encoder);
+
+/**
+ * Finish the stream & fill in the header's frame count, duration, and index offset
+ */
+int pfv_encoder_finish(struct PfvEncoder *encoder);
+
+/**
+ * Get the data written so far by an encoder opened with pfv_encoder_open_memory, writing its length to `len`.
+ * The pointer is valid until the next call on the encoder. Returns null for file encoders
+ */
+const uint8_t *pfv_encoder_memory(const struct PfvEncoder *encoder,
+                                  size_t *len);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* PFV_H */
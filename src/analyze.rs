@@ -0,0 +1,341 @@
+//! Per-macroblock stream analysis.
+//!
+//! An Analyzer decodes a stream like a regular Decoder, and additionally re-parses each frame packet to report how every macroblock was coded:
+//! its block type, motion vector, coefficient count, and how many bits it took up in the bitstream. Block bits count everything coded for the
+//! block itself (P-frame block headers & RLE coefficients) - the huffman table, qtable indices, slice table, and slice padding are not attributed to any block.
+
+use std::{io::Read, ops::Range, fmt::Write};
+
+use crate::{bits::BitReader, dec::{Decoder, DecodeError, FrameType}, frame::VideoFrame, plane::VideoPlane, huffman::{HuffmanTree, HuffmanError}, slice::read_rle};
+
+/// Which plane of the frame a macroblock belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Plane {
+    Y,
+    U,
+    V,
+}
+
+/// How a macroblock was coded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockType {
+    /// Block of an I-frame
+    Intra,
+    /// P-frame block with a motion vector and/or residual coefficients
+    Inter,
+    /// P-frame block copied unchanged from the previous frame
+    Skip,
+    /// Block of a corrupt slice, which the decoder concealed with the previous frame's contents
+    Concealed,
+}
+
+/// Coding information for a single 16x16 macroblock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub plane: Plane,
+    /// Position of the block's top left corner within its plane, in pixels
+    pub x: usize,
+    pub y: usize,
+    pub block_type: BlockType,
+    /// Motion vector (in pixels), pointing at the block's source in the previous frame
+    pub motion_x: i8,
+    pub motion_y: i8,
+    pub has_coefficients: bool,
+    /// Number of nonzero quantized coefficients
+    pub coefficient_count: usize,
+    /// Number of bits spent on this block in the bitstream
+    pub bits: u64,
+}
+
+/// Coding information for a single frame. Drop frames have no blocks
+#[derive(Debug, Clone)]
+pub struct FrameAnalysis {
+    pub index: u64,
+    /// Presentation timestamp in seconds
+    pub pts: f64,
+    pub frame_type: FrameType,
+    /// Size of the frame packet's payload in bytes
+    pub payload_size: usize,
+    pub blocks: Vec<BlockInfo>,
+}
+
+/// Decodes a stream & analyzes each frame
+pub struct Analyzer<TReader: Read> {
+    decoder: Decoder<TReader>,
+    slice_ranges: Vec<Range<usize>>,
+}
+
+impl Plane {
+    fn name(self: &Plane) -> &'static str {
+        match self {
+            Plane::Y => "y",
+            Plane::U => "u",
+            Plane::V => "v",
+        }
+    }
+}
+
+impl BlockType {
+    fn name(self: &BlockType) -> &'static str {
+        match self {
+            BlockType::Intra => "intra",
+            BlockType::Inter => "inter",
+            BlockType::Skip => "skip",
+            BlockType::Concealed => "concealed",
+        }
+    }
+}
+
+impl FrameAnalysis {
+    /// Total bits spent on all blocks of the frame
+    pub fn block_bits(self: &FrameAnalysis) -> u64 {
+        self.blocks.iter().map(|x| x.bits).sum()
+    }
+
+    /// Number of blocks of the given type
+    pub fn count(self: &FrameAnalysis, block_type: BlockType) -> usize {
+        self.blocks.iter().filter(|x| x.block_type == block_type).count()
+    }
+
+    /// Serialize to a single-line JSON object
+    pub fn to_json(self: &FrameAnalysis) -> String {
+        let frame_type = match self.frame_type {
+            FrameType::Intra => "intra",
+            FrameType::Predicted => "predicted",
+            FrameType::Drop => "drop",
+        };
+
+        let mut json = String::with_capacity(128 + self.blocks.len() * 112);
+        write!(json, "{{\"index\":{},\"pts\":{:?},\"frame_type\":\"{}\",\"payload_size\":{},\"blocks\":[", self.index, self.pts, frame_type, self.payload_size).unwrap();
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+
+            write!(json, "{{\"plane\":\"{}\",\"x\":{},\"y\":{},\"type\":\"{}\",\"mv\":[{},{}],\"has_coefficients\":{},\"coefficient_count\":{},\"bits\":{}}}",
+                block.plane.name(), block.x, block.y, block.block_type.name(), block.motion_x, block.motion_y, block.has_coefficients, block.coefficient_count, block.bits).unwrap();
+        }
+
+        json.push_str("]}");
+        json
+    }
+}
+
+impl<TReader: Read> Analyzer<TReader> {
+    pub fn new(decoder: Decoder<TReader>) -> Analyzer<TReader> {
+        Analyzer { decoder, slice_ranges: Vec::new() }
+    }
+
+    pub fn decoder(self: &Analyzer<TReader>) -> &Decoder<TReader> {
+        &self.decoder
+    }
+
+    pub fn into_inner(self: Analyzer<TReader>) -> Decoder<TReader> {
+        self.decoder
+    }
+
+    /// The most recently decoded frame
+    pub fn current_frame(self: &Analyzer<TReader>) -> &VideoFrame {
+        self.decoder.current_frame()
+    }
+
+    /// Decode & analyze the next frame, returning None once the end of the stream has been reached
+    pub fn next_frame(self: &mut Analyzer<TReader>) -> Result<Option<FrameAnalysis>, DecodeError> {
+        let (index, pts, frame_type) = match self.decoder.next_frame()? {
+            Some(v) => (v.index, v.pts, v.frame_type),
+            None => {
+                return Ok(None);
+            }
+        };
+
+        let mut analysis = FrameAnalysis { index, pts, frame_type, payload_size: 0, blocks: Vec::new() };
+
+        if frame_type == FrameType::Drop {
+            return Ok(Some(analysis));
+        }
+
+        // the decoder has already validated the packet layout, so this can't fail
+        if self.decoder.last_packet_slices(&mut self.slice_ranges).is_err() {
+            return Err(DecodeError::FormatError);
+        }
+
+        let payload = self.decoder.last_packet();
+        analysis.payload_size = payload.len();
+
+        let mut table = [0;16];
+        table.copy_from_slice(&payload[0..16]);
+        let tree = HuffmanTree::from_table(&table);
+
+        let positions = block_positions(self.decoder.plane_blocks());
+        let mut positions_remaining = &positions[..];
+
+        for (num_blocks, range) in self.decoder.slice_sizes().into_iter().zip(self.slice_ranges.iter()) {
+            let (slice_positions, next_positions) = positions_remaining.split_at(num_blocks);
+            positions_remaining = next_positions;
+
+            let start = analysis.blocks.len();
+
+            for &(plane, x, y) in slice_positions {
+                let block_type = if frame_type == FrameType::Intra { BlockType::Intra } else { BlockType::Skip };
+                analysis.blocks.push(BlockInfo { plane, x, y, block_type, motion_x: 0, motion_y: 0,
+                    has_coefficients: false, coefficient_count: 0, bits: 0 });
+            }
+
+            let slice_blocks = &mut analysis.blocks[start..];
+
+            let result = if frame_type == FrameType::Intra {
+                analyze_islice(&tree, &payload[range.clone()], slice_blocks)
+            } else {
+                analyze_pslice(&tree, &payload[range.clone()], slice_blocks)
+            };
+
+            if result.is_err() {
+                // the decoder conceals the whole slice
+                for block in slice_blocks {
+                    *block = BlockInfo { block_type: BlockType::Concealed, motion_x: 0, motion_y: 0, has_coefficients: false, coefficient_count: 0, bits: 0, ..*block };
+                }
+            }
+        }
+
+        Ok(Some(analysis))
+    }
+}
+
+/// Plane & pixel position of every macroblock of a frame, in bitstream order: each plane in raster order, Y first
+pub(crate) fn block_positions(plane_blocks: [(usize, usize);3]) -> Vec<(Plane, usize, usize)> {
+    let mut positions = Vec::new();
+
+    for (plane, (blocks_wide, blocks_high)) in [Plane::Y, Plane::U, Plane::V].into_iter().zip(plane_blocks) {
+        for by in 0..blocks_high {
+            for bx in 0..blocks_wide {
+                positions.push((plane, bx * 16, by * 16));
+            }
+        }
+    }
+
+    positions
+}
+
+fn analyze_islice(tree: &HuffmanTree, data: &[u8], blocks: &mut [BlockInfo]) -> Result<(), HuffmanError> {
+    let mut bitreader = BitReader::new(data);
+    let bitstream_length = data.len() as u64 * 8;
+    let mut coefficients = [0;256];
+
+    for block in blocks {
+        let start = bitreader.position_in_bits();
+
+        coefficients.fill(0);
+        read_rle(tree, &mut bitreader, bitstream_length, &mut coefficients)?;
+
+        block.coefficient_count = coefficients.iter().filter(|x| **x != 0).count();
+        block.has_coefficients = true;
+        block.bits = bitreader.position_in_bits() - start;
+    }
+
+    Ok(())
+}
+
+fn analyze_pslice(tree: &HuffmanTree, data: &[u8], blocks: &mut [BlockInfo]) -> Result<(), HuffmanError> {
+    let mut bitreader = BitReader::new(data);
+    let bitstream_length = data.len() as u64 * 8;
+    let mut coefficients = [0;256];
+
+    // block headers come first, followed by the coefficients of each block which has them
+    for block in blocks.iter_mut() {
+        let has_mvec = bitreader.read_bit().map_err(HuffmanError::IOError)?;
+        block.has_coefficients = bitreader.read_bit().map_err(HuffmanError::IOError)?;
+        block.bits = 2;
+
+        if has_mvec {
+            block.motion_x = bitreader.read_signed(7).map_err(HuffmanError::IOError)? as i8;
+            block.motion_y = bitreader.read_signed(7).map_err(HuffmanError::IOError)? as i8;
+            block.bits += 14;
+        }
+
+        if has_mvec || block.has_coefficients {
+            block.block_type = BlockType::Inter;
+        }
+    }
+
+    for block in blocks.iter_mut().filter(|x| x.has_coefficients) {
+        let start = bitreader.position_in_bits();
+
+        coefficients.fill(0);
+        read_rle(tree, &mut bitreader, bitstream_length, &mut coefficients)?;
+
+        block.coefficient_count = coefficients.iter().filter(|x| **x != 0).count();
+        block.bits += bitreader.position_in_bits() - start;
+    }
+
+    Ok(())
+}
+
+/// Overlay block types & motion vectors on top of a decoded frame.
+/// Luma blocks are tinted by type (intra: red, inter: blue, concealed: magenta, skip: untinted) and motion vectors are drawn as white arrows from each block's center
+pub fn visualize(frame: &VideoFrame, analysis: &FrameAnalysis) -> VideoFrame {
+    let mut out = frame.clone();
+
+    // flatten luma contrast so that the overlay stands out
+    for px in out.plane_y.pixels.iter_mut() {
+        *px = (*px / 2) + 64;
+    }
+
+    for block in analysis.blocks.iter().filter(|x| x.plane == Plane::Y) {
+        let tint = match block.block_type {
+            BlockType::Intra => Some((90, 240)),
+            BlockType::Inter => Some((240, 110)),
+            BlockType::Concealed => Some((200, 220)),
+            BlockType::Skip => None,
+        };
+
+        if let Some((u, v)) = tint {
+            fill_block(&mut out.plane_u, block.x / 2, block.y / 2, 8, u);
+            fill_block(&mut out.plane_v, block.x / 2, block.y / 2, 8, v);
+        }
+    }
+
+    for block in analysis.blocks.iter().filter(|x| x.plane == Plane::Y && (x.motion_x != 0 || x.motion_y != 0)) {
+        let x0 = block.x as i32 + 8;
+        let y0 = block.y as i32 + 8;
+        let x1 = x0 + block.motion_x as i32;
+        let y1 = y0 + block.motion_y as i32;
+
+        draw_line(&mut out, x0, y0, x1, y1);
+
+        // arrowhead: two short strokes angled back from the tip
+        let len = ((block.motion_x as f32).powi(2) + (block.motion_y as f32).powi(2)).sqrt();
+        let dx = block.motion_x as f32 / len;
+        let dy = block.motion_y as f32 / len;
+        let head = len.min(4.0);
+
+        for (sx, sy) in [(-dx - dy, -dy + dx), (-dx + dy, -dy - dx)] {
+            draw_line(&mut out, x1, y1, x1 + (sx * head).round() as i32, y1 + (sy * head).round() as i32);
+        }
+    }
+
+    out
+}
+
+/// Fill a square area of a plane, clipped to the plane
+fn fill_block(plane: &mut VideoPlane, x: usize, y: usize, size: usize, value: u8) {
+    for row in y..(y + size).min(plane.height) {
+        for col in x..(x + size).min(plane.width) {
+            plane.pixels[row * plane.width + col] = value;
+        }
+    }
+}
+
+/// Draw a white line into the luma plane, clipped to the frame
+fn draw_line(frame: &mut VideoFrame, x0: i32, y0: i32, x1: i32, y1: i32) {
+    let steps = (x1 - x0).abs().max((y1 - y0).abs()).max(1);
+
+    for i in 0..=steps {
+        let x = x0 + ((x1 - x0) * i) / steps;
+        let y = y0 + ((y1 - y0) * i) / steps;
+
+        if x >= 0 && y >= 0 && (x as usize) < frame.plane_y.width && (y as usize) < frame.plane_y.height {
+            frame.plane_y.pixels[y as usize * frame.plane_y.width + x as usize] = 255;
+        }
+    }
+}
@@ -0,0 +1,373 @@
+//! Async encoder & decoder wrappers (requires the "async" feature and a Tokio runtime).
+//!
+//! I/O is performed asynchronously, while encoding & decoding frames (the CPU-heavy part) runs on Tokio's blocking thread pool,
+//! which in turn hands slices to the encoder/decoder's own thread pool. The async functions here are not cancel-safe: if one of their futures
+//! is dropped part way through (or the encoder/decoder panics, or writing fails), its state is lost, and every later call returns an error.
+
+use std::io::SeekFrom;
+#[cfg(feature = "multithreading")]
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{audio::AudioFormat, dec::{Decoder, DecodeError, OwnedDecodedFrame}, enc::{Encoder, EncoderBuilder, EncodeError, FrameStats}, frame::{Timebase, VideoFrame}, stream::{FeedBuffer, StreamDecoder}, track::TrackId};
+
+/// Number of bytes requested from the reader at a time
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Error returned once an earlier call was interrupted part way through
+fn poisoned() -> std::io::Error {
+    std::io::Error::other("unusable after an earlier call was interrupted")
+}
+
+fn task_panicked() -> std::io::Error {
+    std::io::Error::other("task panicked")
+}
+
+pub struct AsyncDecoder<R: AsyncRead + Unpin> {
+    reader: R,
+    stream: Option<StreamDecoder>,
+    chunk: Vec<u8>,
+    bytes_read: u64,
+    audio: Vec<i16>,
+    /// Set while a call which can't be resumed is in progress, so that it stays set if the call is interrupted
+    poisoned: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncDecoder<R> {
+    /// Create a new decoder, reading the stream header from the given source
+    pub async fn new(reader: R, #[cfg(feature = "multithreading")] num_threads: usize) -> Result<AsyncDecoder<R>, DecodeError> {
+        #[cfg(feature = "multithreading")]
+        let stream = StreamDecoder::new(num_threads);
+
+        #[cfg(not(feature = "multithreading"))]
+        let stream = StreamDecoder::new();
+
+        AsyncDecoder::open(reader, stream).await
+    }
+
+    /// Create a new decoder which runs on an existing thread pool (see Decoder::new_with_thread_pool)
+    #[cfg(feature = "multithreading")]
+    pub async fn new_with_thread_pool(reader: R, threadpool: Arc<rayon::ThreadPool>) -> Result<AsyncDecoder<R>, DecodeError> {
+        AsyncDecoder::open(reader, StreamDecoder::new_with_thread_pool(threadpool)).await
+    }
+
+    async fn open(reader: R, stream: StreamDecoder) -> Result<AsyncDecoder<R>, DecodeError> {
+        let mut decoder = AsyncDecoder { reader, stream: Some(stream), chunk: vec![0;READ_CHUNK_SIZE], bytes_read: 0, audio: Vec::new(), poisoned: false };
+
+        while !decoder.stream_mut()?.try_read_header()? {
+            if !decoder.read_chunk().await? {
+                return Err(DecodeError::IOError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
+            }
+        }
+
+        Ok(decoder)
+    }
+
+    /// The underlying decoder, which can be used to query dimensions, timebase, metadata, and tracks, select tracks,
+    /// or take user data & subtitles which have been read so far. None if an earlier call was interrupted
+    pub fn decoder(self: &AsyncDecoder<R>) -> Option<&Decoder<FeedBuffer>> {
+        if self.poisoned {
+            return None;
+        }
+
+        self.stream.as_ref().and_then(|x| x.decoder())
+    }
+
+    /// Mutable access to the underlying decoder
+    pub fn decoder_mut(self: &mut AsyncDecoder<R>) -> Option<&mut Decoder<FeedBuffer>> {
+        if self.poisoned {
+            return None;
+        }
+
+        self.stream.as_mut().and_then(|x| x.decoder_mut())
+    }
+
+    fn stream_mut(self: &mut AsyncDecoder<R>) -> Result<&mut StreamDecoder, DecodeError> {
+        match self.stream.as_mut() {
+            Some(v) if !self.poisoned => Ok(v),
+            _ => Err(DecodeError::IOError(poisoned()))
+        }
+    }
+
+    /// Decode the next frame (including drop frames, like Decoder::next_frame). Returns None at the end of the stream.
+    /// Audio of the selected audio track preceding the frame is buffered up, and can be retrieved with take_audio
+    pub async fn next_frame(self: &mut AsyncDecoder<R>) -> Result<Option<OwnedDecodedFrame>, DecodeError> {
+        // read until the whole frame has arrived
+        while !self.stream_mut()?.has_next_frame()? {
+            if !self.read_chunk().await? {
+                return Err(DecodeError::IOError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
+            }
+        }
+
+        // the stream decoder is moved onto the blocking thread pool, so it's lost if this future is dropped before it comes back
+        let mut stream = match self.stream.take() {
+            Some(v) => v,
+            None => {
+                return Err(DecodeError::IOError(poisoned()));
+            }
+        };
+
+        self.poisoned = true;
+        let mut audio = std::mem::take(&mut self.audio);
+
+        let task = tokio::task::spawn_blocking(move || {
+            let result = match stream.next_frame_with_audio(&mut |samples| audio.extend_from_slice(samples)) {
+                Ok(Some(v)) => Ok(Some(OwnedDecodedFrame { index: v.index, pts: v.pts, frame_type: v.frame_type, frame: v.frame.clone() })),
+                Ok(None) => Ok(None),
+                Err(e) => Err(e)
+            };
+
+            (stream, audio, result)
+        });
+
+        let (stream, audio, result) = match task.await {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(DecodeError::IOError(task_panicked()));
+            }
+        };
+
+        self.stream = Some(stream);
+        self.audio = audio;
+        self.poisoned = false;
+
+        result
+    }
+
+    /// Take all audio samples (interleaved, from the selected audio track) which have been decoded so far
+    pub fn take_audio(self: &mut AsyncDecoder<R>) -> Vec<i16> {
+        std::mem::take(&mut self.audio)
+    }
+
+    /// Read the next chunk from the source & feed it to the stream decoder. Returns false at the end of the source
+    async fn read_chunk(self: &mut AsyncDecoder<R>) -> Result<bool, DecodeError> {
+        let len = match self.reader.read(&mut self.chunk).await {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(DecodeError::IOError(e));
+            }
+        };
+
+        let stream = match self.stream.as_mut() {
+            Some(v) if !self.poisoned => v,
+            _ => {
+                return Err(DecodeError::IOError(poisoned()));
+            }
+        };
+
+        self.bytes_read += len as u64;
+        stream.feed(&self.chunk[..len]);
+
+        Ok(len > 0)
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> AsyncDecoder<R> {
+    /// Rewind to the first frame of the stream
+    pub async fn reset(self: &mut AsyncDecoder<R>) -> Result<(), std::io::Error> {
+        let stream = match self.stream.as_mut() {
+            Some(v) if !self.poisoned => v,
+            _ => {
+                return Err(poisoned());
+            }
+        };
+
+        // the header was read when the decoder was opened
+        let header_len = stream.header_len().unwrap_or(0);
+
+        // seek relative to the current position, as the stream need not start at offset 0 of the reader
+        self.poisoned = true;
+        self.reader.seek(SeekFrom::Current(header_len as i64 - self.bytes_read as i64)).await?;
+        self.bytes_read = header_len;
+        self.poisoned = false;
+
+        stream.reset();
+        self.audio.clear();
+
+        Ok(())
+    }
+}
+
+pub struct AsyncEncoder<W: AsyncWrite + Unpin> {
+    writer: W,
+    encoder: Option<Encoder<Vec<u8>>>,
+    /// Set while a call which can't be resumed is in progress, so that it stays set if the call is interrupted
+    poisoned: bool,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncEncoder<W> {
+    pub fn new(writer: W, width: usize, height: usize, framerate: u32, quality: i32, #[cfg(feature = "multithreading")] num_threads: usize) -> Result<AsyncEncoder<W>, EncodeError> {
+        #[cfg(feature = "multithreading")]
+        {
+            AsyncEncoder::new_with_timebase(writer, width, height, Timebase::from_framerate(framerate), false, quality, num_threads)
+        }
+
+        #[cfg(not(feature = "multithreading"))]
+        {
+            AsyncEncoder::new_with_timebase(writer, width, height, Timebase::from_framerate(framerate), false, quality)
+        }
+    }
+
+    /// Create a new encoder with a rational timebase (see Encoder::new_with_timebase)
+    pub fn new_with_timebase(writer: W, width: usize, height: usize, timebase: Timebase, variable_framerate: bool, quality: i32, #[cfg(feature = "multithreading")] num_threads: usize) -> Result<AsyncEncoder<W>, EncodeError> {
+        #[cfg(feature = "multithreading")]
+        let encoder = Encoder::new_with_timebase(Vec::new(), width, height, timebase, variable_framerate, quality, num_threads)?;
+
+        #[cfg(not(feature = "multithreading"))]
+        let encoder = Encoder::new_with_timebase(Vec::new(), width, height, timebase, variable_framerate, quality)?;
+
+        Ok(AsyncEncoder { writer, encoder: Some(encoder), poisoned: false })
+    }
+
+    /// Create a new encoder from an EncoderBuilder
+    pub fn from_builder(writer: W, builder: EncoderBuilder) -> Result<AsyncEncoder<W>, EncodeError> {
+        let encoder = builder.build(Vec::new())?;
+        Ok(AsyncEncoder { writer, encoder: Some(encoder), poisoned: false })
+    }
+
+    fn encoder_mut(self: &mut AsyncEncoder<W>) -> Result<&mut Encoder<Vec<u8>>, EncodeError> {
+        match self.encoder.as_mut() {
+            Some(v) if !self.poisoned => Ok(v),
+            _ => Err(EncodeError::Io(poisoned()))
+        }
+    }
+
+    /// See Encoder::write_metadata
+    pub fn write_metadata(self: &mut AsyncEncoder<W>, key: &str, value: &str) -> Result<(), EncodeError> {
+        self.encoder_mut()?.write_metadata(key, value)
+    }
+
+    /// See Encoder::add_audio_track
+    pub fn add_audio_track(self: &mut AsyncEncoder<W>, format: AudioFormat, language: &str) -> Result<TrackId, EncodeError> {
+        self.encoder_mut()?.add_audio_track(format, language)
+    }
+
+    /// See Encoder::add_subtitle_track
+    pub fn add_subtitle_track(self: &mut AsyncEncoder<W>, language: &str) -> Result<TrackId, EncodeError> {
+        self.encoder_mut()?.add_subtitle_track(language)
+    }
+
+    /// See Encoder::set_audio_format
+    pub fn set_audio_format(self: &mut AsyncEncoder<W>, format: AudioFormat) -> Result<(), EncodeError> {
+        self.encoder_mut()?.set_audio_format(format)
+    }
+
+    /// See Encoder::set_frame_pts
+    pub fn set_frame_pts(self: &mut AsyncEncoder<W>, pts: u64) -> Result<(), EncodeError> {
+        self.encoder_mut()?.set_frame_pts(pts)
+    }
+
+    /// See Encoder::set_stats_callback. The callback runs on Tokio's blocking thread pool
+    pub fn set_stats_callback<F: FnMut(&FrameStats) + Send + 'static>(self: &mut AsyncEncoder<W>, callback: F) -> Result<(), EncodeError> {
+        self.encoder_mut()?.set_stats_callback(callback);
+        Ok(())
+    }
+
+    pub async fn encode_iframe(self: &mut AsyncEncoder<W>, frame: &VideoFrame) -> Result<(), EncodeError> {
+        let frame = frame.clone();
+        self.run_blocking(move |encoder| encoder.encode_iframe(&frame)).await
+    }
+
+    pub async fn encode_pframe(self: &mut AsyncEncoder<W>, frame: &VideoFrame) -> Result<(), EncodeError> {
+        let frame = frame.clone();
+        self.run_blocking(move |encoder| encoder.encode_pframe(&frame)).await
+    }
+
+    /// See Encoder::encode_frame
+    pub async fn encode_frame(self: &mut AsyncEncoder<W>, frame: &VideoFrame) -> Result<(), EncodeError> {
+        let frame = frame.clone();
+        self.run_blocking(move |encoder| encoder.encode_frame(&frame)).await
+    }
+
+    pub async fn encode_dropframe(self: &mut AsyncEncoder<W>) -> Result<(), EncodeError> {
+        self.encoder_mut()?.encode_dropframe()?;
+        self.write_encoded().await
+    }
+
+    /// See Encoder::encode_audio
+    pub async fn encode_audio(self: &mut AsyncEncoder<W>, samples: &[i16]) -> Result<(), EncodeError> {
+        let samples = samples.to_vec();
+        self.run_blocking(move |encoder| encoder.encode_audio(&samples)).await
+    }
+
+    /// See Encoder::encode_audio_track
+    pub async fn encode_audio_track(self: &mut AsyncEncoder<W>, track: TrackId, samples: &[i16]) -> Result<(), EncodeError> {
+        let samples = samples.to_vec();
+        self.run_blocking(move |encoder| encoder.encode_audio_track(track, &samples)).await
+    }
+
+    /// See Encoder::write_subtitle
+    pub async fn write_subtitle(self: &mut AsyncEncoder<W>, track: TrackId, pts: u64, duration: u64, text: &str) -> Result<(), EncodeError> {
+        self.encoder_mut()?.write_subtitle(track, pts, duration, text)?;
+        self.write_encoded().await
+    }
+
+    /// See Encoder::write_user_data
+    pub async fn write_user_data(self: &mut AsyncEncoder<W>, pts: u64, data: &[u8]) -> Result<(), EncodeError> {
+        self.encoder_mut()?.write_user_data(pts, data)?;
+        self.write_encoded().await
+    }
+
+    /// Finish the PFV stream & flush the writer. Unlike Encoder, this is not done automatically when the encoder is dropped
+    pub async fn finish(self: &mut AsyncEncoder<W>) -> Result<(), EncodeError> {
+        self.encoder_mut()?.finish()?;
+        self.write_encoded().await?;
+        self.writer.flush().await?;
+
+        Ok(())
+    }
+
+    /// Run an encoder operation on the blocking thread pool, then write out whatever it encoded
+    async fn run_blocking<F>(self: &mut AsyncEncoder<W>, op: F) -> Result<(), EncodeError> where
+        F: FnOnce(&mut Encoder<Vec<u8>>) -> Result<(), EncodeError> + Send + 'static {
+        // the encoder is moved onto the blocking thread pool, so it's lost if this future is dropped before it comes back
+        if self.poisoned {
+            return Err(EncodeError::Io(poisoned()));
+        }
+
+        let mut encoder = match self.encoder.take() {
+            Some(v) => v,
+            None => {
+                return Err(EncodeError::Io(poisoned()));
+            }
+        };
+
+        self.poisoned = true;
+
+        let task = tokio::task::spawn_blocking(move || {
+            let result = op(&mut encoder);
+            (encoder, result)
+        });
+
+        let (encoder, result) = match task.await {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(EncodeError::Io(task_panicked()));
+            }
+        };
+
+        self.encoder = Some(encoder);
+        self.poisoned = false;
+
+        result?;
+        self.write_encoded().await
+    }
+
+    /// Write encoded data buffered up by the encoder. If this fails or is interrupted, part of the buffer may have been written, so the encoder stays poisoned
+    async fn write_encoded(self: &mut AsyncEncoder<W>) -> Result<(), EncodeError> {
+        let buffer = match self.encoder.as_mut() {
+            Some(v) if !self.poisoned => v.writer_mut(),
+            _ => {
+                return Err(EncodeError::Io(poisoned()));
+            }
+        };
+
+        self.poisoned = true;
+        self.writer.write_all(buffer).await?;
+        buffer.clear();
+        self.poisoned = false;
+
+        Ok(())
+    }
+}
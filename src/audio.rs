@@ -0,0 +1,40 @@
+//! Audio track format.
+//!
+//! A stream may carry one interleaved 16-bit audio track alongside its video, stored either as raw PCM or compressed with QOA.
+
+/// Codec used to store audio packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    /// Uncompressed 16-bit little endian PCM
+    Pcm,
+    /// QOA ("Quite OK Audio") - lossy, 3.2 bits per sample, very cheap to decode
+    Qoa,
+}
+
+/// Format of a stream's audio track
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub codec: AudioCodec,
+    /// Number of interleaved channels (1 to 8)
+    pub channels: u32,
+    /// Sample frames per second
+    pub samplerate: u32,
+}
+
+impl AudioCodec {
+    #[cfg(feature = "std")]
+    pub(crate) fn to_id(self: AudioCodec) -> u8 {
+        match self {
+            AudioCodec::Pcm => 1,
+            AudioCodec::Qoa => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<AudioCodec> {
+        match id {
+            1 => Some(AudioCodec::Pcm),
+            2 => Some(AudioCodec::Qoa),
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::{fs::File, io::{BufReader, BufWriter, Write}, path::{Path, PathBuf}, process::ExitCode};
+
+use pfv_rs::{analyze::{Analyzer, visualize}, dec::Decoder, frame::VideoFrame, validate::validate};
+
+const USAGE: &str = "usage:
+    pfv analyze <input.pfv> [--output <file.json>] [--visualize <dir>]
+    pfv verify <input.pfv>...
+
+commands:
+    analyze     decode a stream & print per-macroblock coding info as JSON.
+                --output writes the JSON to a file instead of stdout.
+                --visualize writes every frame to <dir> as a PPM image, overlaid with block types & motion vectors
+    verify      check that streams are well-formed, printing every problem found.
+                exits with status 1 if any stream has errors";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let result = match args.first().map(|x| x.as_str()) {
+        Some("analyze") => run_analyze(&args[1..]),
+        Some("verify") => run_verify(&args[1..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_analyze(args: &[String]) -> Result<(), String> {
+    let mut input = None;
+    let mut output = None;
+    let mut visualize_dir = None;
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" | "-o" => {
+                output = Some(PathBuf::from(args.next().ok_or("--output requires a path")?));
+            }
+            "--visualize" => {
+                visualize_dir = Some(PathBuf::from(args.next().ok_or("--visualize requires a directory")?));
+            }
+            _ if input.is_none() && !arg.starts_with('-') => {
+                input = Some(PathBuf::from(arg));
+            }
+            _ => {
+                return Err(format!("unexpected argument: {}\n\n{}", arg, USAGE));
+            }
+        }
+    }
+
+    let input = input.ok_or(format!("missing input file\n\n{}", USAGE))?;
+
+    let file = File::open(&input).map_err(|e| format!("failed to open {}: {}", input.display(), e))?;
+
+    #[cfg(feature = "multithreading")]
+    let decoder = Decoder::new(BufReader::new(file), 0);
+
+    #[cfg(not(feature = "multithreading"))]
+    let decoder = Decoder::new(BufReader::new(file));
+
+    let decoder = decoder.map_err(|e| format!("failed to open stream: {:?}", e))?;
+
+    if let Some(dir) = &visualize_dir {
+        std::fs::create_dir_all(dir).map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+    }
+
+    let mut out: Box<dyn Write> = match &output {
+        Some(path) => Box::new(BufWriter::new(File::create(path).map_err(|e| format!("failed to create {}: {}", path.display(), e))?)),
+        None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+
+    let mut analyzer = Analyzer::new(decoder);
+
+    let write_err = |e: std::io::Error| format!("failed to write output: {}", e);
+
+    write!(out, "{{\"width\":{},\"height\":{},\"frames\":[", analyzer.decoder().width(), analyzer.decoder().height()).map_err(write_err)?;
+
+    let mut first = true;
+
+    while let Some(analysis) = analyzer.next_frame().map_err(|e| format!("failed to decode frame: {:?}", e))? {
+        write!(out, "{}\n{}", if first { "" } else { "," }, analysis.to_json()).map_err(write_err)?;
+        first = false;
+
+        if let Some(dir) = &visualize_dir {
+            let path = dir.join(format!("frame_{:06}.ppm", analysis.index));
+            let overlay = visualize(analyzer.current_frame(), &analysis);
+            write_ppm(&path, &overlay).map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+        }
+    }
+
+    writeln!(out, "\n]}}").map_err(write_err)?;
+    out.flush().map_err(write_err)?;
+
+    Ok(())
+}
+
+fn run_verify(args: &[String]) -> Result<(), String> {
+    if args.is_empty() || args.iter().any(|x| x.starts_with('-')) {
+        return Err(format!("expected one or more input files\n\n{}", USAGE));
+    }
+
+    let mut num_invalid = 0;
+
+    for path in args {
+        let file = File::open(path).map_err(|e| format!("failed to open {}: {}", path, e))?;
+        let report = validate(BufReader::new(file));
+
+        println!("{}:", path);
+
+        for line in report.to_string().lines() {
+            println!("    {}", line);
+        }
+
+        if !report.is_valid() {
+            num_invalid += 1;
+        }
+    }
+
+    if num_invalid > 0 {
+        return Err(format!("{} of {} streams are invalid", num_invalid, args.len()));
+    }
+
+    Ok(())
+}
+
+/// Write a frame as a binary PPM image, converting to RGB with the JPEG (full range BT.601) YCbCr matrix
+fn write_ppm(path: &Path, frame: &VideoFrame) -> Result<(), std::io::Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write!(writer, "P6\n{} {}\n255\n", frame.width, frame.height)?;
+
+    let mut row = Vec::with_capacity(frame.width * 3);
+
+    for j in 0..frame.height {
+        row.clear();
+
+        for i in 0..frame.width {
+            let y = frame.plane_y.pixels[j * frame.plane_y.width + i] as f32;
+            let u = frame.plane_u.pixels[(j / 2) * frame.plane_u.width + (i / 2)] as f32 - 128.0;
+            let v = frame.plane_v.pixels[(j / 2) * frame.plane_v.width + (i / 2)] as f32 - 128.0;
+
+            let r = y + (1.402 * v);
+            let g = y - (0.344136 * u) - (0.714136 * v);
+            let b = y + (1.772 * u);
+
+            row.push(r.clamp(0.0, 255.0) as u8);
+            row.push(g.clamp(0.0, 255.0) as u8);
+            row.push(b.clamp(0.0, 255.0) as u8);
+        }
+
+        writer.write_all(&row)?;
+    }
+
+    writer.flush()
+}
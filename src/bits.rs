@@ -0,0 +1,63 @@
+use crate::io;
+
+/// Reads a bitstream from a byte slice, least significant bit of each byte first (the bit order the encoder writes slices in)
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: u64,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0 }
+    }
+
+    /// Number of bits consumed so far
+    pub fn position_in_bits(self: &BitReader<'a>) -> u64 {
+        self.position
+    }
+
+    /// Look at the next `bits` bits (up to 32) without consuming them. Bits past the end of the data read as 0
+    pub fn peek(self: &BitReader<'a>, bits: u32) -> u32 {
+        debug_assert!(bits <= 32);
+
+        let start = (self.position / 8) as usize;
+        let mut window = [0;8];
+
+        if start < self.data.len() {
+            let len = (self.data.len() - start).min(8);
+            window[..len].copy_from_slice(&self.data[start..(start + len)]);
+        }
+
+        let v = u64::from_le_bytes(window) >> (self.position % 8);
+        (v & ((1 << bits) - 1)) as u32
+    }
+
+    /// Advance by the given number of bits, which may move past the end of the data (any read after that fails)
+    pub fn skip(self: &mut BitReader<'a>, bits: u32) {
+        self.position += bits as u64;
+    }
+
+    /// Read an unsigned value of up to 32 bits. The first bit read is the least significant bit of the result
+    pub fn read(self: &mut BitReader<'a>, bits: u32) -> Result<u32, io::Error> {
+        if self.position + bits as u64 > self.data.len() as u64 * 8 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        let v = self.peek(bits);
+        self.position += bits as u64;
+
+        Ok(v)
+    }
+
+    pub fn read_bit(self: &mut BitReader<'a>) -> Result<bool, io::Error> {
+        Ok(self.read(1)? != 0)
+    }
+
+    /// Read a two's complement value of 1 to 32 bits (the sign bit is read last)
+    pub fn read_signed(self: &mut BitReader<'a>, bits: u32) -> Result<i32, io::Error> {
+        debug_assert!(bits > 0 && bits <= 32);
+
+        let shift = 32 - bits;
+        Ok(((self.read(bits)? << shift) as i32) >> shift)
+    }
+}
@@ -0,0 +1,715 @@
+//! C ABI, enabled with the capi feature.
+//!
+//! Decoders & encoders are opaque handles created by the pfv_*_open_* functions and released with the matching close function. Functions which can fail
+//! return PFV_OK (or 1 / 0, for functions documented to do so) on success, and one of the negative PFV_ERROR_* codes otherwise.
+//! include/pfv.h is generated from this module by cbindgen (see build.rs).
+//!
+//! Every function expects the handles & pointers it is given to be valid (null handles are rejected, but dangling ones can't be detected),
+//! and a handle must not be used from two threads at once. Panics are caught at the boundary rather than unwinding into the caller, and reported as PFV_ERROR_PANIC.
+
+#![allow(clippy::missing_safety_doc)]
+
+use std::{cell::RefCell, ffi::{c_char, c_int, c_void, CStr}, fs::File, io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write}, panic::{self, AssertUnwindSafe}, ptr, rc::Rc, slice};
+
+use crate::{audio::{AudioCodec, AudioFormat}, dec::{DecodeError, Decoder, FrameType}, enc::{EncodeError, Encoder}, frame::{FrameTarget, VideoFrame}, plane::VideoPlane, track::TrackId};
+
+pub const PFV_OK: c_int = 0;
+/// Reading or writing the stream failed
+pub const PFV_ERROR_IO: c_int = -1;
+/// Data isn't a PFV stream, or is truncated or corrupt
+pub const PFV_ERROR_FORMAT: c_int = -2;
+/// Stream was written by an unsupported codec version
+pub const PFV_ERROR_VERSION: c_int = -3;
+/// A null pointer, invalid string, or out of range value was passed in
+pub const PFV_ERROR_INVALID_ARGUMENT: c_int = -4;
+/// The call isn't allowed at this point, for example writing metadata after the first frame or encoding after finishing
+pub const PFV_ERROR_STATE: c_int = -5;
+/// An internal error (a Rust panic) occurred. The handle is left in an unspecified state, and should only be closed
+pub const PFV_ERROR_PANIC: c_int = -6;
+
+/// How a frame was coded in the stream
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PfvFrameType {
+    Intra,
+    Predicted,
+    /// Nothing changed since the previous frame
+    Drop,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PfvAudioCodec {
+    Pcm,
+    Qoa,
+}
+
+/// Planes of a decoded I420 frame, pointing into the decoder's frame buffer. Valid until the next call on the decoder
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PfvPlanes {
+    pub width: u32,
+    pub height: u32,
+    pub y: *const u8,
+    pub y_stride: u32,
+    pub u: *const u8,
+    pub v: *const u8,
+    /// Stride of both chroma planes, which are half the width & height of the luma plane
+    pub uv_stride: u32,
+}
+
+/// A frame returned by pfv_decoder_next_frame
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PfvFrame {
+    /// Index of this frame in the stream (drop frames count as frames)
+    pub index: u64,
+    /// Presentation timestamp in seconds
+    pub pts: f64,
+    pub frame_type: PfvFrameType,
+    /// For drop frames these are unchanged from the previous frame
+    pub planes: PfvPlanes,
+}
+
+/// Called by pfv_decoder_advance_delta for every frame which is presented
+pub type PfvVideoCallback = Option<unsafe extern "C" fn(planes: *const PfvPlanes, user_data: *mut c_void)>;
+
+/// Called by pfv_decoder_advance_delta with decoded audio, as `len` interleaved 16-bit samples
+pub type PfvAudioCallback = Option<unsafe extern "C" fn(samples: *const i16, len: usize, user_data: *mut c_void)>;
+
+trait SeekableRead: Read + Seek {}
+
+impl<T: Read + Seek> SeekableRead for T {}
+
+trait SeekableWrite: Write + Seek {}
+
+impl<T: Write + Seek> SeekableWrite for T {}
+
+/// Opaque decoder handle
+pub struct PfvDecoder {
+    decoder: Decoder<Box<dyn SeekableRead>>,
+}
+
+/// Opaque encoder handle
+pub struct PfvEncoder {
+    encoder: Encoder<Box<dyn SeekableWrite>>,
+    frame: VideoFrame,
+    memory: Option<SharedBuffer>,
+}
+
+/// Output of an in-memory encoder, shared with its handle so that it can be read back after encoding
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Cursor<Vec<u8>>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SharedBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.borrow_mut().seek(pos)
+    }
+}
+
+fn io_error(e: io::Error) -> c_int {
+    match e.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => PFV_ERROR_FORMAT,
+        _ => PFV_ERROR_IO
+    }
+}
+
+fn decode_error(e: DecodeError) -> c_int {
+    match e {
+        DecodeError::FormatError => PFV_ERROR_FORMAT,
+        DecodeError::VersionError => PFV_ERROR_VERSION,
+        DecodeError::InvalidTrack => PFV_ERROR_INVALID_ARGUMENT,
+        DecodeError::IOError(e) => io_error(e),
+    }
+}
+
+fn encode_error(e: EncodeError) -> c_int {
+    match e {
+        EncodeError::Io(_) => PFV_ERROR_IO,
+        EncodeError::HeaderWritten | EncodeError::AlreadyFinished | EncodeError::WriteFailed => PFV_ERROR_STATE,
+        _ => PFV_ERROR_INVALID_ARGUMENT
+    }
+}
+
+/// Map Ok(true) to 1, Ok(false) to 0, and errors to an error code
+fn status(result: Result<bool, io::Error>) -> c_int {
+    match result {
+        Ok(v) => v as c_int,
+        Err(e) => io_error(e)
+    }
+}
+
+/// Run the body of an entry point, returning `on_panic` if it panics instead of unwinding across the FFI boundary
+pub(crate) fn guard<T>(on_panic: T, body: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(v) => v,
+        Err(_) => on_panic
+    }
+}
+
+/// Like guard, for the functions which open a handle: a panic returns null & writes PFV_ERROR_PANIC to `error`
+fn guard_open<T>(error: *mut c_int, body: impl FnOnce() -> *mut T) -> *mut T {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(v) => v,
+        Err(_) => {
+            unsafe { set_error(error, PFV_ERROR_PANIC) };
+            ptr::null_mut()
+        }
+    }
+}
+
+unsafe fn set_error(error: *mut c_int, code: c_int) {
+    if !error.is_null() {
+        *error = code;
+    }
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+
+    CStr::from_ptr(s).to_str().ok()
+}
+
+fn planes(frame: &VideoFrame) -> PfvPlanes {
+    PfvPlanes { width: frame.width as u32, height: frame.height as u32,
+        y: frame.plane_y.pixels.as_ptr(), y_stride: frame.plane_y.width as u32,
+        u: frame.plane_u.pixels.as_ptr(), v: frame.plane_v.pixels.as_ptr(), uv_stride: frame.plane_u.width as u32 }
+}
+
+/// View a caller-provided plane of `rows` rows as a slice, or None if it's null or its stride is narrower than `width`
+unsafe fn plane_slice<'a>(data: *const u8, stride: u32, width: usize, rows: usize) -> Option<&'a [u8]> {
+    if data.is_null() || (stride as usize) < width {
+        return None;
+    }
+
+    Some(slice::from_raw_parts(data, stride as usize * rows))
+}
+
+unsafe fn plane_slice_mut<'a>(data: *mut u8, stride: u32, width: usize, rows: usize) -> Option<&'a mut [u8]> {
+    if data.is_null() || (stride as usize) < width {
+        return None;
+    }
+
+    Some(slice::from_raw_parts_mut(data, stride as usize * rows))
+}
+
+fn copy_plane(plane: &mut VideoPlane, src: &[u8], stride: u32) {
+    let width = plane.width;
+
+    for (row, src_row) in plane.pixels.chunks_exact_mut(width).zip(src.chunks(stride as usize)) {
+        row.copy_from_slice(&src_row[..width]);
+    }
+}
+
+fn open_decoder(reader: Box<dyn SeekableRead>, num_threads: u32, error: *mut c_int) -> *mut PfvDecoder {
+    #[cfg(feature = "multithreading")]
+    let decoder = Decoder::new(reader, num_threads.max(1) as usize);
+
+    #[cfg(not(feature = "multithreading"))]
+    let decoder = {
+        let _ = num_threads;
+        Decoder::new(reader)
+    };
+
+    match decoder {
+        Ok(v) => {
+            unsafe { set_error(error, PFV_OK) };
+            Box::into_raw(Box::new(PfvDecoder { decoder: v }))
+        }
+        Err(e) => {
+            unsafe { set_error(error, decode_error(e)) };
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Open a decoder reading from memory. The data is not copied, and must stay valid until the decoder is closed.
+/// num_threads of 0 is treated as 1, and num_threads is ignored if the library was built without the multithreading feature.
+/// Returns null on failure, in which case the error code is written to `error` (which may be null)
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_open_memory(data: *const u8, len: usize, num_threads: u32, error: *mut c_int) -> *mut PfvDecoder {
+    guard_open(error, || {
+        if data.is_null() {
+            set_error(error, PFV_ERROR_INVALID_ARGUMENT);
+            return ptr::null_mut();
+        }
+
+        let data: &'static [u8] = slice::from_raw_parts(data, len);
+        open_decoder(Box::new(Cursor::new(data)), num_threads, error)
+    })
+}
+
+/// Open a decoder reading from a file, given its UTF-8 path. Behaves like pfv_decoder_open_memory otherwise
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_open_file(path: *const c_char, num_threads: u32, error: *mut c_int) -> *mut PfvDecoder {
+    guard_open(error, || {
+        let path = match to_str(path) {
+            Some(v) => v,
+            None => {
+                set_error(error, PFV_ERROR_INVALID_ARGUMENT);
+                return ptr::null_mut();
+            }
+        };
+
+        match File::open(path) {
+            Ok(v) => open_decoder(Box::new(BufReader::new(v)), num_threads, error),
+            Err(_) => {
+                set_error(error, PFV_ERROR_IO);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Release a decoder. Null is ignored
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_close(decoder: *mut PfvDecoder) {
+    guard((), || {
+        if !decoder.is_null() {
+            drop(Box::from_raw(decoder));
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_width(decoder: *const PfvDecoder) -> u32 {
+    guard(0, || {
+        match decoder.as_ref() {
+            Some(v) => v.decoder.width() as u32,
+            None => 0
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_height(decoder: *const PfvDecoder) -> u32 {
+    guard(0, || {
+        match decoder.as_ref() {
+            Some(v) => v.decoder.height() as u32,
+            None => 0
+        }
+    })
+}
+
+/// Get the stream's timebase: each tick lasts num / den seconds
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_timebase(decoder: *const PfvDecoder, num: *mut u32, den: *mut u32) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        let decoder = match decoder.as_ref() {
+            Some(v) => v,
+            None => {
+                return PFV_ERROR_INVALID_ARGUMENT;
+            }
+        };
+
+        if num.is_null() || den.is_null() {
+            return PFV_ERROR_INVALID_ARGUMENT;
+        }
+
+        let timebase = decoder.decoder.timebase();
+        *num = timebase.num;
+        *den = timebase.den;
+
+        PFV_OK
+    })
+}
+
+/// Total number of frames, or -1 if the stream wasn't finalized
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_frame_count(decoder: *const PfvDecoder) -> i64 {
+    guard(-1, || {
+        decoder.as_ref().and_then(|x| x.decoder.frame_count()).map(|x| x as i64).unwrap_or(-1)
+    })
+}
+
+/// Duration in seconds, or -1 if the stream wasn't finalized
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_duration(decoder: *const PfvDecoder) -> f64 {
+    guard(-1.0, || {
+        decoder.as_ref().and_then(|x| x.decoder.duration()).unwrap_or(-1.0)
+    })
+}
+
+/// Channel count of the selected audio track, or 0 if there is none
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_audio_channels(decoder: *const PfvDecoder) -> u32 {
+    guard(0, || {
+        match decoder.as_ref() {
+            Some(v) => v.decoder.channels(),
+            None => 0
+        }
+    })
+}
+
+/// Samplerate of the selected audio track, or 0 if there is none
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_audio_samplerate(decoder: *const PfvDecoder) -> u32 {
+    guard(0, || {
+        match decoder.as_ref() {
+            Some(v) => v.decoder.samplerate(),
+            None => 0
+        }
+    })
+}
+
+/// Select which audio track is handed to the audio callback, or -1 for no audio. Returns PFV_ERROR_INVALID_ARGUMENT if the track is not an audio track
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_select_audio_track(decoder: *mut PfvDecoder, track: c_int) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        let decoder = match decoder.as_mut() {
+            Some(v) => v,
+            None => {
+                return PFV_ERROR_INVALID_ARGUMENT;
+            }
+        };
+
+        let track = if track < 0 {
+            None
+        } else {
+            match TrackId::try_from(track) {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    return PFV_ERROR_INVALID_ARGUMENT;
+                }
+            }
+        };
+
+        match decoder.decoder.select_audio_track(track) {
+            Ok(_) => PFV_OK,
+            Err(e) => decode_error(e)
+        }
+    })
+}
+
+/// Decode the next frame into `frame`. Returns 1 if a frame was decoded, 0 at the end of the stream, or an error code
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_next_frame(decoder: *mut PfvDecoder, frame: *mut PfvFrame) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        let decoder = match decoder.as_mut() {
+            Some(v) => v,
+            None => {
+                return PFV_ERROR_INVALID_ARGUMENT;
+            }
+        };
+
+        if frame.is_null() {
+            return PFV_ERROR_INVALID_ARGUMENT;
+        }
+
+        match decoder.decoder.next_frame() {
+            Ok(Some(v)) => {
+                let frame_type = match v.frame_type {
+                    FrameType::Intra => PfvFrameType::Intra,
+                    FrameType::Predicted => PfvFrameType::Predicted,
+                    FrameType::Drop => PfvFrameType::Drop,
+                };
+
+                *frame = PfvFrame { index: v.index, pts: v.pts, frame_type, planes: planes(v.frame) };
+                1
+            }
+            Ok(None) => 0,
+            Err(e) => decode_error(e)
+        }
+    })
+}
+
+/// Decode the next frame directly into caller-provided I420 buffers. Each plane must hold stride * rows bytes, and chroma planes are half the width & height of the luma plane.
+/// Returns 1 if a frame was decoded, 0 at the end of the stream, or an error code
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_next_frame_into(decoder: *mut PfvDecoder, y: *mut u8, y_stride: u32, u: *mut u8, u_stride: u32, v: *mut u8, v_stride: u32) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        let decoder = match decoder.as_mut() {
+            Some(v) => v,
+            None => {
+                return PFV_ERROR_INVALID_ARGUMENT;
+            }
+        };
+
+        let width = decoder.decoder.width();
+        let height = decoder.decoder.height();
+
+        let planes = (plane_slice_mut(y, y_stride, width, height), plane_slice_mut(u, u_stride, width / 2, height / 2), plane_slice_mut(v, v_stride, width / 2, height / 2));
+
+        match planes {
+            (Some(y), Some(u), Some(v)) => {
+                let mut target = FrameTarget { y, y_stride: y_stride as usize, u, u_stride: u_stride as usize, v, v_stride: v_stride as usize };
+                status(decoder.decoder.decode_next_into(&mut target))
+            }
+            _ => PFV_ERROR_INVALID_ARGUMENT
+        }
+    })
+}
+
+/// Advance the playback clock by `delta` seconds, presenting every frame whose timestamp has been reached. Either callback may be null.
+/// Returns 1 while the stream is still playing, 0 once it has ended, or an error code
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_advance_delta(decoder: *mut PfvDecoder, delta: f64, on_video: PfvVideoCallback, on_audio: PfvAudioCallback, user_data: *mut c_void) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        let decoder = match decoder.as_mut() {
+            Some(v) => v,
+            None => {
+                return PFV_ERROR_INVALID_ARGUMENT;
+            }
+        };
+
+        status(decoder.decoder.advance_delta_with_audio(delta, &mut |frame| {
+            if let Some(f) = on_video {
+                f(&planes(frame), user_data);
+            }
+        }, &mut |samples| {
+            if let Some(f) = on_audio {
+                f(samples.as_ptr(), samples.len(), user_data);
+            }
+        }))
+    })
+}
+
+/// Seek so that the next frame decoded is the given frame, and set the playback clock to its timestamp.
+/// Returns 1 on success, 0 if the stream has fewer frames, or an error code
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_seek(decoder: *mut PfvDecoder, frame: u64) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        match decoder.as_mut() {
+            Some(v) => status(v.decoder.seek(frame)),
+            None => PFV_ERROR_INVALID_ARGUMENT
+        }
+    })
+}
+
+/// Rewind to the first frame of the stream
+#[no_mangle]
+pub unsafe extern "C" fn pfv_decoder_reset(decoder: *mut PfvDecoder) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        match decoder.as_mut() {
+            Some(v) => status(v.decoder.reset().map(|_| false)),
+            None => PFV_ERROR_INVALID_ARGUMENT
+        }
+    })
+}
+
+fn open_encoder(writer: Box<dyn SeekableWrite>, memory: Option<SharedBuffer>, width: u32, height: u32, framerate: u32, quality: c_int, num_threads: u32) -> Result<PfvEncoder, c_int> {
+    #[cfg(feature = "multithreading")]
+    let encoder = Encoder::new(writer, width as usize, height as usize, framerate, quality, num_threads.max(1) as usize);
+
+    #[cfg(not(feature = "multithreading"))]
+    let encoder = {
+        let _ = num_threads;
+        Encoder::new(writer, width as usize, height as usize, framerate, quality)
+    };
+
+    match encoder {
+        Ok(v) => Ok(PfvEncoder { encoder: v, frame: VideoFrame::new(width as usize, height as usize), memory }),
+        Err(e) => Err(encode_error(e))
+    }
+}
+
+/// Turn the result of open_encoder into a handle, writing the error code to `error`
+unsafe fn encoder_handle(result: Result<PfvEncoder, c_int>, error: *mut c_int) -> *mut PfvEncoder {
+    match result {
+        Ok(v) => {
+            set_error(error, PFV_OK);
+            Box::into_raw(Box::new(v))
+        }
+        Err(e) => {
+            set_error(error, e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Open an encoder writing to a file, given its UTF-8 path. Quality ranges from 0 to 10. num_threads of 0 is treated as 1, and num_threads is ignored if the library was built without the multithreading feature.
+/// Returns null on failure, in which case the error code is written to `error` (which may be null)
+#[no_mangle]
+pub unsafe extern "C" fn pfv_encoder_open_file(path: *const c_char, width: u32, height: u32, framerate: u32, quality: c_int, num_threads: u32, error: *mut c_int) -> *mut PfvEncoder {
+    guard_open(error, || {
+        let path = match to_str(path) {
+            Some(v) => v,
+            None => {
+                set_error(error, PFV_ERROR_INVALID_ARGUMENT);
+                return ptr::null_mut();
+            }
+        };
+
+        match File::create(path) {
+            Ok(v) => encoder_handle(open_encoder(Box::new(BufWriter::new(v)), None, width, height, framerate, quality, num_threads), error),
+            Err(_) => {
+                set_error(error, PFV_ERROR_IO);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Open an encoder writing to memory, otherwise behaving like pfv_encoder_open_file. Read the result back with pfv_encoder_memory once the encoder has finished
+#[no_mangle]
+pub unsafe extern "C" fn pfv_encoder_open_memory(width: u32, height: u32, framerate: u32, quality: c_int, num_threads: u32, error: *mut c_int) -> *mut PfvEncoder {
+    guard_open(error, || {
+        let buffer = SharedBuffer::default();
+        encoder_handle(open_encoder(Box::new(buffer.clone()), Some(buffer), width, height, framerate, quality, num_threads), error)
+    })
+}
+
+/// Release an encoder. An encoder which hasn't been finished is finished first, but without filling in the header's totals (see pfv_encoder_finish). Null is ignored
+#[no_mangle]
+pub unsafe extern "C" fn pfv_encoder_close(encoder: *mut PfvEncoder) {
+    guard((), || {
+        if !encoder.is_null() {
+            drop(Box::from_raw(encoder));
+        }
+    })
+}
+
+/// Add a metadata entry (see the KEY_* constants of the Rust metadata module for well-known keys). Must be called before the first frame
+#[no_mangle]
+pub unsafe extern "C" fn pfv_encoder_write_metadata(encoder: *mut PfvEncoder, key: *const c_char, value: *const c_char) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        match (encoder.as_mut(), to_str(key), to_str(value)) {
+            (Some(encoder), Some(key), Some(value)) => {
+                match encoder.encoder.write_metadata(key, value) {
+                    Ok(_) => PFV_OK,
+                    Err(e) => encode_error(e)
+                }
+            }
+            _ => PFV_ERROR_INVALID_ARGUMENT
+        }
+    })
+}
+
+/// Give the stream an audio track. Must be called before the first frame
+#[no_mangle]
+pub unsafe extern "C" fn pfv_encoder_set_audio_format(encoder: *mut PfvEncoder, codec: PfvAudioCodec, channels: u32, samplerate: u32) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        let encoder = match encoder.as_mut() {
+            Some(v) => v,
+            None => {
+                return PFV_ERROR_INVALID_ARGUMENT;
+            }
+        };
+
+        let codec = match codec {
+            PfvAudioCodec::Pcm => AudioCodec::Pcm,
+            PfvAudioCodec::Qoa => AudioCodec::Qoa,
+        };
+
+        match encoder.encoder.set_audio_format(AudioFormat { codec, channels, samplerate }) {
+            Ok(_) => PFV_OK,
+            Err(e) => encode_error(e)
+        }
+    })
+}
+
+/// Encode `len` interleaved 16-bit samples of audio, continuing on from the previously encoded samples
+#[no_mangle]
+pub unsafe extern "C" fn pfv_encoder_encode_audio(encoder: *mut PfvEncoder, samples: *const i16, len: usize) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        let encoder = match encoder.as_mut() {
+            Some(v) => v,
+            None => {
+                return PFV_ERROR_INVALID_ARGUMENT;
+            }
+        };
+
+        if samples.is_null() {
+            return PFV_ERROR_INVALID_ARGUMENT;
+        }
+
+        match encoder.encoder.encode_audio(slice::from_raw_parts(samples, len)) {
+            Ok(_) => PFV_OK,
+            Err(e) => encode_error(e)
+        }
+    })
+}
+
+/// Encode an I420 frame, choosing between I-frame & P-frame automatically. Each plane must hold stride * rows bytes, and chroma planes are half the width & height of the luma plane
+#[no_mangle]
+pub unsafe extern "C" fn pfv_encoder_encode_frame(encoder: *mut PfvEncoder, y: *const u8, y_stride: u32, u: *const u8, u_stride: u32, v: *const u8, v_stride: u32) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        let encoder = match encoder.as_mut() {
+            Some(v) => v,
+            None => {
+                return PFV_ERROR_INVALID_ARGUMENT;
+            }
+        };
+
+        let frame = &mut encoder.frame;
+        let planes = (plane_slice(y, y_stride, frame.plane_y.width, frame.plane_y.height),
+            plane_slice(u, u_stride, frame.plane_u.width, frame.plane_u.height),
+            plane_slice(v, v_stride, frame.plane_v.width, frame.plane_v.height));
+
+        match planes {
+            (Some(y), Some(u), Some(v)) => {
+                copy_plane(&mut frame.plane_y, y, y_stride);
+                copy_plane(&mut frame.plane_u, u, u_stride);
+                copy_plane(&mut frame.plane_v, v, v_stride);
+            }
+            _ => {
+                return PFV_ERROR_INVALID_ARGUMENT;
+            }
+        }
+
+        match encoder.encoder.encode_frame(&encoder.frame) {
+            Ok(_) => PFV_OK,
+            Err(e) => encode_error(e)
+        }
+    })
+}
+
+/// Encode a drop frame, which repeats the previous frame
+#[no_mangle]
+pub unsafe extern "C" fn pfv_encoder_encode_dropframe(encoder: *mut PfvEncoder) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        match encoder.as_mut() {
+            Some(v) => {
+                match v.encoder.encode_dropframe() {
+                    Ok(_) => PFV_OK,
+                    Err(e) => encode_error(e)
+                }
+            }
+            None => PFV_ERROR_INVALID_ARGUMENT
+        }
+    })
+}
+
+/// Finish the stream & fill in the header's frame count, duration, and index offset
+#[no_mangle]
+pub unsafe extern "C" fn pfv_encoder_finish(encoder: *mut PfvEncoder) -> c_int {
+    guard(PFV_ERROR_PANIC, || {
+        match encoder.as_mut() {
+            Some(v) => {
+                match v.encoder.finalize() {
+                    Ok(_) => PFV_OK,
+                    Err(e) => encode_error(e)
+                }
+            }
+            None => PFV_ERROR_INVALID_ARGUMENT
+        }
+    })
+}
+
+/// Get the data written so far by an encoder opened with pfv_encoder_open_memory, writing its length to `len`.
+/// The pointer is valid until the next call on the encoder. Returns null for file encoders
+#[no_mangle]
+pub unsafe extern "C" fn pfv_encoder_memory(encoder: *const PfvEncoder, len: *mut usize) -> *const u8 {
+    guard(ptr::null(), || {
+        match encoder.as_ref().and_then(|x| x.memory.as_ref()) {
+            Some(buffer) if !len.is_null() => {
+                let data = buffer.0.borrow();
+                *len = data.get_ref().len();
+                data.get_ref().as_ptr()
+            }
+            _ => ptr::null()
+        }
+    })
+}
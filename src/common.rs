@@ -1,7 +1,38 @@
 pub const PFV_MAGIC: &[u8] = b"PFVIDEO\0";
-pub const PFV_VERSION: u32 = 211;
+pub const PFV_VERSION: u32 = 350;
 
-use crate::{dct::{DctQuantizedMatrix8x8, DctMatrix8x8, FP_BITS}, plane::VideoPlane};
+/// Codec version 2.1.1 - frames are coded as a single bitstream with no slice table
+pub const PFV_VERSION_LEGACY: u32 = 211;
+
+/// Header flag: frame packet headers carry a 64-bit presentation timestamp (in timebase ticks) for variable framerate content
+pub const PFV_FLAG_TIMESTAMPS: u8 = 1;
+
+/// Header flag: the stream info block (frame count, duration, max packet size, index offset) has been filled in by the encoder
+pub const PFV_FLAG_FINALIZED: u8 = 2;
+
+/// Offset of the header flags byte from the start of the stream. The stream info block immediately follows it
+#[cfg(feature = "std")]
+pub const PFV_FLAGS_OFFSET: u64 = 24;
+
+/// Size of the stream info block in bytes
+#[cfg(feature = "std")]
+pub const PFV_STREAM_INFO_LEN: u64 = 28;
+
+/// Largest motion vector component magnitude which fits in a P-frame block header
+pub const MAX_MOTION_VECTOR: i32 = 63;
+
+/// Packet types
+pub const PFV_PACKET_EOF: u8 = 0;
+pub const PFV_PACKET_IFRAME: u8 = 1;
+pub const PFV_PACKET_PFRAME: u8 = 2;
+pub const PFV_PACKET_USER_DATA: u8 = 3;
+pub const PFV_PACKET_AUDIO: u8 = 4;
+pub const PFV_PACKET_SUBTITLE: u8 = 5;
+pub const PFV_PACKET_INDEX: u8 = 6;
+
+use alloc::{vec, vec::Vec};
+
+use crate::{dct::{DctQuantizedMatrix8x8, DctMatrix8x8, RdoModel, FP_BITS}, plane::VideoPlane, simd};
 
 #[cfg(feature = "multithreading")]
 use rayon::prelude::*;
@@ -22,12 +53,14 @@ pub struct MacroBlock {
     pub pixels: [u8;256]
 }
 
+#[cfg(feature = "std")]
 pub struct EncodedIFrame {
     pub y: EncodedIPlane,
     pub u: EncodedIPlane,
     pub v: EncodedIPlane,
 }
 
+#[cfg(feature = "std")]
 pub struct EncodedPFrame {
     pub y: EncodedPPlane,
     pub u: EncodedPPlane,
@@ -50,6 +83,21 @@ pub struct EncodedPPlane {
     pub blocks: Vec<DeltaEncodedMacroBlock>,
 }
 
+/// Motion search settings for P-frame blocks
+#[derive(Clone, Copy)]
+pub struct MotionSearch {
+    /// Blocks whose motion-compensated error is at or below this are coded as skips
+    pub skip_err: u64,
+    /// Initial step size of the search (see VideoPlane::search_step)
+    pub search_step: i32,
+}
+
+impl MotionSearch {
+    pub fn new(skip_err: u64, search_range: u32) -> MotionSearch {
+        MotionSearch { skip_err, search_step: VideoPlane::search_step(search_range) }
+    }
+}
+
 pub struct DeltaBlock {
     pub width: usize,
     pub height: usize,
@@ -95,7 +143,28 @@ impl MacroBlock {
         }
     }
 
+    /// Copy this block into a pixel buffer with the given row stride, starting at column dx of the first row
+    pub fn blit_into(self: &MacroBlock, target: &mut [u8], stride: usize, dx: usize) {
+        if simd::copy_rows(&mut target[dx..], stride, &self.pixels, 16, 16, 16) {
+            return;
+        }
+
+        for row in 0..16 {
+            let src_offset = row * 16;
+            let dst_offset = (row * stride) + dx;
+
+            target[dst_offset..(dst_offset + 16)].copy_from_slice(&self.pixels[src_offset..(src_offset + 16)]);
+        }
+    }
+
     pub fn apply_residuals(self: &mut MacroBlock, from: &MacroBlock) {
+        if !simd::apply_residuals(&mut self.pixels, &from.pixels) {
+            self.apply_residuals_scalar(from);
+        }
+    }
+
+    /// Portable version of `apply_residuals`
+    pub fn apply_residuals_scalar(self: &mut MacroBlock, from: &MacroBlock) {
         for (delta, pixel) in self.pixels.iter_mut().zip(from.pixels) {
             let d = (*delta as i16 - 128) * 2;
             let p = pixel as i16;
@@ -122,15 +191,26 @@ impl VideoPlane {
         residuals
     }
 
-    fn calc_error(from: &VideoPlane, to: &VideoPlane, ref_lms: f32) -> f32 {
+    /// Sum of squared differences between two planes, in exact integer math so that encoding decisions don't depend on floating point behavior
+    pub(crate) fn calc_error(from: &VideoPlane, to: &VideoPlane, ref_err: u64) -> u64 {
         assert!(from.width == to.width && from.height == to.height);
 
-        let mut sum = 0.0;
+        match simd::calc_error(&from.pixels, &to.pixels, ref_err) {
+            Some(v) => v,
+            None => VideoPlane::calc_error_scalar(from, to, ref_err)
+        }
+    }
+
+    /// Portable version of `calc_error`
+    pub(crate) fn calc_error_scalar(from: &VideoPlane, to: &VideoPlane, ref_err: u64) -> u64 {
+        assert!(from.width == to.width && from.height == to.height);
+
+        let mut sum = 0;
 
         for (_, (a, b)) in from.pixels.iter().zip(&to.pixels).enumerate() {
-            let diff = *a as f32 - *b as f32;
-            sum += diff * diff;
-            if sum >= ref_lms {
+            let diff = *a as i32 - *b as i32;
+            sum += (diff * diff) as u64;
+            if sum >= ref_err {
                 return sum;
             }
         }
@@ -138,23 +218,23 @@ impl VideoPlane {
         return sum;
     }
 
-    fn encode_block(src: &VideoPlane, q_table: &[i32;64]) -> EncodedMacroBlock {
+    fn encode_block(src: &VideoPlane, q_table: &[i32;64], rdo: Option<&RdoModel>) -> EncodedMacroBlock {
         debug_assert!(src.width == 16 && src.height == 16);
 
         // split into 4 subblocks and encode each one
         let subblocks = [
-            VideoPlane::encode_subblock(&src.get_slice(0, 0, 8, 8), q_table),
-            VideoPlane::encode_subblock(&src.get_slice(8, 0, 8, 8), q_table),
-            VideoPlane::encode_subblock(&src.get_slice(0, 8, 8, 8), q_table),
-            VideoPlane::encode_subblock(&src.get_slice(8, 8, 8, 8), q_table)];
+            VideoPlane::encode_subblock(&src.get_slice(0, 0, 8, 8), q_table, rdo),
+            VideoPlane::encode_subblock(&src.get_slice(8, 0, 8, 8), q_table, rdo),
+            VideoPlane::encode_subblock(&src.get_slice(0, 8, 8, 8), q_table, rdo),
+            VideoPlane::encode_subblock(&src.get_slice(8, 8, 8, 8), q_table, rdo)];
 
         EncodedMacroBlock { subblocks: subblocks }
     }
 
-    fn block_search(src: &VideoPlane, refplane: &VideoPlane, cx: i32, cy: i32, stepsize: i32) -> (i32, i32, f32, VideoPlane) {
+    fn block_search(src: &VideoPlane, refplane: &VideoPlane, cx: i32, cy: i32, stepsize: i32) -> (i32, i32, u64, VideoPlane) {
         let mut best_dx = 0;
         let mut best_dy = 0;
-        let mut best_err = f32::INFINITY;
+        let mut best_err = u64::MAX;
         let mut best_slice = VideoPlane::new(16, 16);
 
         // test center point first
@@ -166,6 +246,10 @@ impl VideoPlane {
 
         // search 8 locations around center point at multiples of step size
         for my in -1..2 {
+            if stepsize == 0 {
+                // motion search disabled
+                break;
+            }
 
             let offsy = cy + (my * stepsize);
             if offsy < 0 || offsy > refplane.height as i32 - 16 {
@@ -203,13 +287,30 @@ impl VideoPlane {
         }
     }
 
-    fn encode_block_delta(src: &VideoPlane, refplane: &VideoPlane, bx: usize, by: usize, q_table: &[i32;64], px_err: f32) -> DeltaEncodedMacroBlock {
-        debug_assert!(src.width == 16 && src.height == 16);
+    /// Initial step size of the motion search for the given search range. Each step halves the step size,
+    /// so starting at step size N covers offsets of up to 2N - 1 pixels
+    fn search_step(search_range: u32) -> i32 {
+        // motion vector components are stored as 7 bit signed values
+        let range = search_range.min(MAX_MOTION_VECTOR as u32) as i32;
+
+        if range == 0 {
+            return 0;
+        }
 
-        let min_err = px_err * px_err * 256.0;
+        let mut step = 1;
 
-        // four step search around block pos to find delta which minimizes error
-        let (best_dx, best_dy, best_err, prev_block) = VideoPlane::block_search(src, refplane, bx as i32, by as i32, 8);
+        while (step * 4) - 1 <= range {
+            step *= 2;
+        }
+
+        step
+    }
+
+    fn encode_block_delta(src: &VideoPlane, refplane: &VideoPlane, bx: usize, by: usize, q_table: &[i32;64], rdo: Option<&RdoModel>, motion: MotionSearch) -> DeltaEncodedMacroBlock {
+        debug_assert!(src.width == 16 && src.height == 16);
+
+        // step search around block pos to find delta which minimizes error
+        let (best_dx, best_dy, best_err, prev_block) = VideoPlane::block_search(src, refplane, bx as i32, by as i32, motion.search_step);
 
         let sx = bx as i32 + best_dx;
         let sy = by as i32 + best_dy;
@@ -218,7 +319,7 @@ impl VideoPlane {
         assert!(sy >= 0 && sy <= refplane.height as i32 - 16);
 
         // if the best delta is small enough, skip coefficients
-        if best_err <= min_err {
+        if best_err <= motion.skip_err {
             DeltaEncodedMacroBlock { motion_x: best_dx as i8, motion_y: best_dy as i8, subblocks: None }
         } else {
             // generate delta values
@@ -226,16 +327,21 @@ impl VideoPlane {
 
             // split into 4 subblocks and encode each one
             let subblocks = [
-                VideoPlane::encode_subblock_delta(&delta_block.get_slice(0, 0, 8, 8), q_table),
-                VideoPlane::encode_subblock_delta(&delta_block.get_slice(8, 0, 8, 8), q_table),
-                VideoPlane::encode_subblock_delta(&delta_block.get_slice(0, 8, 8, 8), q_table),
-                VideoPlane::encode_subblock_delta(&delta_block.get_slice(8, 8, 8, 8), q_table)];
+                VideoPlane::encode_subblock_delta(&delta_block.get_slice(0, 0, 8, 8), q_table, rdo),
+                VideoPlane::encode_subblock_delta(&delta_block.get_slice(8, 0, 8, 8), q_table, rdo),
+                VideoPlane::encode_subblock_delta(&delta_block.get_slice(0, 8, 8, 8), q_table, rdo),
+                VideoPlane::encode_subblock_delta(&delta_block.get_slice(8, 8, 8, 8), q_table, rdo)];
+
+            // RDO may zero out the whole residual, in which case the block is cheaper to code as skipped
+            if rdo.is_some() && subblocks.iter().all(|x| x.m.iter().all(|c| *c == 0)) {
+                return DeltaEncodedMacroBlock { motion_x: best_dx as i8, motion_y: best_dy as i8, subblocks: None };
+            }
 
             DeltaEncodedMacroBlock { motion_x: best_dx as i8, motion_y: best_dy as i8, subblocks: Some(subblocks) }
         }
     }
     
-    fn decode_block(src: &EncodedMacroBlock, q_table: &[i32;64]) -> MacroBlock {
+    pub(crate) fn decode_block(src: &EncodedMacroBlock, q_table: &[i32;64]) -> MacroBlock {
         let subblocks = [
             VideoPlane::decode_subblock(&src.subblocks[0], q_table),
             VideoPlane::decode_subblock(&src.subblocks[1], q_table),
@@ -251,7 +357,7 @@ impl VideoPlane {
         block
     }
 
-    fn decode_block_delta(src: &DeltaEncodedMacroBlock, refplane: &VideoPlane, bx: usize, by: usize, q_table: &[i32;64]) -> MacroBlock {
+    pub(crate) fn decode_block_delta(src: &DeltaEncodedMacroBlock, refplane: &VideoPlane, bx: usize, by: usize, q_table: &[i32;64]) -> MacroBlock {
         let sx = bx as i32 + src.motion_x as i32;
         let sy = by as i32 + src.motion_y as i32;
 
@@ -284,7 +390,7 @@ impl VideoPlane {
         };
     }
 
-    fn encode_subblock(src: &VideoPlane, q_table: &[i32;64]) -> DctQuantizedMatrix8x8 {
+    fn encode_subblock(src: &VideoPlane, q_table: &[i32;64], rdo: Option<&RdoModel>) -> DctQuantizedMatrix8x8 {
         assert!(src.width == 8 && src.height == 8);
 
         let mut dct = DctMatrix8x8::new();
@@ -294,10 +400,13 @@ impl VideoPlane {
         dct.dct_transform_rows();
         dct.dct_transform_columns();
 
-        dct.encode(q_table)
+        match rdo {
+            Some(model) => dct.encode_rdo(q_table, model),
+            None => dct.encode(q_table)
+        }
     }
 
-    fn encode_subblock_delta(src: &DeltaBlock, q_table: &[i32;64]) -> DctQuantizedMatrix8x8 {
+    fn encode_subblock_delta(src: &DeltaBlock, q_table: &[i32;64], rdo: Option<&RdoModel>) -> DctQuantizedMatrix8x8 {
         assert!(src.width == 8 && src.height == 8);
 
         let mut dct = DctMatrix8x8::new();
@@ -307,7 +416,10 @@ impl VideoPlane {
         dct.dct_transform_rows();
         dct.dct_transform_columns();
 
-        dct.encode(q_table)
+        match rdo {
+            Some(model) => dct.encode_rdo(q_table, model),
+            None => dct.encode(q_table)
+        }
     }
 
     fn decode_subblock(src: &DctQuantizedMatrix8x8, q_table: &[i32;64]) -> [u8;64] {
@@ -327,6 +439,11 @@ impl VideoPlane {
     pub fn get_block(self: &VideoPlane, sx: usize, sy: usize) -> MacroBlock {
         let mut dest: MacroBlock = MacroBlock { pixels: [0;256] };
 
+        let src_offset = (sy * self.width) + sx;
+        if simd::copy_rows(&mut dest.pixels, 16, &self.pixels[src_offset..], self.width, 16, 16) {
+            return dest;
+        }
+
         for row in 0..16 {
             let src_row = row + sy;
             let src_offset = (src_row * self.width) + sx;
@@ -339,6 +456,12 @@ impl VideoPlane {
     }
 
     pub fn blit_block(self: &mut VideoPlane, block: &MacroBlock, dx: usize, dy: usize) {
+        let dst_offset = (dy * self.width) + dx;
+        let dst_stride = self.width;
+        if simd::copy_rows(&mut self.pixels[dst_offset..], dst_stride, &block.pixels, 16, 16, 16) {
+            return;
+        }
+
         for row in 0..16 {
             let dest_row = row + dy;
             let src_offset = row * 16;
@@ -348,7 +471,7 @@ impl VideoPlane {
         }
     }
 
-    pub fn encode_plane(self: &VideoPlane, q_table: &[i32;64], clear_color: u8, #[cfg(feature = "multithreading")] tp: &rayon::ThreadPool) -> EncodedIPlane {
+    pub fn encode_plane(self: &VideoPlane, q_table: &[i32;64], rdo: Option<&RdoModel>, clear_color: u8, #[cfg(feature = "multithreading")] tp: &rayon::ThreadPool) -> EncodedIPlane {
         let pad_width: usize = self.width + (16 - (self.width % 16)) % 16;
         let pad_height = self.height + (16 - (self.height % 16)) % 16;
         let mut img_copy = VideoPlane::new(pad_width, pad_height);
@@ -373,19 +496,19 @@ impl VideoPlane {
         #[cfg(feature = "multithreading")]
         let enc_result: Vec<_> = tp.install(|| {
             blocks.par_iter().map(|x| {
-                VideoPlane::encode_block(x, q_table)
+                VideoPlane::encode_block(x, q_table, rdo)
             }).collect()
         });
 
         #[cfg(not(feature = "multithreading"))]
         let enc_result: Vec<_> = blocks.iter().map(|x| {
-            VideoPlane::encode_block(x, q_table)
+            VideoPlane::encode_block(x, q_table, rdo)
         }).collect();
 
         EncodedIPlane { width: pad_width, height: pad_height, blocks_wide: blocks_wide, blocks_high: blocks_high, blocks: enc_result }
     }
 
-    pub fn encode_plane_delta(self: &VideoPlane, refplane: &VideoPlane, q_table: &[i32;64], px_err: f32, clear_color: u8, #[cfg(feature = "multithreading")] tp: &rayon::ThreadPool) -> EncodedPPlane {
+    pub fn encode_plane_delta(self: &VideoPlane, refplane: &VideoPlane, q_table: &[i32;64], rdo: Option<&RdoModel>, motion: MotionSearch, clear_color: u8, #[cfg(feature = "multithreading")] tp: &rayon::ThreadPool) -> EncodedPPlane {
         let pad_width: usize = self.width + (16 - (self.width % 16)) % 16;
         let pad_height = self.height + (16 - (self.height % 16)) % 16;
         let mut img_copy = VideoPlane::new(pad_width, pad_height);
@@ -409,12 +532,12 @@ impl VideoPlane {
         // encode each macroblock in parallel
         #[cfg(feature = "multithreading")]
         let enc_result: Vec<_> = tp.install(|| {blocks.par_iter().map(|(block, bx, by)| {
-            VideoPlane::encode_block_delta(block, refplane, *bx, *by, q_table, px_err)
+            VideoPlane::encode_block_delta(block, refplane, *bx, *by, q_table, rdo, motion)
         }).collect()});
 
         #[cfg(not(feature = "multithreading"))]
         let enc_result: Vec<_> = blocks.iter().map(|(block, bx, by)| {
-            VideoPlane::encode_block_delta(block, refplane, *bx, *by, q_table, px_err)
+            VideoPlane::encode_block_delta(block, refplane, *bx, *by, q_table, rdo, motion)
         }).collect();
 
         EncodedPPlane { width: pad_width, height: pad_height, blocks_wide: blocks_wide, blocks_high: blocks_high, blocks: enc_result }
@@ -474,52 +597,6 @@ impl VideoPlane {
         plane
     }
 
-    pub fn decode_plane_into(src: &EncodedIPlane, q_table: &[i32;64], target: &mut VideoPlane, #[cfg(feature = "multithreading")] tp: &rayon::ThreadPool) {
-        let total_blocks = src.blocks_wide * src.blocks_high;
-
-        #[cfg(feature = "multithreading")]
-        let results: Vec<_> = tp.install(|| {(0..total_blocks).into_par_iter().map(|x| {
-            VideoPlane::decode_block(&src.blocks[x], q_table)
-        }).collect()});
-
-        #[cfg(not(feature = "multithreading"))]
-        let results: Vec<_> = (0..total_blocks).into_iter().map(|x| {
-            VideoPlane::decode_block(&src.blocks[x], q_table)
-        }).collect();
-
-        for block_y in 0..src.blocks_high {
-            for block_x in 0..src.blocks_wide {
-                let block = &results[block_x + (block_y * src.blocks_wide)];
-                target.blit_block(block, block_x * 16, block_y * 16);
-            }
-        }
-    }
-
-    pub fn decode_plane_delta_into(src: &EncodedPPlane, refplane: &mut VideoPlane, q_table: &[i32;64], #[cfg(feature = "multithreading")] tp: &rayon::ThreadPool) {
-        let total_blocks = src.blocks_wide * src.blocks_high;
-
-        #[cfg(feature = "multithreading")]
-        let results: Vec<_> = tp.install(|| {(0..total_blocks).into_par_iter().map(|x| {
-            let bx = x % src.blocks_wide;
-            let by = x / src.blocks_wide;
-            VideoPlane::decode_block_delta(&src.blocks[x], refplane, bx * 16, by * 16, q_table)
-        }).collect()});
-
-        #[cfg(not(feature = "multithreading"))]
-        let results: Vec<_> = (0..total_blocks).into_iter().map(|x| {
-            let bx = x % src.blocks_wide;
-            let by = x / src.blocks_wide;
-            VideoPlane::decode_block_delta(&src.blocks[x], refplane, bx * 16, by * 16, q_table)
-        }).collect();
-
-        for block_y in 0..src.blocks_high {
-            for block_x in 0..src.blocks_wide {
-                let block = &results[block_x + (block_y * src.blocks_wide)];
-                refplane.blit_block(block, block_x * 16, block_y * 16);
-            }
-        }
-    }
-
     pub fn reduce(self: &VideoPlane) -> VideoPlane {
         let mut new_slice = VideoPlane::new(self.width / 2, self.height / 2);
 
@@ -1,3 +1,5 @@
+use crate::simd;
+
 pub const FP_BITS: i32 = 8;
 
 /// Scale factors to be applied to coefficients at encode & decode time, in 24.8 fixed point
@@ -13,6 +15,7 @@ pub static DCT_SCALE_FACTOR: [i32;64] = [
 ];
 
 /// Quantization table for intra-frames (I-Frames)
+#[cfg(feature = "std")]
 pub static Q_TABLE_INTRA: [i32;64] = [
     8, 16, 19, 22, 26, 27, 29, 34,
     16, 16, 22, 24, 27, 29, 34, 37,
@@ -25,6 +28,7 @@ pub static Q_TABLE_INTRA: [i32;64] = [
 ];
 
 /// Quantization table for inter-frames (P-Frames)
+#[cfg(feature = "std")]
 pub static Q_TABLE_INTER: [i32;64] = [
     16, 16, 16, 16, 16, 16, 16, 16,
     16, 16, 16, 16, 16, 16, 16, 16,
@@ -46,6 +50,34 @@ pub static ZIGZAG_TABLE: [usize;64] = [
 15, 23, 30, 37, 44, 51, 58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63
 ];
 
+/// Largest quantized coefficient magnitude which can be RLE coded (coefficient sizes must stay below 16 bits including the sign)
+pub const MAX_QUANTIZED_COEFF: i32 = (1 << 14) - 1;
+
+/// Cost model used for rate-distortion optimized quantization
+#[derive(Clone, Copy, Debug)]
+pub struct RdoModel {
+    /// Estimated length in bits of each RLE symbol's huffman code
+    pub symbol_bits: [f32;16],
+    /// Lagrange multiplier weighing bits against squared error, relative to the mean squared quantizer step size
+    pub lambda: f32,
+}
+
+impl RdoModel {
+    pub fn new(lambda: f32) -> RdoModel {
+        // until actual code lengths are known, assume every symbol is equally likely
+        RdoModel { symbol_bits: [4.0;16], lambda }
+    }
+
+    /// Update symbol costs from the huffman code lengths of the last coded frame. Symbols which weren't coded at all are assumed to be expensive
+    pub fn update(self: &mut RdoModel, code_lengths: &[u32;16]) {
+        let max_len = code_lengths.iter().max().copied().unwrap_or(0);
+
+        for (bits, len) in self.symbol_bits.iter_mut().zip(code_lengths) {
+            *bits = if *len > 0 { *len as f32 } else { (max_len + 2) as f32 };
+        }
+    }
+}
+
 /// Represents an 8x8 row-order matrix of DCT coefficients
 #[derive(Clone, Copy, Debug)]
 pub struct DctMatrix8x8 {
@@ -98,6 +130,109 @@ impl DctMatrix8x8 {
         result
     }
 
+    /// Rate-distortion optimized quantization. For each coefficient, the truncated level, the next level up or down, and zero are considered,
+    /// and the combination with the lowest squared error plus lambda-weighted RLE/huffman bit cost is chosen (searching over the position of each nonzero
+    /// coefficient's predecessor, which determines its zero run). Runs carrying over from the previous subblock are not taken into account
+    pub fn encode_rdo(self: &mut DctMatrix8x8, q_table: &[i32;64], model: &RdoModel) -> DctQuantizedMatrix8x8 {
+        let mut coeff = [0.0;64];
+        let mut step = [0.0;64];
+        let mut base = [0;64];
+        let mut mean_step_sq = 0.0;
+
+        for (i, idx) in ZIGZAG_TABLE.iter().enumerate() {
+            let n = self.m[*idx] * DCT_SCALE_FACTOR[*idx];
+            let d = q_table[*idx];
+
+            coeff[i] = n as f32 / (1 << (FP_BITS * 2)) as f32;
+            step[i] = d as f32;
+            base[i] = ((n >> (FP_BITS * 2)) / d).abs();
+            mean_step_sq += step[i] * step[i] / 64.0;
+        }
+
+        let lambda = model.lambda * mean_step_sq;
+
+        // cost of a zero run (including escape symbols for runs longer than 15) followed by a coefficient
+        let mut run_bits = [0.0;64];
+
+        for (run, bits) in run_bits.iter_mut().enumerate() {
+            let mut run = run;
+
+            while run > 15 {
+                *bits += model.symbol_bits[15] + model.symbol_bits[0];
+                run -= 15;
+            }
+
+            *bits += model.symbol_bits[run];
+        }
+
+        // accumulated error of coding each coefficient as zero
+        let mut zero_err = [0.0;65];
+
+        for i in 0..64 {
+            zero_err[i + 1] = zero_err[i] + coeff[i] * coeff[i];
+        }
+
+        // best[i] is the lowest cost of coding coefficients 0..=i with coefficient i being the last nonzero one
+        let mut best = [f32::INFINITY;64];
+        let mut level = [0;64];
+        let mut prev = [None;64];
+
+        for i in 0..64 {
+            // cheapest predecessor: either the start of the block or an earlier nonzero coefficient
+            let mut pred = None;
+            let mut pred_cost = zero_err[i] + lambda * run_bits[i];
+
+            for j in 0..i {
+                let cost = best[j] + (zero_err[i] - zero_err[j + 1]) + lambda * run_bits[i - j - 1];
+
+                if cost < pred_cost {
+                    pred = Some(j);
+                    pred_cost = cost;
+                }
+            }
+
+            let sign = if coeff[i] < 0.0 { -1 } else { 1 };
+
+            for l in [base[i] - 1, base[i], base[i] + 1] {
+                if !(1..=MAX_QUANTIZED_COEFF).contains(&l) {
+                    continue;
+                }
+
+                let size = 32 - (l as u32).leading_zeros() + 1;
+                let err = coeff[i].abs() - l as f32 * step[i];
+                let cost = pred_cost + err * err + lambda * (model.symbol_bits[size as usize] + size as f32);
+
+                if cost < best[i] {
+                    best[i] = cost;
+                    level[i] = sign * l;
+                    prev[i] = pred;
+                }
+            }
+        }
+
+        // pick the last nonzero coefficient (or none at all), then walk back through the chosen predecessors
+        let mut last = None;
+        let mut last_cost = zero_err[64];
+
+        for j in 0..64 {
+            let cost = best[j] + (zero_err[64] - zero_err[j + 1]);
+
+            if cost < last_cost {
+                last = Some(j);
+                last_cost = cost;
+            }
+        }
+
+        let mut result = DctQuantizedMatrix8x8 { m: [0;64] };
+
+        while let Some(j) = last {
+            result.m[j] = level[j] as i16;
+            last = prev[j];
+        }
+
+        result
+    }
+
     pub fn get_row(self: &DctMatrix8x8, index: usize) -> [i32;8] {
         assert!(index < 8);
         let row_offset = index * 8;
@@ -137,6 +272,34 @@ impl DctMatrix8x8 {
 
     /// Perform an in-place DCT transformation of each row of this matrix
     pub fn dct_transform_rows(self: &mut DctMatrix8x8) {
+        if !simd::transform(&mut self.m, true, false) {
+            self.dct_transform_rows_scalar();
+        }
+    }
+
+    /// Perform an in-place DCT transformation of each column of this matrix
+    pub fn dct_transform_columns(self: &mut DctMatrix8x8) {
+        if !simd::transform(&mut self.m, false, false) {
+            self.dct_transform_columns_scalar();
+        }
+    }
+
+    /// Perform an in-place inverse DCT transformation of each row of this matrix
+    pub fn dct_inverse_transform_rows(self: &mut DctMatrix8x8) {
+        if !simd::transform(&mut self.m, true, true) {
+            self.dct_inverse_transform_rows_scalar();
+        }
+    }
+
+    /// Perform an in-place inverse DCT transformation of each column of this matrix
+    pub fn dct_inverse_transform_columns(self: &mut DctMatrix8x8) {
+        if !simd::transform(&mut self.m, false, true) {
+            self.dct_inverse_transform_columns_scalar();
+        }
+    }
+
+    /// Portable version of `dct_transform_rows`
+    pub fn dct_transform_rows_scalar(self: &mut DctMatrix8x8) {
         for idx in 0..8 {
             let mut row = self.get_row(idx);
             DctMatrix8x8::fdct(&mut row);
@@ -144,8 +307,8 @@ impl DctMatrix8x8 {
         }
     }
 
-    /// Perform an in-place DCT transformation of each column of this matrix
-    pub fn dct_transform_columns(self: &mut DctMatrix8x8) {
+    /// Portable version of `dct_transform_columns`
+    pub fn dct_transform_columns_scalar(self: &mut DctMatrix8x8) {
         for idx in 0..8 {
             let mut column = self.get_column(idx);
             DctMatrix8x8::fdct(&mut column);
@@ -153,8 +316,8 @@ impl DctMatrix8x8 {
         }
     }
 
-    /// Perform an in-place inverse DCT transformation of each row of this matrix
-    pub fn dct_inverse_transform_rows(self: &mut DctMatrix8x8) {
+    /// Portable version of `dct_inverse_transform_rows`
+    pub fn dct_inverse_transform_rows_scalar(self: &mut DctMatrix8x8) {
         for idx in 0..8 {
             let mut row = self.get_row(idx);
             DctMatrix8x8::idct(&mut row);
@@ -162,8 +325,8 @@ impl DctMatrix8x8 {
         }
     }
 
-    /// Perform an in-place inverse DCT transformation of each column of this matrix
-    pub fn dct_inverse_transform_columns(self: &mut DctMatrix8x8) {
+    /// Portable version of `dct_inverse_transform_columns`
+    pub fn dct_inverse_transform_columns_scalar(self: &mut DctMatrix8x8) {
         for idx in 0..8 {
             let mut column = self.get_column(idx);
             DctMatrix8x8::idct(&mut column);
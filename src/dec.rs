@@ -1,41 +1,274 @@
-use std::{io::{Read, Seek, Cursor}, slice::{ChunksExact, Iter}};
+use core::ops::Range;
 
-use bitstream_io::{BitReader, BitRead};
-use byteorder::{ReadBytesExt, LittleEndian};
+use alloc::{vec, vec::Vec, string::String, collections::VecDeque};
+use byteorder::{ByteOrder, LittleEndian};
 
-use crate::{common::{PFV_MAGIC, PFV_VERSION, EncodedMacroBlock, EncodedIPlane, DeltaEncodedMacroBlock, EncodedPPlane}, huffman::{HuffmanTree, HuffmanError}, frame::VideoFrame, plane::VideoPlane, dct::{DctQuantizedMatrix8x8}};
+use crate::{bits::BitReader, io::{self, Source, SeekableSource, SeekFrom}, common::{PFV_MAGIC, PFV_VERSION, PFV_VERSION_LEGACY, PFV_FLAG_TIMESTAMPS, PFV_FLAG_FINALIZED, PFV_PACKET_EOF, PFV_PACKET_IFRAME, PFV_PACKET_PFRAME, PFV_PACKET_USER_DATA, PFV_PACKET_AUDIO, PFV_PACKET_SUBTITLE, PFV_PACKET_INDEX, EncodedMacroBlock, DeltaEncodedMacroBlock}, huffman::{HuffmanTree, HuffmanError}, frame::{VideoFrame, FrameTarget, Nv12FrameTarget, Timebase}, plane::VideoPlane, dct::{DctQuantizedMatrix8x8}, slice::{SliceGroup, slice_groups, read_slices, read_rle}, metadata::UserData, audio::{AudioFormat, AudioCodec}, track::{Track, TrackId, TrackKind, Subtitle, VIDEO_TRACK}, qoa::decode_frame};
 
-#[derive(Debug, Clone, Copy)]
+#[cfg(feature = "multithreading")]
+use rayon::prelude::*;
+
+#[cfg(feature = "multithreading")]
+use std::sync::Arc;
+
+/// How a frame was coded in the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    /// I-frame: a complete frame
+    Intra,
+    /// P-frame: coded as a delta from the previous frame
+    Predicted,
+    /// Drop frame: nothing changed since the previous frame
+    Drop,
+}
+
+/// A frame returned by Decoder::next_frame, borrowing the decoder's frame buffer
+pub struct DecodedFrame<'a> {
+    /// Index of this frame in the stream (drop frames count as frames)
+    pub index: u64,
+    /// Presentation timestamp in seconds
+    pub pts: f64,
+    pub frame_type: FrameType,
+    /// Decoded planes. For drop frames these are unchanged from the previous frame
+    pub frame: &'a VideoFrame,
+}
+
+/// An owned copy of a decoded frame, as returned by the Frames iterator
+pub struct OwnedDecodedFrame {
+    pub index: u64,
+    pub pts: f64,
+    pub frame_type: FrameType,
+    pub frame: VideoFrame,
+}
+
+/// Iterator over the remaining frames of a decoder, returned by Decoder::frames
+pub struct Frames<'a, TReader: Source> {
+    decoder: &'a mut Decoder<TReader>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
 struct DeltaBlockHeader {
     mvec_x: i8,
     mvec_y: i8,
     has_coeff: bool,
 }
 
-pub struct Decoder<TReader: Read + Seek> {
-    reader: TReader,
+/// Largest amount of a packet which is allocated before its data has actually been read
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Most user data packets kept around for Decoder::take_user_data. Once the queue is full, the oldest packets are dropped
+pub const MAX_QUEUED_USER_DATA: usize = 256;
+
+/// Most subtitle cues kept around for Decoder::take_subtitle. Once the queue is full, the oldest cues are dropped
+pub const MAX_QUEUED_SUBTITLES: usize = 256;
+
+/// Wraps the decoder's reader & keeps track of how many bytes have been consumed, so that the decoder only needs Seek in order to reset
+struct StreamReader<R: Source> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: Source> StreamReader<R> {
+    fn read(self: &mut StreamReader<R>, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let len = self.inner.read(buf)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn read_exact(self: &mut StreamReader<R>, mut buf: &mut [u8]) -> Result<(), io::Error> {
+        while !buf.is_empty() {
+            match self.read(buf)? {
+                0 => {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                len => {
+                    buf = &mut buf[len..];
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_u8(self: &mut StreamReader<R>) -> Result<u8, io::Error> {
+        let mut data = [0;1];
+        self.read_exact(&mut data)?;
+        Ok(data[0])
+    }
+
+    fn read_u16(self: &mut StreamReader<R>) -> Result<u16, io::Error> {
+        let mut data = [0;2];
+        self.read_exact(&mut data)?;
+        Ok(LittleEndian::read_u16(&data))
+    }
+
+    fn read_u32(self: &mut StreamReader<R>) -> Result<u32, io::Error> {
+        let mut data = [0;4];
+        self.read_exact(&mut data)?;
+        Ok(LittleEndian::read_u32(&data))
+    }
+
+    fn read_u64(self: &mut StreamReader<R>) -> Result<u64, io::Error> {
+        let mut data = [0;8];
+        self.read_exact(&mut data)?;
+        Ok(LittleEndian::read_u64(&data))
+    }
+
+    /// Append exactly len bytes to out. The buffer grows as data arrives rather than all at once, so a corrupt length can't trigger a huge allocation
+    fn read_to_vec(self: &mut StreamReader<R>, len: u64, out: &mut Vec<u8>) -> Result<(), io::Error> {
+        let mut remaining = len;
+
+        while remaining > 0 {
+            let start = out.len();
+            out.resize(start + remaining.min(READ_CHUNK_SIZE) as usize, 0);
+
+            let read_len = self.read(&mut out[start..])?;
+            out.truncate(start + read_len);
+
+            if read_len == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+
+            remaining -= read_len as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Skip over the given number of bytes by reading & discarding them, which works for non-seekable readers
+    fn skip(self: &mut StreamReader<R>, len: u64) -> Result<(), io::Error> {
+        let mut buf = [0;1024];
+        let mut remaining = len;
+
+        while remaining > 0 {
+            let read_len = self.read(&mut buf[..(remaining.min(1024) as usize)])?;
+
+            if read_len == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+
+            remaining -= read_len as u64;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Decoder<TReader: Source> {
+    reader: StreamReader<TReader>,
+    version: u32,
     width: usize,
     height: usize,
-    framerate: u32,
+    timebase: Timebase,
+    has_timestamps: bool,
     qtables: Vec<[i32;64]>,
+    metadata: Vec<(String, String)>,
+    user_data: VecDeque<UserData>,
+    stream_info: Option<StreamInfo>,
+    tracks: Vec<Track>,
+    audio_track: Option<TrackId>,
+    subtitle_track: Option<TrackId>,
+    subtitles: VecDeque<Subtitle>,
+    audio: VecDeque<AudioChunk>,
     framebuffer: VideoFrame,
+    backbuffer: VideoFrame,
     retframe: VideoFrame,
-    delta_accum: f64,
+    clock: f64,
     eof: bool,
     reset_pos: u64,
+    frame_index: u64,
+    frame_pts: u64,
+    pending_packet: Option<PacketHeader>,
+    slice_groups: Vec<SliceGroup>,
+    scratch: DecoderScratch,
     #[cfg(feature = "multithreading")]
-    threadpool: rayon::ThreadPool
+    threadpool: Arc<rayon::ThreadPool>
+}
+
+/// A packet read by Decoder::read_raw_packet. Its payload is left in Decoder::last_packet
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RawPacket {
+    /// Byte offset of the packet header from the start of the stream
+    pub offset: u64,
+    pub packet_type: u8,
+    pub pts: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PacketHeader {
+    packet_type: u8,
+    len: u32,
+    pts: Option<u64>,
+}
+
+/// Buffers which are reused from frame to frame, so that steady-state decoding doesn't allocate.
+/// (When decoding is called from outside the decoder's thread pool, rayon's own job queue still allocates a small block every few dozen frames)
+struct DecoderScratch {
+    packet: Vec<u8>,
+    slice_ranges: Vec<Range<usize>>,
+    coefficients: Vec<i16>,
+    block_headers: Vec<DeltaBlockHeader>,
+    concealed: Vec<bool>,
+    /// Sample buffers of audio chunks which have been handed to the audio callback, reused for later packets
+    audio: Vec<Vec<i16>>,
+}
+
+/// Decoded audio waiting for the playback clock to reach its first sample
+struct AudioChunk {
+    pos: u64,
+    samples: Vec<i16>,
+}
+
+/// Totals recorded in the header by Encoder::finalize
+#[derive(Debug, Clone, Copy)]
+struct StreamInfo {
+    frame_count: u64,
+    duration: u64,
+    max_packet_size: u32,
+    index_offset: u64,
+}
+
+/// Entry of the stream index, pointing at an I-frame packet
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    frame: u64,
+    offset: u64,
 }
 
 #[derive(Debug)]
 pub enum DecodeError {
     FormatError,
     VersionError,
-    IOError(std::io::Error)
+    /// A track ID which doesn't refer to a track of the right kind was passed to the decoder
+    InvalidTrack,
+    IOError(io::Error)
 }
 
-impl<TReader: Read + Seek> Decoder<TReader> {
-    pub fn new(mut reader: TReader, #[cfg(feature = "multithreading")] num_threads: usize) -> Result<Decoder<TReader>, DecodeError> {
+impl<TReader: Source> Decoder<TReader> {
+    /// Create a new decoder reading from the given source (any std::io::Read, or an io::Source without std). Seeking isn't required, so this works on pipes, sockets, or decompression streams - reset additionally requires an io::SeekableSource
+    pub fn new(reader: TReader, #[cfg(feature = "multithreading")] num_threads: usize) -> Result<Decoder<TReader>, DecodeError> {
+        #[cfg(feature = "multithreading")]
+        {
+            Decoder::open(reader, Arc::new(rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap()))
+        }
+
+        #[cfg(not(feature = "multithreading"))]
+        {
+            Decoder::open(reader)
+        }
+    }
+
+    /// Create a new decoder which runs on an existing thread pool instead of creating its own, so that several decoders (and encoders) can share one set of threads.
+    /// See group::DecoderGroup to decode several streams side by side
+    #[cfg(feature = "multithreading")]
+    pub fn new_with_thread_pool(reader: TReader, threadpool: Arc<rayon::ThreadPool>) -> Result<Decoder<TReader>, DecodeError> {
+        Decoder::open(reader, threadpool)
+    }
+
+    fn open(reader: TReader, #[cfg(feature = "multithreading")] threadpool: Arc<rayon::ThreadPool>) -> Result<Decoder<TReader>, DecodeError> {
+        let mut reader = StreamReader { inner: reader, position: 0 };
+
         // read header
         let mut magic = [0;8];
         match reader.read_exact(&mut magic) {
@@ -52,9 +285,9 @@ impl<TReader: Read + Seek> Decoder<TReader> {
         }
 
         // read version
-        match reader.read_u32::<LittleEndian>() {
+        let version = match reader.read_u32() {
             Ok(ver) => {
-                if ver != PFV_VERSION {
+                if ver != PFV_VERSION && ver != PFV_VERSION_LEGACY {
                     return Err(DecodeError::VersionError);
                 }
 
@@ -65,28 +298,73 @@ impl<TReader: Read + Seek> Decoder<TReader> {
             }
         };
 
-        let width = match reader.read_u16::<LittleEndian>() {
+        let width = match reader.read_u16() {
             Ok(v) => v,
             Err(e) => {
                 return Err(DecodeError::IOError(e));
             }
         };
 
-        let height = match reader.read_u16::<LittleEndian>() {
+        let height = match reader.read_u16() {
             Ok(v) => v,
             Err(e) => {
                 return Err(DecodeError::IOError(e));
             }
         };
 
-        let framerate = match reader.read_u16::<LittleEndian>() {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(DecodeError::IOError(e));
-            }
+        // frames are stored as 4:2:0, so odd dimensions can't be represented (and an empty frame has no slices to decode)
+        if width == 0 || height == 0 || width % 2 != 0 || height % 2 != 0 {
+            return Err(DecodeError::FormatError);
+        }
+
+        let (timebase, flags) = if version == PFV_VERSION {
+            let num = match reader.read_u32() {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(DecodeError::IOError(e));
+                }
+            };
+
+            let den = match reader.read_u32() {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(DecodeError::IOError(e));
+                }
+            };
+
+            let flags = match reader.read_u8() {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(DecodeError::IOError(e));
+                }
+            };
+
+            (Timebase { num, den }, flags)
+        } else {
+            // legacy streams store an integer framerate
+            let framerate = match reader.read_u16() {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(DecodeError::IOError(e));
+                }
+            };
+
+            (Timebase::from_framerate(framerate as u32), 0)
+        };
+
+        let has_timestamps = flags & PFV_FLAG_TIMESTAMPS != 0;
+
+        let stream_info = if version == PFV_VERSION {
+            Decoder::<TReader>::read_stream_info(&mut reader, flags)?
+        } else {
+            None
         };
 
-        let num_qtable = match reader.read_u16::<LittleEndian>() {
+        if timebase.num == 0 || timebase.den == 0 {
+            return Err(DecodeError::FormatError);
+        }
+
+        let num_qtable = match reader.read_u16() {
             Ok(v) => v,
             Err(e) => {
                 return Err(DecodeError::IOError(e));
@@ -99,7 +377,7 @@ impl<TReader: Read + Seek> Decoder<TReader> {
             let mut qtable = [0;64];
 
             for i in 0..64 {
-                qtable[i] = match reader.read_u16::<LittleEndian>() {
+                qtable[i] = match reader.read_u16() {
                     Ok(v) => v as i32,
                     Err(e) => {
                         return Err(DecodeError::IOError(e));
@@ -110,26 +388,71 @@ impl<TReader: Read + Seek> Decoder<TReader> {
             qtables.push(qtable);
         }
 
-        let reset_pos = match reader.stream_position() {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(DecodeError::IOError(e));
-            }
+        let metadata = if version == PFV_VERSION {
+            Decoder::<TReader>::read_metadata(&mut reader)?
+        } else {
+            Vec::new()
+        };
+
+        let mut tracks = vec![Track { id: VIDEO_TRACK, kind: TrackKind::Video, language: String::new() }];
+
+        if version == PFV_VERSION {
+            Decoder::<TReader>::read_tracks(&mut reader, &mut tracks)?;
+        }
+
+        // by default, receive the first track of each kind
+        let audio_track = tracks.iter().find(|x| matches!(x.kind, TrackKind::Audio(_))).map(|x| x.id);
+        let subtitle_track = tracks.iter().find(|x| x.kind == TrackKind::Subtitle).map(|x| x.id);
+
+        let reset_pos = reader.position;
+
+        let framebuffer = VideoFrame::new_padded(width as usize, height as usize);
+
+        let blocks_wide = framebuffer.plane_y.width / 16;
+        let blocks_high = framebuffer.plane_y.height / 16;
+
+        let chroma_blocks_wide = framebuffer.plane_u.width / 16;
+        let chroma_blocks_high = framebuffer.plane_u.height / 16;
+
+        let total_blocks = (blocks_wide * blocks_high) + (chroma_blocks_wide * chroma_blocks_high * 2);
+
+        let slice_groups = if version == PFV_VERSION_LEGACY {
+            // legacy streams code the entire frame as one bitstream
+            vec![SliceGroup { num_slices: 1, blocks_per_slice: total_blocks }]
+        } else {
+            slice_groups(blocks_wide, blocks_high, chroma_blocks_wide, chroma_blocks_high).to_vec()
+        };
+
+        let scratch = DecoderScratch {
+            packet: Vec::new(),
+            slice_ranges: Vec::with_capacity(blocks_high + (chroma_blocks_high * 2)),
+            coefficients: vec![0;total_blocks * 256],
+            block_headers: vec![DeltaBlockHeader::default();total_blocks],
+            concealed: vec![false;total_blocks],
+            audio: Vec::new(),
         };
 
         #[cfg(feature = "multithreading")]
         {
-            Ok(Decoder { reader: reader, width: width as usize, height: height as usize, framerate: framerate as u32,
-                qtables: qtables, framebuffer: VideoFrame::new_padded(width as usize, height as usize),
-                retframe: VideoFrame::new(width as usize, height as usize), delta_accum: 0.0, eof: false, reset_pos: reset_pos,
-                threadpool: rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap() })
+            Ok(Decoder { reader, version, width: width as usize, height: height as usize, timebase, has_timestamps, stream_info,
+                metadata, user_data: VecDeque::new(),
+                tracks, audio_track, subtitle_track, subtitles: VecDeque::new(), audio: VecDeque::new(),
+                qtables, backbuffer: VideoFrame::new_padded(width as usize, height as usize), framebuffer,
+                retframe: VideoFrame::new(width as usize, height as usize), clock: 0.0, eof: false, reset_pos, frame_index: 0,
+                frame_pts: 0, pending_packet: None,
+                slice_groups, scratch,
+                threadpool })
         }
 
         #[cfg(not(feature = "multithreading"))]
         {
-            Ok(Decoder { reader: reader, width: width as usize, height: height as usize, framerate: framerate as u32,
-                qtables: qtables, framebuffer: VideoFrame::new_padded(width as usize, height as usize),
-                retframe: VideoFrame::new(width as usize, height as usize), delta_accum: 0.0, eof: false, reset_pos: reset_pos, })
+            Ok(Decoder { reader, version, width: width as usize, height: height as usize, timebase, has_timestamps, stream_info,
+                metadata, user_data: VecDeque::new(),
+                tracks, audio_track, subtitle_track, subtitles: VecDeque::new(), audio: VecDeque::new(),
+                qtables, backbuffer: VideoFrame::new_padded(width as usize, height as usize), framebuffer,
+                retframe: VideoFrame::new(width as usize, height as usize), clock: 0.0, eof: false, reset_pos, frame_index: 0,
+                frame_pts: 0, pending_packet: None,
+                slice_groups, scratch, })
         }
     }
 
@@ -141,378 +464,1181 @@ impl<TReader: Read + Seek> Decoder<TReader> {
         return self.height;
     }
 
+    /// Nominal framerate, rounded to the nearest integer
     pub fn framerate(self: &Decoder<TReader>) -> u32 {
-        return self.framerate;
+        let num = self.timebase.num as u64;
+        ((self.timebase.den as u64 + (num / 2)) / num) as u32
     }
 
-    pub fn reset(self: &mut Decoder<TReader>) -> Result<(), std::io::Error> {
-        self.eof = false;
-        self.reader.seek(std::io::SeekFrom::Start(self.reset_pos))?;
-        Ok(())
+    /// Timebase of frame timestamps. For constant framerate streams, one tick is one frame
+    pub fn timebase(self: &Decoder<TReader>) -> Timebase {
+        self.timebase
     }
 
-    pub fn advance_delta<FV>(self: &mut Decoder<TReader>, delta: f64, onvideo: &mut FV) -> Result<bool, std::io::Error>  where
-        FV: FnMut(&VideoFrame) {
-        self.delta_accum += delta;
-        let delta_per_frame = 1.0 / self.framerate as f64;
+    /// Whether frames carry their own timestamps (variable framerate)
+    pub fn is_variable_framerate(self: &Decoder<TReader>) -> bool {
+        self.has_timestamps
+    }
 
-        while self.delta_accum >= delta_per_frame {
-            if self.advance_frame(onvideo)? == false {
-                return Ok(false);
-            }
-            self.delta_accum -= delta_per_frame;
-        }
+    /// Total number of frames in the stream (including drop frames), if the encoder recorded it in the header (see Encoder::finalize)
+    pub fn frame_count(self: &Decoder<TReader>) -> Option<u64> {
+        self.stream_info.map(|x| x.frame_count)
+    }
 
-        Ok(true)
+    /// Duration of the stream in seconds (the end of its last frame), if the encoder recorded it in the header
+    pub fn duration(self: &Decoder<TReader>) -> Option<f64> {
+        self.stream_info.map(|x| self.timebase.to_seconds(x.duration))
     }
 
-    pub fn advance_frame<FV>(self: &mut Decoder<TReader>, onvideo: &mut FV) -> Result<bool, std::io::Error> where
-        FV: FnMut(&VideoFrame) {
-        if self.eof {
-            return Ok(false);
-        }
+    /// Size in bytes (including packet headers) of the largest packet in the stream, if the encoder recorded it in the header
+    pub fn max_packet_size(self: &Decoder<TReader>) -> Option<u32> {
+        self.stream_info.map(|x| x.max_packet_size)
+    }
 
-        loop {
-            // read next packet header
-            // if we hit EOF, return false
+    /// Byte offset (from the start of the stream) of the index packet listing every I-frame, if the encoder recorded it in the header
+    pub fn index_offset(self: &Decoder<TReader>) -> Option<u64> {
+        self.stream_info.map(|x| x.index_offset)
+    }
 
-            let packet_type = self.reader.read_u8()?;
-            let packet_len = self.reader.read_u32::<LittleEndian>()?;
+    /// All tracks declared by the stream. Track 0 is always the video track
+    pub fn tracks(self: &Decoder<TReader>) -> &[Track] {
+        &self.tracks
+    }
 
-            match packet_type {
-                0 => {
-                    // EOF marker
-                    self.eof = true;
-                    return Ok(false);
-                }
-                1 => {
-                    // iframe. if payload length is zero, this is a drop frame (do nothing)
-                    if packet_len > 0 {
-                        let mut data = vec![0;packet_len as usize];
-                        self.reader.read_exact(&mut data)?;
-                        self.decode_iframe(&data)?;
+    /// Select which audio track is handed to the audio callback (or None for no audio). By default, the first audio track is selected.
+    /// Returns DecodeError::InvalidTrack (leaving the selection unchanged) if the track is not an audio track
+    pub fn select_audio_track(self: &mut Decoder<TReader>, track: Option<TrackId>) -> Result<(), DecodeError> {
+        if let Some(id) = track {
+            if !matches!(self.tracks.get(id as usize).map(|x| x.kind), Some(TrackKind::Audio(_))) {
+                return Err(DecodeError::InvalidTrack);
+            }
+        }
 
-                        self.retframe.plane_y.blit(&self.framebuffer.plane_y, 0, 0, 0, 0, self.retframe.plane_y.width, self.retframe.plane_y.height);
-                        self.retframe.plane_u.blit(&self.framebuffer.plane_u, 0, 0, 0, 0, self.retframe.plane_u.width, self.retframe.plane_u.height);
-                        self.retframe.plane_v.blit(&self.framebuffer.plane_v, 0, 0, 0, 0, self.retframe.plane_v.width, self.retframe.plane_v.height);
+        self.audio_track = track;
+        self.clear_audio();
 
-                        onvideo(&self.retframe);
-                    }
-                    break;
-                }
-                2 => {
-                    // pframe
-                    let mut data = vec![0;packet_len as usize];
-                    self.reader.read_exact(&mut data)?;
-                    self.decode_pframe(&data)?;
-
-                    self.retframe.plane_y.blit(&self.framebuffer.plane_y, 0, 0, 0, 0, self.retframe.plane_y.width, self.retframe.plane_y.height);
-                    self.retframe.plane_u.blit(&self.framebuffer.plane_u, 0, 0, 0, 0, self.retframe.plane_u.width, self.retframe.plane_u.height);
-                    self.retframe.plane_v.blit(&self.framebuffer.plane_v, 0, 0, 0, 0, self.retframe.plane_v.width, self.retframe.plane_v.height);
-
-                    onvideo(&self.retframe);
-                    break;
-                }
-                _ => {
-                    // unrecognized packet type, just skip over packet payload
-                    self.reader.seek(std::io::SeekFrom::Current(packet_len as i64))?;
-                }
+        Ok(())
+    }
+
+    /// Select which subtitle track's cues are queued up for take_subtitle (or None for no subtitles). By default, the first subtitle track is selected.
+    /// Returns DecodeError::InvalidTrack (leaving the selection unchanged) if the track is not a subtitle track
+    pub fn select_subtitle_track(self: &mut Decoder<TReader>, track: Option<TrackId>) -> Result<(), DecodeError> {
+        if let Some(id) = track {
+            if self.tracks.get(id as usize).map(|x| x.kind) != Some(TrackKind::Subtitle) {
+                return Err(DecodeError::InvalidTrack);
             }
         }
 
-        Ok(true)
+        self.subtitle_track = track;
+        self.subtitles.clear();
+
+        Ok(())
     }
 
-    fn decode_iframe(self: &mut Decoder<TReader>, payload: &[u8]) -> Result<(), std::io::Error> {
-        let reader = Cursor::new(payload);
-        let mut bitreader = BitReader::endian(reader, bitstream_io::LittleEndian);
+    /// Format of the selected audio track, if any
+    pub fn audio_format(self: &Decoder<TReader>) -> Option<AudioFormat> {
+        match self.audio_track.map(|x| self.tracks[x as usize].kind) {
+            Some(TrackKind::Audio(format)) => Some(format),
+            _ => None
+        }
+    }
 
-        let bitstream_length = bitreader.seek_bits(std::io::SeekFrom::End(0))?;
-        bitreader.seek_bits(std::io::SeekFrom::Start(0))?;
+    /// Number of interleaved channels of the selected audio track, or 0 if no audio track is selected
+    pub fn channels(self: &Decoder<TReader>) -> u32 {
+        self.audio_format().map(|x| x.channels).unwrap_or(0)
+    }
 
-        // read symbol frequency table
-        let mut table = [0;16];
+    /// Samplerate of the selected audio track, or 0 if no audio track is selected
+    pub fn samplerate(self: &Decoder<TReader>) -> u32 {
+        self.audio_format().map(|x| x.samplerate).unwrap_or(0)
+    }
 
-        for i in 0..16 {
-            table[i] = bitreader.read::<u8>(8).unwrap();
-        }
+    /// Take the oldest cue of the selected subtitle track which has been read so far.
+    /// Like user data, cues preceding a frame are available once that frame has been returned, and at most MAX_QUEUED_SUBTITLES cues are kept
+    pub fn take_subtitle(self: &mut Decoder<TReader>) -> Option<Subtitle> {
+        self.subtitles.pop_front()
+    }
 
-        // construct huffman tree
-        let tree = HuffmanTree::from_table(&table);
+    /// Key/value metadata entries from the stream header, in the order they were written
+    pub fn metadata(self: &Decoder<TReader>) -> &[(String, String)] {
+        &self.metadata
+    }
 
-        // fetch qtables
-        let qtable_y = &self.qtables[bitreader.read::<u8>(8).unwrap() as usize];
-        let qtable_u = &self.qtables[bitreader.read::<u8>(8).unwrap() as usize];
-        let qtable_v = &self.qtables[bitreader.read::<u8>(8).unwrap() as usize];
+    /// Look up the first metadata entry with the given key
+    pub fn get_metadata(self: &Decoder<TReader>, key: &str) -> Option<&str> {
+        self.metadata.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
 
-        // decode RLE coefficients
-        let blocks_wide = self.framebuffer.plane_y.width / 16;
-        let blocks_high = self.framebuffer.plane_y.height / 16;
+    /// Reset decoding state back to the first frame, assuming the caller has already repositioned the source to just after the header
+    pub(crate) fn rewind(self: &mut Decoder<TReader>) {
+        self.eof = false;
+        self.frame_index = 0;
+        self.frame_pts = 0;
+        self.clock = 0.0;
+        self.pending_packet = None;
+        self.user_data.clear();
+        self.subtitles.clear();
+        self.clear_audio();
+
+        // streams may start with a p-frame, which is predicted from a blank frame
+        self.framebuffer.plane_y.pixels.fill(0);
+        self.framebuffer.plane_u.pixels.fill(128);
+        self.framebuffer.plane_v.pixels.fill(128);
+
+        self.reader.position = self.reset_pos;
+    }
 
-        let chroma_blocks_wide = self.framebuffer.plane_u.width / 16;
-        let chroma_blocks_high = self.framebuffer.plane_u.height / 16;
+    /// Length of the stream header in bytes (the offset of the first packet from the start of the stream)
+    pub(crate) fn header_len(self: &Decoder<TReader>) -> u64 {
+        self.reset_pos
+    }
 
-        let total_blocks = (blocks_wide * blocks_high) + (chroma_blocks_wide * chroma_blocks_high * 2);
-        let total_subblocks = total_blocks * 4;
+    /// The source the decoder reads from
+    pub(crate) fn source_mut(self: &mut Decoder<TReader>) -> &mut TReader {
+        &mut self.reader.inner
+    }
 
-        let mut coefficients = vec![0;total_subblocks * 64 as usize];
+    /// Take the oldest user data packet which has been read so far.
+    /// User data packets are queued up as the decoder reads through the stream, so any user data preceding a frame is available once that frame has been returned.
+    /// At most MAX_QUEUED_USER_DATA packets are kept, so callers which don't take user data as they go only see the most recent packets
+    pub fn take_user_data(self: &mut Decoder<TReader>) -> Option<UserData> {
+        self.user_data.pop_front()
+    }
 
-        let mut out_idx = 0;
-        while out_idx < coefficients.len() {
-            let num_zeroes = match tree.read(&mut bitreader, bitstream_length) {
-                Ok(v) => v,
-                Err(e) => match e {
-                    HuffmanError::DecodeError => unreachable!(),
-                    HuffmanError::IOError(e2) => {
-                        return Err(e2);
-                    },
-                }
-            } as usize;
+    pub fn advance_delta<FV>(self: &mut Decoder<TReader>, delta: f64, onvideo: &mut FV) -> Result<bool, io::Error>  where
+        FV: FnMut(&VideoFrame) {
+        self.advance_delta_with_audio(delta, onvideo, &mut |_| {})
+    }
+
+    /// Like advance_delta, but also hands decoded audio to the onaudio callback as interleaved 16-bit samples.
+    /// Each audio packet is held back until the playback clock reaches the position of its first sample & then delivered in order of position,
+    /// so audio doesn't depend on how it was interleaved with video. Audio which is stored further along than that is delivered as soon as it's read
+    pub fn advance_delta_with_audio<FV, FA>(self: &mut Decoder<TReader>, delta: f64, onvideo: &mut FV, onaudio: &mut FA) -> Result<bool, io::Error>  where
+        FV: FnMut(&VideoFrame),
+        FA: FnMut(&[i16]) {
+        if self.eof {
+            return Ok(false);
+        }
 
-            out_idx += num_zeroes;
+        self.clock += delta;
 
-            let num_bits = match tree.read(&mut bitreader, bitstream_length) {
-                Ok(v) => v,
-                Err(e) => match e {
-                    HuffmanError::DecodeError => unreachable!(),
-                    HuffmanError::IOError(e2) => {
-                        return Err(e2);
-                    },
+        // present every frame whose timestamp has been reached
+        loop {
+            let pts = match self.peek_frame_pts()? {
+                Some(v) => v,
+                None => {
+                    // nothing left to keep in sync with
+                    self.deliver_audio(f64::INFINITY, onaudio);
+                    self.eof = true;
+                    return Ok(false);
                 }
             };
 
-            // if num_bits is 0, then this is only a run of 0s with no value
-            if num_bits > 0 {
-                let coeff = match bitreader.read_signed::<i16>(num_bits as u32) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        return Err(e);
-                    }
-                };
-                coefficients[out_idx] = coeff;
+            if self.timebase.to_seconds(pts) > self.clock {
+                break;
+            }
 
-                out_idx += 1;
+            if self.advance_frame(onvideo)? == false {
+                return Ok(false);
             }
         }
 
-        let mut subblocks = coefficients.chunks_exact(64);
+        self.deliver_audio(self.clock, onaudio);
 
-        // deserialize each plane
-        #[cfg(feature = "multithreading")]
-        {
-            Decoder::<TReader>::deserialize_plane(self.framebuffer.plane_y.width, self.framebuffer.plane_y.height,
-                &mut subblocks, qtable_y, &mut self.framebuffer.plane_y, &self.threadpool);
-                
-            Decoder::<TReader>::deserialize_plane(self.framebuffer.plane_u.width, self.framebuffer.plane_u.height,
-                &mut subblocks, qtable_u, &mut self.framebuffer.plane_u, &self.threadpool);
-                
-            Decoder::<TReader>::deserialize_plane(self.framebuffer.plane_v.width, self.framebuffer.plane_v.height,
-                &mut subblocks, qtable_v, &mut self.framebuffer.plane_v, &self.threadpool);
-        }
+        Ok(true)
+    }
 
-        #[cfg(not(feature = "multithreading"))]
-        {
-            Decoder::<TReader>::deserialize_plane(self.framebuffer.plane_y.width, self.framebuffer.plane_y.height,
-                &mut subblocks, qtable_y, &mut self.framebuffer.plane_y);
-                
-            Decoder::<TReader>::deserialize_plane(self.framebuffer.plane_u.width, self.framebuffer.plane_u.height,
-                &mut subblocks, qtable_u, &mut self.framebuffer.plane_u);
-                
-            Decoder::<TReader>::deserialize_plane(self.framebuffer.plane_v.width, self.framebuffer.plane_v.height,
-                &mut subblocks, qtable_v, &mut self.framebuffer.plane_v);
-        }
+    /// Hand queued audio which starts at or before the given time (in seconds) to the callback
+    pub(crate) fn deliver_audio<FA: FnMut(&[i16])>(self: &mut Decoder<TReader>, time: f64, onaudio: &mut FA) {
+        let samplerate = self.samplerate() as f64;
 
-        Ok(())
+        while let Some(chunk) = self.audio.front() {
+            if chunk.pos as f64 / samplerate > time {
+                break;
+            }
+
+            let chunk = self.audio.pop_front().unwrap();
+            onaudio(&chunk.samples);
+            self.scratch.audio.push(chunk.samples);
+        }
     }
 
-    fn decode_pframe(self: &mut Decoder<TReader>, payload: &[u8]) -> Result<(), std::io::Error> {
-        let reader = Cursor::new(payload);
-        let mut bitreader = BitReader::endian(reader, bitstream_io::LittleEndian);
+    fn clear_audio(self: &mut Decoder<TReader>) {
+        while let Some(chunk) = self.audio.pop_front() {
+            self.scratch.audio.push(chunk.samples);
+        }
+    }
 
-        let bitstream_length = bitreader.seek_bits(std::io::SeekFrom::End(0))?;
-        bitreader.seek_bits(std::io::SeekFrom::Start(0))?;
+    pub fn advance_frame<FV>(self: &mut Decoder<TReader>, onvideo: &mut FV) -> Result<bool, io::Error> where
+        FV: FnMut(&VideoFrame) {
+        match self.decode_next_frame()? {
+            None => Ok(false),
+            Some(FrameType::Drop) => Ok(true),
+            Some(_) => {
+                self.update_retframe();
+                onvideo(&self.retframe);
+                Ok(true)
+            }
+        }
+    }
 
-        // read symbol frequency table
-        let mut table = [0;16];
+    /// Decode the next frame, returning None once the decoder has reached the end of the file.
+    /// Unlike advance_frame, drop frames are returned as well (with frame_type set to FrameType::Drop) so callers can tell when nothing changed.
+    /// Like the other frame-by-frame functions, this skips over audio - there is no playback clock to schedule it against, so use advance_delta_with_audio for audio
+    pub fn next_frame(self: &mut Decoder<TReader>) -> Result<Option<DecodedFrame<'_>>, DecodeError> {
+        let frame_type = match self.decode_next_frame() {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(DecodeError::IOError(e));
+            }
+        };
 
-        for i in 0..16 {
-            table[i] = bitreader.read::<u8>(8).unwrap();
+        if frame_type != FrameType::Drop {
+            self.update_retframe();
         }
 
-        // construct huffman tree
-        let tree = HuffmanTree::from_table(&table);
+        let index = self.frame_index - 1;
 
-        // fetch qtables
-        let qtable_y = &self.qtables[bitreader.read::<u8>(8)? as usize];
-        let qtable_u = &self.qtables[bitreader.read::<u8>(8)? as usize];
-        let qtable_v = &self.qtables[bitreader.read::<u8>(8)? as usize];
+        Ok(Some(DecodedFrame { index, pts: self.timebase.to_seconds(self.frame_pts), frame_type, frame: &self.retframe }))
+    }
 
-        // read block headers
-        let blocks_wide = self.framebuffer.plane_y.width / 16;
-        let blocks_high = self.framebuffer.plane_y.height / 16;
+    /// Returns an iterator over the remaining frames. Each item owns a copy of its frame - use next_frame to avoid the copy
+    pub fn frames(self: &mut Decoder<TReader>) -> Frames<'_, TReader> {
+        Frames { decoder: self }
+    }
 
-        let chroma_blocks_wide = self.framebuffer.plane_u.width / 16;
-        let chroma_blocks_high = self.framebuffer.plane_u.height / 16;
+    fn update_retframe(self: &mut Decoder<TReader>) {
+        self.retframe.plane_y.blit(&self.framebuffer.plane_y, 0, 0, 0, 0, self.retframe.plane_y.width, self.retframe.plane_y.height);
+        self.retframe.plane_u.blit(&self.framebuffer.plane_u, 0, 0, 0, 0, self.retframe.plane_u.width, self.retframe.plane_u.height);
+        self.retframe.plane_v.blit(&self.framebuffer.plane_v, 0, 0, 0, 0, self.retframe.plane_v.width, self.retframe.plane_v.height);
+    }
 
-        let total_blocks = (blocks_wide * blocks_high) + (chroma_blocks_wide * chroma_blocks_high * 2);
+    /// Decode the next frame directly into caller-provided planar buffers.
+    /// Drop frames also write the (unchanged) current frame, so the target always holds the frame at this point in the stream.
+    /// Returns Ok(false) if the decoder has reached the end of the file, in which case the target is left untouched.
+    /// Panics if any target plane is too small for its stride & the video dimensions
+    pub fn decode_next_into(self: &mut Decoder<TReader>, target: &mut FrameTarget) -> Result<bool, io::Error> {
+        if self.decode_next_frame()?.is_none() {
+            return Ok(false);
+        }
 
-        let mut block_headers = Vec::with_capacity(total_blocks);
+        let chroma_width = self.width / 2;
+        let chroma_height = self.height / 2;
 
-        for _ in 0..total_blocks {
-            let mut header = DeltaBlockHeader { mvec_x: 0, mvec_y: 0, has_coeff: false };
-            let has_mvec = bitreader.read_bit()?;
-            header.has_coeff = bitreader.read_bit()?;
+        self.framebuffer.plane_y.copy_into(target.y, target.y_stride, self.width, self.height);
+        self.framebuffer.plane_u.copy_into(target.u, target.u_stride, chroma_width, chroma_height);
+        self.framebuffer.plane_v.copy_into(target.v, target.v_stride, chroma_width, chroma_height);
 
-            if has_mvec {
-                header.mvec_x = bitreader.read_signed(7)?;
-                header.mvec_y = bitreader.read_signed(7)?;
-            }
+        Ok(true)
+    }
 
-            block_headers.push(header);
+    /// Decode the next frame directly into caller-provided NV12 buffers (chroma planes are interleaved as U/V pairs).
+    /// Behaves like decode_next_into otherwise
+    pub fn decode_next_into_nv12(self: &mut Decoder<TReader>, target: &mut Nv12FrameTarget) -> Result<bool, io::Error> {
+        if self.decode_next_frame()?.is_none() {
+            return Ok(false);
         }
 
-        // decode block coefficients
+        let chroma_width = self.width / 2;
+        let chroma_height = self.height / 2;
 
-        let mut coefficients = vec![0;total_blocks * 256];
+        self.framebuffer.plane_y.copy_into(target.y, target.y_stride, self.width, self.height);
+        self.framebuffer.plane_u.interleave_into(&self.framebuffer.plane_v, target.uv, target.uv_stride, chroma_width, chroma_height);
 
-        for (idx, header) in block_headers.iter().enumerate() {
-            let mut block_coeff = [0;256];
-            let block_offset = idx * 256;
-            if header.has_coeff {
-                // read 256 coefficients from bit stream
-                let mut out_idx = 0;
-                while out_idx < 256 {
-                    let num_zeroes = match tree.read(&mut bitreader, bitstream_length) {
-                        Ok(v) => v,
-                        Err(e) => match e {
-                            HuffmanError::DecodeError => unreachable!(),
-                            HuffmanError::IOError(e2) => {
-                                return Err(e2);
-                            },
-                        }
-                    } as usize;
+        Ok(true)
+    }
 
-                    out_idx += num_zeroes;
+    /// Read the next packet header (or take the one already read by peek_frame_pts)
+    fn read_packet_header(self: &mut Decoder<TReader>) -> Result<PacketHeader, io::Error> {
+        if let Some(header) = self.pending_packet.take() {
+            return Ok(header);
+        }
 
-                    let num_bits = match tree.read(&mut bitreader, bitstream_length) {
-                        Ok(v) => v,
-                        Err(e) => match e {
-                            HuffmanError::DecodeError => unreachable!(),
-                            HuffmanError::IOError(e2) => {
-                                return Err(e2);
-                            },
-                        }
-                    };
+        let packet_type = self.reader.read_u8()?;
+        let packet_len = self.reader.read_u32()?;
 
-                    // if num_bits is 0, then this is only a run of 0s with no value
-                    if num_bits > 0 {
-                        let coeff = bitreader.read_signed::<i16>(num_bits as u32)?;
-                        block_coeff[out_idx] = coeff;
+        // frame packets of variable framerate streams carry a timestamp
+        let pts = if self.has_timestamps && (packet_type == PFV_PACKET_IFRAME || packet_type == PFV_PACKET_PFRAME) {
+            Some(self.reader.read_u64()?)
+        } else {
+            None
+        };
+
+        Ok(PacketHeader { packet_type, len: packet_len, pts })
+    }
+
+    /// Find the timestamp (in ticks) of the next frame without decoding it. Returns None at the end of the file
+    pub(crate) fn peek_frame_pts(self: &mut Decoder<TReader>) -> Result<Option<u64>, io::Error> {
+        loop {
+            let header = self.read_packet_header()?;
+
+            match header.packet_type {
+                PFV_PACKET_EOF => {
+                    self.pending_packet = Some(header);
+                    return Ok(None);
+                }
+                PFV_PACKET_IFRAME | PFV_PACKET_PFRAME => {
+                    self.pending_packet = Some(header);
+                    return Ok(Some(header.pts.unwrap_or(self.frame_index)));
+                }
+                PFV_PACKET_USER_DATA => {
+                    self.read_user_data(header.len)?;
+                }
+                PFV_PACKET_SUBTITLE => {
+                    self.read_subtitle(header.len)?;
+                }
+                PFV_PACKET_AUDIO => {
+                    self.read_audio(header.len)?;
+                }
+                _ => {
+                    // unrecognized packet type, just skip over packet payload
+                    self.reader.skip(header.len as u64)?;
+                }
+            }
+        }
+    }
+
+    /// Read packets until the next frame has been decoded into the framebuffer. Returns None at the end of the file
+    fn decode_next_frame(self: &mut Decoder<TReader>) -> Result<Option<FrameType>, io::Error> {
+        if self.eof {
+            return Ok(None);
+        }
+
+        loop {
+            // read next packet header
+            // if we hit EOF, return None
+
+            let header = self.read_packet_header()?;
+            let packet_len = header.len;
 
-                        out_idx += 1;
+            match header.packet_type {
+                PFV_PACKET_EOF => {
+                    // EOF marker
+                    self.eof = true;
+                    return Ok(None);
+                }
+                PFV_PACKET_IFRAME | PFV_PACKET_PFRAME => {
+                    // constant framerate streams advance by one tick per frame
+                    self.frame_pts = header.pts.unwrap_or(self.frame_index);
+
+                    // iframe. if payload length is zero, this is a drop frame (framebuffer is unchanged)
+                    if header.packet_type == PFV_PACKET_IFRAME && packet_len == 0 {
+                        self.frame_index += 1;
+                        return Ok(Some(FrameType::Drop));
                     }
+
+                    self.scratch.packet.resize(packet_len as usize, 0);
+                    self.reader.read_exact(&mut self.scratch.packet)?;
+
+                    let frame_type = if header.packet_type == PFV_PACKET_IFRAME {
+                        self.decode_iframe()?;
+                        FrameType::Intra
+                    } else {
+                        self.decode_pframe()?;
+                        FrameType::Predicted
+                    };
+
+                    self.frame_index += 1;
+
+                    return Ok(Some(frame_type));
+                }
+                PFV_PACKET_USER_DATA => {
+                    self.read_user_data(packet_len)?;
                 }
+                PFV_PACKET_SUBTITLE => {
+                    self.read_subtitle(packet_len)?;
+                }
+                PFV_PACKET_AUDIO => {
+                    // audio is only queued up while playing back with advance_delta_with_audio
+                    self.reader.skip(packet_len as u64)?;
+                }
+                _ => {
+                    // unrecognized packet type, just skip over packet payload
+                    self.reader.skip(packet_len as u64)?;
+                }
+            }
+        }
+    }
+
+    fn read_user_data(self: &mut Decoder<TReader>, packet_len: u32) -> Result<(), io::Error> {
+        if packet_len < 8 {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        let pts_ticks = self.reader.read_u64()?;
+        let data_len = packet_len as u64 - 8;
+
+        let mut data = Vec::new();
+        self.reader.read_to_vec(data_len, &mut data)?;
+
+        if self.user_data.len() == MAX_QUEUED_USER_DATA {
+            self.user_data.pop_front();
+        }
+
+        self.user_data.push_back(UserData { pts_ticks, pts: self.timebase.to_seconds(pts_ticks), data });
+
+        Ok(())
+    }
+
+    /// Decode an audio packet & queue its samples up for playback if it belongs to the selected audio track. Audio packets which fail to decode are skipped
+    fn read_audio(self: &mut Decoder<TReader>, packet_len: u32) -> Result<(), io::Error> {
+        // track ID + position of first sample frame
+        let header_len = 9;
+
+        if packet_len < header_len {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        let track = self.reader.read_u8()?;
+
+        let format = match self.tracks.get(track as usize).map(|x| x.kind) {
+            Some(TrackKind::Audio(format)) if self.audio_track == Some(track) => format,
+            _ => {
+                // not the selected track (or an audio track using a codec we don't know about), skip packet
+                self.reader.skip((packet_len - 1) as u64)?;
+                return Ok(());
+            }
+        };
+
+        let pos = self.reader.read_u64()?;
+
+        self.scratch.packet.resize((packet_len - header_len) as usize, 0);
+        self.reader.read_exact(&mut self.scratch.packet)?;
+
+        let channels = format.channels as usize;
+        let mut samples = self.scratch.audio.pop().unwrap_or_default();
+        samples.clear();
+
+        let valid = match format.codec {
+            AudioCodec::Pcm => {
+                if self.scratch.packet.len().is_multiple_of(channels * 2) {
+                    samples.extend(self.scratch.packet.chunks_exact(2).map(|x| i16::from_le_bytes([x[0], x[1]])));
+                    true
+                } else {
+                    false
+                }
+            }
+            AudioCodec::Qoa => {
+                decode_frame(&self.scratch.packet, channels, &mut samples).is_some()
+            }
+        };
+
+        if valid && !samples.is_empty() {
+            // packets need not be stored in order, so keep the queue sorted by position
+            let index = self.audio.iter().rposition(|x| x.pos <= pos).map(|x| x + 1).unwrap_or(0);
+            self.audio.insert(index, AudioChunk { pos, samples });
+        } else {
+            self.scratch.audio.push(samples);
+        }
+
+        Ok(())
+    }
+
+    fn read_subtitle(self: &mut Decoder<TReader>, packet_len: u32) -> Result<(), io::Error> {
+        if packet_len < 17 {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        let track = self.reader.read_u8()?;
+
+        if self.subtitle_track != Some(track) {
+            self.reader.skip((packet_len - 1) as u64)?;
+            return Ok(());
+        }
+
+        let pts = self.reader.read_u64()?;
+        let duration = self.reader.read_u64()?;
+        let text_len = packet_len as u64 - 17;
+
+        let mut text = Vec::new();
+        self.reader.read_to_vec(text_len, &mut text)?;
+
+        let text = match String::from_utf8(text) {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(io::Error::from(io::ErrorKind::InvalidData));
+            }
+        };
+
+        if self.subtitles.len() == MAX_QUEUED_SUBTITLES {
+            self.subtitles.pop_front();
+        }
+
+        self.subtitles.push_back(Subtitle { track, pts: self.timebase.to_seconds(pts), duration: self.timebase.to_seconds(duration), text });
+
+        Ok(())
+    }
+
+    /// Read the stream info block. Returns None if the encoder never filled it in (for example because it was writing to a non-seekable destination)
+    fn read_stream_info(reader: &mut StreamReader<TReader>, flags: u8) -> Result<Option<StreamInfo>, DecodeError> {
+        let mut data = [0;28];
+
+        match reader.read_exact(&mut data) {
+            Ok(_) => {}
+            Err(e) => {
+                return Err(DecodeError::IOError(e));
+            }
+        };
+
+        if flags & PFV_FLAG_FINALIZED == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(StreamInfo {
+            frame_count: LittleEndian::read_u64(&data[0..8]),
+            duration: LittleEndian::read_u64(&data[8..16]),
+            max_packet_size: LittleEndian::read_u32(&data[16..20]),
+            index_offset: LittleEndian::read_u64(&data[20..28]),
+        }))
+    }
+
+    fn read_tracks(reader: &mut StreamReader<TReader>, tracks: &mut Vec<Track>) -> Result<(), DecodeError> {
+        let count = match reader.read_u8() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(DecodeError::IOError(e));
+            }
+        };
+
+        for _ in 0..count {
+            let kind = match reader.read_u8() {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(DecodeError::IOError(e));
+                }
+            };
+
+            let language_len = match reader.read_u16() {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(DecodeError::IOError(e));
+                }
+            };
+
+            let language = Decoder::<TReader>::read_string(reader, language_len as u64)?;
+            let id = tracks.len() as TrackId;
+
+            let kind = match kind {
+                1 => {
+                    match Decoder::<TReader>::read_audio_format(reader)? {
+                        Some(format) => TrackKind::Audio(format),
+                        None => {
+                            return Err(DecodeError::FormatError);
+                        }
+                    }
+                }
+                2 => TrackKind::Subtitle,
+                _ => {
+                    return Err(DecodeError::FormatError);
+                }
+            };
+
+            tracks.push(Track { id, kind, language });
+        }
+
+        Ok(())
+    }
+
+    fn read_audio_format(reader: &mut StreamReader<TReader>) -> Result<Option<AudioFormat>, DecodeError> {
+        let codec = match reader.read_u8() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(DecodeError::IOError(e));
+            }
+        };
+
+        let channels = match reader.read_u8() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(DecodeError::IOError(e));
+            }
+        };
+
+        let samplerate = match reader.read_u32() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(DecodeError::IOError(e));
+            }
+        };
+
+        if codec == 0 {
+            return Ok(None);
+        }
+
+        if channels == 0 || samplerate == 0 {
+            return Err(DecodeError::FormatError);
+        }
+
+        // audio tracks using a codec we don't know about are ignored, rather than refusing to play the video
+        Ok(AudioCodec::from_id(codec).map(|codec| AudioFormat { codec, channels: channels as u32, samplerate }))
+    }
+
+    fn read_metadata(reader: &mut StreamReader<TReader>) -> Result<Vec<(String, String)>, DecodeError> {
+        let count = match reader.read_u16() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(DecodeError::IOError(e));
+            }
+        };
+
+        let mut metadata = Vec::with_capacity(count as usize);
+
+        for _ in 0..count {
+            let key_len = match reader.read_u16() {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(DecodeError::IOError(e));
+                }
+            };
+
+            let key = Decoder::<TReader>::read_string(reader, key_len as u64)?;
+
+            let value_len = match reader.read_u32() {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(DecodeError::IOError(e));
+                }
+            };
+
+            let value = Decoder::<TReader>::read_string(reader, value_len as u64)?;
+
+            metadata.push((key, value));
+        }
+
+        Ok(metadata)
+    }
+
+    fn read_string(reader: &mut StreamReader<TReader>, len: u64) -> Result<String, DecodeError> {
+        // read_to_vec doesn't allocate the whole length up front, so a corrupt length can't trigger a huge allocation
+        let mut bytes = Vec::new();
+
+        match reader.read_to_vec(len, &mut bytes) {
+            Ok(_) => {}
+            Err(e) => {
+                return Err(DecodeError::IOError(e));
+            }
+        };
+
+        match String::from_utf8(bytes) {
+            Ok(v) => Ok(v),
+            Err(_) => Err(DecodeError::FormatError)
+        }
+    }
+
+    fn decode_iframe(self: &mut Decoder<TReader>) -> Result<(), io::Error> {
+        let payload = &self.scratch.packet[..];
+
+        if payload.len() < 19 {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        // read symbol frequency table
+        let mut table = [0;16];
+        table.copy_from_slice(&payload[0..16]);
+
+        // construct huffman tree
+        let tree = HuffmanTree::from_table(&table);
+
+        // fetch qtables
+        let qtable_y = Decoder::<TReader>::get_qtable(&self.qtables, payload[16])?;
+        let qtable_u = Decoder::<TReader>::get_qtable(&self.qtables, payload[17])?;
+        let qtable_v = Decoder::<TReader>::get_qtable(&self.qtables, payload[18])?;
+
+        Decoder::<TReader>::read_slice_table(self.version, &self.slice_groups, payload, &mut self.scratch.slice_ranges)?;
+
+        #[cfg(feature = "multithreading")]
+        let tp = if self.threadpool.current_num_threads() > 1 { Some(self.threadpool.as_ref()) } else { None };
+
+        // decode RLE coefficients of each slice
+        let mut coeff_remaining = &mut self.scratch.coefficients[..];
+        let mut concealed_remaining = &mut self.scratch.concealed[..];
+        let mut ranges_remaining = &self.scratch.slice_ranges[..];
+
+        for group in &self.slice_groups {
+            let (group_coeff, next_coeff) = coeff_remaining.split_at_mut(group.num_slices * group.blocks_per_slice * 256);
+            let (group_concealed, next_concealed) = concealed_remaining.split_at_mut(group.num_slices * group.blocks_per_slice);
+            let (group_ranges, next_ranges) = ranges_remaining.split_at(group.num_slices);
+            coeff_remaining = next_coeff;
+            concealed_remaining = next_concealed;
+            ranges_remaining = next_ranges;
+
+            let decode_slice = |((slice_coeff, slice_concealed), range): ((&mut [i16], &mut [bool]), &Range<usize>)| {
+                let data = &payload[range.clone()];
+                let mut bitreader = BitReader::new(data);
+                let bitstream_length = data.len() as u64 * 8;
+
+                slice_coeff.fill(0);
+                let is_corrupt = read_rle(&tree, &mut bitreader, bitstream_length, slice_coeff).is_err();
+
+                // if slice is corrupt, leave the affected blocks unchanged from the previous frame
+                slice_concealed.fill(is_corrupt);
+            };
+
+            #[cfg(feature = "multithreading")]
+            if let Some(tp) = tp {
+                tp.install(|| {
+                    group_coeff.par_chunks_mut(group.blocks_per_slice * 256).zip(group_concealed.par_chunks_mut(group.blocks_per_slice))
+                        .zip(group_ranges.par_iter()).for_each(decode_slice);
+                });
+                continue;
             }
-            coefficients[block_offset..block_offset+256].copy_from_slice(&block_coeff);
+
+            group_coeff.chunks_mut(group.blocks_per_slice * 256).zip(group_concealed.chunks_mut(group.blocks_per_slice))
+                .zip(group_ranges.iter()).for_each(decode_slice);
         }
 
-        let mut subblocks = coefficients.chunks_exact(64);
-        let mut headers = block_headers.iter();
+        // reconstruct each plane into the backbuffer
+        let coefficients = &self.scratch.coefficients[..];
+        let concealed = &self.scratch.concealed[..];
+        let (coeff_y, coeff_uv) = coefficients.split_at(self.framebuffer.plane_y.pixels.len());
+        let (coeff_u, coeff_v) = coeff_uv.split_at(self.framebuffer.plane_u.pixels.len());
+        let (concealed_y, concealed_uv) = concealed.split_at(coeff_y.len() / 256);
+        let (concealed_u, concealed_v) = concealed_uv.split_at(coeff_u.len() / 256);
 
-        // deserialize each plane
         #[cfg(feature = "multithreading")]
         {
-            Decoder::<TReader>::deserialize_plane_delta(self.framebuffer.plane_y.width, self.framebuffer.plane_y.height,
-                &mut headers, &mut subblocks, qtable_y, &mut self.framebuffer.plane_y, &self.threadpool);
-                
-            Decoder::<TReader>::deserialize_plane_delta(self.framebuffer.plane_u.width, self.framebuffer.plane_u.height,
-                &mut headers, &mut subblocks, qtable_u, &mut self.framebuffer.plane_u, &self.threadpool);
-                
-            Decoder::<TReader>::deserialize_plane_delta(self.framebuffer.plane_v.width, self.framebuffer.plane_v.height,
-                &mut headers, &mut subblocks, qtable_v, &mut self.framebuffer.plane_v, &self.threadpool);
+            Decoder::<TReader>::reconstruct_plane(coeff_y, concealed_y, qtable_y, &self.framebuffer.plane_y, &mut self.backbuffer.plane_y, tp);
+            Decoder::<TReader>::reconstruct_plane(coeff_u, concealed_u, qtable_u, &self.framebuffer.plane_u, &mut self.backbuffer.plane_u, tp);
+            Decoder::<TReader>::reconstruct_plane(coeff_v, concealed_v, qtable_v, &self.framebuffer.plane_v, &mut self.backbuffer.plane_v, tp);
         }
 
         #[cfg(not(feature = "multithreading"))]
         {
-            Decoder::<TReader>::deserialize_plane_delta(self.framebuffer.plane_y.width, self.framebuffer.plane_y.height,
-                &mut headers, &mut subblocks, qtable_y, &mut self.framebuffer.plane_y);
-                
-            Decoder::<TReader>::deserialize_plane_delta(self.framebuffer.plane_u.width, self.framebuffer.plane_u.height,
-                &mut headers, &mut subblocks, qtable_u, &mut self.framebuffer.plane_u);
-                
-            Decoder::<TReader>::deserialize_plane_delta(self.framebuffer.plane_v.width, self.framebuffer.plane_v.height,
-                &mut headers, &mut subblocks, qtable_v, &mut self.framebuffer.plane_v);
+            Decoder::<TReader>::reconstruct_plane(coeff_y, concealed_y, qtable_y, &self.framebuffer.plane_y, &mut self.backbuffer.plane_y);
+            Decoder::<TReader>::reconstruct_plane(coeff_u, concealed_u, qtable_u, &self.framebuffer.plane_u, &mut self.backbuffer.plane_u);
+            Decoder::<TReader>::reconstruct_plane(coeff_v, concealed_v, qtable_v, &self.framebuffer.plane_v, &mut self.backbuffer.plane_v);
         }
 
+        core::mem::swap(&mut self.framebuffer, &mut self.backbuffer);
+
         Ok(())
     }
 
-    fn deserialize_plane(width: usize, height: usize, subblocks: &mut ChunksExact<i16>, q_table: &[i32;64], target: &mut VideoPlane, #[cfg(feature = "multithreading")] tp: &rayon::ThreadPool) {
-        let blocks_wide = width / 16;
-        let blocks_high = height / 16;
-        let total_blocks = blocks_wide * blocks_high;
+    fn decode_pframe(self: &mut Decoder<TReader>) -> Result<(), io::Error> {
+        let payload = &self.scratch.packet[..];
+
+        if payload.len() < 19 {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        // read symbol frequency table
+        let mut table = [0;16];
+        table.copy_from_slice(&payload[0..16]);
+
+        // construct huffman tree
+        let tree = HuffmanTree::from_table(&table);
 
-        let mut enc_plane = EncodedIPlane { blocks_wide: blocks_wide, blocks_high: blocks_high, width: width, height: height,
-            blocks: Vec::with_capacity(total_blocks) };
+        // fetch qtables
+        let qtable_y = Decoder::<TReader>::get_qtable(&self.qtables, payload[16])?;
+        let qtable_u = Decoder::<TReader>::get_qtable(&self.qtables, payload[17])?;
+        let qtable_v = Decoder::<TReader>::get_qtable(&self.qtables, payload[18])?;
 
-        for _ in 0..total_blocks {
-            let s0 = subblocks.next().unwrap();
-            let s1 = subblocks.next().unwrap();
-            let s2 = subblocks.next().unwrap();
-            let s3 = subblocks.next().unwrap();
+        Decoder::<TReader>::read_slice_table(self.version, &self.slice_groups, payload, &mut self.scratch.slice_ranges)?;
 
-            let block = EncodedMacroBlock { subblocks: [
-                DctQuantizedMatrix8x8::from_slice(s0),
-                DctQuantizedMatrix8x8::from_slice(s1),
-                DctQuantizedMatrix8x8::from_slice(s2),
-                DctQuantizedMatrix8x8::from_slice(s3),
-            ] };
+        #[cfg(feature = "multithreading")]
+        let tp = if self.threadpool.current_num_threads() > 1 { Some(self.threadpool.as_ref()) } else { None };
+
+        // decode block headers & RLE coefficients of each slice
+        let mut headers_remaining = &mut self.scratch.block_headers[..];
+        let mut coeff_remaining = &mut self.scratch.coefficients[..];
+        let mut ranges_remaining = &self.scratch.slice_ranges[..];
+
+        for group in &self.slice_groups {
+            let (group_headers, next_headers) = headers_remaining.split_at_mut(group.num_slices * group.blocks_per_slice);
+            let (group_coeff, next_coeff) = coeff_remaining.split_at_mut(group.num_slices * group.blocks_per_slice * 256);
+            let (group_ranges, next_ranges) = ranges_remaining.split_at(group.num_slices);
+            headers_remaining = next_headers;
+            coeff_remaining = next_coeff;
+            ranges_remaining = next_ranges;
+
+            let decode_slice = |((slice_headers, slice_coeff), range): ((&mut [DeltaBlockHeader], &mut [i16]), &Range<usize>)| {
+                let data = &payload[range.clone()];
+                let mut bitreader = BitReader::new(data);
+                let bitstream_length = data.len() as u64 * 8;
+
+                slice_coeff.fill(0);
+
+                if Decoder::<TReader>::read_pslice(&tree, &mut bitreader, bitstream_length, slice_headers, slice_coeff).is_err() {
+                    // slice is corrupt - conceal by copying the affected blocks from the previous frame
+                    slice_headers.fill(DeltaBlockHeader::default());
+                }
+            };
+
+            #[cfg(feature = "multithreading")]
+            if let Some(tp) = tp {
+                tp.install(|| {
+                    group_headers.par_chunks_mut(group.blocks_per_slice).zip(group_coeff.par_chunks_mut(group.blocks_per_slice * 256))
+                        .zip(group_ranges.par_iter()).for_each(decode_slice);
+                });
+                continue;
+            }
 
-            enc_plane.blocks.push(block);
+            group_headers.chunks_mut(group.blocks_per_slice).zip(group_coeff.chunks_mut(group.blocks_per_slice * 256))
+                .zip(group_ranges.iter()).for_each(decode_slice);
         }
 
+        // reconstruct each plane into the backbuffer
+        let coefficients = &self.scratch.coefficients[..];
+        let headers = &self.scratch.block_headers[..];
+        let (coeff_y, coeff_uv) = coefficients.split_at(self.framebuffer.plane_y.pixels.len());
+        let (coeff_u, coeff_v) = coeff_uv.split_at(self.framebuffer.plane_u.pixels.len());
+        let (headers_y, headers_uv) = headers.split_at(coeff_y.len() / 256);
+        let (headers_u, headers_v) = headers_uv.split_at(coeff_u.len() / 256);
+
         #[cfg(feature = "multithreading")]
-        VideoPlane::decode_plane_into(&enc_plane, q_table, target, tp);
+        {
+            Decoder::<TReader>::reconstruct_plane_delta(coeff_y, headers_y, qtable_y, &self.framebuffer.plane_y, &mut self.backbuffer.plane_y, tp);
+            Decoder::<TReader>::reconstruct_plane_delta(coeff_u, headers_u, qtable_u, &self.framebuffer.plane_u, &mut self.backbuffer.plane_u, tp);
+            Decoder::<TReader>::reconstruct_plane_delta(coeff_v, headers_v, qtable_v, &self.framebuffer.plane_v, &mut self.backbuffer.plane_v, tp);
+        }
 
         #[cfg(not(feature = "multithreading"))]
-        VideoPlane::decode_plane_into(&enc_plane, q_table, target);
-    }
-
-    fn deserialize_plane_delta(width: usize, height: usize, headers: &mut Iter<DeltaBlockHeader>, subblocks: &mut ChunksExact<i16>, q_table: &[i32;64], target: &mut VideoPlane,
-        #[cfg(feature = "multithreading")] tp: &rayon::ThreadPool) {
-        let blocks_wide = width / 16;
-        let blocks_high = height / 16;
-        let total_blocks = blocks_wide * blocks_high;
-
-        let mut enc_plane = EncodedPPlane { blocks_wide: blocks_wide, blocks_high: blocks_high, width: width, height: height,
-            blocks: Vec::with_capacity(total_blocks) };
-
-        for _ in 0..total_blocks {
-            let header = headers.next().unwrap();
-
-            let s0 = subblocks.next().unwrap();
-            let s1 = subblocks.next().unwrap();
-            let s2 = subblocks.next().unwrap();
-            let s3 = subblocks.next().unwrap();
-
-            let block = DeltaEncodedMacroBlock {
-                motion_x: header.mvec_x,
-                motion_y: header.mvec_y,
-                subblocks: if header.has_coeff { Some([
-                    DctQuantizedMatrix8x8::from_slice(s0),
-                    DctQuantizedMatrix8x8::from_slice(s1),
-                    DctQuantizedMatrix8x8::from_slice(s2),
-                    DctQuantizedMatrix8x8::from_slice(s3),
-                ]) } else { None }
-            };
+        {
+            Decoder::<TReader>::reconstruct_plane_delta(coeff_y, headers_y, qtable_y, &self.framebuffer.plane_y, &mut self.backbuffer.plane_y);
+            Decoder::<TReader>::reconstruct_plane_delta(coeff_u, headers_u, qtable_u, &self.framebuffer.plane_u, &mut self.backbuffer.plane_u);
+            Decoder::<TReader>::reconstruct_plane_delta(coeff_v, headers_v, qtable_v, &self.framebuffer.plane_v, &mut self.backbuffer.plane_v);
+        }
+
+        core::mem::swap(&mut self.framebuffer, &mut self.backbuffer);
+
+        Ok(())
+    }
+
+    fn read_pslice(tree: &HuffmanTree, bitreader: &mut BitReader, bitstream_length: u64, headers: &mut [DeltaBlockHeader], coefficients: &mut [i16]) -> Result<(), HuffmanError> {
+        // read block headers
+        for header in headers.iter_mut() {
+            *header = DeltaBlockHeader::default();
+
+            let has_mvec = bitreader.read_bit().map_err(HuffmanError::IOError)?;
+            header.has_coeff = bitreader.read_bit().map_err(HuffmanError::IOError)?;
 
-            enc_plane.blocks.push(block);
+            if has_mvec {
+                header.mvec_x = bitreader.read_signed(7).map_err(HuffmanError::IOError)? as i8;
+                header.mvec_y = bitreader.read_signed(7).map_err(HuffmanError::IOError)? as i8;
+            }
         }
 
+        // decode block coefficients
+        for (header, block_coeff) in headers.iter().zip(coefficients.chunks_exact_mut(256)) {
+            if header.has_coeff {
+                // read 256 coefficients from bit stream
+                read_rle(tree, bitreader, bitstream_length, block_coeff)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_qtable(qtables: &[[i32;64]], index: u8) -> Result<&[i32;64], io::Error> {
+        match qtables.get(index as usize) {
+            Some(v) => Ok(v),
+            None => Err(io::Error::from(io::ErrorKind::InvalidData))
+        }
+    }
+
+    /// Locate the bitstream of each slice within a frame packet
+    fn read_slice_table(version: u32, slice_groups: &[SliceGroup], payload: &[u8], ranges: &mut Vec<Range<usize>>) -> Result<(), io::Error> {
+        if version == PFV_VERSION_LEGACY {
+            // legacy streams code the entire frame as one bitstream
+            ranges.clear();
+            ranges.push(19..payload.len());
+            return Ok(());
+        }
+
+        let num_slices = slice_groups.iter().map(|x| x.num_slices).sum();
+
+        if !read_slices(&payload[19..], num_slices, ranges) {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        for range in ranges.iter_mut() {
+            *range = (range.start + 19)..(range.end + 19);
+        }
+
+        Ok(())
+    }
+
+    /// Decode I-frame macroblocks directly into the target plane, one row of blocks at a time
+    fn reconstruct_plane(coefficients: &[i16], concealed: &[bool], q_table: &[i32;64], refplane: &VideoPlane, target: &mut VideoPlane,
+        #[cfg(feature = "multithreading")] tp: Option<&rayon::ThreadPool>) {
+        let blocks_wide = target.width / 16;
+        let stride = target.width;
+
+        let decode_row = |block_y: usize, rows: &mut [u8], row_coeff: &[i16], row_concealed: &[bool]| {
+            for block_x in 0..blocks_wide {
+                let block = if row_concealed[block_x] {
+                    refplane.get_block(block_x * 16, block_y * 16)
+                } else {
+                    let c = &row_coeff[(block_x * 256)..((block_x + 1) * 256)];
+
+                    let src = EncodedMacroBlock { subblocks: [
+                        DctQuantizedMatrix8x8::from_slice(&c[0..64]),
+                        DctQuantizedMatrix8x8::from_slice(&c[64..128]),
+                        DctQuantizedMatrix8x8::from_slice(&c[128..192]),
+                        DctQuantizedMatrix8x8::from_slice(&c[192..256]),
+                    ] };
+
+                    VideoPlane::decode_block(&src, q_table)
+                };
+
+                block.blit_into(rows, stride, block_x * 16);
+            }
+        };
+
         #[cfg(feature = "multithreading")]
-        VideoPlane::decode_plane_delta_into(&enc_plane, target, q_table, tp);
+        if let Some(tp) = tp {
+            tp.install(|| {
+                target.pixels.par_chunks_mut(stride * 16).zip(coefficients.par_chunks(blocks_wide * 256)).zip(concealed.par_chunks(blocks_wide))
+                    .enumerate().for_each(|(block_y, ((rows, row_coeff), row_blocks))| decode_row(block_y, rows, row_coeff, row_blocks));
+            });
+            return;
+        }
 
-        #[cfg(not(feature = "multithreading"))]
-        VideoPlane::decode_plane_delta_into(&enc_plane, target, q_table);
+        target.pixels.chunks_mut(stride * 16).zip(coefficients.chunks(blocks_wide * 256)).zip(concealed.chunks(blocks_wide))
+            .enumerate().for_each(|(block_y, ((rows, row_coeff), row_blocks))| decode_row(block_y, rows, row_coeff, row_blocks));
     }
-}
\ No newline at end of file
+
+    /// Decode P-frame macroblocks directly into the target plane, one row of blocks at a time
+    fn reconstruct_plane_delta(coefficients: &[i16], headers: &[DeltaBlockHeader], q_table: &[i32;64], refplane: &VideoPlane, target: &mut VideoPlane,
+        #[cfg(feature = "multithreading")] tp: Option<&rayon::ThreadPool>) {
+        let blocks_wide = target.width / 16;
+        let stride = target.width;
+
+        let decode_row = |block_y: usize, rows: &mut [u8], row_coeff: &[i16], row_headers: &[DeltaBlockHeader]| {
+            for block_x in 0..blocks_wide {
+                let mut header = row_headers[block_x];
+
+                // motion vectors pointing outside of the reference plane can only come from a corrupt stream - ignore them
+                let sx = (block_x * 16) as i32 + header.mvec_x as i32;
+                let sy = (block_y * 16) as i32 + header.mvec_y as i32;
+
+                if sx < 0 || sx > refplane.width as i32 - 16 || sy < 0 || sy > refplane.height as i32 - 16 {
+                    header = DeltaBlockHeader::default();
+                }
+
+                let c = &row_coeff[(block_x * 256)..((block_x + 1) * 256)];
+
+                let src = DeltaEncodedMacroBlock {
+                    motion_x: header.mvec_x,
+                    motion_y: header.mvec_y,
+                    subblocks: if header.has_coeff { Some([
+                        DctQuantizedMatrix8x8::from_slice(&c[0..64]),
+                        DctQuantizedMatrix8x8::from_slice(&c[64..128]),
+                        DctQuantizedMatrix8x8::from_slice(&c[128..192]),
+                        DctQuantizedMatrix8x8::from_slice(&c[192..256]),
+                    ]) } else { None }
+                };
+
+                let block = VideoPlane::decode_block_delta(&src, refplane, block_x * 16, block_y * 16, q_table);
+                block.blit_into(rows, stride, block_x * 16);
+            }
+        };
+
+        #[cfg(feature = "multithreading")]
+        if let Some(tp) = tp {
+            tp.install(|| {
+                target.pixels.par_chunks_mut(stride * 16).zip(coefficients.par_chunks(blocks_wide * 256)).zip(headers.par_chunks(blocks_wide))
+                    .enumerate().for_each(|(block_y, ((rows, row_coeff), row_blocks))| decode_row(block_y, rows, row_coeff, row_blocks));
+            });
+            return;
+        }
+
+        target.pixels.chunks_mut(stride * 16).zip(coefficients.chunks(blocks_wide * 256)).zip(headers.chunks(blocks_wide))
+            .enumerate().for_each(|(block_y, ((rows, row_coeff), row_blocks))| decode_row(block_y, rows, row_coeff, row_blocks));
+    }
+}
+
+// raw packet access used by stream analysis & validation
+#[cfg(feature = "std")]
+impl<TReader: Source> Decoder<TReader> {
+    /// The most recently presented frame
+    pub(crate) fn current_frame(self: &Decoder<TReader>) -> &VideoFrame {
+        &self.retframe
+    }
+
+    #[cfg(test)]
+    pub(crate) fn version(self: &Decoder<TReader>) -> u32 {
+        self.version
+    }
+
+    pub(crate) fn qtables(self: &Decoder<TReader>) -> &[[i32;64]] {
+        &self.qtables
+    }
+
+    /// Read the next packet of any type without interpreting it. Returns None if the source ends cleanly between two packets
+    pub(crate) fn read_raw_packet(self: &mut Decoder<TReader>) -> Result<Option<RawPacket>, io::Error> {
+        let offset = self.reader.position;
+
+        let mut packet_type = [0;1];
+        if self.reader.read(&mut packet_type)? == 0 {
+            return Ok(None);
+        }
+
+        let packet_type = packet_type[0];
+        let packet_len = self.reader.read_u32()?;
+
+        let pts = if self.has_timestamps && (packet_type == PFV_PACKET_IFRAME || packet_type == PFV_PACKET_PFRAME) {
+            Some(self.reader.read_u64()?)
+        } else {
+            None
+        };
+
+        // don't trust the length enough to allocate all of it up front
+        self.scratch.packet.clear();
+        self.reader.read_to_vec(packet_len as u64, &mut self.scratch.packet)?;
+
+        Ok(Some(RawPacket { offset, packet_type, pts }))
+    }
+
+    /// Payload of the most recently decoded frame packet
+    pub(crate) fn last_packet(self: &Decoder<TReader>) -> &[u8] {
+        &self.scratch.packet
+    }
+
+    /// Locate the bitstream of each slice within the most recently decoded frame packet
+    pub(crate) fn last_packet_slices(self: &Decoder<TReader>, ranges: &mut Vec<Range<usize>>) -> Result<(), io::Error> {
+        Decoder::<TReader>::read_slice_table(self.version, &self.slice_groups, &self.scratch.packet, ranges)
+    }
+
+    /// Number of macroblocks per slice, for each slice of a frame (in bitstream order)
+    pub(crate) fn slice_sizes(self: &Decoder<TReader>) -> Vec<usize> {
+        self.slice_groups.iter().flat_map(|x| core::iter::repeat_n(x.blocks_per_slice, x.num_slices)).collect()
+    }
+
+    /// Size of the Y, U, and V planes in macroblocks
+    pub(crate) fn plane_blocks(self: &Decoder<TReader>) -> [(usize, usize);3] {
+        [
+            (self.framebuffer.plane_y.width / 16, self.framebuffer.plane_y.height / 16),
+            (self.framebuffer.plane_u.width / 16, self.framebuffer.plane_u.height / 16),
+            (self.framebuffer.plane_v.width / 16, self.framebuffer.plane_v.height / 16),
+        ]
+    }
+}
+
+impl<TReader: SeekableSource> Decoder<TReader> {
+    /// Rewind to the first frame of the stream
+    pub fn reset(self: &mut Decoder<TReader>) -> Result<(), io::Error> {
+        self.jump(self.reset_pos)?;
+        self.rewind();
+
+        Ok(())
+    }
+
+    /// Seek so that the next frame decoded is the given frame (counting from 0, drop frames included), and set the playback clock to its timestamp.
+    /// Finalized streams jump to the closest preceding I-frame listed in the stream's index & decode forward from there; other streams (and streams whose index
+    /// is damaged) decode forward from the current frame (or from the start, when seeking backwards). Subtitles & user data from before the target frame are discarded, as is audio up to the target frame.
+    /// Returns Ok(false) if the stream has fewer frames, leaving the decoder at the end of the stream
+    pub fn seek(self: &mut Decoder<TReader>, frame: u64) -> Result<bool, io::Error> {
+        let keyframe = match self.stream_info {
+            Some(info) => self.find_keyframe(info.index_offset, frame)?,
+            None => None
+        };
+
+        // decoding forward from the current frame is cheaper when no I-frame lies in between
+        let current = !self.eof && self.frame_index <= frame;
+
+        match keyframe {
+            Some(entry) if !(current && entry.frame <= self.frame_index) => {
+                self.jump(entry.offset)?;
+                self.rewind();
+                self.reader.position = entry.offset;
+                self.frame_index = entry.frame;
+            }
+            _ => {
+                if !current {
+                    self.reset()?;
+                }
+            }
+        }
+
+        while self.frame_index < frame {
+            if self.decode_next_frame()?.is_none() {
+                return Ok(false);
+            }
+        }
+
+        self.update_retframe();
+        self.user_data.clear();
+        self.subtitles.clear();
+        self.clear_audio();
+
+        match self.peek_frame_pts()? {
+            Some(v) => {
+                self.clock = self.timebase.to_seconds(v);
+                Ok(true)
+            }
+            None => Ok(false)
+        }
+    }
+
+    /// Reposition the source at the given byte offset from the start of the stream
+    fn jump(self: &mut Decoder<TReader>, offset: u64) -> Result<(), io::Error> {
+        // seek relative to the current position, as the stream need not start at offset 0 of the reader
+        self.reader.inner.seek(SeekFrom::Current(offset as i64 - self.reader.position as i64))?;
+        self.reader.position = offset;
+
+        Ok(())
+    }
+
+    /// Look up the last I-frame before the given frame in the stream's index, leaving the source where it was.
+    /// Packets belonging to a frame (such as its subtitles) are written ahead of it, so an I-frame at the target itself would skip them.
+    /// Returns None if the index can't be read, so that seek falls back to decoding forward
+    fn find_keyframe(self: &mut Decoder<TReader>, index_offset: u64, frame: u64) -> Result<Option<IndexEntry>, io::Error> {
+        let resume = self.reader.position;
+        let index = self.read_index(index_offset);
+        self.jump(resume)?;
+
+        let data = match index {
+            Ok(v) => v,
+            Err(_) => {
+                return Ok(None);
+            }
+        };
+
+        // entries are in stream order
+        let keyframe = data.chunks_exact(24)
+            .map(|x| IndexEntry { frame: LittleEndian::read_u64(&x[0..8]), offset: LittleEndian::read_u64(&x[16..24]) })
+            .take_while(|x| x.frame < frame)
+            .last();
+
+        Ok(keyframe)
+    }
+
+    /// Read the payload of the index packet at the given offset. Leaves the source at an arbitrary position
+    fn read_index(self: &mut Decoder<TReader>, index_offset: u64) -> Result<Vec<u8>, io::Error> {
+        // measure the stream, so that a corrupt length can't make us read past its end
+        let current = self.reader.inner.seek(SeekFrom::Current(0))?;
+        let end = self.reader.inner.seek(SeekFrom::End(0))?;
+        let stream_len = end - current + self.reader.position;
+        self.reader.position = stream_len;
+
+        self.jump(index_offset)?;
+
+        let packet_type = self.reader.read_u8()?;
+        let packet_len = self.reader.read_u32()?;
+
+        if packet_type != PFV_PACKET_INDEX || packet_len % 24 != 0 || index_offset + 5 + packet_len as u64 > stream_len {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        let mut data = Vec::new();
+        self.reader.read_to_vec(packet_len as u64, &mut data)?;
+
+        Ok(data)
+    }
+}
+
+impl<'a, TReader: Source> Iterator for Frames<'a, TReader> {
+    type Item = Result<OwnedDecodedFrame, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.decoder.next_frame() {
+            Ok(Some(v)) => Some(Ok(OwnedDecodedFrame { index: v.index, pts: v.pts, frame_type: v.frame_type, frame: v.frame.clone() })),
+            Ok(None) => None,
+            Err(e) => Some(Err(e))
+        }
+    }
+}
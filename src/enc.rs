@@ -1,189 +1,1016 @@
-use std::io::{Write, Cursor};
+use std::io::{Write, Seek, SeekFrom, Cursor};
+use std::time::{Duration, Instant};
+#[cfg(feature = "multithreading")]
+use std::sync::Arc;
 
 use bitstream_io::{BitWriter, BitWrite};
 use byteorder::{WriteBytesExt, LittleEndian};
 
-use crate::common::{EncodedIFrame, PFV_MAGIC, PFV_VERSION, EncodedPFrame};
-use crate::frame::VideoFrame;
-use crate::dct::{Q_TABLE_INTER, Q_TABLE_INTRA};
+use crate::common::{EncodedIFrame, PFV_MAGIC, PFV_VERSION, PFV_FLAG_TIMESTAMPS, PFV_FLAG_FINALIZED, PFV_FLAGS_OFFSET, PFV_STREAM_INFO_LEN, PFV_PACKET_EOF, PFV_PACKET_INDEX, PFV_PACKET_IFRAME, PFV_PACKET_PFRAME, PFV_PACKET_USER_DATA, PFV_PACKET_AUDIO, PFV_PACKET_SUBTITLE, EncodedPFrame, MotionSearch, MAX_MOTION_VECTOR};
+use crate::audio::{AudioFormat, AudioCodec};
+use crate::track::{Track, TrackId, TrackKind, VIDEO_TRACK};
+use crate::qoa::{QoaLms, QOA_FRAME_LEN, encode_frame};
+use crate::frame::{VideoFrame, Timebase};
+use crate::metadata::KEY_COLORIMETRY;
+use crate::metrics::{FrameMetrics, compare_cropped};
+use crate::dct::{Q_TABLE_INTER, Q_TABLE_INTRA, RdoModel};
 use crate::plane::VideoPlane;
 use crate::rle::{rle_encode, rle_create_huffman, update_table};
+use crate::slice::{slice_layout, write_rle, rle_bits, write_slices};
+use crate::dec::FrameType;
+
+/// Wraps the encoder's writer & keeps track of how many bytes have been written, so that packet offsets can be recorded without requiring Seek.
+/// Also remembers whether a write has failed, as that may have left a partially written packet behind
+struct StreamWriter<W: Write> {
+    inner: W,
+    position: u64,
+    failed: bool,
+}
+
+impl<W: Write> Write for StreamWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = match self.inner.write(buf) {
+            Ok(v) => v,
+            Err(e) => {
+                // interrupted writes are retried by write_all
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    self.failed = true;
+                }
+
+                return Err(e);
+            }
+        };
+
+        // write_all fails if nothing could be written
+        if len == 0 && !buf.is_empty() {
+            self.failed = true;
+        }
+
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Debug)]
+pub enum EncodeError {
+    /// Width or height is zero, odd, or larger than 65535, or a frame doesn't match the encoder's dimensions
+    InvalidDimensions,
+    /// Quality is outside of the 0..=10 range
+    InvalidQuality,
+    /// Timebase numerator or denominator is zero
+    InvalidTimebase,
+    /// Frame timestamp was set on a constant framerate encoder, or is lower than the previous frame's
+    InvalidTimestamp,
+    /// Audio format has an unsupported channel count or samplerate
+    InvalidAudioFormat,
+    /// Track doesn't exist or is of the wrong kind, or the stream has too many tracks
+    InvalidTrack,
+    /// Custom quantization table contains a zero (or a value which doesn't fit in 16 bits)
+    InvalidQTable,
+    /// An EncoderBuilder setting (px_err threshold, motion search range) is out of range
+    InvalidSetting,
+    /// Metadata, user data, subtitle text, or audio samples are too long or malformed
+    InvalidData,
+    /// Metadata & tracks can only be added before the header has been written (along with the first packet)
+    HeaderWritten,
+    /// Stream has already been finished
+    AlreadyFinished,
+    /// An earlier write to the output failed, possibly in the middle of a packet, so nothing more can be written to the stream
+    WriteFailed,
+    Io(std::io::Error)
+}
+
+impl From<std::io::Error> for EncodeError {
+    fn from(value: std::io::Error) -> Self {
+        EncodeError::Io(value)
+    }
+}
+
+/// Lagrange multiplier for RDO quantization, relative to the mean squared quantizer step size
+const RDO_LAMBDA: f32 = 0.1;
+
+/// Encoder presets, trading encoding speed & file size for quality
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// Coarser quantization, aggressive block skipping, and a narrow motion search
+    Fast,
+    /// The same settings as Encoder::new at quality 5, plus a keyframe every 60 frames
+    Balanced,
+    /// Fine quantization, little block skipping, a wide motion search, and frequent keyframes
+    Archival,
+}
+
+/// Configures & creates an Encoder. Settings which aren't specified default to those used by Encoder::new at quality 5
+pub struct EncoderBuilder {
+    width: usize,
+    height: usize,
+    timebase: Timebase,
+    variable_framerate: bool,
+    luma_quality: i32,
+    chroma_quality: i32,
+    intra_qtables: Option<([i32;64], [i32;64])>,
+    inter_qtables: Option<([i32;64], [i32;64])>,
+    px_err: Option<f32>,
+    search_range: u32,
+    keyframe_interval: u32,
+    rdo: bool,
+    report_metrics: bool,
+    colorimetry: Option<String>,
+    #[cfg(feature = "multithreading")]
+    num_threads: usize,
+    #[cfg(feature = "multithreading")]
+    threadpool: Option<Arc<rayon::ThreadPool>>,
+}
+
+impl EncoderBuilder {
+    pub fn new(width: usize, height: usize) -> EncoderBuilder {
+        EncoderBuilder { width, height, timebase: Timebase::from_framerate(30), variable_framerate: false,
+            luma_quality: 5, chroma_quality: 5, intra_qtables: None, inter_qtables: None, px_err: None, search_range: 15, keyframe_interval: 0, rdo: false, report_metrics: false, colorimetry: None,
+            #[cfg(feature = "multithreading")]
+            num_threads: 0,
+            #[cfg(feature = "multithreading")]
+            threadpool: None }
+    }
+
+    /// Apply a preset, overwriting the quality, px_err, motion search, and keyframe settings
+    pub fn preset(self: EncoderBuilder, preset: Preset) -> EncoderBuilder {
+        match preset {
+            Preset::Fast => self.quality(7).px_err(14.0).motion_search_range(7).keyframe_interval(120),
+            Preset::Balanced => self.quality(5).px_err(7.5).motion_search_range(15).keyframe_interval(60),
+            Preset::Archival => self.quality(1).px_err(0.5).motion_search_range(63).keyframe_interval(30),
+        }
+    }
+
+    /// Integer framerate, with each frame lasting one tick. Defaults to 30
+    pub fn framerate(self: EncoderBuilder, framerate: u32) -> EncoderBuilder {
+        self.timebase(Timebase::from_framerate(framerate), false)
+    }
+
+    /// Rational timebase (see Encoder::new_with_timebase)
+    pub fn timebase(mut self: EncoderBuilder, timebase: Timebase, variable_framerate: bool) -> EncoderBuilder {
+        self.timebase = timebase;
+        self.variable_framerate = variable_framerate;
+        self
+    }
+
+    /// Quality of both luma & chroma planes, from 0 (best) to 10 (smallest)
+    pub fn quality(self: EncoderBuilder, quality: i32) -> EncoderBuilder {
+        self.luma_quality(quality).chroma_quality(quality)
+    }
+
+    /// Quality of the Y plane, from 0 (best) to 10 (smallest). Also determines the default px_err threshold
+    pub fn luma_quality(mut self: EncoderBuilder, quality: i32) -> EncoderBuilder {
+        self.luma_quality = quality;
+        self
+    }
+
+    /// Quality of the U & V planes, from 0 (best) to 10 (smallest)
+    pub fn chroma_quality(mut self: EncoderBuilder, quality: i32) -> EncoderBuilder {
+        self.chroma_quality = quality;
+        self
+    }
+
+    /// Use custom quantization tables (in natural, row-major order) for I-frames instead of scaling the default table by quality
+    pub fn intra_qtables(mut self: EncoderBuilder, luma: [i32;64], chroma: [i32;64]) -> EncoderBuilder {
+        self.intra_qtables = Some((luma, chroma));
+        self
+    }
+
+    /// Use custom quantization tables (in natural, row-major order) for P-frames instead of scaling the default table by quality
+    pub fn inter_qtables(mut self: EncoderBuilder, luma: [i32;64], chroma: [i32;64]) -> EncoderBuilder {
+        self.inter_qtables = Some((luma, chroma));
+        self
+    }
+
+    /// Per-pixel error below which P-frame macroblocks are copied from the previous frame without coding a residual. Defaults to 1.5x the luma quality
+    pub fn px_err(mut self: EncoderBuilder, px_err: f32) -> EncoderBuilder {
+        self.px_err = Some(px_err);
+        self
+    }
+
+    /// Largest motion vector component (in pixels, up to 63) the P-frame motion search will consider. 0 disables motion search. Defaults to 15
+    pub fn motion_search_range(mut self: EncoderBuilder, range: u32) -> EncoderBuilder {
+        self.search_range = range;
+        self
+    }
+
+    /// Number of frames between I-frames when using Encoder::encode_frame. 0 (the default) only makes the first frame an I-frame
+    pub fn keyframe_interval(mut self: EncoderBuilder, interval: u32) -> EncoderBuilder {
+        self.keyframe_interval = interval;
+        self
+    }
+
+    /// Enable rate-distortion optimized quantization: instead of truncating each DCT coefficient, pick the quantized levels which best trade
+    /// off error against their actual RLE/huffman bit cost (estimated from the code lengths of the previous frame). This makes encoding considerably slower
+    pub fn rdo_quantization(mut self: EncoderBuilder, enabled: bool) -> EncoderBuilder {
+        self.rdo = enabled;
+        self
+    }
+
+    /// Compute PSNR, SSIM, and MS-SSIM of each reconstructed frame against its source, and pass them to the stats callback (see Encoder::set_stats_callback).
+    /// This is useful for tuning quality settings, but is slow
+    pub fn report_metrics(mut self: EncoderBuilder, enabled: bool) -> EncoderBuilder {
+        self.report_metrics = enabled;
+        self
+    }
+
+    /// Colorimetry of the source material (for example "bt709"), stored as a metadata entry
+    pub fn colorimetry(mut self: EncoderBuilder, colorimetry: &str) -> EncoderBuilder {
+        self.colorimetry = Some(colorimetry.to_string());
+        self
+    }
+
+    /// Number of threads for the encoder's own thread pool (0 picks one per CPU). Ignored if a thread pool is provided
+    #[cfg(feature = "multithreading")]
+    pub fn num_threads(mut self: EncoderBuilder, num_threads: usize) -> EncoderBuilder {
+        self.num_threads = num_threads;
+        self
+    }
+
+    /// Run on an existing thread pool instead of creating a new one
+    #[cfg(feature = "multithreading")]
+    pub fn thread_pool(mut self: EncoderBuilder, threadpool: Arc<rayon::ThreadPool>) -> EncoderBuilder {
+        self.threadpool = Some(threadpool);
+        self
+    }
+
+    pub fn build<W: Write>(self: EncoderBuilder, writer: W) -> Result<Encoder<W>, EncodeError> {
+        if self.width == 0 || self.height == 0 || !self.width.is_multiple_of(2) || !self.height.is_multiple_of(2) || self.width > u16::MAX as usize || self.height > u16::MAX as usize {
+            return Err(EncodeError::InvalidDimensions);
+        }
+
+        if !(0..=10).contains(&self.luma_quality) || !(0..=10).contains(&self.chroma_quality) {
+            return Err(EncodeError::InvalidQuality);
+        }
+
+        if self.timebase.num == 0 || self.timebase.den == 0 {
+            return Err(EncodeError::InvalidTimebase);
+        }
+
+        let px_err = self.px_err.unwrap_or(self.luma_quality as f32 * 1.5);
+
+        if !px_err.is_finite() || px_err < 0.0 || self.search_range > MAX_MOTION_VECTOR as u32 {
+            return Err(EncodeError::InvalidSetting);
+        }
+
+        // px_err is an RMS error per pixel - convert it once to an integer limit on a 16x16 block's sum of squared errors,
+        // so skip decisions are exact integer comparisons (for integer e, e <= x is the same as e <= floor(x))
+        let skip_err = (px_err * px_err * 256.0) as u64;
+
+        let qscale_l = self.luma_quality as f32 * 0.25;
+        let qscale_c = self.chroma_quality as f32 * 0.25;
+
+        let (qtable_intra_l, qtable_intra_c) = self.intra_qtables.unwrap_or((
+            Q_TABLE_INTRA.map(|x| (x as f32 * qscale_l * 0.5).max(1.0) as i32),
+            Q_TABLE_INTRA.map(|x| (x as f32 * qscale_c).max(1.0) as i32)));
+
+        let (qtable_inter_l, qtable_inter_c) = self.inter_qtables.unwrap_or((
+            Q_TABLE_INTER.map(|x| (x as f32 * qscale_l * 0.5).max(1.0) as i32),
+            Q_TABLE_INTER.map(|x| (x as f32 * qscale_c).max(1.0) as i32)));
+
+        let qtables = [&qtable_intra_l, &qtable_intra_c, &qtable_inter_l, &qtable_inter_c];
+
+        if qtables.iter().any(|table| table.iter().any(|x| *x < 1 || *x > u16::MAX as i32)) {
+            return Err(EncodeError::InvalidQTable);
+        }
+
+        #[cfg(feature = "multithreading")]
+        let threadpool = match self.threadpool {
+            Some(v) => v,
+            None => Arc::new(rayon::ThreadPoolBuilder::new().num_threads(self.num_threads).build().unwrap())
+        };
+
+        let rdo = if self.rdo { Some(RdoModel::new(RDO_LAMBDA)) } else { None };
+
+        let metadata = match self.colorimetry {
+            Some(v) => vec![(KEY_COLORIMETRY.to_string(), v)],
+            None => Vec::new()
+        };
+
+        #[cfg(feature = "multithreading")]
+        let enc = {
+            Encoder { width: self.width, height: self.height, timebase: self.timebase, variable_framerate: self.variable_framerate, next_pts: 0,
+                prev_frame: VideoFrame::new_padded(self.width, self.height),
+                skip_err,
+                search_range: self.search_range,
+                keyframe_interval: self.keyframe_interval,
+                frames_since_keyframe: None,
+                rdo_intra: rdo,
+                rdo_inter: rdo,
+                qtable_inter_l,
+                qtable_inter_c,
+                qtable_intra_l,
+                qtable_intra_c,
+                writer: StreamWriter { inner: writer, position: 0, failed: false },
+                metadata,
+                tracks: vec![Track { id: VIDEO_TRACK, kind: TrackKind::Video, language: String::new() }],
+                audio_tracks: Vec::new(),
+                frame_count: 0,
+                max_packet_size: 0,
+                index: Vec::new(),
+                index_offset: 0,
+                report_metrics: self.report_metrics,
+                stats_callback: None,
+                header_written: false,
+                finished: false,
+                threadpool }
+        };
+
+        #[cfg(not(feature = "multithreading"))]
+        let enc = {
+            Encoder { width: self.width, height: self.height, timebase: self.timebase, variable_framerate: self.variable_framerate, next_pts: 0,
+                prev_frame: VideoFrame::new_padded(self.width, self.height),
+                skip_err,
+                search_range: self.search_range,
+                keyframe_interval: self.keyframe_interval,
+                frames_since_keyframe: None,
+                rdo_intra: rdo,
+                rdo_inter: rdo,
+                qtable_inter_l,
+                qtable_inter_c,
+                qtable_intra_l,
+                qtable_intra_c,
+                writer: StreamWriter { inner: writer, position: 0, failed: false },
+                metadata,
+                tracks: vec![Track { id: VIDEO_TRACK, kind: TrackKind::Video, language: String::new() }],
+                audio_tracks: Vec::new(),
+                frame_count: 0,
+                max_packet_size: 0,
+                index: Vec::new(),
+                index_offset: 0,
+                report_metrics: self.report_metrics,
+                stats_callback: None,
+                header_written: false,
+                finished: false, }
+        };
+
+        Ok(enc)
+    }
+}
+
+/// Statistics about an encoded frame, passed to the callback set with Encoder::set_stats_callback
+#[derive(Debug, Clone)]
+pub struct FrameStats {
+    /// Index of the frame in the stream (counting drop frames)
+    pub index: u64,
+    pub frame_type: FrameType,
+    /// Size of the frame's packet in bytes, including the packet header
+    pub packet_size: u64,
+    /// Bits spent on everything other than motion vectors & coefficients: packet header, huffman table, slice table, block flags, and padding
+    pub header_bits: u64,
+    /// Bits spent on P-frame motion vectors
+    pub motion_bits: u64,
+    /// Bits spent on RLE & huffman coded DCT coefficients
+    pub coefficient_bits: u64,
+    /// Number of macroblocks in the frame, across all three planes (0 for drop frames)
+    pub total_blocks: usize,
+    /// Number of P-frame macroblocks which were copied from the previous frame without coding a residual
+    pub skipped_blocks: usize,
+    /// Average motion vector length in pixels over all P-frame macroblocks
+    pub avg_motion: f32,
+    /// Time spent encoding & writing the frame (not including computing metrics)
+    pub encode_time: Duration,
+    /// Quality of the reconstructed frame compared to its source, if enabled with EncoderBuilder::report_metrics (always None for drop frames)
+    pub metrics: Option<FrameMetrics>,
+}
+
+/// Bit usage of an encoded frame packet
+struct PacketStats {
+    code_lengths: [u32;16],
+    motion_bits: u64,
+    coefficient_bits: u64,
+    total_blocks: usize,
+    skipped_blocks: usize,
+    motion_sum: f32,
+}
+
+impl FrameStats {
+    fn new(index: u64, frame_type: FrameType, packet_size: u64, packet: &PacketStats, start_time: Instant) -> FrameStats {
+        FrameStats { index, frame_type, packet_size,
+            header_bits: (packet_size * 8) - packet.motion_bits - packet.coefficient_bits,
+            motion_bits: packet.motion_bits,
+            coefficient_bits: packet.coefficient_bits,
+            total_blocks: packet.total_blocks,
+            skipped_blocks: packet.skipped_blocks,
+            avg_motion: if packet.total_blocks > 0 { packet.motion_sum / packet.total_blocks as f32 } else { 0.0 },
+            encode_time: start_time.elapsed(),
+            metrics: None }
+    }
+}
+
+type StatsCallback = Box<dyn FnMut(&FrameStats) + Send>;
+
+/// Entry of the stream index, pointing at an I-frame packet
+struct IndexEntry {
+    frame: u64,
+    pts: u64,
+    offset: u64,
+}
 
 pub struct Encoder<W: Write> {
     width: usize,
     height: usize,
-    framerate: u32,
+    timebase: Timebase,
+    variable_framerate: bool,
+    next_pts: u64,
     prev_frame: VideoFrame,
-    px_err: f32,
+    skip_err: u64,
+    search_range: u32,
+    keyframe_interval: u32,
+    frames_since_keyframe: Option<u32>,
+    rdo_intra: Option<RdoModel>,
+    rdo_inter: Option<RdoModel>,
     qtable_inter_l: [i32;64],
     qtable_inter_c: [i32;64],
     qtable_intra_l: [i32;64],
     qtable_intra_c: [i32;64],
-    writer: W,
+    writer: StreamWriter<W>,
+    metadata: Vec<(String, String)>,
+    tracks: Vec<Track>,
+    audio_tracks: Vec<AudioTrackState>,
+    frame_count: u64,
+    max_packet_size: u64,
+    index: Vec<IndexEntry>,
+    index_offset: u64,
+    report_metrics: bool,
+    stats_callback: Option<StatsCallback>,
+    header_written: bool,
     finished: bool,
     #[cfg(feature = "multithreading")]
-    threadpool: rayon::ThreadPool
+    threadpool: Arc<rayon::ThreadPool>
+}
+
+/// Encoding state of an audio track
+struct AudioTrackState {
+    track: TrackId,
+    format: AudioFormat,
+    pos: u64,
+    lms: Vec<QoaLms>,
 }
 
 impl<W: Write> Drop for Encoder<W> {
     fn drop(&mut self) {
+        // errors can't be reported from here (and panicking while unwinding would abort), so call finish explicitly to find out whether it succeeded
+        if !self.finished {
+            let _ = self.finish();
+        }
+    }
+}
+
+impl<W: Write + Seek> Encoder<W> {
+    /// Finish the PFV stream (if it hasn't been finished already), then seek back & fill in the header's stream info block, so that decoders can report
+    /// the frame count & duration up front. The writer is left positioned at the end of the stream
+    pub fn finalize(self: &mut Encoder<W>) -> Result<(), EncodeError> {
+        // don't seek around & patch the header of a stream which is already broken
+        if self.writer.failed {
+            return Err(EncodeError::WriteFailed);
+        }
+
         if !self.finished {
-            self.finish().unwrap();
-        }   
+            self.finish()?;
+        }
+
+        let flags = PFV_FLAG_FINALIZED | if self.variable_framerate { PFV_FLAG_TIMESTAMPS } else { 0 };
+        let end = self.writer.position;
+
+        // seek relative to the current position, as the stream need not start at offset 0 of the writer
+        let writer = &mut self.writer.inner;
+        writer.seek(SeekFrom::Current(PFV_FLAGS_OFFSET as i64 - end as i64))?;
+
+        writer.write_u8(flags)?;
+        writer.write_u64::<LittleEndian>(self.frame_count)?;
+        writer.write_u64::<LittleEndian>(self.next_pts)?;
+        writer.write_u32::<LittleEndian>(self.max_packet_size as u32)?;
+        writer.write_u64::<LittleEndian>(self.index_offset)?;
+
+        writer.seek(SeekFrom::Current(end as i64 - (PFV_FLAGS_OFFSET + 1 + PFV_STREAM_INFO_LEN) as i64))?;
+
+        Ok(())
     }
 }
 
 impl<W: Write> Encoder<W> {
-    pub fn new(writer: W, width: usize, height: usize, framerate: u32, quality: i32, #[cfg(feature = "multithreading")] num_threads: usize) -> Result<Encoder<W>, std::io::Error> {
-        assert!(quality >= 0 && quality <= 10);
+    pub fn new(writer: W, width: usize, height: usize, framerate: u32, quality: i32, #[cfg(feature = "multithreading")] num_threads: usize) -> Result<Encoder<W>, EncodeError> {
+        #[cfg(feature = "multithreading")]
+        {
+            Encoder::new_with_timebase(writer, width, height, Timebase::from_framerate(framerate), false, quality, num_threads)
+        }
 
-        let qscale = quality as f32 * 0.25;
-        let px_err = quality as f32 * 1.5;
+        #[cfg(not(feature = "multithreading"))]
+        {
+            Encoder::new_with_timebase(writer, width, height, Timebase::from_framerate(framerate), false, quality)
+        }
+    }
 
+    /// Create a new encoder with a rational timebase.
+    /// If variable_framerate is false, each frame lasts exactly one tick. Otherwise every frame packet stores its own timestamp in ticks,
+    /// which can be set with set_frame_pts (by default each frame is one tick after the previous one)
+    pub fn new_with_timebase(writer: W, width: usize, height: usize, timebase: Timebase, variable_framerate: bool, quality: i32, #[cfg(feature = "multithreading")] num_threads: usize) -> Result<Encoder<W>, EncodeError> {
         #[cfg(feature = "multithreading")]
-        let mut enc = {
-            Encoder { width: width, height: height, framerate: framerate,
-                prev_frame: VideoFrame::new_padded(width, height),
-                px_err: px_err,
-                qtable_inter_l: Q_TABLE_INTER.map(|x| (x as f32 * qscale * 0.5).max(1.0) as i32),
-                qtable_inter_c: Q_TABLE_INTER.map(|x| (x as f32 * qscale).max(1.0) as i32),
-                qtable_intra_l: Q_TABLE_INTRA.map(|x| (x as f32 * qscale * 0.5).max(1.0) as i32),
-                qtable_intra_c: Q_TABLE_INTRA.map(|x| (x as f32 * qscale).max(1.0) as i32),
-                writer: writer,
-                finished: false,
-                threadpool: rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap() }
-        };
+        {
+            EncoderBuilder::new(width, height).timebase(timebase, variable_framerate).quality(quality).num_threads(num_threads).build(writer)
+        }
 
         #[cfg(not(feature = "multithreading"))]
-        let mut enc = {
-            Encoder { width: width, height: height, framerate: framerate,
-                prev_frame: VideoFrame::new_padded(width, height),
-                px_err: px_err,
-                qtable_inter_l: Q_TABLE_INTER.map(|x| (x as f32 * qscale * 0.5).max(1.0) as i32),
-                qtable_inter_c: Q_TABLE_INTER.map(|x| (x as f32 * qscale).max(1.0) as i32),
-                qtable_intra_l: Q_TABLE_INTRA.map(|x| (x as f32 * qscale * 0.5).max(1.0) as i32),
-                qtable_intra_c: Q_TABLE_INTRA.map(|x| (x as f32 * qscale).max(1.0) as i32),
-                writer: writer,
-                finished: false, }
+        {
+            EncoderBuilder::new(width, height).timebase(timebase, variable_framerate).quality(quality).build(writer)
+        }
+    }
+
+    /// Create a new encoder which runs on an existing thread pool instead of creating its own (see EncoderBuilder for more settings)
+    #[cfg(feature = "multithreading")]
+    pub fn new_with_thread_pool(writer: W, width: usize, height: usize, framerate: u32, quality: i32, threadpool: Arc<rayon::ThreadPool>) -> Result<Encoder<W>, EncodeError> {
+        EncoderBuilder::new(width, height).framerate(framerate).quality(quality).thread_pool(threadpool).build(writer)
+    }
+
+    /// The writer the encoder writes to
+    #[cfg(feature = "async")]
+    pub(crate) fn writer_mut(self: &mut Encoder<W>) -> &mut W {
+        &mut self.writer.inner
+    }
+
+    /// Add a key/value entry to the header's metadata chunk (see the metadata module for conventional keys).
+    /// The header is written along with the first packet, so all metadata must be added before encoding any frames or user data
+    pub fn write_metadata(self: &mut Encoder<W>, key: &str, value: &str) -> Result<(), EncodeError> {
+        if self.header_written {
+            return Err(EncodeError::HeaderWritten);
+        }
+
+        if key.len() > u16::MAX as usize || value.len() > u32::MAX as usize || self.metadata.len() >= u16::MAX as usize {
+            return Err(EncodeError::InvalidData);
+        }
+
+        self.metadata.push((key.to_string(), value.to_string()));
+
+        Ok(())
+    }
+
+    /// Add an audio track to the stream, returning its track ID. Like metadata, this must be called before encoding any frames or user data
+    pub fn add_audio_track(self: &mut Encoder<W>, format: AudioFormat, language: &str) -> Result<TrackId, EncodeError> {
+        if format.channels < 1 || format.channels > 8 || format.samplerate == 0 || format.samplerate > 0xFFFFFF {
+            return Err(EncodeError::InvalidAudioFormat);
+        }
+
+        let id = self.add_track(TrackKind::Audio(format), language)?;
+        self.audio_tracks.push(AudioTrackState { track: id, format, pos: 0, lms: vec![QoaLms::new();format.channels as usize] });
+
+        Ok(id)
+    }
+
+    /// Add a subtitle track to the stream, returning its track ID. Like metadata, this must be called before encoding any frames or user data
+    pub fn add_subtitle_track(self: &mut Encoder<W>, language: &str) -> Result<TrackId, EncodeError> {
+        self.add_track(TrackKind::Subtitle, language)
+    }
+
+    /// Add an audio track with no language tag. Shorthand for add_audio_track for streams with a single audio track
+    pub fn set_audio_format(self: &mut Encoder<W>, format: AudioFormat) -> Result<(), EncodeError> {
+        if !self.audio_tracks.is_empty() {
+            return Err(EncodeError::InvalidTrack);
+        }
+
+        self.add_audio_track(format, "")?;
+
+        Ok(())
+    }
+
+    fn add_track(self: &mut Encoder<W>, kind: TrackKind, language: &str) -> Result<TrackId, EncodeError> {
+        if self.header_written {
+            return Err(EncodeError::HeaderWritten);
+        }
+
+        if self.tracks.len() > TrackId::MAX as usize {
+            return Err(EncodeError::InvalidTrack);
+        }
+
+        if language.len() > u16::MAX as usize {
+            return Err(EncodeError::InvalidData);
+        }
+
+        let id = self.tracks.len() as TrackId;
+        self.tracks.push(Track { id, kind, language: language.to_string() });
+
+        Ok(id)
+    }
+
+    /// Encode interleaved 16-bit audio samples into the first audio track, continuing on from the previously encoded samples.
+    /// The decoder holds audio back until the playback clock reaches it, but can't play audio it hasn't read yet, so for smooth playback each chunk of audio should be
+    /// encoded no later than the video frames it plays alongside
+    pub fn encode_audio(self: &mut Encoder<W>, samples: &[i16]) -> Result<(), EncodeError> {
+        let track = match self.audio_tracks.first() {
+            Some(v) => v.track,
+            None => {
+                return Err(EncodeError::InvalidTrack);
+            }
         };
 
-        enc.write_header()?;
+        self.encode_audio_track(track, samples)
+    }
 
-        Ok(enc)
+    /// Encode interleaved 16-bit audio samples into the given audio track. See encode_audio
+    pub fn encode_audio_track(self: &mut Encoder<W>, track: TrackId, samples: &[i16]) -> Result<(), EncodeError> {
+        self.check_not_finished()?;
+
+        let index = match self.audio_tracks.iter().position(|x| x.track == track) {
+            Some(v) => v,
+            None => {
+                return Err(EncodeError::InvalidTrack);
+            }
+        };
+
+        let channels = self.audio_tracks[index].format.channels as usize;
+
+        if !samples.len().is_multiple_of(channels) {
+            return Err(EncodeError::InvalidData);
+        }
+
+        self.ensure_header()?;
+
+        let state = &mut self.audio_tracks[index];
+        let mut packet_data = Vec::new();
+
+        // split into packets of at most one QOA frame's worth of samples
+        for chunk in samples.chunks(QOA_FRAME_LEN * channels) {
+            packet_data.clear();
+
+            match state.format.codec {
+                AudioCodec::Pcm => {
+                    for sample in chunk {
+                        packet_data.write_i16::<LittleEndian>(*sample)?;
+                    }
+                }
+                AudioCodec::Qoa => {
+                    encode_frame(chunk, channels, state.format.samplerate, &mut state.lms, &mut packet_data);
+                }
+            }
+
+            // packet payload begins with the track ID & the position of its first sample frame
+            let start = self.writer.position;
+            self.writer.write_u8(PFV_PACKET_AUDIO)?;
+            self.writer.write_u32::<LittleEndian>((packet_data.len() + 9) as u32)?;
+            self.writer.write_u8(track)?;
+            self.writer.write_u64::<LittleEndian>(state.pos)?;
+            self.writer.write_all(&packet_data)?;
+
+            state.pos += (chunk.len() / channels) as u64;
+            self.max_packet_size = self.max_packet_size.max(self.writer.position - start);
+        }
+
+        Ok(())
     }
 
-    pub fn encode_iframe(self: &mut Encoder<W>, frame: &VideoFrame) -> Result<(), std::io::Error> {
-        assert!(frame.width == self.width && frame.height == self.height);
-        assert!(frame.plane_y.width == frame.width && frame.plane_y.height == frame.height);
-        assert!(frame.plane_u.width == frame.width / 2 && frame.plane_u.height == frame.height / 2);
-        assert!(frame.plane_v.width == frame.width / 2 && frame.plane_v.height == frame.height / 2);
-        assert!(!self.finished);
+    /// Write a subtitle cue to the given subtitle track. Timestamp & duration are in timebase ticks.
+    /// Like user data, cues are delivered in stream order, so they should be written before the frame they first appear on
+    pub fn write_subtitle(self: &mut Encoder<W>, track: TrackId, pts: u64, duration: u64, text: &str) -> Result<(), EncodeError> {
+        self.check_not_finished()?;
+
+        if self.tracks.get(track as usize).map(|x| x.kind) != Some(TrackKind::Subtitle) {
+            return Err(EncodeError::InvalidTrack);
+        }
+
+        if text.len() > (u32::MAX as usize) - 17 {
+            return Err(EncodeError::InvalidData);
+        }
+
+        self.ensure_header()?;
+
+        let start = self.writer.position;
+        self.writer.write_u8(PFV_PACKET_SUBTITLE)?;
+        self.writer.write_u32::<LittleEndian>((text.len() + 17) as u32)?;
+        self.writer.write_u8(track)?;
+        self.writer.write_u64::<LittleEndian>(pts)?;
+        self.writer.write_u64::<LittleEndian>(duration)?;
+        self.writer.write_all(text.as_bytes())?;
+        self.end_packet(start);
+
+        Ok(())
+    }
+
+    /// Write a user data packet (subtitle cues, gameplay events, chapter markers, etc) with a timestamp in timebase ticks.
+    /// User data is delivered to the decoder in stream order, so it should be written before the frame it applies to
+    pub fn write_user_data(self: &mut Encoder<W>, pts: u64, data: &[u8]) -> Result<(), EncodeError> {
+        self.check_not_finished()?;
+
+        if data.len() > (u32::MAX as usize) - 8 {
+            return Err(EncodeError::InvalidData);
+        }
+
+        self.ensure_header()?;
+
+        let start = self.writer.position;
+        self.writer.write_u8(PFV_PACKET_USER_DATA)?;
+        self.writer.write_u32::<LittleEndian>((data.len() + 8) as u32)?;
+        self.writer.write_u64::<LittleEndian>(pts)?;
+        self.writer.write_all(data)?;
+        self.end_packet(start);
+
+        Ok(())
+    }
+
+    /// Set the timestamp (in timebase ticks) of the next encoded frame. Only valid for variable framerate encoders, and timestamps must be strictly increasing
+    pub fn set_frame_pts(self: &mut Encoder<W>, pts: u64) -> Result<(), EncodeError> {
+        if !self.variable_framerate || pts < self.next_pts {
+            return Err(EncodeError::InvalidTimestamp);
+        }
+
+        self.next_pts = pts;
+
+        Ok(())
+    }
+
+    /// Set a callback which receives statistics about each frame once it has been encoded (including drop frames)
+    pub fn set_stats_callback<F: FnMut(&FrameStats) + Send + 'static>(self: &mut Encoder<W>, callback: F) {
+        self.stats_callback = Some(Box::new(callback));
+    }
+
+    fn report_stats(self: &mut Encoder<W>, mut stats: FrameStats, frame: Option<&VideoFrame>) {
+        if let Some(callback) = &mut self.stats_callback {
+            // prev_frame now holds the reconstruction of the frame, exactly as the decoder will see it
+            if let Some(frame) = frame {
+                if self.report_metrics {
+                    stats.metrics = Some(compare_cropped(frame, &self.prev_frame));
+                }
+            }
+
+            callback(&stats);
+        }
+    }
+
+    fn check_not_finished(self: &Encoder<W>) -> Result<(), EncodeError> {
+        if self.finished {
+            return Err(EncodeError::AlreadyFinished);
+        }
+
+        if self.writer.failed {
+            return Err(EncodeError::WriteFailed);
+        }
+
+        Ok(())
+    }
+
+    fn check_frame(self: &Encoder<W>, frame: &VideoFrame) -> Result<(), EncodeError> {
+        self.check_not_finished()?;
+
+        if frame.width != self.width || frame.height != self.height
+            || frame.plane_y.width != frame.width || frame.plane_y.height != frame.height
+            || frame.plane_u.width != frame.width / 2 || frame.plane_u.height != frame.height / 2
+            || frame.plane_v.width != frame.width / 2 || frame.plane_v.height != frame.height / 2 {
+            return Err(EncodeError::InvalidDimensions);
+        }
+
+        Ok(())
+    }
+
+    fn take_frame_pts(self: &mut Encoder<W>) -> Option<u64> {
+        let pts = self.next_pts;
+        self.next_pts += 1;
+        self.frame_count += 1;
+
+        if self.variable_framerate {
+            Some(pts)
+        } else {
+            None
+        }
+    }
+
+    pub fn encode_iframe(self: &mut Encoder<W>, frame: &VideoFrame) -> Result<(), EncodeError> {
+        self.check_frame(frame)?;
+
+        self.ensure_header()?;
+
+        let start_time = Instant::now();
+        let index = self.frame_count;
+        let packet;
+        let packet_size;
 
         #[cfg(feature = "multithreading")]
         {
-            let enc_y = frame.plane_y.encode_plane(&self.qtable_intra_l, 0, &self.threadpool);
+            let enc_y = frame.plane_y.encode_plane(&self.qtable_intra_l, self.rdo_intra.as_ref(), 0, &self.threadpool);
             let dec_y = VideoPlane::decode_plane(&enc_y, &self.qtable_intra_l, &self.threadpool);
 
-            let enc_u = frame.plane_u.encode_plane(&self.qtable_intra_c, 128, &self.threadpool);
+            let enc_u = frame.plane_u.encode_plane(&self.qtable_intra_c, self.rdo_intra.as_ref(), 128, &self.threadpool);
             let dec_u = VideoPlane::decode_plane(&enc_u, &self.qtable_intra_c, &self.threadpool);
 
-            let enc_v = frame.plane_v.encode_plane(&self.qtable_intra_c, 128, &self.threadpool);
+            let enc_v = frame.plane_v.encode_plane(&self.qtable_intra_c, self.rdo_intra.as_ref(), 128, &self.threadpool);
             let dec_v = VideoPlane::decode_plane(&enc_v, &self.qtable_intra_c, &self.threadpool);
 
             let enc_frame = EncodedIFrame { y: enc_y, u: enc_u, v: enc_v };
 
+            let start = self.writer.position;
+            self.index.push(IndexEntry { frame: self.frame_count, pts: self.next_pts, offset: start });
+
+            let pts = self.take_frame_pts();
+            packet = Encoder::<W>::write_iframe_packet(&enc_frame, pts, &mut self.writer)?;
+
+            // only once the packet has been written does the reconstruction become the reference frame
             self.prev_frame.plane_y.blit(&dec_y, 0, 0, 0, 0, dec_y.width, dec_y.height);
             self.prev_frame.plane_u.blit(&dec_u, 0, 0, 0, 0, dec_u.width, dec_u.height);
             self.prev_frame.plane_v.blit(&dec_v, 0, 0, 0, 0, dec_v.width, dec_v.height);
 
-            Encoder::<W>::write_iframe_packet(&enc_frame, &mut self.writer)?;
+            if let Some(model) = &mut self.rdo_intra {
+                model.update(&packet.code_lengths);
+            }
+
+            packet_size = self.end_packet(start);
         }
 
         #[cfg(not(feature = "multithreading"))]
         {
-            let enc_y = frame.plane_y.encode_plane(&self.qtable_intra_l, 0);
+            let enc_y = frame.plane_y.encode_plane(&self.qtable_intra_l, self.rdo_intra.as_ref(), 0);
             let dec_y = VideoPlane::decode_plane(&enc_y, &self.qtable_intra_l);
 
-            let enc_u = frame.plane_u.encode_plane(&self.qtable_intra_c, 128);
+            let enc_u = frame.plane_u.encode_plane(&self.qtable_intra_c, self.rdo_intra.as_ref(), 128);
             let dec_u = VideoPlane::decode_plane(&enc_u, &self.qtable_intra_c);
 
-            let enc_v = frame.plane_v.encode_plane(&self.qtable_intra_c, 128);
+            let enc_v = frame.plane_v.encode_plane(&self.qtable_intra_c, self.rdo_intra.as_ref(), 128);
             let dec_v = VideoPlane::decode_plane(&enc_v, &self.qtable_intra_c);
 
             let enc_frame = EncodedIFrame { y: enc_y, u: enc_u, v: enc_v };
 
+            let start = self.writer.position;
+            self.index.push(IndexEntry { frame: self.frame_count, pts: self.next_pts, offset: start });
+
+            let pts = self.take_frame_pts();
+            packet = Encoder::<W>::write_iframe_packet(&enc_frame, pts, &mut self.writer)?;
+
+            // only once the packet has been written does the reconstruction become the reference frame
             self.prev_frame.plane_y.blit(&dec_y, 0, 0, 0, 0, dec_y.width, dec_y.height);
             self.prev_frame.plane_u.blit(&dec_u, 0, 0, 0, 0, dec_u.width, dec_u.height);
             self.prev_frame.plane_v.blit(&dec_v, 0, 0, 0, 0, dec_v.width, dec_v.height);
 
-            Encoder::<W>::write_iframe_packet(&enc_frame, &mut self.writer)?;
+            if let Some(model) = &mut self.rdo_intra {
+                model.update(&packet.code_lengths);
+            }
+
+            packet_size = self.end_packet(start);
         }
 
+        self.frames_since_keyframe = Some(0);
+        self.report_stats(FrameStats::new(index, FrameType::Intra, packet_size, &packet, start_time), Some(frame));
+
         Ok(())
     }
 
-    pub fn encode_pframe(self: &mut Encoder<W>, frame: &VideoFrame) -> Result<(), std::io::Error> {
-        assert!(frame.width == self.width && frame.height == self.height);
-        assert!(frame.plane_y.width == frame.width && frame.plane_y.height == frame.height);
-        assert!(frame.plane_u.width == frame.width / 2 && frame.plane_u.height == frame.height / 2);
-        assert!(frame.plane_v.width == frame.width / 2 && frame.plane_v.height == frame.height / 2);
-        assert!(!self.finished);
+    pub fn encode_pframe(self: &mut Encoder<W>, frame: &VideoFrame) -> Result<(), EncodeError> {
+        self.check_frame(frame)?;
+
+        self.ensure_header()?;
+
+        let start_time = Instant::now();
+        let index = self.frame_count;
+        let packet;
+        let packet_size;
+        let motion = MotionSearch::new(self.skip_err, self.search_range);
 
         #[cfg(feature = "multithreading")]
         {
-            let enc_y = frame.plane_y.encode_plane_delta(&self.prev_frame.plane_y, &self.qtable_inter_l, self.px_err, 0, &self.threadpool);
+            let enc_y = frame.plane_y.encode_plane_delta(&self.prev_frame.plane_y, &self.qtable_inter_l, self.rdo_inter.as_ref(), motion, 0, &self.threadpool);
             let dec_y = VideoPlane::decode_plane_delta(&enc_y, &self.prev_frame.plane_y, &self.qtable_inter_l, &self.threadpool);
 
-            let enc_u = frame.plane_u.encode_plane_delta(&self.prev_frame.plane_u, &self.qtable_inter_c, self.px_err, 128, &self.threadpool);
+            let enc_u = frame.plane_u.encode_plane_delta(&self.prev_frame.plane_u, &self.qtable_inter_c, self.rdo_inter.as_ref(), motion, 128, &self.threadpool);
             let dec_u = VideoPlane::decode_plane_delta(&enc_u, &self.prev_frame.plane_u, &self.qtable_inter_c, &self.threadpool);
 
-            let enc_v = frame.plane_v.encode_plane_delta(&self.prev_frame.plane_v, &self.qtable_inter_c, self.px_err, 128, &self.threadpool);
+            let enc_v = frame.plane_v.encode_plane_delta(&self.prev_frame.plane_v, &self.qtable_inter_c, self.rdo_inter.as_ref(), motion, 128, &self.threadpool);
             let dec_v = VideoPlane::decode_plane_delta(&enc_v, &self.prev_frame.plane_v, &self.qtable_inter_c, &self.threadpool);
 
             let enc_frame = EncodedPFrame { y: enc_y, u: enc_u, v: enc_v };
 
+            let start = self.writer.position;
+            let pts = self.take_frame_pts();
+            packet = Encoder::<W>::write_pframe_packet(&enc_frame, pts, &mut self.writer)?;
+
+            // only once the packet has been written does the reconstruction become the reference frame
             self.prev_frame.plane_y.blit(&dec_y, 0, 0, 0, 0, dec_y.width, dec_y.height);
             self.prev_frame.plane_u.blit(&dec_u, 0, 0, 0, 0, dec_u.width, dec_u.height);
             self.prev_frame.plane_v.blit(&dec_v, 0, 0, 0, 0, dec_v.width, dec_v.height);
-            
-            Encoder::<W>::write_pframe_packet(&enc_frame, &mut self.writer)?;
+
+            if let Some(model) = &mut self.rdo_inter {
+                model.update(&packet.code_lengths);
+            }
+
+            packet_size = self.end_packet(start);
         }
 
         #[cfg(not(feature = "multithreading"))]
         {
-            let enc_y = frame.plane_y.encode_plane_delta(&self.prev_frame.plane_y, &self.qtable_inter_l, self.px_err, 0);
+            let enc_y = frame.plane_y.encode_plane_delta(&self.prev_frame.plane_y, &self.qtable_inter_l, self.rdo_inter.as_ref(), motion, 0);
             let dec_y = VideoPlane::decode_plane_delta(&enc_y, &self.prev_frame.plane_y, &self.qtable_inter_l);
 
-            let enc_u = frame.plane_u.encode_plane_delta(&self.prev_frame.plane_u, &self.qtable_inter_c, self.px_err, 128);
+            let enc_u = frame.plane_u.encode_plane_delta(&self.prev_frame.plane_u, &self.qtable_inter_c, self.rdo_inter.as_ref(), motion, 128);
             let dec_u = VideoPlane::decode_plane_delta(&enc_u, &self.prev_frame.plane_u, &self.qtable_inter_c);
 
-            let enc_v = frame.plane_v.encode_plane_delta(&self.prev_frame.plane_v, &self.qtable_inter_c, self.px_err, 128);
+            let enc_v = frame.plane_v.encode_plane_delta(&self.prev_frame.plane_v, &self.qtable_inter_c, self.rdo_inter.as_ref(), motion, 128);
             let dec_v = VideoPlane::decode_plane_delta(&enc_v, &self.prev_frame.plane_v, &self.qtable_inter_c);
 
             let enc_frame = EncodedPFrame { y: enc_y, u: enc_u, v: enc_v };
 
+            let start = self.writer.position;
+            let pts = self.take_frame_pts();
+            packet = Encoder::<W>::write_pframe_packet(&enc_frame, pts, &mut self.writer)?;
+
+            // only once the packet has been written does the reconstruction become the reference frame
             self.prev_frame.plane_y.blit(&dec_y, 0, 0, 0, 0, dec_y.width, dec_y.height);
             self.prev_frame.plane_u.blit(&dec_u, 0, 0, 0, 0, dec_u.width, dec_u.height);
             self.prev_frame.plane_v.blit(&dec_v, 0, 0, 0, 0, dec_v.width, dec_v.height);
 
-            Encoder::<W>::write_pframe_packet(&enc_frame, &mut self.writer)?;
+            if let Some(model) = &mut self.rdo_inter {
+                model.update(&packet.code_lengths);
+            }
+
+            packet_size = self.end_packet(start);
         }
 
+        self.frames_since_keyframe = self.frames_since_keyframe.map(|x| x + 1);
+        self.report_stats(FrameStats::new(index, FrameType::Predicted, packet_size, &packet, start_time), Some(frame));
+
         Ok(())
     }
 
-    pub fn encode_dropframe(self: &mut Encoder<W>) -> Result<(), std::io::Error> {
-        assert!(!self.finished);
+    /// Encode a frame, choosing between an I-frame and a P-frame: the first frame is always an I-frame, and after that one is inserted
+    /// every keyframe_interval frames (see EncoderBuilder::keyframe_interval)
+    pub fn encode_frame(self: &mut Encoder<W>, frame: &VideoFrame) -> Result<(), EncodeError> {
+        let is_keyframe = match self.frames_since_keyframe {
+            Some(v) => self.keyframe_interval > 0 && v + 1 >= self.keyframe_interval,
+            None => true
+        };
+
+        if is_keyframe {
+            self.encode_iframe(frame)
+        }
+        else {
+            self.encode_pframe(frame)
+        }
+    }
+
+    pub fn encode_dropframe(self: &mut Encoder<W>) -> Result<(), EncodeError> {
+        self.check_not_finished()?;
+
+        self.ensure_header()?;
+
+        let start_time = Instant::now();
+        let index = self.frame_count;
+
+        let start = self.writer.position;
+        let pts = self.take_frame_pts();
+        Encoder::<W>::write_drop_packet(pts, &mut self.writer)?;
+        let packet_size = self.end_packet(start);
+
+        let packet = PacketStats { code_lengths: [0;16], motion_bits: 0, coefficient_bits: 0, total_blocks: 0, skipped_blocks: 0, motion_sum: 0.0 };
+        self.report_stats(FrameStats::new(index, FrameType::Drop, packet_size, &packet, start_time), None);
 
-        Encoder::<W>::write_drop_packet(&mut self.writer)?;
         Ok(())
     }
 
-    pub fn finish(self: &mut Encoder<W>) -> Result<(), std::io::Error> {
-        assert!(!self.finished);
+    pub fn finish(self: &mut Encoder<W>) -> Result<(), EncodeError> {
+        self.check_not_finished()?;
+
+        self.ensure_header()?;
 
         self.finished = true;
+        self.write_index()?;
+
+        let start = self.writer.position;
         Encoder::write_eof(&mut self.writer)?;
+        self.end_packet(start);
+
+        Ok(())
+    }
+
+    /// Track the size of a packet which has just been written. Returns its size in bytes
+    fn end_packet(self: &mut Encoder<W>, start: u64) -> u64 {
+        let size = self.writer.position - start;
+        self.max_packet_size = self.max_packet_size.max(size);
+
+        size
+    }
+
+    /// Write the index packet, which lists the frame number, timestamp, and byte offset (from the start of the stream) of every I-frame
+    fn write_index(self: &mut Encoder<W>) -> Result<(), std::io::Error> {
+        self.index_offset = self.writer.position;
+
+        self.writer.write_u8(PFV_PACKET_INDEX)?;
+        self.writer.write_u32::<LittleEndian>((self.index.len() * 24) as u32)?;
+
+        for entry in &self.index {
+            self.writer.write_u64::<LittleEndian>(entry.frame)?;
+            self.writer.write_u64::<LittleEndian>(entry.pts)?;
+            self.writer.write_u64::<LittleEndian>(entry.offset)?;
+        }
+
+        self.end_packet(self.index_offset);
+
+        Ok(())
+    }
+
+    fn ensure_header(self: &mut Encoder<W>) -> Result<(), std::io::Error> {
+        if !self.header_written {
+            self.header_written = true;
+            self.write_header()?;
+        }
+
         Ok(())
     }
 
@@ -194,7 +1021,12 @@ impl<W: Write> Encoder<W> {
 
         self.writer.write_u16::<LittleEndian>(self.width as u16)?;
         self.writer.write_u16::<LittleEndian>(self.height as u16)?;
-        self.writer.write_u16::<LittleEndian>(self.framerate as u16)?;
+        self.writer.write_u32::<LittleEndian>(self.timebase.num)?;
+        self.writer.write_u32::<LittleEndian>(self.timebase.den)?;
+        self.writer.write_u8(if self.variable_framerate { PFV_FLAG_TIMESTAMPS } else { 0 })?;
+
+        // reserve space for the stream info block, which is filled in by finalize
+        self.writer.write_all(&[0;PFV_STREAM_INFO_LEN as usize])?;
 
         // write q-tables
         self.writer.write_u16::<LittleEndian>(4)?;
@@ -215,26 +1047,60 @@ impl<W: Write> Encoder<W> {
             self.writer.write_u16::<LittleEndian>(v as u16)?;
         }
 
+        // write metadata chunk
+        self.writer.write_u16::<LittleEndian>(self.metadata.len() as u16)?;
+
+        for (key, value) in &self.metadata {
+            self.writer.write_u16::<LittleEndian>(key.len() as u16)?;
+            self.writer.write_all(key.as_bytes())?;
+            self.writer.write_u32::<LittleEndian>(value.len() as u32)?;
+            self.writer.write_all(value.as_bytes())?;
+        }
+
+        // write track list
+        self.writer.write_u8((self.tracks.len() - 1) as u8)?;
+
+        for track in &self.tracks[1..] {
+            self.writer.write_u8(track.kind.to_id())?;
+            self.writer.write_u16::<LittleEndian>(track.language.len() as u16)?;
+            self.writer.write_all(track.language.as_bytes())?;
+
+            if let TrackKind::Audio(format) = track.kind {
+                self.writer.write_u8(format.codec.to_id())?;
+                self.writer.write_u8(format.channels as u8)?;
+                self.writer.write_u32::<LittleEndian>(format.samplerate)?;
+            }
+        }
+
         Ok(())
     }
 
-    fn write_eof(writer: &mut W) -> Result<(), std::io::Error> {
+    fn write_eof(writer: &mut StreamWriter<W>) -> Result<(), std::io::Error> {
         // write packet header
-        writer.write_u8(0)?; // packet type = EOF
+        writer.write_u8(PFV_PACKET_EOF)?;
         writer.write_u32::<LittleEndian>(0)?;
 
         Ok(())
     }
 
-    fn write_drop_packet(writer: &mut W) -> Result<(), std::io::Error> {
+    fn write_pts(pts: Option<u64>, writer: &mut StreamWriter<W>) -> Result<(), std::io::Error> {
+        if let Some(pts) = pts {
+            writer.write_u64::<LittleEndian>(pts)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_drop_packet(pts: Option<u64>, writer: &mut StreamWriter<W>) -> Result<(), std::io::Error> {
         // write packet header
-        writer.write_u8(1)?; // packet type = iframe
+        writer.write_u8(PFV_PACKET_IFRAME)?;
         writer.write_u32::<LittleEndian>(0)?;
+        Encoder::<W>::write_pts(pts, writer)?;
 
         Ok(())
     }
 
-    fn write_iframe_packet(f: &EncodedIFrame, writer: &mut W) -> Result<(), std::io::Error> {
+    fn write_iframe_packet(f: &EncodedIFrame, pts: Option<u64>, writer: &mut StreamWriter<W>) -> Result<PacketStats, std::io::Error> {
         // serialize packet data
         let mut packet_data = Cursor::new(Vec::new());
         let mut bitwriter = BitWriter::endian(&mut packet_data, bitstream_io::LittleEndian);
@@ -297,39 +1163,45 @@ impl<W: Write> Encoder<W> {
         bitwriter.write(8, 1_u8)?;
         bitwriter.write(8, 1_u8)?;
 
-        // serialize blocks to bitstream
-        for block in &block_coeff {
-            for sq in block {
-                let num_zeroes = tree.get_code(sq.num_zeroes);
-                let num_bits = tree.get_code(sq.coeff_size);
+        // serialize blocks to bitstream, one independently decodable slice per row of macroblocks
+        let slices = slice_layout(f.y.blocks_wide, f.y.blocks_high, f.u.blocks_wide, f.u.blocks_high);
+        let mut slice_data = Vec::with_capacity(slices.len());
 
-                debug_assert!(num_zeroes.len > 0 && num_bits.len > 0);
+        for slice in &slices {
+            let mut data = Cursor::new(Vec::new());
+            let mut slice_writer = BitWriter::endian(&mut data, bitstream_io::LittleEndian);
 
-                bitwriter.write(num_zeroes.len, num_zeroes.val)?;
-                bitwriter.write(num_bits.len, num_bits.val)?;
-
-                if sq.coeff_size > 0 {
-                    bitwriter.write_signed(sq.coeff_size as u32, sq.coeff)?;
-                }
+            for block in &block_coeff[slice.block_offset..(slice.block_offset + slice.num_blocks)] {
+                write_rle(block, &tree, &mut slice_writer)?;
             }
+
+            // flush any partial bytes
+            slice_writer.byte_align()?;
+            slice_data.push(data.into_inner());
         }
 
-        // flush any partial bytes
-        bitwriter.byte_align()?;
+        write_slices(&slice_data, &mut bitwriter)?;
 
         // retrieve packet payload bytes
         let packet_data = packet_data.into_inner();
 
         // write packet header + data
 
-        writer.write_u8(1)?; // packet type = iframe
+        writer.write_u8(PFV_PACKET_IFRAME)?;
         writer.write_u32::<LittleEndian>(packet_data.len() as u32)?;
+        Encoder::<W>::write_pts(pts, writer)?;
         writer.write_all(&packet_data)?;
 
-        Ok(())
+        // code lengths let the RDO cost model follow the statistics of the stream
+        Ok(PacketStats { code_lengths: std::array::from_fn(|i| tree.get_code(i as u8).len),
+            motion_bits: 0,
+            coefficient_bits: block_coeff.iter().map(|x| rle_bits(x, &tree)).sum(),
+            total_blocks: block_coeff.len(),
+            skipped_blocks: 0,
+            motion_sum: 0.0 })
     }
 
-    fn write_pframe_packet(f: &EncodedPFrame, writer: &mut W) -> Result<(), std::io::Error> {
+    fn write_pframe_packet(f: &EncodedPFrame, pts: Option<u64>, writer: &mut StreamWriter<W>) -> Result<PacketStats, std::io::Error> {
         // serialize packet data
         let mut packet_data = Cursor::new(Vec::new());
         let mut bitwriter = BitWriter::endian(&mut packet_data, bitstream_io::LittleEndian);
@@ -353,6 +1225,7 @@ impl<W: Write> Encoder<W> {
                     block_coeff.push(rle_sequence);
                 }
                 None => {
+                    block_coeff.push(Vec::new());
                 }
             }
         }
@@ -372,6 +1245,7 @@ impl<W: Write> Encoder<W> {
                     block_coeff.push(rle_sequence);
                 }
                 None => {
+                    block_coeff.push(Vec::new());
                 }
             }
         }
@@ -391,6 +1265,7 @@ impl<W: Write> Encoder<W> {
                     block_coeff.push(rle_sequence);
                 }
                 None => {
+                    block_coeff.push(Vec::new());
                 }
             }
         }
@@ -410,73 +1285,59 @@ impl<W: Write> Encoder<W> {
         bitwriter.write(8, 3_u8)?;
         bitwriter.write(8, 3_u8)?;
 
-        // write block headers
-        for b in &f.y.blocks {
-            let has_mvec = b.motion_x != 0 || b.motion_y != 0;
+        // serialize block headers + block data to bitstream, one independently decodable slice per row of macroblocks
+        let slices = slice_layout(f.y.blocks_wide, f.y.blocks_high, f.u.blocks_wide, f.u.blocks_high);
+        let block_headers: Vec<_> = f.y.blocks.iter().chain(&f.u.blocks).chain(&f.v.blocks).collect();
+        let mut slice_data = Vec::with_capacity(slices.len());
 
-            bitwriter.write_bit(has_mvec)?;
-            bitwriter.write_bit(b.subblocks.is_some())?;
-
-            if has_mvec {
-                bitwriter.write_signed(7, b.motion_x as i32)?;
-                bitwriter.write_signed(7, b.motion_y as i32)?;
-            }
-        }
+        for slice in &slices {
+            let mut data = Cursor::new(Vec::new());
+            let mut slice_writer = BitWriter::endian(&mut data, bitstream_io::LittleEndian);
+            let slice_range = slice.block_offset..(slice.block_offset + slice.num_blocks);
 
-        for b in &f.u.blocks {
-            let has_mvec = b.motion_x != 0 || b.motion_y != 0;
+            // write block headers
+            for b in &block_headers[slice_range.clone()] {
+                let has_mvec = b.motion_x != 0 || b.motion_y != 0;
 
-            bitwriter.write_bit(has_mvec)?;
-            bitwriter.write_bit(b.subblocks.is_some())?;
+                slice_writer.write_bit(has_mvec)?;
+                slice_writer.write_bit(b.subblocks.is_some())?;
 
-            if has_mvec {
-                bitwriter.write_signed(7, b.motion_x as i32)?;
-                bitwriter.write_signed(7, b.motion_y as i32)?;
+                if has_mvec {
+                    slice_writer.write_signed(7, b.motion_x as i32)?;
+                    slice_writer.write_signed(7, b.motion_y as i32)?;
+                }
             }
-        }
-
-        for b in &f.v.blocks {
-            let has_mvec = b.motion_x != 0 || b.motion_y != 0;
-
-            bitwriter.write_bit(has_mvec)?;
-            bitwriter.write_bit(b.subblocks.is_some())?;
-
-            if has_mvec {
-                bitwriter.write_signed(7, b.motion_x as i32)?;
-                bitwriter.write_signed(7, b.motion_y as i32)?;
 
-                assert!(b.motion_x >= -16 && b.motion_x <= 16);
-                assert!(b.motion_y >= -16 && b.motion_y <= 16);
+            // write block coefficients
+            for block in &block_coeff[slice_range] {
+                write_rle(block, &tree, &mut slice_writer)?;
             }
-        }
-
-        // serialize block data to bitstream
-        for block in &block_coeff {
-            for sq in block {
-                let num_zeroes = tree.get_code(sq.num_zeroes);
-                let num_bits = tree.get_code(sq.coeff_size);
 
-                bitwriter.write(num_zeroes.len, num_zeroes.val)?;
-                bitwriter.write(num_bits.len, num_bits.val)?;
-
-                if sq.coeff_size > 0 {
-                    bitwriter.write_signed(sq.coeff_size as u32, sq.coeff)?;
-                }
-            }
+            // flush any partial bytes
+            slice_writer.byte_align()?;
+            slice_data.push(data.into_inner());
         }
 
-        // flush any partial bytes
-        bitwriter.byte_align()?;
+        write_slices(&slice_data, &mut bitwriter)?;
 
         // retrieve packet payload bytes
         let packet_data = packet_data.into_inner();
 
         // write packet header + data
 
-        writer.write_u8(2)?; // packet type = pframe
+        writer.write_u8(PFV_PACKET_PFRAME)?;
         writer.write_u32::<LittleEndian>(packet_data.len() as u32)?;
+        Encoder::<W>::write_pts(pts, writer)?;
         writer.write_all(&packet_data)?;
 
-        Ok(())
+        let motion_vectors = block_headers.iter().filter(|b| b.motion_x != 0 || b.motion_y != 0);
+
+        // code lengths let the RDO cost model follow the statistics of the stream
+        Ok(PacketStats { code_lengths: std::array::from_fn(|i| tree.get_code(i as u8).len),
+            motion_bits: motion_vectors.clone().count() as u64 * 14,
+            coefficient_bits: block_coeff.iter().map(|x| rle_bits(x, &tree)).sum(),
+            total_blocks: block_headers.len(),
+            skipped_blocks: block_headers.iter().filter(|b| b.subblocks.is_none()).count(),
+            motion_sum: motion_vectors.map(|b| ((b.motion_x as f32).powi(2) + (b.motion_y as f32).powi(2)).sqrt()).sum() })
     }
 }
\ No newline at end of file
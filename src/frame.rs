@@ -1,5 +1,6 @@
 use crate::plane::VideoPlane;
 
+#[derive(Clone)]
 pub struct VideoFrame {
     pub width: usize,
     pub height: usize,
@@ -58,4 +59,42 @@ impl VideoFrame {
             plane_u: plane_u.reduce(),
             plane_v: plane_v.reduce() }
     }
+}
+
+/// Caller-owned planar (I420) output buffers for a decoded frame.
+/// Each plane may have its own stride, which must be at least as wide as the plane (chroma planes are half the width & height of the luma plane)
+pub struct FrameTarget<'a> {
+    pub y: &'a mut [u8],
+    pub y_stride: usize,
+    pub u: &'a mut [u8],
+    pub u_stride: usize,
+    pub v: &'a mut [u8],
+    pub v_stride: usize,
+}
+
+/// Caller-owned NV12 output buffers for a decoded frame: a full-size luma plane followed by a half-size plane of interleaved U/V pairs
+pub struct Nv12FrameTarget<'a> {
+    pub y: &'a mut [u8],
+    pub y_stride: usize,
+    pub uv: &'a mut [u8],
+    pub uv_stride: usize,
+}
+
+/// A rational timebase: each tick lasts num / den seconds (for example 1001 / 30000 for 29.97 fps content)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timebase {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl Timebase {
+    /// Timebase with one tick per frame at an integer framerate
+    pub fn from_framerate(framerate: u32) -> Timebase {
+        Timebase { num: 1, den: framerate }
+    }
+
+    /// Convert a timestamp in ticks to seconds
+    pub fn to_seconds(self: &Timebase, ticks: u64) -> f64 {
+        (ticks as f64 * self.num as f64) / self.den as f64
+    }
 }
\ No newline at end of file
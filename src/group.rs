@@ -0,0 +1,157 @@
+//! Decoding several streams on one shared thread pool (requires the "multithreading" feature).
+//!
+//! Each step of a DecoderGroup advances every stream at once: streams are spawned onto the shared pool as separate tasks, so the slices of
+//! all streams are interleaved by rayon's work stealing instead of each stream grabbing every thread in turn. The order in which streams are
+//! spawned rotates from one step to the next, so no stream is consistently scheduled ahead of the others.
+
+use std::{io::Read, sync::Arc};
+
+use crate::{dec::{Decoder, DecodeError}, frame::VideoFrame};
+
+/// Identifies a stream within a DecoderGroup. IDs stay valid until the stream is removed, and are not reused afterwards
+pub type StreamId = usize;
+
+pub struct DecoderGroup<TReader: Read + Send> {
+    threadpool: Arc<rayon::ThreadPool>,
+    decoders: Vec<Option<Decoder<TReader>>>,
+    next_start: usize,
+}
+
+/// What happened to one stream during a step
+struct StepResult {
+    presented: bool,
+    audio: Vec<i16>,
+    result: Result<bool, std::io::Error>,
+}
+
+impl<TReader: Read + Send> DecoderGroup<TReader> {
+    /// Create a new group with its own thread pool of the given size (0 picks one thread per CPU)
+    pub fn new(num_threads: usize) -> DecoderGroup<TReader> {
+        DecoderGroup::new_with_thread_pool(Arc::new(rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap()))
+    }
+
+    /// Create a new group which runs on an existing thread pool
+    pub fn new_with_thread_pool(threadpool: Arc<rayon::ThreadPool>) -> DecoderGroup<TReader> {
+        DecoderGroup { threadpool, decoders: Vec::new(), next_start: 0 }
+    }
+
+    /// The thread pool shared by every stream in the group (which can also be handed to encoders or other decoders)
+    pub fn thread_pool(self: &DecoderGroup<TReader>) -> &Arc<rayon::ThreadPool> {
+        &self.threadpool
+    }
+
+    /// Open a stream from the given source & add it to the group
+    pub fn add(self: &mut DecoderGroup<TReader>, reader: TReader) -> Result<StreamId, DecodeError> {
+        let decoder = Decoder::new_with_thread_pool(reader, self.threadpool.clone())?;
+        self.decoders.push(Some(decoder));
+
+        Ok(self.decoders.len() - 1)
+    }
+
+    /// Remove a stream from the group, returning its decoder (which keeps running on the group's thread pool)
+    pub fn remove(self: &mut DecoderGroup<TReader>, id: StreamId) -> Option<Decoder<TReader>> {
+        match self.decoders.get_mut(id) {
+            Some(v) => v.take(),
+            None => None
+        }
+    }
+
+    /// The decoder of a stream, which can be used to query its dimensions, metadata, and tracks, or take user data & subtitles
+    pub fn decoder(self: &DecoderGroup<TReader>, id: StreamId) -> Option<&Decoder<TReader>> {
+        match self.decoders.get(id) {
+            Some(v) => v.as_ref(),
+            None => None
+        }
+    }
+
+    /// Mutable access to the decoder of a stream (for example to select tracks)
+    pub fn decoder_mut(self: &mut DecoderGroup<TReader>, id: StreamId) -> Option<&mut Decoder<TReader>> {
+        match self.decoders.get_mut(id) {
+            Some(v) => v.as_mut(),
+            None => None
+        }
+    }
+
+    /// Number of streams in the group
+    pub fn len(self: &DecoderGroup<TReader>) -> usize {
+        self.decoders.iter().filter(|x| x.is_some()).count()
+    }
+
+    pub fn is_empty(self: &DecoderGroup<TReader>) -> bool {
+        self.len() == 0
+    }
+
+    /// Advance every stream by delta seconds (see Decoder::advance_delta). onvideo is called with the latest frame of each stream which presented one or more frames.
+    /// Returns false once every stream has reached its end. If any stream fails, the other streams are still advanced and the error of the first failing stream is returned
+    pub fn advance_delta<FV>(self: &mut DecoderGroup<TReader>, delta: f64, onvideo: &mut FV) -> Result<bool, std::io::Error> where
+        FV: FnMut(StreamId, &VideoFrame) {
+        self.advance_delta_with_audio(delta, onvideo, &mut |_, _| {})
+    }
+
+    /// Like advance_delta, but also hands audio of each stream's selected audio track to the onaudio callback as interleaved 16-bit samples
+    pub fn advance_delta_with_audio<FV, FA>(self: &mut DecoderGroup<TReader>, delta: f64, onvideo: &mut FV, onaudio: &mut FA) -> Result<bool, std::io::Error> where
+        FV: FnMut(StreamId, &VideoFrame),
+        FA: FnMut(StreamId, &[i16]) {
+        let mut results: Vec<Option<StepResult>> = (0..self.decoders.len()).map(|_| None).collect();
+
+        let num_decoders = self.decoders.len();
+        let start = if num_decoders > 0 { self.next_start % num_decoders } else { 0 };
+        self.next_start = start + 1;
+
+        {
+            // rotate the spawn order so that the same stream doesn't always get a head start
+            let mut tasks: Vec<_> = self.decoders.iter_mut().zip(results.iter_mut()).collect();
+            tasks.rotate_left(start);
+
+            self.threadpool.scope(|s| {
+                for (decoder, result) in tasks {
+                    if let Some(decoder) = decoder {
+                        s.spawn(move |_| {
+                            let mut presented = false;
+                            let mut audio = Vec::new();
+
+                            let step = decoder.advance_delta_with_audio(delta, &mut |_| presented = true, &mut |samples| audio.extend_from_slice(samples));
+                            *result = Some(StepResult { presented, audio, result: step });
+                        });
+                    }
+                }
+            });
+        }
+
+        let mut playing = false;
+        let mut error = None;
+
+        for (id, result) in results.into_iter().enumerate() {
+            let result = match result {
+                Some(v) => v,
+                None => {
+                    continue;
+                }
+            };
+
+            if !result.audio.is_empty() {
+                onaudio(id, &result.audio);
+            }
+
+            if result.presented {
+                onvideo(id, self.decoders[id].as_ref().unwrap().current_frame());
+            }
+
+            match result.result {
+                Ok(v) => {
+                    playing |= v;
+                }
+                Err(e) => {
+                    if error.is_none() {
+                        error = Some(e);
+                    }
+                }
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(playing)
+        }
+    }
+}
@@ -1,20 +1,34 @@
-use std::io::{Read, Seek};
-
-use bitstream_io::{BitRead, BitReader, Endianness};
+use crate::{bits::BitReader, io};
 
 #[derive(Debug)]
 pub enum HuffmanError {
     DecodeError,
-    IOError(std::io::Error),
+    IOError(io::Error),
+}
+
+impl From<HuffmanError> for io::Error {
+    fn from(value: HuffmanError) -> Self {
+        match value {
+            HuffmanError::DecodeError => io::Error::from(io::ErrorKind::InvalidData),
+            HuffmanError::IOError(e) => e,
+        }
+    }
 }
 
 pub struct HuffmanTree {
+    // only needed for encoding
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
     codes: [Code;16],
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
     table: [u8;16],
     dec_table: [Code;256],
-    root: Box<Node>,
+    nodes: [Node;MAX_NODES],
+    root: usize,
 }
 
+/// 16 leaf nodes + 15 internal nodes
+const MAX_NODES: usize = 31;
+
 #[derive(Clone, Copy)]
 pub struct Code {
     pub val: u32,
@@ -36,31 +50,30 @@ impl Code {
     }
 }
 
+/// Tree node. Nodes are stored in a fixed-size array & refer to their children by index, so that
+/// building a tree for every frame doesn't touch the heap
+#[derive(Clone, Copy)]
 struct Node {
     freq: u32,
     ch: Option<u8>,
-    left: Option<Box<Node>>,
-    right: Option<Box<Node>>,
+    left: Option<u8>,
+    right: Option<u8>,
 }
 
 impl Node {
     pub fn new(freq: u32, ch: Option<u8>) -> Node {
         Node { freq: freq, ch: ch, left: None, right: None }
     }
-
-    pub fn into_box(self: Node) -> Box<Node> {
-        Box::new(self)
-    }
 }
 
 impl HuffmanTree {
     pub fn empty() -> HuffmanTree {
-        HuffmanTree { codes: [Code::new();16], table: [0;16], dec_table: [Code::new();256], root: Node::new(0, None).into_box() }
+        HuffmanTree { codes: [Code::new();16], table: [0;16], dec_table: [Code::new();256], nodes: [Node::new(0, None);MAX_NODES], root: 0 }
     }
 
-    fn get_insert_index(node: &Box<Node>, p: &[Box<Node>]) -> usize {
+    fn get_insert_index(nodes: &[Node], node: usize, p: &[u8]) -> usize {
         for i in 0..p.len() {
-            if node.freq > p[i].freq {
+            if nodes[node].freq > nodes[p[i] as usize].freq {
                 return i;
             }
         }
@@ -69,37 +82,63 @@ impl HuffmanTree {
     }
 
     pub fn from_table(table: &[u8;16]) -> HuffmanTree {
-        let mut p:Vec<Box<Node>> = Vec::new();
+        let mut nodes = [Node::new(0, None);MAX_NODES];
+        let mut num_nodes = 0;
+
+        // list of node indices still to be merged, kept sorted by descending frequency
+        let mut p = [0_u8;16];
+        let mut p_len = 0;
 
         for (ch, fr) in table.iter().enumerate() {
             if *fr > 0 {
-                p.push(Node::new(*fr as u32, Some(ch as u8)).into_box());
+                nodes[num_nodes] = Node::new(*fr as u32, Some(ch as u8));
+                p[p_len] = num_nodes as u8;
+                num_nodes += 1;
+                p_len += 1;
+            }
+        }
+
+        // start with a sorted list (stable insertion sort, so symbols with equal frequency keep their order)
+        for i in 1..p_len {
+            let n = p[i];
+            let mut j = i;
+
+            while j > 0 && nodes[p[j - 1] as usize].freq < nodes[n as usize].freq {
+                p[j] = p[j - 1];
+                j -= 1;
             }
+
+            p[j] = n;
         }
 
-        // start with a sorted list
-        p.sort_by(|a, b| (&(b.freq)).cmp(&(a.freq)));
+        while p_len > 1 {
+            let a = p[p_len - 1];
+            let b = p[p_len - 2];
+            p_len -= 2;
 
-        while p.len() > 1 {
-            let a = p.pop().unwrap();
-            let b = p.pop().unwrap();
-            let mut c = Node::new(a.freq + b.freq, None).into_box();
+            let mut c = Node::new(nodes[a as usize].freq + nodes[b as usize].freq, None);
             c.left = Some(a);
             c.right = Some(b);
 
+            nodes[num_nodes] = c;
+            let c = num_nodes;
+            num_nodes += 1;
+
             // insertion sort new node back into list
-            let insert_pos = HuffmanTree::get_insert_index(&c, &p);
-            p.insert(insert_pos, c);
+            let insert_pos = HuffmanTree::get_insert_index(&nodes, c, &p[..p_len]);
+            p.copy_within(insert_pos..p_len, insert_pos + 1);
+            p[insert_pos] = c as u8;
+            p_len += 1;
         }
 
-        if p.len() == 0 {
+        if p_len == 0 {
             return HuffmanTree::empty();
         }
 
-        let root = p.pop().unwrap();
+        let root = p[0] as usize;
 
         let mut codes = [Code::new();16];
-        assign_codes(&root, &mut codes, Code::new());
+        assign_codes(&nodes, root, &mut codes, Code::new());
 
         // generate pre-masked decoder table for codes of 8 bits or less - allows us to just read in a whole u8 and index into this table to get a code
         // if a code is longer than 8 bits, it falls back to the slow tree traversal path
@@ -115,15 +154,16 @@ impl HuffmanTree {
             }
         }
 
-        HuffmanTree { codes: codes, table: table.clone(), dec_table: dec_table, root: root }
+        HuffmanTree { codes, table: *table, dec_table, nodes, root }
     }
 
+    #[cfg(feature = "std")]
     pub fn get_table(self: &HuffmanTree) -> &[u8;16] {
         &self.table
     }
 
-    fn read_slow<R: Read, E: Endianness>(self: &HuffmanTree, bitreader: &mut BitReader<R, E>) -> Result<u8, HuffmanError> {
-        let mut node = &self.root;
+    fn read_slow(self: &HuffmanTree, bitreader: &mut BitReader) -> Result<u8, HuffmanError> {
+        let mut node = &self.nodes[self.root];
 
         loop {
             if let Some(ch) = node.ch {
@@ -137,14 +177,14 @@ impl HuffmanTree {
                 };
 
                 if bit {
-                    if let Some(ref r) = node.right {
-                        node = r;
+                    if let Some(r) = node.right {
+                        node = &self.nodes[r as usize];
                     } else {
                         return Err(HuffmanError::DecodeError);
                     }
                 } else {
-                    if let Some(ref l) = node.left {
-                        node = l;
+                    if let Some(l) = node.left {
+                        node = &self.nodes[l as usize];
                     } else {
                         return Err(HuffmanError::DecodeError);
                     }
@@ -153,65 +193,46 @@ impl HuffmanTree {
         }
     }
 
-    pub fn read<R: Read + Seek, E: Endianness>(self: &HuffmanTree, reader: &mut BitReader<R, E>, max_bits: u64) -> Result<u8, HuffmanError> {
+    pub fn read(self: &HuffmanTree, reader: &mut BitReader, max_bits: u64) -> Result<u8, HuffmanError> {
         // workaround to avoid reading past the end of the stream
-        let bit_pos = match reader.position_in_bits() {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(HuffmanError::IOError(e));
-            }
-        };
+        let bit_pos = reader.position_in_bits();
+
+        if bit_pos >= max_bits {
+            return Err(HuffmanError::IOError(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+
         let bits_remaining = max_bits - bit_pos;
         let read_bits = bits_remaining.min(8);
 
-        let cur = match reader.read::<u8>(read_bits as u32) {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(HuffmanError::IOError(e));
-            }
-        };
+        let cur = reader.peek(read_bits as u32);
 
         let c = self.dec_table[cur as usize];
         if c.len == 0 {
             // couldn't find code in fast table, try slow lookup instead
-            match reader.seek_bits(std::io::SeekFrom::Current(-(read_bits as i64))) {
-                Ok(v) => v,
-                Err(e) => {
-                    return Err(HuffmanError::IOError(e));
-                }
-            };
-
             return self.read_slow(reader);
         } else {
-            let rewind = (read_bits as i64) - c.len as i64;
-            
-            match reader.seek_bits(std::io::SeekFrom::Current(-rewind)) {
-                Ok(v) => v,
-                Err(e) => {
-                    return Err(HuffmanError::IOError(e));
-                }
-            };
-
+            reader.skip(c.len);
             return Ok(c.symbol);
         }
     }
 
+    #[cfg(feature = "std")]
     pub fn get_code(self: &HuffmanTree, val: u8) -> Code {
         self.codes[val as usize]
     }
 }
 
-fn assign_codes(p: &Box<Node>, h: &mut [Code;16], s: Code) {
-    if let Some(ch) = p.ch {
+fn assign_codes(nodes: &[Node], p: usize, h: &mut [Code;16], s: Code) {
+    if let Some(ch) = nodes[p].ch {
         let s = Code { val: s.val, len: s.len, symbol: ch };
         h[ch as usize] = s;
     } else {
-        if let Some(ref l) = p.left {
-            assign_codes(l, h, s.append(false));
+        if let Some(l) = nodes[p].left {
+            assign_codes(nodes, l as usize, h, s.append(false));
         }
 
-        if let Some(ref r) = p.right {
-            assign_codes(r, h, s.append(true));
+        if let Some(r) = nodes[p].right {
+            assign_codes(nodes, r as usize, h, s.append(true));
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,187 @@
+//! Minimal I/O abstraction used by the decoder.
+//!
+//! The decoder reads from any Source, and Decoder::reset additionally requires a SeekableSource. With the std feature (the default), these are implemented
+//! for every std::io::Read / std::io::Seek type and Error, ErrorKind, SeekFrom & Cursor are the std::io types. Without std, this module provides
+//! small replacements for them, and Source can be implemented for whatever the platform reads files or memory through.
+
+#[cfg(not(feature = "std"))]
+use core::fmt;
+
+#[cfg(feature = "std")]
+pub use std::io::{Error, ErrorKind, SeekFrom, Cursor};
+
+/// A source of stream data
+pub trait Source {
+    /// Read up to buf.len() bytes into buf, returning how many bytes were read. Returns 0 only once the end of the source has been reached
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
+}
+
+/// A source which can be repositioned
+pub trait SeekableSource: Source {
+    /// Seek to the given position, returning the new position from the start of the source
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error>;
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> Source for R {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            match std::io::Read::read(self, buf) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                result => {
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read + std::io::Seek> SeekableSource for R {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        std::io::Seek::seek(self, pos)
+    }
+}
+
+/// Kind of I/O error (a subset of std::io::ErrorKind)
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The source ended in the middle of a packet
+    UnexpectedEof,
+    /// The stream contains data which can't be decoded
+    InvalidData,
+    /// Seek to a position before the start of the source
+    InvalidInput,
+    /// Any other error, reported by the source
+    Other,
+}
+
+/// I/O error (stands in for std::io::Error)
+#[cfg(not(feature = "std"))]
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+}
+
+#[cfg(not(feature = "std"))]
+impl Error {
+    pub fn kind(self: &Error) -> ErrorKind {
+        self.kind
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl From<ErrorKind> for Error {
+    fn from(value: ErrorKind) -> Self {
+        Error { kind: value }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ErrorKind::UnexpectedEof => write!(f, "unexpected end of file"),
+            ErrorKind::InvalidData => write!(f, "invalid data"),
+            ErrorKind::InvalidInput => write!(f, "invalid input parameter"),
+            ErrorKind::Other => write!(f, "other error"),
+        }
+    }
+}
+
+/// Position to seek to (stands in for std::io::SeekFrom)
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// Wraps an in-memory buffer to make it a SeekableSource (stands in for std::io::Cursor)
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Default)]
+pub struct Cursor<T> {
+    inner: T,
+    pos: u64,
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> Cursor<T> {
+    pub fn new(inner: T) -> Cursor<T> {
+        Cursor { inner, pos: 0 }
+    }
+
+    pub fn into_inner(self: Cursor<T>) -> T {
+        self.inner
+    }
+
+    pub fn get_ref(self: &Cursor<T>) -> &T {
+        &self.inner
+    }
+
+    pub fn position(self: &Cursor<T>) -> u64 {
+        self.pos
+    }
+
+    pub fn set_position(self: &mut Cursor<T>, pos: u64) {
+        self.pos = pos;
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T: AsRef<[u8]>> Source for Cursor<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let data = self.inner.as_ref();
+        let start = self.pos.min(data.len() as u64) as usize;
+        let len = buf.len().min(data.len() - start);
+
+        buf[..len].copy_from_slice(&data[start..(start + len)]);
+        self.pos += len as u64;
+
+        Ok(len)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T: AsRef<[u8]>> SeekableSource for Cursor<T> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(v) => {
+                self.pos = v;
+                return Ok(v);
+            }
+            SeekFrom::End(v) => (self.inner.as_ref().len() as u64, v),
+            SeekFrom::Current(v) => (self.pos, v),
+        };
+
+        match base.checked_add_signed(offset) {
+            Some(v) => {
+                self.pos = v;
+                Ok(v)
+            }
+            None => Err(Error::from(ErrorKind::InvalidInput))
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Source for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = buf.len().min(self.len());
+        let (head, tail) = self.split_at(len);
+
+        buf[..len].copy_from_slice(head);
+        *self = tail;
+
+        Ok(len)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<S: Source + ?Sized> Source for &mut S {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        (**self).read(buf)
+    }
+}
@@ -1,22 +1,59 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
 pub mod plane;
 pub mod frame;
-pub mod enc;
 pub mod dec;
+pub mod io;
+pub mod metadata;
+pub mod audio;
+pub mod track;
+pub mod stream;
+
+#[cfg(feature = "std")]
+pub mod enc;
+
+#[cfg(feature = "std")]
+pub mod metrics;
+
+#[cfg(feature = "std")]
+pub mod analyze;
+
+#[cfg(feature = "std")]
+pub mod validate;
 
+#[cfg(feature = "multithreading")]
+pub mod group;
+
+#[cfg(feature = "async")]
+pub mod async_io;
+
+#[cfg(feature = "capi")]
+pub mod capi;
+
+mod bits;
 mod dct;
 mod common;
 mod huffman;
+#[cfg(feature = "std")]
 mod rle;
+mod simd;
+mod slice;
+mod qoa;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
-    use std::{path::Path, fs::{File, self}, io::{Cursor, Seek, Read}, time::Instant, hint::black_box};
+    use std::{path::Path, fs::{File, self}, io::{Cursor, Seek, Read}, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}, time::Instant, hint::black_box, cell::Cell, alloc::{GlobalAlloc, Layout, System}};
 
-    use bitstream_io::{BitWriter, BitWrite, BitReader, BitRead};
+    use bitstream_io::{BitWriter, BitWrite};
     use byteorder::{ReadBytesExt, LittleEndian};
     use image::{io::Reader as ImageReader, RgbImage};
 
-    use crate::{dct::*, frame::VideoFrame, plane::VideoPlane, enc::Encoder, dec::Decoder, rle};
+    use crate::{bits::BitReader, io, dct::*, common::MacroBlock, frame::{VideoFrame, FrameTarget, Nv12FrameTarget, Timebase}, plane::VideoPlane, enc::{Encoder, EncoderBuilder, EncodeError, Preset, FrameStats}, dec::{self, Decoder, DecodeError, FrameType}, metadata, metrics, analyze::{self, Analyzer, BlockType}, validate, audio::{AudioFormat, AudioCodec}, track::TrackKind, stream::StreamDecoder, rle, simd};
+
+    #[cfg(feature = "multithreading")]
+    use crate::group::DecoderGroup;
 
     const DCT_B2_NORMALIZER: [i32;8] = [
         91, 105, 95, 75, 91, 75, 95, 105
@@ -126,11 +163,8 @@ mod tests {
 
         println!("Test data encoded to {} bytes", rle_coded.len());
 
-        let mut rle_reader = Cursor::new(rle_coded);
-        let mut bitreader = BitReader::endian(&mut rle_reader, bitstream_io::LittleEndian);
-
-        let total_bits = bitreader.seek_bits(std::io::SeekFrom::End(0)).unwrap();
-        bitreader.seek_bits(std::io::SeekFrom::Start(0)).unwrap();
+        let mut bitreader = BitReader::new(&rle_coded);
+        let total_bits = rle_coded.len() as u64 * 8;
 
         let mut out_data = [0;10];
 
@@ -143,7 +177,7 @@ mod tests {
 
             // if num_bits is 0, then this is only a run of 0s with no value
             if num_bits > 0 {
-                let coeff = bitreader.read_signed::<i16>(num_bits as u32).unwrap();
+                let coeff = bitreader.read_signed(num_bits as u32).unwrap() as i16;
                 out_data[out_idx] = coeff;
 
                 out_idx += 1;
@@ -203,11 +237,8 @@ mod tests {
 
         println!("Test data encoded ({} bytes -> {} bytes, {} bits)", infile_len, rle_coded.len(), bits_written);
 
-        let mut rle_reader = Cursor::new(rle_coded);
-        let mut bitreader = BitReader::endian(&mut rle_reader, bitstream_io::LittleEndian);
-
-        let total_bits = bitreader.seek_bits(std::io::SeekFrom::End(0)).unwrap();
-        bitreader.seek_bits(std::io::SeekFrom::Start(0)).unwrap();
+        let mut bitreader = BitReader::new(&rle_coded);
+        let total_bits = rle_coded.len() as u64 * 8;
 
         let mut out_data = vec![0;test_data.len()];
 
@@ -225,7 +256,7 @@ mod tests {
 
             // if num_bits is 0, then this is only a run of 0s with no value
             if num_bits > 0 {
-                let coeff = bitreader.read_signed::<i16>(num_bits as u32).unwrap();
+                let coeff = bitreader.read_signed(num_bits as u32).unwrap() as i16;
                 out_data[out_idx] = coeff;
                 out_idx += 1;
             }
@@ -242,7 +273,7 @@ mod tests {
     fn test_encode_1() {
         let test_frame = load_frame("test1.png");
         let outfile = File::create("test.pfv").unwrap();
-        let mut encoder = Encoder::new(outfile, test_frame.width, test_frame.height, 30, 5, 6).unwrap();
+        let mut encoder = new_encoder(outfile, test_frame.width, test_frame.height, 30, 5, 6).unwrap();
         
         encoder.encode_iframe(&test_frame).unwrap();
         encoder.encode_pframe(&test_frame).unwrap();
@@ -254,7 +285,7 @@ mod tests {
     #[test]
     fn test_decode_1() {
         let infile = File::open("test.pfv").unwrap();
-        let mut decoder = Decoder::new(infile, 6).unwrap();
+        let mut decoder = new_decoder(infile, 6).unwrap();
 
         let mut outframe = 0;
 
@@ -271,7 +302,7 @@ mod tests {
     #[test]
     fn test_encode_2() {
         let outfile = File::create("test2.pfv").unwrap();
-        let mut encoder = Encoder::new(outfile, 512, 384, 30, 2, 6).unwrap();
+        let mut encoder = new_encoder(outfile, 512, 384, 30, 2, 6).unwrap();
 
         for frame_id in 1..162 {
             let frame_path = format!("test_frames/{:0>3}.png", frame_id);
@@ -294,7 +325,7 @@ mod tests {
     #[test]
     fn test_decode_2() {
         let infile = File::open("test2.pfv").unwrap();
-        let mut decoder = Decoder::new(infile, 6).unwrap();
+        let mut decoder = new_decoder(infile, 6).unwrap();
 
         let mut outframe = 0;
  
@@ -318,7 +349,7 @@ mod tests {
 
             let infile = Cursor::new(filebuf);
 
-            let mut decoder = Decoder::new(infile, 6).unwrap();
+            let mut decoder = new_decoder(infile, 6).unwrap();
 
             let mut outframe = 0;
 
@@ -334,6 +365,2697 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_slice_threads() {
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+
+        for frame_id in 0..8 {
+            let frame = gen_frame(96, 64, frame_id);
+
+            if frame_id % 4 == 0 {
+                encoder.encode_iframe(&frame).unwrap();
+            } else {
+                encoder.encode_pframe(&frame).unwrap();
+            }
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        // slices are decoded in parallel, so output must not depend on thread count
+        let frames_1 = decode_all(&encoded, 1);
+        let frames_4 = decode_all(&encoded, 4);
+
+        assert!(frames_1.len() == 8);
+        assert!(frames_1 == frames_4);
+    }
+
+    #[test]
+    fn test_deterministic_output() {
+        let frames: Vec<_> = (0..12).map(|t| gen_frame(96, 64, t)).collect();
+
+        // encoding is done in integer or exactly rounded float math & blocks are merged in order,
+        // so output must be byte-identical regardless of thread count, SIMD support, or architecture
+        for preset in [Preset::Fast, Preset::Balanced, Preset::Archival] {
+            for rdo in [false, true] {
+                let mut outputs = Vec::new();
+
+                for num_threads in [1, 2, 4, 0] {
+                    let mut encoded = Vec::new();
+                    let mut encoder = EncoderBuilder::new(96, 64).preset(preset).rdo_quantization(rdo).threads(num_threads).build(&mut encoded).unwrap();
+
+                    for frame in &frames {
+                        encoder.encode_frame(frame).unwrap();
+                    }
+
+                    encoder.finish().unwrap();
+                    drop(encoder);
+
+                    outputs.push(encoded);
+                }
+
+                assert!(outputs.iter().all(|x| *x == outputs[0]), "{:?} (rdo: {}) output depends on thread count", preset, rdo);
+            }
+        }
+
+        // pinned so that a change in output on another platform, in a build without multithreading (where the thread counts are ignored),
+        // or from a change to the encoder or decoder is caught
+        let mut encoded = Vec::new();
+        let mut encoder = EncoderBuilder::new(96, 64).preset(Preset::Balanced).rdo_quantization(true).keyframe_interval(6).threads(3).build(&mut encoded).unwrap();
+
+        for frame in &frames {
+            encoder.encode_frame(frame).unwrap();
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let hash = fnv1a(&encoded);
+        assert!(hash == 0x6ae5372ac0eb41f3, "encoded output changed: {:016x}", hash);
+
+        for num_threads in [1, 2, 4, 0] {
+            let mut decoder = new_decoder(Cursor::new(&encoded), num_threads).unwrap();
+            let mut checksums = Vec::new();
+
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                checksums.push(frame_checksum(frame.frame));
+            }
+
+            let hash = fnv1a(checksums.iter().flat_map(|x| x.to_le_bytes()));
+
+            assert!(checksums.len() == 12);
+            assert!(hash == 0xb1561d5d439d07ff, "decoded output changed with {} threads: {:016x}", num_threads, hash);
+        }
+    }
+
+    #[test]
+    fn test_decode_no_alloc() {
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+
+        for frame_id in 0..8 {
+            let frame = gen_frame(96, 64, frame_id);
+
+            if frame_id % 4 == 0 {
+                encoder.encode_iframe(&frame).unwrap();
+            } else {
+                encoder.encode_pframe(&frame).unwrap();
+            }
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        // slices are decoded on the pool's worker threads, so their allocations count too
+        #[cfg(feature = "multithreading")]
+        let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(4).start_handler(|_| track_allocations()).build().unwrap());
+
+        #[cfg(feature = "multithreading")]
+        let mut decoder = Decoder::new_with_thread_pool(Cursor::new(&encoded), pool.clone()).unwrap();
+
+        #[cfg(not(feature = "multithreading"))]
+        let mut decoder = Decoder::new(Cursor::new(&encoded)).unwrap();
+
+        let mut checksum = 0_u64;
+
+        // first pass warms up the decoder's scratch buffers
+        while decoder.advance_frame(&mut |frame| {
+            checksum += frame.plane_y.pixels[0] as u64;
+        }).unwrap() {}
+
+        decoder.reset().unwrap();
+
+        // once warmed up, steady-state playback must not touch the heap at all, on the calling thread or any worker thread.
+        // playback is driven from inside the pool: jobs submitted from outside go through rayon's global queue, which allocates a block every 63 submissions
+        let allocations = count_allocations(|| {
+            #[cfg(feature = "multithreading")]
+            pool.install(|| {
+                assert!(rayon::current_num_threads() == 4);
+
+                while decoder.advance_frame(&mut |frame| {
+                    checksum += frame.plane_y.pixels[0] as u64;
+                }).unwrap() {}
+            });
+
+            #[cfg(not(feature = "multithreading"))]
+            while decoder.advance_frame(&mut |frame| {
+                checksum += frame.plane_y.pixels[0] as u64;
+            }).unwrap() {}
+        });
+
+        black_box(checksum);
+        assert!(allocations == 0, "decoder performed {} allocations", allocations);
+    }
+
+    #[test]
+    fn test_decode_into() {
+        // dimensions which aren't a multiple of the macroblock size, so the padded framebuffer must be cropped
+        let (width, height) = (100, 72);
+
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, width, height, 30, 5, 4).unwrap();
+
+        for frame_id in 0..6 {
+            let frame = gen_frame(width, height, frame_id);
+
+            if frame_id == 0 {
+                encoder.encode_iframe(&frame).unwrap();
+            } else if frame_id == 3 {
+                encoder.encode_dropframe().unwrap();
+            } else {
+                encoder.encode_pframe(&frame).unwrap();
+            }
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let expected = decode_all(&encoded, 4);
+        assert!(expected.len() == 5);
+
+        // drop frames repeat the previous frame
+        let mut expected_all = expected.clone();
+        expected_all.insert(3, expected[2].clone());
+
+        let (chroma_width, chroma_height) = (width / 2, height / 2);
+        let (y_stride, u_stride, v_stride, uv_stride) = (128, 64, 56, 112);
+
+        let mut decoder = new_decoder(Cursor::new(&encoded), 4).unwrap();
+        let mut y = vec![0xAA;y_stride * height];
+        let mut u = vec![0xAA;u_stride * chroma_height];
+        let mut v = vec![0xAA;(v_stride * (chroma_height - 1)) + chroma_width];
+        let mut num_frames = 0;
+
+        while decoder.decode_next_into(&mut FrameTarget { y: &mut y, y_stride, u: &mut u, u_stride, v: &mut v, v_stride }).unwrap() {
+            let (ey, eu, ev) = &expected_all[num_frames];
+
+            for row in 0..height {
+                assert!(y[(row * y_stride)..(row * y_stride + width)] == ey[(row * width)..((row + 1) * width)]);
+
+                // padding between rows must be left untouched
+                assert!(y[(row * y_stride + width)..((row + 1) * y_stride)].iter().all(|x| *x == 0xAA));
+            }
+
+            for row in 0..chroma_height {
+                assert!(u[(row * u_stride)..(row * u_stride + chroma_width)] == eu[(row * chroma_width)..((row + 1) * chroma_width)]);
+                assert!(v[(row * v_stride)..(row * v_stride + chroma_width)] == ev[(row * chroma_width)..((row + 1) * chroma_width)]);
+            }
+
+            num_frames += 1;
+        }
+
+        assert!(num_frames == 6);
+
+        let mut decoder = new_decoder(Cursor::new(&encoded), 4).unwrap();
+        let mut uv = vec![0;uv_stride * chroma_height];
+        let mut num_frames = 0;
+
+        while decoder.decode_next_into_nv12(&mut Nv12FrameTarget { y: &mut y, y_stride, uv: &mut uv, uv_stride }).unwrap() {
+            let (ey, eu, ev) = &expected_all[num_frames];
+
+            for row in 0..height {
+                assert!(y[(row * y_stride)..(row * y_stride + width)] == ey[(row * width)..((row + 1) * width)]);
+            }
+
+            for row in 0..chroma_height {
+                for x in 0..chroma_width {
+                    assert!(uv[(row * uv_stride) + (x * 2)] == eu[(row * chroma_width) + x]);
+                    assert!(uv[(row * uv_stride) + (x * 2) + 1] == ev[(row * chroma_width) + x]);
+                }
+            }
+
+            num_frames += 1;
+        }
+
+        assert!(num_frames == 6);
+    }
+
+    #[test]
+    fn test_frame_iterator() {
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 25, 5, 4).unwrap();
+
+        for frame_id in 0..6 {
+            let frame = gen_frame(96, 64, frame_id);
+
+            if frame_id == 0 {
+                encoder.encode_iframe(&frame).unwrap();
+            } else if frame_id == 2 {
+                encoder.encode_dropframe().unwrap();
+            } else {
+                encoder.encode_pframe(&frame).unwrap();
+            }
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let expected = decode_all(&encoded, 4);
+        assert!(expected.len() == 5);
+
+        let mut decoder = new_decoder(Cursor::new(&encoded), 4).unwrap();
+        let frames: Vec<_> = decoder.frames().map(|x| x.unwrap()).collect();
+
+        assert!(frames.len() == 6);
+        assert!(frames.iter().map(|x| x.frame_type).collect::<Vec<_>>() == [FrameType::Intra, FrameType::Predicted, FrameType::Drop,
+            FrameType::Predicted, FrameType::Predicted, FrameType::Predicted]);
+
+        for (i, frame) in frames.iter().enumerate() {
+            assert!(frame.index == i as u64);
+            assert!((frame.pts - (i as f64 / 25.0)).abs() < 1e-9);
+        }
+
+        // drop frames hand back the previous frame's planes unchanged
+        let planes: Vec<_> = frames.iter().map(|x| (x.frame.plane_y.pixels.clone(), x.frame.plane_u.pixels.clone(), x.frame.plane_v.pixels.clone())).collect();
+        assert!(planes[2] == planes[1]);
+        assert!(planes[..2] == expected[..2]);
+        assert!(planes[3..] == expected[2..]);
+
+        // borrowing API returns the same sequence, and restarts after a reset
+        decoder.reset().unwrap();
+        let mut num_frames = 0;
+
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            assert!(frame.index == num_frames);
+            assert!(frame.frame.plane_y.pixels == planes[num_frames as usize].0);
+            num_frames += 1;
+        }
+
+        assert!(num_frames == 6);
+    }
+
+    #[test]
+    fn test_timebase() {
+        // 29.97 fps constant framerate
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder_with_timebase(&mut encoded, 96, 64, Timebase { num: 1001, den: 30000 }, false, 5, 4).unwrap();
+
+        for frame_id in 0..4 {
+            encoder.encode_pframe(&gen_frame(96, 64, frame_id)).unwrap();
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let mut decoder = new_decoder(Cursor::new(&encoded), 4).unwrap();
+        assert!(decoder.timebase() == Timebase { num: 1001, den: 30000 });
+        assert!(decoder.framerate() == 30);
+        assert!(!decoder.is_variable_framerate());
+
+        let frame = decoder.frames().nth(3).unwrap().unwrap();
+        assert!((frame.pts - (3.0 * 1001.0 / 30000.0)).abs() < 1e-9);
+
+        // frame N is due at N * 1001/30000s, just after the Nth 1/30s step of the playback clock, so each step presents exactly one frame
+        decoder.reset().unwrap();
+        let mut presented = Vec::new();
+
+        for _ in 0..4 {
+            let mut count = 0;
+            decoder.advance_delta(1.0 / 30.0, &mut |_| { count += 1; }).unwrap();
+            presented.push(count);
+        }
+
+        assert!(presented == [1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_versions() {
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+
+        for frame_id in 0..4 {
+            encoder.encode_pframe(&gen_frame(96, 64, frame_id)).unwrap();
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let decode_all = |encoded: &[u8]| -> Vec<u64> {
+            let mut decoder = new_decoder(Cursor::new(encoded), 4).unwrap();
+            let mut checksums = Vec::new();
+
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                checksums.push(frame_checksum(frame.frame));
+            }
+
+            checksums
+        };
+
+        // 3.5.0
+        assert!(new_decoder(Cursor::new(&encoded), 4).unwrap().version() == crate::common::PFV_VERSION);
+        let expected = decode_all(&encoded);
+        assert!(expected.len() == 4);
+
+        // 2.1.1
+        let legacy = transcode_legacy(&encoded, &mut |_, _| {});
+        assert!(new_decoder(Cursor::new(&legacy), 4).unwrap().version() == crate::common::PFV_VERSION_LEGACY);
+        assert!(decode_all(&legacy) == expected);
+
+        // anything else (including the unreleased 3.0.0 - 3.4.0 development versions) is rejected
+        for version in [0, 210, 212, 300, 310, 320, 330, 340, 351] {
+            let mut patched = encoded.clone();
+            patched[8..12].copy_from_slice(&(version as u32).to_le_bytes());
+            assert!(matches!(new_decoder(Cursor::new(&patched), 4), Err(DecodeError::VersionError)), "version {} was accepted", version);
+        }
+    }
+
+    #[test]
+    fn test_invalid_frame_size() {
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 10, 6, 30, 5, 1).unwrap();
+        encoder.encode_iframe(&gen_frame(10, 6, 0)).unwrap();
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let legacy = transcode_legacy(&encoded, &mut |_, _| {});
+
+        // an empty or odd frame size is rejected when opening the stream, rather than panicking once frames are decoded
+        for stream in [&encoded, &legacy] {
+            for (width, height) in [(10, 0), (0, 6), (0, 0), (11, 6)] {
+                let mut patched = stream.clone();
+                patched[12..14].copy_from_slice(&(width as u16).to_le_bytes());
+                patched[14..16].copy_from_slice(&(height as u16).to_le_bytes());
+                assert!(matches!(new_decoder(Cursor::new(&patched), 4), Err(DecodeError::FormatError)), "{}x{} was accepted", width, height);
+            }
+        }
+    }
+
+    #[test]
+    fn test_variable_framerate() {
+        // timestamps in milliseconds
+        let timestamps = [0, 40, 100, 110, 250];
+
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder_with_timebase(&mut encoded, 96, 64, Timebase { num: 1, den: 1000 }, true, 5, 4).unwrap();
+
+        for (frame_id, pts) in timestamps.iter().enumerate() {
+            encoder.set_frame_pts(*pts).unwrap();
+
+            if frame_id == 3 {
+                encoder.encode_dropframe().unwrap();
+            } else {
+                encoder.encode_pframe(&gen_frame(96, 64, frame_id)).unwrap();
+            }
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let mut decoder = new_decoder(Cursor::new(&encoded), 4).unwrap();
+        assert!(decoder.is_variable_framerate());
+
+        let frames: Vec<_> = decoder.frames().map(|x| x.unwrap()).collect();
+        assert!(frames.len() == 5);
+
+        for (frame, pts) in frames.iter().zip(timestamps) {
+            assert!((frame.pts - (pts as f64 / 1000.0)).abs() < 1e-9);
+        }
+
+        assert!(frames[3].frame_type == FrameType::Drop);
+
+        // advance_delta presents frames once the playback clock reaches their timestamp
+        decoder.reset().unwrap();
+        let mut presented = Vec::new();
+
+        while decoder.advance_delta(0.05, &mut |frame| {
+            presented.push(frame.plane_y.pixels.clone());
+        }).unwrap() {
+            presented.push(Vec::new());
+        }
+
+        // each tick is marked by an empty entry: t=0.05 shows 0 & 40ms, t=0.1 shows 100ms, t=0.15 & 0.2 show nothing, t=0.25 shows 250ms
+        let per_tick: Vec<usize> = presented.split(|x| x.is_empty()).map(|x| x.len()).collect();
+        assert!(per_tick[..5] == [2, 1, 0, 0, 1]);
+        assert!(presented.last().unwrap() == &frames[4].frame.plane_y.pixels);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+
+        encoder.write_metadata(metadata::KEY_TITLE, "Test Video").unwrap();
+        encoder.write_metadata(metadata::KEY_COLORIMETRY, "bt709").unwrap();
+        encoder.write_metadata("custom:tag", "ünïcödé").unwrap();
+
+        encoder.write_user_data(0, b"chapter 1").unwrap();
+        encoder.encode_iframe(&gen_frame(96, 64, 0)).unwrap();
+        encoder.encode_pframe(&gen_frame(96, 64, 1)).unwrap();
+        encoder.write_user_data(2, b"subtitle").unwrap();
+        encoder.write_user_data(2, &[]).unwrap();
+        encoder.encode_pframe(&gen_frame(96, 64, 2)).unwrap();
+        encoder.write_user_data(3, b"end").unwrap();
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let mut decoder = new_decoder(Cursor::new(&encoded), 4).unwrap();
+
+        assert!(decoder.metadata().len() == 3);
+        assert!(decoder.get_metadata(metadata::KEY_TITLE) == Some("Test Video"));
+        assert!(decoder.get_metadata(metadata::KEY_COLORIMETRY) == Some("bt709"));
+        assert!(decoder.get_metadata("custom:tag") == Some("ünïcödé"));
+        assert!(decoder.get_metadata(metadata::KEY_SOURCE).is_none());
+
+        // user data preceding each frame is available once that frame has been returned
+        let mut received = Vec::new();
+
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            let index = frame.index;
+
+            while let Some(user_data) = decoder.take_user_data() {
+                received.push((index, user_data.pts_ticks, user_data.data));
+            }
+        }
+
+        while let Some(user_data) = decoder.take_user_data() {
+            received.push((u64::MAX, user_data.pts_ticks, user_data.data));
+        }
+
+        assert!(received == [
+            (0, 0, b"chapter 1".to_vec()),
+            (2, 2, b"subtitle".to_vec()),
+            (2, 2, Vec::new()),
+            (u64::MAX, 3, b"end".to_vec()),
+        ]);
+
+        // user data doesn't disturb the decoded frames
+        assert!(decode_all(&encoded, 4).len() == 3);
+
+        // user data which is never taken doesn't pile up
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+
+        for pts in 0..(dec::MAX_QUEUED_USER_DATA as u64 + 50) {
+            encoder.write_user_data(pts, &[0;16]).unwrap();
+        }
+
+        encoder.encode_iframe(&gen_frame(96, 64, 0)).unwrap();
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let mut decoder = new_decoder(Cursor::new(&encoded), 4).unwrap();
+        while decoder.next_frame().unwrap().is_some() {}
+
+        let mut received = Vec::new();
+        while let Some(user_data) = decoder.take_user_data() {
+            received.push(user_data.pts_ticks);
+        }
+
+        assert!(received.len() == dec::MAX_QUEUED_USER_DATA);
+        assert!(received[0] == 50 && *received.last().unwrap() == dec::MAX_QUEUED_USER_DATA as u64 + 49);
+    }
+
+    #[test]
+    fn test_audio() {
+        let samplerate = 44100;
+        let samples_per_frame = samplerate / 30;
+
+        // stereo sine sweep, with an extra chunk longer than a single QOA frame at the start
+        let gen_audio = |start: usize, len: usize| {
+            let mut samples = Vec::with_capacity(len * 2);
+
+            for i in start..(start + len) {
+                let t = i as f32 / samplerate as f32;
+                samples.push(((t * 440.0 * std::f32::consts::TAU).sin() * 12000.0) as i16);
+                samples.push(((t * (220.0 + t * 200.0) * std::f32::consts::TAU).sin() * 8000.0) as i16);
+            }
+
+            samples
+        };
+
+        for codec in [AudioCodec::Pcm, AudioCodec::Qoa] {
+            let mut encoded = Vec::new();
+            let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+            encoder.set_audio_format(AudioFormat { codec, channels: 2, samplerate: samplerate as u32 }).unwrap();
+
+            let mut source = Vec::new();
+
+            for frame_id in 0..8 {
+                let len = if frame_id == 0 { samples_per_frame * 5 } else { samples_per_frame };
+                let audio = gen_audio(source.len() / 2, len);
+                encoder.encode_audio(&audio).unwrap();
+                source.extend_from_slice(&audio);
+
+                encoder.encode_pframe(&gen_frame(96, 64, frame_id)).unwrap();
+            }
+
+            encoder.finish().unwrap();
+            drop(encoder);
+
+            let mut decoder = new_decoder(Cursor::new(&encoded), 4).unwrap();
+            assert!(decoder.audio_format() == Some(AudioFormat { codec, channels: 2, samplerate: samplerate as u32 }));
+            assert!(decoder.channels() == 2 && decoder.samplerate() == samplerate as u32);
+
+            let mut num_frames = 0;
+            let decoded = play_audio(&mut decoder, &mut |_| num_frames += 1);
+
+            assert!(num_frames == 8);
+            assert!(decoded.len() == source.len());
+
+            match codec {
+                AudioCodec::Pcm => {
+                    assert!(decoded == source);
+                }
+                AudioCodec::Qoa => {
+                    let signal: f64 = source.iter().map(|x| (*x as f64) * (*x as f64)).sum();
+                    let noise: f64 = source.iter().zip(&decoded).map(|(a, b)| (*a as f64 - *b as f64).powi(2)).sum();
+                    let snr = 10.0 * (signal / noise).log10();
+
+                    println!("QOA SNR: {} dB", snr);
+                    assert!(snr > 30.0);
+                }
+            }
+
+            // frame-only APIs skip over audio
+            assert!(decode_all(&encoded, 4).len() == 8);
+        }
+    }
+
+    #[test]
+    fn test_audio_interleaving() {
+        let format = AudioFormat { codec: AudioCodec::Pcm, channels: 1, samplerate: 3000 };
+
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+        encoder.set_audio_format(format).unwrap();
+
+        for frame_id in 0..8 {
+            encoder.encode_audio(&[frame_id as i16;100]).unwrap();
+            encoder.encode_pframe(&gen_frame(96, 64, frame_id)).unwrap();
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let header_len = new_decoder(Cursor::new(&encoded), 4).unwrap().header_len() as usize;
+
+        // split the stream into packets
+        let mut packets = Vec::new();
+        let mut offset = header_len;
+
+        while offset < encoded.len() {
+            let len = u32::from_le_bytes(encoded[(offset + 1)..(offset + 5)].try_into().unwrap()) as usize;
+            packets.push(&encoded[offset..(offset + 5 + len)]);
+            offset += 5 + len;
+        }
+
+        let (audio, other): (Vec<_>, Vec<_>) = packets.into_iter().partition(|x| x[0] == crate::common::PFV_PACKET_AUDIO);
+        assert!(audio.len() == 8);
+
+        // move the audio of the second half up front in reverse order, and swap the audio of each pair of frames in the first half
+        let mut reordered = encoded[..header_len].to_vec();
+
+        for packet in audio[4..].iter().rev() {
+            reordered.extend_from_slice(packet);
+        }
+
+        for (frame_id, packet) in other.iter().enumerate() {
+            if frame_id == 0 || frame_id == 2 {
+                reordered.extend_from_slice(audio[frame_id + 1]);
+                reordered.extend_from_slice(audio[frame_id]);
+            }
+
+            reordered.extend_from_slice(packet);
+        }
+
+        assert!(reordered.len() == encoded.len() && reordered != encoded);
+
+        // both streams play the same audio at the same time
+        for stream in [&encoded, &reordered] {
+            let mut decoder = new_decoder(Cursor::new(stream), 4).unwrap();
+            let decoded = play_audio(&mut decoder, &mut |_| {});
+
+            let expected: Vec<i16> = (0..8).flat_map(|x| [x;100]).collect();
+            assert!(decoded == expected);
+        }
+    }
+
+    /// Play back a stream with advance_delta_with_audio at 30fps & return the decoded audio.
+    /// Checks that each audio chunk is delivered once the playback clock reaches its first sample, but not before (except for the rest of the audio at the end of the video)
+    fn play_audio<R: io::Source, FV: FnMut(&VideoFrame)>(decoder: &mut Decoder<R>, onvideo: &mut FV) -> Vec<i16> {
+        let channels = decoder.channels() as usize;
+        let samplerate = decoder.samplerate() as f64;
+
+        let mut decoded = Vec::new();
+        let mut clock = 0.0;
+
+        loop {
+            clock += 1.0 / 30.0;
+
+            let mut latest_start = 0.0;
+            let playing = decoder.advance_delta_with_audio(1.0 / 30.0, onvideo, &mut |audio| {
+                latest_start = (decoded.len() / channels) as f64 / samplerate;
+                decoded.extend_from_slice(audio);
+            }).unwrap();
+
+            if !playing {
+                return decoded;
+            }
+
+            // nothing was delivered early, and the next chunk is still due
+            assert!(latest_start <= clock);
+            assert!((decoded.len() / channels) as f64 / samplerate > clock);
+        }
+    }
+
+    #[test]
+    fn test_tracks() {
+        let english = AudioFormat { codec: AudioCodec::Pcm, channels: 1, samplerate: 22050 };
+        let japanese = AudioFormat { codec: AudioCodec::Qoa, channels: 2, samplerate: 44100 };
+
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+        let en_audio = encoder.add_audio_track(english, "en").unwrap();
+        let ja_audio = encoder.add_audio_track(japanese, "ja").unwrap();
+        let en_subs = encoder.add_subtitle_track("en").unwrap();
+        let ja_subs = encoder.add_subtitle_track("ja").unwrap();
+
+        for frame_id in 0..4 {
+            encoder.encode_audio_track(en_audio, &[frame_id as i16;735]).unwrap();
+            encoder.encode_audio_track(ja_audio, &[0;2940]).unwrap();
+            encoder.write_subtitle(en_subs, frame_id, 1, &format!("Line {}", frame_id)).unwrap();
+            encoder.write_subtitle(ja_subs, frame_id, 1, &format!("行 {}", frame_id)).unwrap();
+            encoder.encode_pframe(&gen_frame(96, 64, frame_id as usize)).unwrap();
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let mut decoder = new_decoder(Cursor::new(&encoded), 4).unwrap();
+        let tracks = decoder.tracks();
+
+        assert!(tracks.len() == 5);
+        assert!(tracks[0].kind == TrackKind::Video);
+        assert!(tracks[1].kind == TrackKind::Audio(english) && tracks[1].language == "en");
+        assert!(tracks[2].kind == TrackKind::Audio(japanese) && tracks[2].language == "ja");
+        assert!(tracks[3].kind == TrackKind::Subtitle && tracks[3].language == "en");
+        assert!(tracks[4].kind == TrackKind::Subtitle && tracks[4].language == "ja");
+
+        // first audio & subtitle tracks are selected by default
+        assert!(decoder.audio_format() == Some(english));
+
+        let mut decoded = Vec::new();
+        while decoder.advance_delta_with_audio(1.0 / 30.0, &mut |_| {}, &mut |audio| decoded.extend_from_slice(audio)).unwrap() {}
+
+        assert!(decoded.len() == 735 * 4);
+        assert!(decoded[735 * 3] == 3);
+
+        let mut subtitles = Vec::new();
+        while let Some(cue) = decoder.take_subtitle() {
+            assert!(cue.track == en_subs);
+            subtitles.push(cue.text);
+        }
+
+        assert!(subtitles == ["Line 0", "Line 1", "Line 2", "Line 3"]);
+
+        // tracks of the wrong kind (or which don't exist) are rejected, leaving the selection as it was
+        assert!(matches!(decoder.select_audio_track(Some(en_subs)), Err(DecodeError::InvalidTrack)));
+        assert!(matches!(decoder.select_audio_track(Some(0)), Err(DecodeError::InvalidTrack)));
+        assert!(matches!(decoder.select_subtitle_track(Some(ja_audio)), Err(DecodeError::InvalidTrack)));
+        assert!(matches!(decoder.select_subtitle_track(Some(5)), Err(DecodeError::InvalidTrack)));
+        assert!(decoder.audio_format() == Some(english));
+
+        // switch to the other language
+        decoder.reset().unwrap();
+        decoder.select_audio_track(Some(ja_audio)).unwrap();
+        decoder.select_subtitle_track(Some(ja_subs)).unwrap();
+        assert!(decoder.channels() == 2 && decoder.samplerate() == 44100);
+
+        let mut decoded = Vec::new();
+        while decoder.advance_delta_with_audio(1.0 / 30.0, &mut |_| {}, &mut |audio| decoded.extend_from_slice(audio)).unwrap() {}
+
+        assert!(decoded.len() == 2940 * 4);
+
+        let cue = decoder.take_subtitle().unwrap();
+        assert!(cue.track == ja_subs && cue.text == "行 0" && cue.duration == 1.0 / 30.0);
+
+        // deselecting leaves nothing to play
+        decoder.reset().unwrap();
+        decoder.select_audio_track(None).unwrap();
+        decoder.select_subtitle_track(None).unwrap();
+        assert!(decoder.channels() == 0);
+
+        let mut num_callbacks = 0;
+        while decoder.advance_delta_with_audio(1.0 / 30.0, &mut |_| {}, &mut |_| num_callbacks += 1).unwrap() {}
+
+        assert!(num_callbacks == 0);
+        assert!(decoder.take_subtitle().is_none());
+
+        // cues which are never taken don't pile up
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+        let subs = encoder.add_subtitle_track("en").unwrap();
+
+        for pts in 0..(dec::MAX_QUEUED_SUBTITLES as u64 + 50) {
+            encoder.write_subtitle(subs, pts, 1, "line").unwrap();
+        }
+
+        encoder.encode_iframe(&gen_frame(96, 64, 0)).unwrap();
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let mut decoder = new_decoder(Cursor::new(&encoded), 4).unwrap();
+        while decoder.next_frame().unwrap().is_some() {}
+
+        let mut cues = Vec::new();
+        while let Some(cue) = decoder.take_subtitle() {
+            cues.push(cue.pts);
+        }
+
+        assert!(cues.len() == dec::MAX_QUEUED_SUBTITLES);
+        assert!(cues[0] == 50.0 / 30.0);
+    }
+
+    /// A source which isn't std::io::Read & only hands out a few bytes per call
+    struct TrickleSource {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl io::Source for TrickleSource {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+            let len = buf.len().min(3).min(self.data.len() - self.pos);
+            buf[..len].copy_from_slice(&self.data[self.pos..(self.pos + len)]);
+            self.pos += len;
+
+            Ok(len)
+        }
+    }
+
+    impl io::SeekableSource for TrickleSource {
+        fn seek(&mut self, pos: io::SeekFrom) -> Result<u64, io::Error> {
+            self.pos = match pos {
+                io::SeekFrom::Start(v) => v as usize,
+                io::SeekFrom::End(v) => (self.data.len() as i64 + v) as usize,
+                io::SeekFrom::Current(v) => (self.pos as i64 + v) as usize,
+            };
+
+            Ok(self.pos as u64)
+        }
+    }
+
+    #[test]
+    fn test_custom_source() {
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+        let music = encoder.add_audio_track(AudioFormat { codec: AudioCodec::Qoa, channels: 1, samplerate: 22050 }, "en").unwrap();
+        let commentary = encoder.add_audio_track(AudioFormat { codec: AudioCodec::Pcm, channels: 1, samplerate: 22050 }, "en").unwrap();
+        let subs = encoder.add_subtitle_track("en").unwrap();
+
+        for frame_id in 0..6 {
+            encoder.encode_audio_track(music, &[frame_id as i16 * 100;735]).unwrap();
+            encoder.encode_audio_track(commentary, &[1;735]).unwrap();
+            encoder.write_subtitle(subs, frame_id, 1, &format!("Line {}", frame_id)).unwrap();
+            encoder.encode_frame(&gen_frame(96, 64, frame_id as usize)).unwrap();
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let expected = decode_all(&encoded, 4);
+        let mut decoder = new_decoder(TrickleSource { data: encoded, pos: 0 }, 4).unwrap();
+
+        // second pass goes through reset, which needs SeekableSource
+        for _ in 0..2 {
+            let mut frames = Vec::new();
+            let mut num_samples = 0;
+
+            while decoder.advance_delta_with_audio(1.0 / 30.0, &mut |frame| {
+                frames.push((frame.plane_y.pixels.clone(), frame.plane_u.pixels.clone(), frame.plane_v.pixels.clone()));
+            }, &mut |audio| num_samples += audio.len()).unwrap() {}
+
+            assert!(frames == expected);
+            assert!(num_samples == 735 * 6);
+
+            let mut num_subtitles = 0;
+            while decoder.take_subtitle().is_some() {
+                num_subtitles += 1;
+            }
+
+            assert!(num_subtitles == 6);
+            decoder.reset().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_bit_reader() {
+        let mut rng = TestRng(0x2468_ace0);
+        let mut values = Vec::new();
+        let mut data = Vec::new();
+
+        {
+            let mut writer = BitWriter::endian(&mut data, bitstream_io::LittleEndian);
+
+            for _ in 0..2000 {
+                let bits = (rng.next() % 32) as u32 + 1;
+                let value = (rng.next() & ((1 << bits) - 1)) as u32;
+                writer.write(bits, value).unwrap();
+                values.push((bits, value));
+            }
+
+            writer.byte_align().unwrap();
+        }
+
+        let mut reader = BitReader::new(&data);
+        let mut total_bits = 0;
+
+        for (i, (bits, value)) in values.iter().enumerate() {
+            assert!(reader.peek(*bits) == *value);
+
+            if i % 2 == 0 {
+                assert!(reader.read(*bits).unwrap() == *value);
+            } else {
+                // sign bit is the last (most significant) bit written
+                let expected = if (value >> (bits - 1)) & 1 == 1 { *value as i64 - (1 << bits) } else { *value as i64 };
+                assert!(reader.read_signed(*bits).unwrap() as i64 == expected);
+            }
+
+            total_bits += *bits as u64;
+            assert!(reader.position_in_bits() == total_bits);
+        }
+
+        // reads past the end fail instead of returning padding
+        let padding = data.len() as u64 * 8 - total_bits;
+        reader.skip(padding as u32);
+        assert!(reader.read_bit().is_err());
+    }
+
+    /// Read-only source which hands out data in small, irregular chunks, like a pipe or stdin would
+    struct PipeReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+        chunk: usize,
+    }
+
+    impl<'a> Read for PipeReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.chunk = (self.chunk * 7 + 3) % 61 + 1;
+            let len = buf.len().min(self.chunk).min(self.data.len() - self.pos);
+
+            buf[..len].copy_from_slice(&self.data[self.pos..(self.pos + len)]);
+            self.pos += len;
+
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_read_only() {
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+        let audio = encoder.add_audio_track(AudioFormat { codec: AudioCodec::Qoa, channels: 1, samplerate: 22050 }, "").unwrap();
+        let subtitles = encoder.add_subtitle_track("").unwrap();
+
+        for frame_id in 0..6 {
+            encoder.encode_audio_track(audio, &[0;735]).unwrap();
+            encoder.write_user_data(frame_id, &[frame_id as u8]).unwrap();
+            encoder.write_subtitle(subtitles, frame_id, 1, "subtitle").unwrap();
+
+            if frame_id == 3 {
+                encoder.encode_dropframe().unwrap();
+            } else {
+                encoder.encode_pframe(&gen_frame(96, 64, frame_id as usize)).unwrap();
+            }
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let expected = decode_all(&encoded, 4);
+
+        // deselected tracks are skipped by reading past them rather than seeking
+        let mut decoder = new_decoder(PipeReader { data: &encoded, pos: 0, chunk: 0 }, 4).unwrap();
+        decoder.select_audio_track(None).unwrap();
+        decoder.select_subtitle_track(None).unwrap();
+
+        let mut frames = Vec::new();
+        while decoder.advance_frame(&mut |frame| frames.push(frame.clone())).unwrap() {}
+
+        assert!(frames.len() == expected.len());
+
+        for (a, b) in frames.iter().zip(&expected) {
+            assert!(a.plane_y.pixels == b.0 && a.plane_u.pixels == b.1 && a.plane_v.pixels == b.2);
+        }
+
+        let mut user_data = Vec::new();
+        while let Some(packet) = decoder.take_user_data() {
+            user_data.push(packet.data[0]);
+        }
+
+        assert!(user_data == [0, 1, 2, 3, 4, 5]);
+
+        // when the reader can seek, reset rewinds to the first frame even if the stream doesn't start at offset 0
+        let mut embedded = vec![0xAB;13];
+        embedded.extend_from_slice(&encoded);
+
+        let mut cursor = Cursor::new(&embedded);
+        cursor.seek(std::io::SeekFrom::Start(13)).unwrap();
+
+        let mut decoder = new_decoder(cursor, 4).unwrap();
+        let first = decoder.next_frame().unwrap().unwrap().frame.plane_y.pixels.clone();
+        while decoder.next_frame().unwrap().is_some() {}
+
+        decoder.reset().unwrap();
+        assert!(decoder.next_frame().unwrap().unwrap().frame.plane_y.pixels == first);
+    }
+
+    #[test]
+    fn test_stream_decoder() {
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder_with_timebase(&mut encoded, 96, 64, Timebase { num: 1, den: 1000 }, true, 5, 4).unwrap();
+        encoder.write_metadata(metadata::KEY_TITLE, "streamed").unwrap();
+        encoder.set_audio_format(AudioFormat { codec: AudioCodec::Pcm, channels: 1, samplerate: 8000 }).unwrap();
+
+        for frame_id in 0..6 {
+            encoder.encode_audio(&[frame_id as i16;320]).unwrap();
+            encoder.write_user_data(frame_id * 40, &[frame_id as u8]).unwrap();
+            encoder.set_frame_pts(frame_id * 40).unwrap();
+
+            if frame_id == 2 {
+                encoder.encode_dropframe().unwrap();
+            } else {
+                encoder.encode_pframe(&gen_frame(96, 64, frame_id as usize)).unwrap();
+            }
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let expected = decode_all(&encoded, 4);
+
+        for chunk_size in [1, 7, 300, encoded.len()] {
+            let mut decoder = new_stream_decoder(4);
+            let mut frames = Vec::new();
+            let mut num_frames = 0;
+            let mut audio = Vec::new();
+
+            for chunk in encoded.chunks(chunk_size) {
+                decoder.feed(chunk);
+
+                while let Some(frame) = decoder.next_frame_with_audio(&mut |samples| audio.extend_from_slice(samples)).unwrap() {
+                    assert!(frame.index == num_frames && frame.pts == frame.index as f64 * 0.04);
+                    num_frames += 1;
+
+                    if frame.frame_type != FrameType::Drop {
+                        frames.push(frame.frame.plane_y.pixels.clone());
+                    }
+                }
+            }
+
+            assert!(decoder.is_finished());
+            assert!(num_frames == 6 && frames.len() == expected.len());
+            assert!(frames.iter().zip(&expected).all(|(a, b)| *a == b.0));
+            assert!(audio.len() == 320 * 6 && audio[320 * 5] == 5);
+
+            let decoder = decoder.decoder_mut().unwrap();
+            assert!(decoder.get_metadata(metadata::KEY_TITLE) == Some("streamed"));
+
+            let mut user_data = Vec::new();
+            while let Some(packet) = decoder.take_user_data() {
+                user_data.push(packet.data[0]);
+            }
+
+            assert!(user_data == [0, 1, 2, 3, 4, 5]);
+        }
+
+        // nothing is decoded before the header has fully arrived
+        let mut decoder = new_stream_decoder(4);
+        decoder.feed(&encoded[..20]);
+        assert!(decoder.next_frame().unwrap().is_none() && decoder.decoder().is_none());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async() {
+        use crate::async_io::{AsyncDecoder, AsyncEncoder};
+
+        let mut encoded = Vec::new();
+
+        #[cfg(feature = "multithreading")]
+        let mut encoder = AsyncEncoder::new(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+
+        #[cfg(not(feature = "multithreading"))]
+        let mut encoder = AsyncEncoder::new(&mut encoded, 96, 64, 30, 5).unwrap();
+
+        encoder.set_audio_format(AudioFormat { codec: AudioCodec::Qoa, channels: 1, samplerate: 22050 }).unwrap();
+
+        for frame_id in 0..5 {
+            encoder.encode_audio(&[0;735]).await.unwrap();
+            encoder.write_user_data(frame_id, &[frame_id as u8]).await.unwrap();
+            encoder.encode_pframe(&gen_frame(96, 64, frame_id as usize)).await.unwrap();
+        }
+
+        encoder.encode_dropframe().await.unwrap();
+        encoder.finish().await.unwrap();
+        drop(encoder);
+
+        let expected = decode_all(&encoded, 4);
+
+        #[cfg(feature = "multithreading")]
+        let mut decoder = AsyncDecoder::new(Cursor::new(&encoded), 4).await.unwrap();
+
+        #[cfg(not(feature = "multithreading"))]
+        let mut decoder = AsyncDecoder::new(Cursor::new(&encoded)).await.unwrap();
+
+        assert!(decoder.decoder().unwrap().width() == 96 && decoder.decoder().unwrap().height() == 64);
+
+        for _ in 0..2 {
+            let mut frames = Vec::new();
+
+            while let Some(frame) = decoder.next_frame().await.unwrap() {
+                assert!(frame.index == frames.len() as u64);
+                frames.push(frame);
+            }
+
+            assert!(frames.len() == 6 && frames[5].frame_type == FrameType::Drop);
+            assert!(frames.iter().zip(&expected).all(|(a, b)| a.frame.plane_y.pixels == b.0 && a.frame.plane_u.pixels == b.1));
+            assert!(decoder.take_audio().len() == 735 * 5);
+            assert!(decoder.decoder_mut().unwrap().take_user_data().map(|x| x.data) == Some(vec![0]));
+
+            decoder.reset().await.unwrap();
+        }
+
+        // dropping a call while the decoder is on the blocking thread pool leaves it unusable, which later calls report as errors
+        let mut cancelled = false;
+
+        for _ in 0..expected.len() {
+            cancelled = tokio::select! {
+                biased;
+                _ = decoder.next_frame() => false,
+                _ = std::future::ready(()) => true,
+            };
+
+            if cancelled {
+                break;
+            }
+        }
+
+        assert!(cancelled);
+        assert!(decoder.next_frame().await.is_err() && decoder.reset().await.is_err());
+        assert!(decoder.decoder().is_none() && decoder.decoder_mut().is_none());
+
+        // same for the encoder
+        let mut encoded = Vec::new();
+
+        #[cfg(feature = "multithreading")]
+        let mut encoder = AsyncEncoder::new(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+
+        #[cfg(not(feature = "multithreading"))]
+        let mut encoder = AsyncEncoder::new(&mut encoded, 96, 64, 30, 5).unwrap();
+
+        let frame = gen_frame(96, 64, 0);
+        let mut cancelled = false;
+
+        for _ in 0..10 {
+            cancelled = tokio::select! {
+                biased;
+                _ = encoder.encode_frame(&frame) => false,
+                _ = std::future::ready(()) => true,
+            };
+
+            if cancelled {
+                break;
+            }
+        }
+
+        assert!(cancelled);
+        assert!(encoder.encode_frame(&frame).await.is_err() && encoder.encode_dropframe().await.is_err());
+        assert!(encoder.set_stats_callback(|_| {}).is_err() && encoder.finish().await.is_err());
+    }
+
+    #[test]
+    fn test_finalize() {
+        fn encode<W: std::io::Write>(encoder: &mut Encoder<W>) {
+            for frame_id in 0..10 {
+                encoder.write_user_data(0, &[0;100]).unwrap();
+
+                if frame_id == 4 {
+                    encoder.encode_dropframe().unwrap();
+                } else if frame_id % 5 == 0 {
+                    encoder.encode_iframe(&gen_frame(96, 64, frame_id)).unwrap();
+                } else {
+                    encoder.encode_pframe(&gen_frame(96, 64, frame_id)).unwrap();
+                }
+            }
+        }
+
+        // seekable writers get their header filled in, even if the stream doesn't start at the beginning of the writer
+        let mut cursor = Cursor::new(vec![0xAB;7]);
+        cursor.seek(std::io::SeekFrom::End(0)).unwrap();
+
+        let mut encoder = new_encoder_with_timebase(&mut cursor, 96, 64, Timebase { num: 1, den: 25 }, false, 5, 4).unwrap();
+        encode(&mut encoder);
+        encoder.finalize().unwrap();
+        drop(encoder);
+
+        assert!(cursor.position() == cursor.get_ref().len() as u64);
+
+        let encoded = cursor.into_inner()[7..].to_vec();
+        let mut decoder = new_decoder(Cursor::new(&encoded), 4).unwrap();
+
+        assert!(decoder.frame_count() == Some(10));
+        assert!(decoder.duration() == Some(0.4));
+
+        assert!(decoder.frames().count() == 10);
+
+        // the recorded max packet size must cover every packet
+        let max_packet_size = decoder.max_packet_size().unwrap() as usize;
+        let mut iframe_offsets = Vec::new();
+
+        for packet in find_packets(&encoded) {
+            assert!(packet.payload + packet.len - packet.offset <= max_packet_size);
+
+            if packet.packet_type == crate::common::PFV_PACKET_IFRAME && packet.len > 0 {
+                iframe_offsets.push(packet.offset as u64);
+            }
+        }
+
+        // index packet lists every I-frame
+        let index_offset = decoder.index_offset().unwrap() as usize;
+        let mut index = Cursor::new(&encoded[index_offset..]);
+
+        assert!(index.read_u8().unwrap() == 6);
+        assert!(index.read_u32::<LittleEndian>().unwrap() == 48);
+
+        for (i, iframe_offset) in iframe_offsets.iter().enumerate() {
+            assert!(index.read_u64::<LittleEndian>().unwrap() == i as u64 * 5);
+            assert!(index.read_u64::<LittleEndian>().unwrap() == i as u64 * 5);
+            assert!(index.read_u64::<LittleEndian>().unwrap() == *iframe_offset);
+        }
+
+        // non-seekable writers leave the totals unknown, but produce the same frames
+        let mut unfinalized = Vec::new();
+        let mut encoder = new_encoder_with_timebase(&mut unfinalized, 96, 64, Timebase { num: 1, den: 25 }, false, 5, 4).unwrap();
+        encode(&mut encoder);
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let decoder = new_decoder(Cursor::new(&unfinalized), 4).unwrap();
+        assert!(decoder.frame_count().is_none() && decoder.duration().is_none() && decoder.max_packet_size().is_none());
+        assert!(decode_all(&unfinalized, 4) == decode_all(&encoded, 4));
+    }
+
+    #[cfg(feature = "capi")]
+    #[test]
+    fn test_capi() {
+        use std::{ffi::{c_void, CString}, ptr, slice};
+        use crate::capi::*;
+
+        unsafe extern "C" fn on_video(_planes: *const PfvPlanes, user_data: *mut c_void) {
+            *(user_data as *mut usize) += 1;
+        }
+
+        let frames: Vec<_> = (0..10).map(|t| gen_frame(96, 64, t)).collect();
+
+        unsafe {
+            let mut error = 0;
+            let encoder = pfv_encoder_open_memory(96, 64, 30, 5, 2, &mut error);
+            assert!(!encoder.is_null() && error == PFV_OK);
+
+            let key = CString::new(metadata::KEY_TITLE).unwrap();
+            let value = CString::new("C ABI").unwrap();
+            assert!(pfv_encoder_write_metadata(encoder, key.as_ptr(), value.as_ptr()) == PFV_OK);
+            assert!(pfv_encoder_set_audio_format(encoder, PfvAudioCodec::Pcm, 1, 22050) == PFV_OK);
+
+            for frame in &frames {
+                // luma is passed with padding at the end of each row
+                let mut y = vec![0;128 * 64];
+
+                for row in 0..64 {
+                    y[(row * 128)..(row * 128 + 96)].copy_from_slice(&frame.plane_y.pixels[(row * 96)..(row * 96 + 96)]);
+                }
+
+                assert!(pfv_encoder_encode_audio(encoder, [1_i16;735].as_ptr(), 735) == PFV_OK);
+                assert!(pfv_encoder_encode_frame(encoder, y.as_ptr(), 128, frame.plane_u.pixels.as_ptr(), 48, frame.plane_v.pixels.as_ptr(), 48) == PFV_OK);
+            }
+
+            assert!(pfv_encoder_write_metadata(encoder, key.as_ptr(), value.as_ptr()) == PFV_ERROR_STATE);
+            assert!(pfv_encoder_encode_frame(encoder, ptr::null(), 96, ptr::null(), 48, ptr::null(), 48) == PFV_ERROR_INVALID_ARGUMENT);
+            assert!(pfv_encoder_finish(encoder) == PFV_OK);
+
+            let mut len = 0;
+            let data = pfv_encoder_memory(encoder, &mut len);
+            let encoded = slice::from_raw_parts(data, len).to_vec();
+
+            // same output as the Rust encoder
+            let mut expected = Vec::new();
+            let mut rust_encoder = new_encoder(Cursor::new(&mut expected), 96, 64, 30, 5, 2).unwrap();
+            rust_encoder.write_metadata(metadata::KEY_TITLE, "C ABI").unwrap();
+            rust_encoder.set_audio_format(AudioFormat { codec: AudioCodec::Pcm, channels: 1, samplerate: 22050 }).unwrap();
+
+            for frame in &frames {
+                rust_encoder.encode_audio(&[1;735]).unwrap();
+                rust_encoder.encode_frame(frame).unwrap();
+            }
+
+            rust_encoder.finalize().unwrap();
+            drop(rust_encoder);
+
+            assert!(encoded == expected);
+            pfv_encoder_close(encoder);
+
+            let decoder = pfv_decoder_open_memory(encoded.as_ptr(), encoded.len(), 2, &mut error);
+            assert!(!decoder.is_null() && error == PFV_OK);
+            assert!(pfv_decoder_width(decoder) == 96 && pfv_decoder_height(decoder) == 64);
+            assert!(pfv_decoder_frame_count(decoder) == 10 && pfv_decoder_audio_channels(decoder) == 1);
+
+            // selecting a track which isn't audio is an error rather than a panic
+            assert!(pfv_decoder_select_audio_track(decoder, 0) == PFV_ERROR_INVALID_ARGUMENT);
+            assert!(pfv_decoder_select_audio_track(decoder, 1000) == PFV_ERROR_INVALID_ARGUMENT);
+            assert!(pfv_decoder_select_audio_track(decoder, -1) == PFV_OK && pfv_decoder_audio_channels(decoder) == 0);
+            assert!(pfv_decoder_select_audio_track(decoder, 1) == PFV_OK && pfv_decoder_audio_channels(decoder) == 1);
+
+            let decoded = decode_all(&encoded, 2);
+            let mut frame = std::mem::zeroed::<PfvFrame>();
+
+            for (i, (y, u, _)) in decoded.iter().enumerate() {
+                assert!(pfv_decoder_next_frame(decoder, &mut frame) == 1);
+                assert!(frame.index == i as u64 && frame.planes.y_stride == 96 && frame.planes.uv_stride == 48);
+                assert!(slice::from_raw_parts(frame.planes.y, 96 * 64) == &y[..] && slice::from_raw_parts(frame.planes.u, 48 * 32) == &u[..]);
+            }
+
+            assert!(pfv_decoder_next_frame(decoder, &mut frame) == 0);
+
+            // seek, then decode into caller buffers with padded rows
+            assert!(pfv_decoder_seek(decoder, 6) == 1);
+
+            let mut y = vec![0;100 * 64];
+            let mut u = vec![0;48 * 32];
+            let mut v = vec![0;48 * 32];
+            assert!(pfv_decoder_next_frame_into(decoder, y.as_mut_ptr(), 100, u.as_mut_ptr(), 48, v.as_mut_ptr(), 48) == 1);
+            assert!(y[(100 * 5)..(100 * 5 + 96)] == decoded[6].0[(96 * 5)..(96 * 6)] && v == decoded[6].2);
+            assert!(pfv_decoder_next_frame_into(decoder, y.as_mut_ptr(), 50, u.as_mut_ptr(), 48, v.as_mut_ptr(), 48) == PFV_ERROR_INVALID_ARGUMENT);
+
+            let mut num_frames = 0_usize;
+            assert!(pfv_decoder_reset(decoder) == 0);
+            while pfv_decoder_advance_delta(decoder, 1.0 / 30.0, Some(on_video), None, &mut num_frames as *mut usize as *mut c_void) == 1 {}
+            assert!(num_frames == 10);
+
+            pfv_decoder_close(decoder);
+
+            let garbage = [0_u8;64];
+            assert!(pfv_decoder_open_memory(garbage.as_ptr(), garbage.len(), 1, &mut error).is_null() && error == PFV_ERROR_FORMAT);
+            assert!(pfv_decoder_open_memory(garbage.as_ptr(), 4, 1, &mut error).is_null() && error == PFV_ERROR_FORMAT);
+            assert!(pfv_decoder_next_frame(ptr::null_mut(), &mut frame) == PFV_ERROR_INVALID_ARGUMENT);
+
+            // 0 threads is treated as 1
+            let decoder = pfv_decoder_open_memory(encoded.as_ptr(), encoded.len(), 0, &mut error);
+            assert!(!decoder.is_null() && error == PFV_OK);
+            assert!(pfv_decoder_next_frame(decoder, &mut frame) == 1);
+            pfv_decoder_close(decoder);
+        }
+
+        // panics are reported as an error instead of unwinding into the caller
+        assert!(crate::capi::guard(PFV_ERROR_PANIC, || PFV_OK) == PFV_OK);
+        assert!(crate::capi::guard(PFV_ERROR_PANIC, || -> std::ffi::c_int { panic!("test panic") }) == PFV_ERROR_PANIC);
+    }
+
+    #[test]
+    fn test_seek() {
+        fn encode<W: std::io::Write>(encoder: &mut Encoder<W>) {
+            for frame_id in 0..24 {
+                encoder.write_subtitle(1, frame_id, 1, &format!("Line {}", frame_id)).unwrap();
+
+                if frame_id % 7 == 3 {
+                    encoder.encode_dropframe().unwrap();
+                } else {
+                    encoder.encode_frame(&gen_frame(96, 64, frame_id as usize)).unwrap();
+                }
+            }
+        }
+
+        // stream doesn't start at the beginning of the reader
+        let mut cursor = Cursor::new(vec![0xAB;5]);
+        cursor.seek(std::io::SeekFrom::End(0)).unwrap();
+
+        let mut encoder = EncoderBuilder::new(96, 64).keyframe_interval(5).threads(2).build(&mut cursor).unwrap();
+        encoder.add_subtitle_track("en").unwrap();
+        encode(&mut encoder);
+        encoder.finalize().unwrap();
+        drop(encoder);
+
+        let mut unfinalized = Vec::new();
+        let mut encoder = EncoderBuilder::new(96, 64).keyframe_interval(5).threads(2).build(&mut unfinalized).unwrap();
+        encoder.add_subtitle_track("en").unwrap();
+        encode(&mut encoder);
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let finalized = cursor.into_inner();
+
+        for (stream, has_index) in [(finalized.clone(), true), ([vec![0xAB;5], unfinalized].concat(), false)] {
+            let mut reader = Cursor::new(&stream);
+            reader.set_position(5);
+
+            let mut decoder = new_decoder(reader, 2).unwrap();
+            assert!(decoder.index_offset().is_some() == has_index);
+
+            let expected: Vec<_> = decoder.frames().map(|x| x.unwrap()).collect();
+            assert!(expected.len() == 24);
+
+            for target in [13, 2, 19, 20, 0, 10, 23, 6] {
+                assert!(decoder.seek(target).unwrap());
+
+                let frame = decoder.next_frame().unwrap().unwrap();
+                assert!(frame.index == target && frame.frame_type == expected[target as usize].frame_type);
+                assert!(frame.frame.plane_y.pixels == expected[target as usize].frame.plane_y.pixels);
+                assert!(frame.frame.plane_v.pixels == expected[target as usize].frame.plane_v.pixels);
+
+                // decoding continues normally, & subtitles before the target were dropped
+                if target < 23 {
+                    assert!(decoder.next_frame().unwrap().unwrap().frame.plane_u.pixels == expected[target as usize + 1].frame.plane_u.pixels);
+                }
+
+                assert!(decoder.take_subtitle().unwrap().text == format!("Line {}", target));
+            }
+
+            // the clock is left at the target frame, so playback resumes there
+            assert!(decoder.seek(16).unwrap());
+
+            let mut presented = Vec::new();
+            decoder.advance_delta(0.0, &mut |frame| presented.push(frame.plane_y.pixels.clone())).unwrap();
+            assert!(presented == [expected[16].frame.plane_y.pixels.clone()]);
+
+            assert!(!decoder.seek(24).unwrap());
+            assert!(!decoder.seek(30).unwrap());
+            assert!(decoder.next_frame().unwrap().is_none());
+        }
+
+        // a damaged index (wrong packet type, or a length running past the end of the stream) falls back to decoding forward
+        let mut reader = Cursor::new(&finalized);
+        reader.set_position(5);
+        let mut decoder = new_decoder(reader, 2).unwrap();
+        let index = 5 + decoder.index_offset().unwrap() as usize;
+        let expected: Vec<_> = decoder.frames().map(|x| x.unwrap().frame.plane_y.pixels).collect();
+
+        for (offset, value) in [(index, vec![0xFF]), (index + 1, (24_u32 * 100_000_000).to_le_bytes().to_vec())] {
+            let mut damaged = finalized.clone();
+            damaged[offset..(offset + value.len())].copy_from_slice(&value);
+
+            let mut reader = Cursor::new(&damaged);
+            reader.set_position(5);
+            let mut decoder = new_decoder(reader, 2).unwrap();
+
+            for _ in 0..3 {
+                decoder.next_frame().unwrap();
+            }
+
+            for target in [13, 2] {
+                assert!(decoder.seek(target).unwrap());
+                assert!(decoder.next_frame().unwrap().unwrap().frame.plane_y.pixels == expected[target as usize]);
+                assert!(decoder.next_frame().unwrap().unwrap().frame.plane_y.pixels == expected[target as usize + 1]);
+            }
+        }
+    }
+
+    /// Writer which fails once a given number of bytes have been written
+    struct FailingWriter {
+        remaining: usize,
+        seeks: usize,
+    }
+
+    impl std::io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if buf.len() > self.remaining {
+                return Err(std::io::Error::other("disk full"));
+            }
+
+            self.remaining -= buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for FailingWriter {
+        fn seek(&mut self, _pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.seeks += 1;
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let mut encoded = Vec::new();
+
+        assert!(matches!(new_encoder(&mut encoded, 0, 64, 30, 5, 1), Err(EncodeError::InvalidDimensions)));
+        assert!(matches!(new_encoder(&mut encoded, 95, 64, 30, 5, 1), Err(EncodeError::InvalidDimensions)));
+        assert!(matches!(new_encoder(&mut encoded, 70000, 64, 30, 5, 1), Err(EncodeError::InvalidDimensions)));
+        assert!(matches!(new_encoder(&mut encoded, 96, 64, 30, 11, 1), Err(EncodeError::InvalidQuality)));
+        assert!(matches!(new_encoder(&mut encoded, 96, 64, 30, -1, 1), Err(EncodeError::InvalidQuality)));
+        assert!(matches!(new_encoder(&mut encoded, 96, 64, 0, 5, 1), Err(EncodeError::InvalidTimebase)));
+
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 1).unwrap();
+
+        assert!(matches!(encoder.set_frame_pts(10), Err(EncodeError::InvalidTimestamp)));
+        assert!(matches!(encoder.add_audio_track(AudioFormat { codec: AudioCodec::Pcm, channels: 0, samplerate: 44100 }, ""), Err(EncodeError::InvalidAudioFormat)));
+        assert!(matches!(encoder.encode_audio(&[0;16]), Err(EncodeError::InvalidTrack)));
+
+        let subtitles = encoder.add_subtitle_track("en").unwrap();
+        let audio = encoder.add_audio_track(AudioFormat { codec: AudioCodec::Pcm, channels: 2, samplerate: 44100 }, "").unwrap();
+
+        assert!(matches!(encoder.encode_audio_track(subtitles, &[0;16]), Err(EncodeError::InvalidTrack)));
+        assert!(matches!(encoder.write_subtitle(audio, 0, 1, "hello"), Err(EncodeError::InvalidTrack)));
+        assert!(matches!(encoder.encode_audio_track(audio, &[0;15]), Err(EncodeError::InvalidData)));
+        assert!(matches!(encoder.encode_iframe(&gen_frame(64, 64, 0)), Err(EncodeError::InvalidDimensions)));
+
+        encoder.encode_iframe(&gen_frame(96, 64, 0)).unwrap();
+
+        assert!(matches!(encoder.write_metadata(metadata::KEY_TITLE, "too late"), Err(EncodeError::HeaderWritten)));
+        assert!(matches!(encoder.add_subtitle_track("fr"), Err(EncodeError::HeaderWritten)));
+
+        encoder.finish().unwrap();
+
+        assert!(matches!(encoder.encode_pframe(&gen_frame(96, 64, 1)), Err(EncodeError::AlreadyFinished)));
+        assert!(matches!(encoder.encode_dropframe(), Err(EncodeError::AlreadyFinished)));
+        assert!(matches!(encoder.finish(), Err(EncodeError::AlreadyFinished)));
+
+        drop(encoder);
+
+        // errors made along the way must not have corrupted the stream
+        assert!(decode_all(&encoded, 1).len() == 1);
+
+        // I/O errors are returned, and dropping an encoder whose writer fails must not panic
+        let mut encoder = new_encoder(FailingWriter { remaining: 1000, seeks: 0 }, 96, 64, 30, 5, 1).unwrap();
+        assert!(matches!(encoder.encode_iframe(&gen_frame(96, 64, 0)), Err(EncodeError::Io(_))));
+
+        // the failed packet may have been partially written, so the stream can't be continued
+        assert!(matches!(encoder.encode_pframe(&gen_frame(96, 64, 1)), Err(EncodeError::WriteFailed)));
+        assert!(matches!(encoder.write_user_data(0, &[]), Err(EncodeError::WriteFailed)));
+        assert!(matches!(encoder.finish(), Err(EncodeError::WriteFailed)));
+        drop(encoder);
+
+        let encoder = new_encoder(FailingWriter { remaining: 0, seeks: 0 }, 96, 64, 30, 5, 1).unwrap();
+        drop(encoder);
+
+        // a write failing while finishing leaves the header alone, rather than seeking back to patch it on a broken writer
+        let mut complete = Vec::new();
+        let mut encoder = new_encoder(&mut complete, 96, 64, 30, 5, 1).unwrap();
+        encoder.encode_iframe(&gen_frame(96, 64, 0)).unwrap();
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let mut writer = FailingWriter { remaining: complete.len() - 3, seeks: 0 };
+        let mut encoder = new_encoder(&mut writer, 96, 64, 30, 5, 1).unwrap();
+        encoder.encode_iframe(&gen_frame(96, 64, 0)).unwrap();
+        assert!(matches!(encoder.finish(), Err(EncodeError::Io(_))));
+        assert!(matches!(encoder.finalize(), Err(EncodeError::WriteFailed)));
+        drop(encoder);
+
+        assert!(writer.seeks == 0);
+    }
+
+    #[test]
+    fn test_encoder_builder() {
+        assert!(matches!(EncoderBuilder::new(96, 64).luma_quality(11).build(Vec::new()), Err(EncodeError::InvalidQuality)));
+        assert!(matches!(EncoderBuilder::new(96, 64).px_err(f32::NAN).build(Vec::new()), Err(EncodeError::InvalidSetting)));
+        assert!(matches!(EncoderBuilder::new(96, 64).motion_search_range(64).build(Vec::new()), Err(EncodeError::InvalidSetting)));
+        assert!(matches!(EncoderBuilder::new(96, 64).intra_qtables([0;64], [1;64]).build(Vec::new()), Err(EncodeError::InvalidQTable)));
+
+        // presets trade size for quality
+        let mut sizes = Vec::new();
+
+        #[cfg(feature = "multithreading")]
+        let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap());
+
+        for preset in [Preset::Fast, Preset::Balanced, Preset::Archival] {
+            let mut encoded = Vec::new();
+
+            #[cfg(feature = "multithreading")]
+            let builder = EncoderBuilder::new(96, 64).preset(preset).thread_pool(pool.clone());
+
+            #[cfg(not(feature = "multithreading"))]
+            let builder = EncoderBuilder::new(96, 64).preset(preset);
+
+            let mut encoder = builder.build(&mut encoded).unwrap();
+
+            for t in 0..4 {
+                encoder.encode_frame(&gen_frame(96, 64, t)).unwrap();
+            }
+
+            encoder.finish().unwrap();
+            drop(encoder);
+
+            assert!(decode_all(&encoded, 1).len() == 4);
+            sizes.push(encoded.len());
+        }
+
+        assert!(sizes[0] < sizes[1] && sizes[1] < sizes[2]);
+
+        // each preset's keyframe interval
+        for (preset, interval) in [(Preset::Fast, 120), (Preset::Balanced, 60), (Preset::Archival, 30)] {
+            let mut encoded = Vec::new();
+            let mut encoder = EncoderBuilder::new(16, 16).preset(preset).threads(1).build(&mut encoded).unwrap();
+
+            for t in 0..121 {
+                encoder.encode_frame(&gen_frame(16, 16, t)).unwrap();
+            }
+
+            encoder.finish().unwrap();
+            drop(encoder);
+
+            let mut decoder = new_decoder(Cursor::new(&encoded), 1).unwrap();
+            let mut keyframes = Vec::new();
+
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                if frame.frame_type == FrameType::Intra {
+                    keyframes.push(frame.index);
+                }
+            }
+
+            assert!(keyframes == (0..121).step_by(interval).collect::<Vec<u64>>(), "{:?}", preset);
+        }
+
+        // custom qtables, separate chroma quality, colorimetry, and GOP
+        let mut encoded = Vec::new();
+        let mut encoder = EncoderBuilder::new(96, 64)
+            .framerate(24)
+            .luma_quality(2)
+            .chroma_quality(8)
+            .inter_qtables([4;64], [16;64])
+            .motion_search_range(0)
+            .keyframe_interval(3)
+            .colorimetry("bt709")
+            .threads(1)
+            .build(&mut encoded).unwrap();
+
+        for t in 0..7 {
+            encoder.encode_frame(&gen_frame(96, 64, t)).unwrap();
+        }
+
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let mut decoder = new_decoder(Cursor::new(&encoded), 1).unwrap();
+        assert!(decoder.get_metadata(metadata::KEY_COLORIMETRY) == Some("bt709"));
+        assert!(decoder.timebase() == Timebase::from_framerate(24));
+
+        let mut frame_types = Vec::new();
+
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            frame_types.push(frame.frame_type);
+        }
+
+        assert!(frame_types == [FrameType::Intra, FrameType::Predicted, FrameType::Predicted, FrameType::Intra, FrameType::Predicted, FrameType::Predicted, FrameType::Intra]);
+    }
+
+    #[cfg(feature = "multithreading")]
+    #[test]
+    fn test_decoder_group() {
+        let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(3).build().unwrap());
+
+        // streams with different framerates & lengths, all encoded on the shared pool
+        let mut streams = Vec::new();
+
+        for (framerate, num_frames) in [(30, 6), (15, 4), (60, 9)] {
+            let mut encoded = Vec::new();
+            let mut encoder = Encoder::new_with_thread_pool(&mut encoded, 96, 64, framerate, 5, pool.clone()).unwrap();
+
+            for t in 0..num_frames {
+                encoder.encode_frame(&gen_frame(96, 64, t * 2 + framerate as usize)).unwrap();
+            }
+
+            encoder.finish().unwrap();
+            drop(encoder);
+
+            streams.push(encoded);
+        }
+
+        let mut group = DecoderGroup::new_with_thread_pool(pool.clone());
+        let ids: Vec<_> = streams.iter().map(|x| group.add(Cursor::new(&x[..])).unwrap()).collect();
+
+        assert!(group.len() == 3);
+        assert!(ids == [0, 1, 2]);
+
+        // advancing in steps shorter than a frame of the fastest stream must present each stream's frames in order, with the same output as decoding it alone
+        let expected: Vec<_> = streams.iter().map(|x| decode_all(x, 1)).collect();
+        let mut presented = vec![Vec::new(); 3];
+
+        while group.advance_delta(1.0 / 120.0, &mut |id, frame| {
+            presented[id].push(frame.plane_y.pixels.clone());
+        }).unwrap() {}
+
+        for id in 0..3 {
+            assert!(presented[id].len() == expected[id].len());
+            assert!(presented[id].iter().zip(&expected[id]).all(|(a, b)| *a == b.0));
+        }
+
+        // removed streams are no longer advanced, but keep their IDs reserved
+        assert!(group.remove(1).is_some());
+        assert!(group.remove(1).is_none());
+        assert!(group.decoder(1).is_none());
+        assert!(group.decoder(2).unwrap().framerate() == 60);
+        assert!(group.len() == 2);
+
+        let id = group.add(Cursor::new(&streams[1][..])).unwrap();
+        assert!(id == 3);
+
+        let mut count = 0;
+
+        while group.advance_delta(1.0 / 30.0, &mut |id, _| {
+            assert!(id == 3);
+            count += 1;
+        }).unwrap() {}
+
+        assert!(count == 4);
+    }
+
+    #[test]
+    fn test_rdo_quantization() {
+        // with no weight on bits, RDO may only reduce the error of the truncating quantizer; with a huge weight, it zeroes everything out
+        let mut state = 0x2545F491_u32;
+
+        for _ in 0..16 {
+            let mut dct = DctMatrix8x8::new();
+
+            for px in dct.m.iter_mut() {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                *px = ((state % 256) as i32 - 128) << 8;
+            }
+
+            dct.dct_transform_rows();
+            dct.dct_transform_columns();
+
+            let plain = dct.encode(&Q_TABLE_INTRA);
+            let rdo = dct.encode_rdo(&Q_TABLE_INTRA, &RdoModel::new(0.0));
+            let zeroed = dct.encode_rdo(&Q_TABLE_INTRA, &RdoModel::new(1000.0));
+
+            let err = |q: &DctQuantizedMatrix8x8| -> f64 {
+                ZIGZAG_TABLE.iter().enumerate().map(|(i, idx)| {
+                    let n = (dct.m[*idx] * DCT_SCALE_FACTOR[*idx]) as f64 / 65536.0;
+                    (n - q.m[i] as f64 * Q_TABLE_INTRA[*idx] as f64).powi(2)
+                }).sum()
+            };
+
+            assert!(err(&rdo) <= err(&plain));
+            assert!(zeroed.m.iter().all(|x| *x == 0));
+        }
+
+        // compared to plain quantization at the same quality, RDO should shrink the stream without losing quality
+        let frames: Vec<_> = (0..8).map(|t| gen_frame(128, 96, t)).collect();
+
+        let encode = |quality: i32, rdo: bool| -> (usize, f64) {
+            let mut encoded = Vec::new();
+            let mut encoder = EncoderBuilder::new(128, 96).quality(quality).keyframe_interval(4).rdo_quantization(rdo).threads(1).build(&mut encoded).unwrap();
+
+            for frame in &frames {
+                encoder.encode_frame(frame).unwrap();
+            }
+
+            encoder.finish().unwrap();
+            drop(encoder);
+
+            let decoded = decode_all(&encoded, 1);
+            assert!(decoded.len() == frames.len());
+
+            let psnr = decoded.iter().zip(&frames).map(|(a, b)| metrics::plane_psnr(&b.plane_y, &VideoPlane::from_slice(128, 96, &a.0))).sum::<f64>() / frames.len() as f64;
+            (encoded.len(), psnr)
+        };
+
+        for quality in [3, 5] {
+            let (plain_size, plain_psnr) = encode(quality, false);
+            let (rdo_size, rdo_psnr) = encode(quality, true);
+
+            println!("quality {}: plain {} bytes, {:.2} dB / RDO {} bytes, {:.2} dB", quality, plain_size, plain_psnr, rdo_size, rdo_psnr);
+
+            assert!(rdo_size < plain_size);
+            assert!(rdo_psnr >= plain_psnr);
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let reference = gen_frame(96, 64, 0);
+
+        let identical = metrics::compare(&reference, &reference.clone());
+        assert!(identical.psnr.y.is_infinite() && identical.psnr.u.is_infinite() && identical.psnr.v.is_infinite());
+        assert!((identical.ssim.average() - 1.0).abs() < 1e-9);
+        assert!((identical.ms_ssim.average() - 1.0).abs() < 1e-9);
+
+        // stronger noise must score worse on every metric
+        let add_noise = |amount: u32| -> VideoFrame {
+            let mut frame = reference.clone();
+            let mut state = 0x2545F491_u32;
+
+            for px in frame.plane_y.pixels.iter_mut().chain(frame.plane_u.pixels.iter_mut()) {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                *px = (*px as i32 + (state % (amount * 2 + 1)) as i32 - amount as i32).clamp(0, 255) as u8;
+            }
+
+            frame
+        };
+
+        let light = metrics::compare(&reference, &add_noise(4));
+        let heavy = metrics::compare(&reference, &add_noise(32));
+
+        assert!(light.psnr.y > heavy.psnr.y && light.psnr.u > heavy.psnr.u);
+        assert!(light.ssim.y > heavy.ssim.y && light.ssim.u > heavy.ssim.u);
+        assert!(light.ms_ssim.y > heavy.ms_ssim.y && light.ms_ssim.u > heavy.ms_ssim.u);
+        assert!(light.ssim.y < 1.0 && heavy.ms_ssim.y > 0.0);
+
+        // V plane was left untouched
+        assert!(light.psnr.v.is_infinite());
+
+        // uniform error of 1 on every pixel
+        let mut offset = reference.clone();
+        offset.plane_y.pixels.iter_mut().for_each(|x| *x = x.saturating_add(1));
+        let mismatched = offset.plane_y.pixels.iter().zip(&reference.plane_y.pixels).filter(|(a, b)| a != b).count();
+        let expected = 10.0 * (255.0 * 255.0 * reference.plane_y.pixels.len() as f64 / mismatched as f64).log10();
+        assert!((metrics::psnr(&reference, &offset).y - expected).abs() < 1e-9);
+
+        // encoder-side metrics must match comparing the decoded stream against the source
+        let frames: Vec<_> = (0..4).map(|t| gen_frame(96, 64, t)).collect();
+        let stats = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut encoded = Vec::new();
+        let mut encoder = EncoderBuilder::new(96, 64).quality(6).report_metrics(true).threads(1).build(&mut encoded).unwrap();
+
+        let stats_out = stats.clone();
+        encoder.set_stats_callback(move |x: &FrameStats| stats_out.lock().unwrap().push(x.clone()));
+
+        encoder.encode_iframe(&frames[0]).unwrap();
+        encoder.encode_pframe(&frames[1]).unwrap();
+        encoder.encode_dropframe().unwrap();
+        encoder.encode_pframe(&frames[2]).unwrap();
+        encoder.encode_pframe(&frames[3]).unwrap();
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let stats = stats.lock().unwrap();
+        assert!(stats.iter().map(|x| x.index).eq(0..5));
+        assert!(stats[2].metrics.is_none());
+
+        let mut decoder = new_decoder(Cursor::new(&encoded), 1).unwrap();
+        let mut source = [&frames[0], &frames[1], &frames[1], &frames[2], &frames[3]].into_iter();
+
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            let source = source.next().unwrap();
+
+            if let Some(metrics) = &stats[frame.index as usize].metrics {
+                assert!(*metrics == metrics::compare(source, frame.frame));
+                assert!(metrics.psnr.y > 25.0 && metrics.ssim.y > 0.8);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoder_stats() {
+        let stats = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 1).unwrap();
+
+        let stats_out = stats.clone();
+        encoder.set_stats_callback(move |x: &FrameStats| stats_out.lock().unwrap().push(x.clone()));
+
+        encoder.encode_iframe(&VideoFrame::new(96, 64)).unwrap();
+        encoder.encode_pframe(&VideoFrame::new(96, 64)).unwrap();
+        encoder.encode_pframe(&gen_frame(96, 64, 0)).unwrap();
+        encoder.encode_pframe(&gen_frame(96, 64, 1)).unwrap();
+        encoder.encode_dropframe().unwrap();
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let stats = stats.lock().unwrap();
+
+        assert!(stats.iter().map(|x| x.frame_type).eq([FrameType::Intra, FrameType::Predicted, FrameType::Predicted, FrameType::Predicted, FrameType::Drop]));
+        assert!(stats.iter().all(|x| x.header_bits + x.motion_bits + x.coefficient_bits == x.packet_size * 8));
+        assert!(stats.iter().all(|x| x.metrics.is_none()));
+
+        // frame packets account for everything but the header, the index packet (one I-frame), and the EOF packet
+        let header_len = new_decoder(Cursor::new(&encoded), 1).unwrap().header_len();
+        assert!(stats.iter().map(|x| x.packet_size).sum::<u64>() == encoded.len() as u64 - header_len - (5 + 24) - 5);
+
+        // 6x4 luma macroblocks + 3x2 macroblocks for each chroma plane
+        assert!(stats[0].total_blocks == 36 && stats[0].skipped_blocks == 0 && stats[0].motion_bits == 0);
+        assert!(stats[0].coefficient_bits > 0);
+
+        // an unchanged (and losslessly coded) frame skips every block
+        assert!(stats[1].total_blocks == 36 && stats[1].skipped_blocks == 36);
+        assert!(stats[1].coefficient_bits == 0 && stats[1].motion_bits == 0 && stats[1].avg_motion == 0.0);
+
+        // the gradient scrolls by 3 pixels per frame
+        assert!(stats[2].skipped_blocks < 36 && stats[2].coefficient_bits > 0);
+        assert!(stats[3].motion_bits > 0 && stats[3].motion_bits % 14 == 0 && stats[3].avg_motion > 0.0);
+
+        assert!(stats[4].total_blocks == 0 && stats[4].packet_size == 5 && stats[4].header_bits == 40);
+    }
+
+    #[test]
+    fn test_analyze() {
+        let stats = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 1).unwrap();
+
+        let stats_out = stats.clone();
+        encoder.set_stats_callback(move |x: &FrameStats| stats_out.lock().unwrap().push(x.clone()));
+
+        encoder.encode_iframe(&gen_frame(96, 64, 0)).unwrap();
+        encoder.encode_pframe(&gen_frame(96, 64, 0)).unwrap();
+        encoder.encode_pframe(&gen_frame(96, 64, 1)).unwrap();
+        encoder.encode_dropframe().unwrap();
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let stats = stats.lock().unwrap();
+        let mut analyzer = Analyzer::new(new_decoder(Cursor::new(&encoded), 1).unwrap());
+        let mut frames = Vec::new();
+
+        while let Some(frame) = analyzer.next_frame().unwrap() {
+            let overlay = analyze::visualize(analyzer.current_frame(), &frame);
+            assert!(overlay.width == 96 && overlay.height == 64);
+
+            frames.push(frame);
+        }
+
+        assert!(frames.len() == 4);
+
+        for (frame, stats) in frames.iter().zip(stats.iter()) {
+            assert!(frame.frame_type == stats.frame_type);
+            assert!(frame.blocks.len() == stats.total_blocks);
+            assert!(frame.blocks.iter().filter(|x| !x.has_coefficients).count() == stats.skipped_blocks);
+
+            // every block accounts for its motion vector & coefficients, plus two header bits per P-frame block
+            let header_bits = if frame.frame_type == FrameType::Predicted { frame.blocks.len() as u64 * 2 } else { 0 };
+            assert!(frame.block_bits() == stats.motion_bits + stats.coefficient_bits + header_bits);
+
+            let json = frame.to_json();
+            assert!(json.starts_with(&format!("{{\"index\":{},", frame.index)) && json.ends_with("]}"));
+            assert!(json.matches("\"plane\"").count() == frame.blocks.len());
+        }
+
+        // 6x4 luma blocks, then 3x2 blocks of each chroma plane
+        assert!(frames[0].blocks.iter().all(|x| x.block_type == BlockType::Intra && x.has_coefficients));
+        assert!(frames[0].blocks[7].plane == analyze::Plane::Y && frames[0].blocks[7].x == 16 && frames[0].blocks[7].y == 16);
+        assert!(frames[0].blocks[24].plane == analyze::Plane::U && frames[0].blocks[30].plane == analyze::Plane::V);
+
+        // the gradient scrolls by 3 pixels per frame
+        assert!(frames[1].count(BlockType::Skip) > 0);
+        assert!(frames[2].blocks.iter().any(|x| x.block_type == BlockType::Inter && x.motion_x != 0));
+
+        assert!(frames[3].frame_type == FrameType::Drop && frames[3].blocks.is_empty() && frames[3].payload_size == 0);
+
+        // a garbled slice is reported as concealed
+        let slice_table = slice_table_offset(&find_packets(&encoded)[0]);
+        let num_slices = u16::from_le_bytes([encoded[slice_table], encoded[slice_table + 1]]) as usize;
+        let slice_data = slice_table + 2 + (num_slices * 4);
+        let second_slice = u32::from_le_bytes([encoded[slice_table + 6], encoded[slice_table + 7], encoded[slice_table + 8], encoded[slice_table + 9]]) as usize;
+        encoded[slice_data + second_slice..slice_data + second_slice + 4].fill(0xFF);
+
+        let mut analyzer = Analyzer::new(new_decoder(Cursor::new(&encoded), 1).unwrap());
+        let frame = analyzer.next_frame().unwrap().unwrap();
+        assert!(frame.blocks.iter().enumerate().all(|(i, x)| (x.block_type == BlockType::Concealed) == (6..12).contains(&i)));
+    }
+
+    #[test]
+    fn test_validate() {
+        // a finalized stream using every packet type passes without warnings
+        let mut cursor = Cursor::new(Vec::new());
+        let mut encoder = new_encoder(&mut cursor, 96, 64, 30, 5, 1).unwrap();
+        let audio = encoder.add_audio_track(AudioFormat { codec: AudioCodec::Qoa, channels: 2, samplerate: 22050 }, "en").unwrap();
+        let subtitles = encoder.add_subtitle_track("en").unwrap();
+
+        for frame_id in 0..8 {
+            encoder.write_user_data(frame_id, &[1, 2, 3]).unwrap();
+            encoder.encode_audio_track(audio, &[0;1470]).unwrap();
+            encoder.write_subtitle(subtitles, frame_id, 1, "hello").unwrap();
+
+            if frame_id == 3 {
+                encoder.encode_dropframe().unwrap();
+            } else if frame_id % 4 == 0 {
+                encoder.encode_iframe(&gen_frame(96, 64, frame_id as usize)).unwrap();
+            } else {
+                encoder.encode_pframe(&gen_frame(96, 64, frame_id as usize)).unwrap();
+            }
+        }
+
+        encoder.finalize().unwrap();
+        drop(encoder);
+
+        let report = validate::validate(Cursor::new(cursor.get_ref()));
+        assert!(report.is_valid() && report.issues.is_empty(), "{}", report);
+        assert!(report.frames == 8);
+
+        // a simple stream without any tracks: header, then an I-frame & a P-frame
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 1).unwrap();
+        encoder.encode_iframe(&gen_frame(96, 64, 0)).unwrap();
+        encoder.encode_pframe(&gen_frame(96, 64, 1)).unwrap();
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        assert!(validate::validate(Cursor::new(&encoded)).issues.is_empty());
+
+        let packets = find_packets(&encoded);
+        let iframe = &packets[0];
+        let pframe = &packets[1];
+        let slice_data = |packet: &PacketPos| {
+            let table = slice_table_offset(packet);
+            let num_slices = u16::from_le_bytes([encoded[table], encoded[table + 1]]) as usize;
+            table + 2 + (num_slices * 4)
+        };
+
+        let expect_error = |stream: &[u8], message: &str| {
+            let report = validate::validate(Cursor::new(stream));
+            assert!(!report.is_valid());
+            assert!(report.errors().any(|x| x.message.contains(message)), "expected \"{}\":\n{}", message, report);
+        };
+
+        // odd frame size
+        let mut corrupt = encoded.clone();
+        corrupt[12] = 97;
+        expect_error(&corrupt, "invalid header");
+
+        // qtable index out of range
+        let mut corrupt = encoded.clone();
+        corrupt[iframe.payload + 16] = 200;
+        expect_error(&corrupt, "uses qtable 200");
+
+        // garbled coefficients
+        let mut corrupt = encoded.clone();
+        let start = slice_data(iframe);
+        corrupt[start..start + 8].fill(0xFF);
+        expect_error(&corrupt, "slice 0: block 0");
+
+        // first P-frame block moved one pixel to the left of the plane (has_mvec = 1, has_coeff = 0, motion_x = -1)
+        let mut corrupt = encoded.clone();
+        let start = slice_data(pframe);
+        corrupt[start] = 0xFD;
+        corrupt[start + 1] |= 1;
+        expect_error(&corrupt, "motion vector (-1");
+
+        // truncated stream
+        expect_error(&encoded[..encoded.len() - 20], "truncated");
+        assert!(validate::validate(Cursor::new(&encoded[..pframe.offset])).warnings().any(|x| x.message.contains("EOF")));
+    }
+
+    /// Decode every golden vector listed in tests/vectors/checksums.txt & compare the checksum of each decoded frame.
+    /// The vectors are codec version 2.1.1 & 3.5.0 streams - see generate_golden_vectors for what each one covers
+    #[test]
+    fn test_golden_vectors() {
+        let checksums = fs::read_to_string("tests/vectors/checksums.txt").unwrap();
+        let mut expected: Vec<(String, Vec<u64>)> = Vec::new();
+
+        for line in checksums.lines().filter(|x| !x.starts_with('#') && !x.trim().is_empty()) {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            assert!(fields.len() == 3, "malformed line: {}", line);
+
+            if expected.last().map(|x| x.0.as_str()) != Some(fields[0]) {
+                expected.push((fields[0].to_string(), Vec::new()));
+            }
+
+            let frames = &mut expected.last_mut().unwrap().1;
+            assert!(fields[1].parse::<usize>().unwrap() == frames.len());
+            frames.push(u64::from_str_radix(fields[2], 16).unwrap());
+        }
+
+        assert!(expected.len() == 9);
+        let mut versions = Vec::new();
+
+        for (name, hashes) in &expected {
+            let encoded = fs::read(Path::new("tests/vectors").join(name)).unwrap();
+
+            let report = validate::validate(Cursor::new(&encoded));
+            assert!(report.is_valid(), "{}: {}", name, report);
+
+            // single-threaded & multithreaded decoding must both be bit-exact
+            for num_threads in [1, 4] {
+                let mut decoder = new_decoder(Cursor::new(&encoded), num_threads).unwrap();
+                versions.push(decoder.version());
+
+                let mut actual = Vec::new();
+
+                while let Some(frame) = decoder.next_frame().unwrap() {
+                    actual.push(frame_checksum(frame.frame));
+                }
+
+                assert!(actual == *hashes, "{} decoded with {} threads doesn't match its checksums", name, num_threads);
+            }
+        }
+
+        assert!(versions.contains(&crate::common::PFV_VERSION_LEGACY) && versions.contains(&crate::common::PFV_VERSION));
+    }
+
+    /// Regenerate the golden vectors & their checksums (run with `cargo test generate_golden_vectors -- --ignored`).
+    /// Content is produced with the current encoder, then mostly rewritten as codec version 2.1.1 streams - only regenerate them when the decoder is known to be correct,
+    /// as the checksums are whatever it decodes
+    #[test]
+    #[ignore]
+    fn generate_golden_vectors() {
+        fn encode(width: usize, height: usize, frames: &[Option<(bool, usize)>]) -> Vec<u8> {
+            let mut encoded = Vec::new();
+            let mut encoder = new_encoder(&mut encoded, width, height, 30, 5, 1).unwrap();
+
+            for frame in frames {
+                match frame {
+                    Some((true, t)) => encoder.encode_iframe(&gen_frame(width, height, *t)).unwrap(),
+                    Some((false, t)) => encoder.encode_pframe(&gen_frame(width, height, *t)).unwrap(),
+                    None => encoder.encode_dropframe().unwrap(),
+                }
+            }
+
+            encoder.finish().unwrap();
+            drop(encoder);
+
+            encoded
+        }
+
+        // unedited streams must decode exactly like the stream they were transcoded from
+        fn transcode_unchanged(encoded: Vec<u8>) -> Vec<u8> {
+            let legacy = transcode_legacy(&encoded, &mut |_, _| {});
+            assert!(decode_all(&legacy, 1) == decode_all(&encoded, 1));
+
+            legacy
+        }
+
+        let gop = [Some((true, 0)), Some((false, 1)), Some((false, 2)), Some((false, 3)), Some((false, 4))];
+        let mut vectors = Vec::new();
+
+        // I-frames only
+        vectors.push(("intra.pfv", transcode_unchanged(encode(64, 48, &[Some((true, 0)), Some((true, 5))]))));
+
+        // P-frames with motion vectors & residual coefficients
+        vectors.push(("inter.pfv", transcode_unchanged(encode(64, 48, &gop))));
+
+        // P-frames coded purely as skipped & motion compensated blocks
+        vectors.push(("inter_no_coeff.pfv", transcode_legacy(&encode(64, 48, &gop), &mut |_, blocks| {
+            for block in blocks {
+                block.coeff = None;
+            }
+        })));
+
+        // drop frames between I-frames & P-frames
+        vectors.push(("drop.pfv", transcode_unchanged(encode(64, 48, &[Some((true, 0)), None, Some((false, 2)), None, None, Some((false, 5))]))));
+
+        // sizes which aren't a multiple of the macroblock size (& odd chroma plane sizes)
+        vectors.push(("odd_size.pfv", transcode_unchanged(encode(90, 58, &gop[..3]))));
+        vectors.push(("tiny.pfv", transcode_unchanged(encode(10, 6, &[Some((true, 0)), Some((false, 4)), Some((false, 8))]))));
+
+        // motion vectors reaching as far as the 7-bit range & the plane edges allow: towards the far edge without & with residuals, then towards the near edge
+        vectors.push(("extreme_mv.pfv", transcode_legacy(&encode(160, 128, &gop[..4]), &mut |frame, blocks| {
+            for block in blocks {
+                let reach = |pos: usize, size: usize| {
+                    let back = -(pos.min(64) as i8);
+                    let forward = (size - 16 - pos).min(63) as i8;
+
+                    if (pos >= size - 16 - pos) == (frame < 3) { back } else { forward }
+                };
+
+                block.motion = (reach(block.x, block.plane_width), reach(block.y, block.plane_height));
+
+                if frame == 1 {
+                    block.coeff = None;
+                }
+            }
+        })));
+
+        // codec version 3.5.0 streams: one slice per macroblock row, with audio, subtitle & user data packets interleaved with the frames
+        vectors.push(("slices.pfv", {
+            let mut encoded = Vec::new();
+            let mut encoder = new_encoder_with_timebase(&mut encoded, 96, 64, Timebase { num: 1, den: 1000 }, true, 5, 1).unwrap();
+            let audio = encoder.add_audio_track(AudioFormat { codec: AudioCodec::Pcm, channels: 1, samplerate: 8000 }, "en").unwrap();
+            let subs = encoder.add_subtitle_track("en").unwrap();
+            encoder.write_metadata(metadata::KEY_TITLE, "slices").unwrap();
+
+            for (frame_id, pts) in [0, 40, 100, 110, 250, 290].iter().enumerate() {
+                encoder.set_frame_pts(*pts).unwrap();
+                encoder.encode_audio_track(audio, &[frame_id as i16 * 100;320]).unwrap();
+                encoder.write_subtitle(subs, *pts, 40, &format!("Line {}", frame_id)).unwrap();
+                encoder.write_user_data(*pts, &[frame_id as u8;4]).unwrap();
+
+                match frame_id {
+                    0 => encoder.encode_iframe(&gen_frame(96, 64, 0)).unwrap(),
+                    3 => encoder.encode_dropframe().unwrap(),
+                    _ => encoder.encode_pframe(&gen_frame(96, 64, frame_id)).unwrap(),
+                }
+            }
+
+            encoder.finish().unwrap();
+            drop(encoder);
+
+            encoded
+        }));
+        vectors.push(("slices_odd_size.pfv", encode(90, 58, &gop[..3])));
+
+        let mut checksums = String::from("# <vector> <frame index> <FNV-1a 64 hash of the decoded frame: Y plane, then U, then V, each cropped to the frame size>\n");
+
+        for (name, encoded) in &vectors {
+            fs::write(Path::new("tests/vectors").join(name), encoded).unwrap();
+
+            let mut decoder = new_decoder(Cursor::new(encoded), 1).unwrap();
+            let mut index = 0;
+            let mut prev_hash = None;
+
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                let hash = frame_checksum(frame.frame);
+
+                // every coded frame must actually change the picture, or the vector doesn't cover what it claims to
+                assert!(frame.frame_type == FrameType::Drop || prev_hash != Some(hash), "{}: frame {} decodes identically to the previous frame", name, index);
+
+                checksums.push_str(&format!("{} {} {:016x}\n", name, index, hash));
+                prev_hash = Some(hash);
+                index += 1;
+            }
+        }
+
+        fs::write("tests/vectors/checksums.txt", checksums).unwrap();
+    }
+
+    #[test]
+    fn test_slice_concealment() {
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+        encoder.encode_iframe(&gen_frame(96, 64, 0)).unwrap();
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let clean = decode_all(&encoded, 4);
+
+        let slice_table = slice_table_offset(&find_packets(&encoded)[0]);
+        let num_slices = u16::from_le_bytes([encoded[slice_table], encoded[slice_table + 1]]) as usize;
+        let slice_data = slice_table + 2 + (num_slices * 4);
+        let read_offset = |idx: usize| {
+            let o = slice_table + 2 + (idx * 4);
+            u32::from_le_bytes([encoded[o], encoded[o + 1], encoded[o + 2], encoded[o + 3]]) as usize
+        };
+
+        // 4 rows of luma blocks followed by 2 rows each of U and V blocks
+        assert!(num_slices == 8);
+
+        // garble the second row of luma blocks
+        let start = slice_data + read_offset(1);
+        let end = slice_data + read_offset(2);
+        let mut corrupt = encoded.clone();
+        corrupt[start..end].fill(0xFF);
+
+        let damaged = decode_all(&corrupt, 4);
+        assert!(damaged.len() == 1);
+
+        // every other row of macroblocks must decode exactly as before
+        let (clean_y, damaged_y) = (&clean[0].0, &damaged[0].0);
+        assert!(clean_y[..(96 * 16)] == damaged_y[..(96 * 16)]);
+        assert!(clean_y[(96 * 32)..] == damaged_y[(96 * 32)..]);
+        assert!(clean[0].1 == damaged[0].1);
+        assert!(clean[0].2 == damaged[0].2);
+    }
+
+    #[test]
+    fn test_single_symbol_table() {
+        // a table with a single symbol 0 decodes empty runs without consuming any bits
+        let mut table = [0;16];
+        table[0] = 255;
+        let tree = crate::huffman::HuffmanTree::from_table(&table);
+
+        let data = [0xFF;8];
+        let mut coeff = [0;256];
+        let mut bitreader = BitReader::new(&data);
+        assert!(crate::slice::read_rle(&tree, &mut bitreader, data.len() as u64 * 8, &mut coeff).is_err());
+
+        // in a frame, every slice is concealed instead of the decoder hanging
+        let mut encoded = Vec::new();
+        let mut encoder = new_encoder(&mut encoded, 96, 64, 30, 5, 4).unwrap();
+        encoder.encode_iframe(&gen_frame(96, 64, 0)).unwrap();
+        encoder.encode_iframe(&gen_frame(96, 64, 5)).unwrap();
+        encoder.finish().unwrap();
+        drop(encoder);
+
+        let clean = decode_all(&encoded, 4);
+        assert!(clean[0] != clean[1]);
+
+        let mut corrupt = encoded.clone();
+        let iframe = &find_packets(&encoded)[1];
+        corrupt[iframe.payload..(iframe.payload + 16)].copy_from_slice(&table);
+
+        for num_threads in [1, 4] {
+            let damaged = decode_all(&corrupt, num_threads);
+            assert!(damaged.len() == 2);
+            assert!(damaged[0] == clean[0] && damaged[1] == clean[0]);
+        }
+    }
+
+    #[test]
+    fn test_simd_dct() {
+        let mut rng = TestRng(0x1234_5678);
+
+        for _ in 0..10000 {
+            let mut src = DctMatrix8x8::new();
+
+            // cover the range of values seen by both the forward transform (pixels) and the inverse transform (dequantized coefficients)
+            let range = if rng.next().is_multiple_of(2) { 255 << FP_BITS } else { 1 << 20 };
+            for v in src.m.iter_mut() {
+                *v = (rng.next() % (range * 2)) as i32 - range as i32;
+            }
+
+            let mut a = src;
+            let mut b = src;
+            a.dct_transform_rows();
+            a.dct_transform_columns();
+            b.dct_transform_rows_scalar();
+            b.dct_transform_columns_scalar();
+            assert!(a.m == b.m);
+
+            let mut a = src;
+            let mut b = src;
+            a.dct_inverse_transform_columns();
+            a.dct_inverse_transform_rows();
+            b.dct_inverse_transform_columns_scalar();
+            b.dct_inverse_transform_rows_scalar();
+            assert!(a.m == b.m);
+
+            // the AVX2 path is preferred when available, so make sure the SSE2 path gets exercised too
+            #[cfg(target_arch = "x86_64")]
+            {
+                let mut a = src;
+                let mut b = src;
+                unsafe {
+                    simd::x86::transform_sse2(&mut a.m, true, false);
+                    simd::x86::transform_sse2(&mut a.m, false, false);
+                }
+                b.dct_transform_rows_scalar();
+                b.dct_transform_columns_scalar();
+                assert!(a.m == b.m);
+
+                let mut a = src;
+                let mut b = src;
+                unsafe {
+                    simd::x86::transform_sse2(&mut a.m, false, true);
+                    simd::x86::transform_sse2(&mut a.m, true, true);
+                }
+                b.dct_inverse_transform_columns_scalar();
+                b.dct_inverse_transform_rows_scalar();
+                assert!(a.m == b.m);
+            }
+        }
+    }
+
+    #[test]
+    fn test_simd_pixels() {
+        let mut rng = TestRng(0x8765_4321);
+
+        for _ in 0..1000 {
+            let mut residuals = MacroBlock::new();
+            let mut reference = MacroBlock::new();
+
+            for (r, p) in residuals.pixels.iter_mut().zip(reference.pixels.iter_mut()) {
+                *r = rng.next() as u8;
+                *p = rng.next() as u8;
+            }
+
+            let mut a = MacroBlock { pixels: residuals.pixels };
+            let mut b = MacroBlock { pixels: residuals.pixels };
+            a.apply_residuals(&reference);
+            b.apply_residuals_scalar(&reference);
+            assert!(a.pixels == b.pixels);
+
+            // sums below the early-out threshold must be exact, anything else must at least reach the threshold
+            let plane_a = VideoPlane::from_slice(16, 16, &residuals.pixels);
+            let plane_b = VideoPlane::from_slice(16, 16, &reference.pixels);
+            let full = VideoPlane::calc_error_scalar(&plane_a, &plane_b, u64::MAX);
+            assert!(VideoPlane::calc_error(&plane_a, &plane_b, u64::MAX) == full);
+
+            let threshold = rng.next() % 5_000_000;
+            let err_a = VideoPlane::calc_error(&plane_a, &plane_b, threshold);
+            let err_b = VideoPlane::calc_error_scalar(&plane_a, &plane_b, threshold);
+            assert!((err_a < threshold) == (err_b < threshold));
+            assert!(err_b >= threshold || err_a == err_b);
+
+            // blits
+            let mut plane = VideoPlane::new(48, 48);
+            plane.blit_block(&reference, 16, 32);
+            assert!(plane.get_block(16, 32).pixels == reference.pixels);
+
+            let mut dst = VideoPlane::new(40, 40);
+            dst.blit(&plane, 3, 5, 10, 30, 21, 9);
+            for row in 0..9 {
+                assert!(dst.pixels[((row + 5) * 40 + 3)..((row + 5) * 40 + 24)] == plane.pixels[((row + 30) * 48 + 10)..((row + 30) * 48 + 31)]);
+            }
+        }
+    }
+
+    /// Global allocator which counts allocations made while counting is enabled, on any thread which has opted in with track_allocations
+    /// (the test thread itself, and the worker threads of the pool under test). Other tests run concurrently, so untracked threads aren't counted
+    struct CountingAllocator;
+
+    static COUNT_ALLOCATIONS: AtomicBool = AtomicBool::new(false);
+    static NUM_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    thread_local! {
+        static TRACK_ALLOCATIONS: Cell<bool> = const { Cell::new(false) };
+    }
+
+    impl CountingAllocator {
+        fn record() {
+            if COUNT_ALLOCATIONS.load(Ordering::SeqCst) && TRACK_ALLOCATIONS.try_with(|x| x.get()).unwrap_or(false) {
+                NUM_ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            CountingAllocator::record();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            CountingAllocator::record();
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Count allocations made by the current thread from now on
+    fn track_allocations() {
+        TRACK_ALLOCATIONS.with(|x| x.set(true));
+    }
+
+    /// Count allocations made by tracked threads (including the current one) while running f. Only one test may count at a time
+    fn count_allocations<F: FnOnce()>(f: F) -> usize {
+        static COUNTING: Mutex<()> = Mutex::new(());
+        let _lock = COUNTING.lock().unwrap();
+
+        track_allocations();
+        NUM_ALLOCATIONS.store(0, Ordering::SeqCst);
+        COUNT_ALLOCATIONS.store(true, Ordering::SeqCst);
+        f();
+        COUNT_ALLOCATIONS.store(false, Ordering::SeqCst);
+        NUM_ALLOCATIONS.load(Ordering::SeqCst)
+    }
+
+    /// Small xorshift generator so tests are reproducible without pulling in a dependency
+    struct TestRng(u64);
+
+    impl TestRng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 >> 16
+        }
+    }
+
+    /// FNV-1a 64 hash of a byte sequence
+    fn fnv1a<I: IntoIterator<Item = impl std::borrow::Borrow<u8>>>(bytes: I) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+
+        for b in bytes {
+            hash ^= *b.borrow() as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+
+        hash
+    }
+
+    /// FNV-1a 64 hash of a decoded frame's Y, U, and V planes
+    fn frame_checksum(frame: &VideoFrame) -> u64 {
+        fnv1a(frame.plane_y.pixels.iter().chain(&frame.plane_u.pixels).chain(&frame.plane_v.pixels))
+    }
+
+    /// A macroblock of a frame being rewritten by transcode_legacy
+    struct LegacyBlock {
+        x: usize,
+        y: usize,
+        plane_width: usize,
+        plane_height: usize,
+        motion: (i8, i8),
+        coeff: Option<[i16;256]>,
+    }
+
+    /// Rewrite a stream produced by the current encoder as a codec version 2.1.1 stream: a header without timebase, flags, metadata, or tracks,
+    /// and frames coded as a single bitstream instead of slices. edit is called with the index & blocks of each P-frame before it is written
+    fn transcode_legacy(encoded: &[u8], edit: &mut dyn FnMut(u64, &mut [LegacyBlock])) -> Vec<u8> {
+        let mut decoder = new_decoder(Cursor::new(encoded), 1).unwrap();
+
+        let mut out = Vec::new();
+        out.extend_from_slice(crate::common::PFV_MAGIC);
+        out.extend_from_slice(&211_u32.to_le_bytes());
+        out.extend_from_slice(&(decoder.width() as u16).to_le_bytes());
+        out.extend_from_slice(&(decoder.height() as u16).to_le_bytes());
+        out.extend_from_slice(&(decoder.framerate() as u16).to_le_bytes());
+        out.extend_from_slice(&(decoder.qtables().len() as u16).to_le_bytes());
+
+        for qtable in decoder.qtables() {
+            for q in qtable {
+                out.extend_from_slice(&(*q as u16).to_le_bytes());
+            }
+        }
+
+        let plane_blocks = decoder.plane_blocks();
+        let positions = analyze::block_positions(plane_blocks);
+        let mut slice_ranges = Vec::new();
+        let mut frame_index = 0;
+
+        while let Some(packet) = decoder.read_raw_packet().unwrap() {
+            let payload = decoder.last_packet();
+
+            if packet.packet_type != crate::common::PFV_PACKET_IFRAME && packet.packet_type != crate::common::PFV_PACKET_PFRAME {
+                continue;
+            }
+
+            frame_index += 1;
+
+            if payload.is_empty() {
+                // drop frame
+                out.push(packet.packet_type);
+                out.extend_from_slice(&0_u32.to_le_bytes());
+                continue;
+            }
+
+            let is_iframe = packet.packet_type == crate::common::PFV_PACKET_IFRAME;
+            let tree = crate::huffman::HuffmanTree::from_table(payload[0..16].try_into().unwrap());
+            decoder.last_packet_slices(&mut slice_ranges).unwrap();
+
+            let mut blocks: Vec<_> = positions.iter().map(|(plane, x, y)| {
+                let (blocks_wide, blocks_high) = match plane {
+                    analyze::Plane::Y => plane_blocks[0],
+                    analyze::Plane::U => plane_blocks[1],
+                    analyze::Plane::V => plane_blocks[2],
+                };
+
+                LegacyBlock { x: *x, y: *y, plane_width: blocks_wide * 16, plane_height: blocks_high * 16, motion: (0, 0), coeff: None }
+            }).collect();
+
+            let mut blocks_remaining = &mut blocks[..];
+
+            for (num_blocks, range) in decoder.slice_sizes().into_iter().zip(&slice_ranges) {
+                let (slice_blocks, next_blocks) = blocks_remaining.split_at_mut(num_blocks);
+                blocks_remaining = next_blocks;
+
+                let data = &payload[range.clone()];
+                let mut bitreader = BitReader::new(data);
+
+                if !is_iframe {
+                    for block in slice_blocks.iter_mut() {
+                        let has_mvec = bitreader.read_bit().unwrap();
+                        block.coeff = if bitreader.read_bit().unwrap() { Some([0;256]) } else { None };
+
+                        if has_mvec {
+                            block.motion = (bitreader.read_signed(7).unwrap() as i8, bitreader.read_signed(7).unwrap() as i8);
+                        }
+                    }
+                }
+
+                for block in slice_blocks.iter_mut().filter(|x| is_iframe || x.coeff.is_some()) {
+                    let mut coeff = [0;256];
+                    crate::slice::read_rle(&tree, &mut bitreader, data.len() as u64 * 8, &mut coeff).unwrap();
+                    block.coeff = Some(coeff);
+                }
+            }
+
+            if !is_iframe {
+                edit(frame_index - 1, &mut blocks);
+            }
+
+            // huffman table & qtable indices are unchanged, followed by one bitstream covering every block
+            let mut frame = payload[0..19].to_vec();
+            let mut bitwriter = BitWriter::endian(&mut frame, bitstream_io::LittleEndian);
+
+            if !is_iframe {
+                for block in &blocks {
+                    let has_mvec = block.motion != (0, 0);
+
+                    bitwriter.write_bit(has_mvec).unwrap();
+                    bitwriter.write_bit(block.coeff.is_some()).unwrap();
+
+                    if has_mvec {
+                        bitwriter.write_signed(7, block.motion.0).unwrap();
+                        bitwriter.write_signed(7, block.motion.1).unwrap();
+                    }
+                }
+            }
+
+            for coeff in blocks.iter().filter_map(|x| x.coeff.as_ref()) {
+                let mut sequence = Vec::new();
+                rle::rle_encode(&mut sequence, coeff);
+                crate::slice::write_rle(&sequence, &tree, &mut bitwriter).unwrap();
+            }
+
+            bitwriter.byte_align().unwrap();
+
+            out.push(packet.packet_type);
+            out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            out.extend_from_slice(&frame);
+        }
+
+        // EOF packet
+        out.push(crate::common::PFV_PACKET_EOF);
+        out.extend_from_slice(&0_u32.to_le_bytes());
+
+        out
+    }
+
+    // constructors taking a thread count, which is ignored in builds without multithreading
+
+    fn new_decoder<R: io::Source>(reader: R, num_threads: usize) -> Result<Decoder<R>, DecodeError> {
+        #[cfg(feature = "multithreading")]
+        {
+            Decoder::new(reader, num_threads)
+        }
+
+        #[cfg(not(feature = "multithreading"))]
+        {
+            let _ = num_threads;
+            Decoder::new(reader)
+        }
+    }
+
+    fn new_stream_decoder(num_threads: usize) -> StreamDecoder {
+        #[cfg(feature = "multithreading")]
+        {
+            StreamDecoder::new(num_threads)
+        }
+
+        #[cfg(not(feature = "multithreading"))]
+        {
+            let _ = num_threads;
+            StreamDecoder::new()
+        }
+    }
+
+    fn new_encoder<W: std::io::Write>(writer: W, width: usize, height: usize, framerate: u32, quality: i32, num_threads: usize) -> Result<Encoder<W>, EncodeError> {
+        #[cfg(feature = "multithreading")]
+        {
+            Encoder::new(writer, width, height, framerate, quality, num_threads)
+        }
+
+        #[cfg(not(feature = "multithreading"))]
+        {
+            let _ = num_threads;
+            Encoder::new(writer, width, height, framerate, quality)
+        }
+    }
+
+    fn new_encoder_with_timebase<W: std::io::Write>(writer: W, width: usize, height: usize, timebase: Timebase, variable_framerate: bool, quality: i32, num_threads: usize) -> Result<Encoder<W>, EncodeError> {
+        #[cfg(feature = "multithreading")]
+        {
+            Encoder::new_with_timebase(writer, width, height, timebase, variable_framerate, quality, num_threads)
+        }
+
+        #[cfg(not(feature = "multithreading"))]
+        {
+            let _ = num_threads;
+            Encoder::new_with_timebase(writer, width, height, timebase, variable_framerate, quality)
+        }
+    }
+
+    trait BuilderThreads {
+        fn threads(self, num_threads: usize) -> Self;
+    }
+
+    impl BuilderThreads for EncoderBuilder {
+        fn threads(self, num_threads: usize) -> EncoderBuilder {
+            #[cfg(feature = "multithreading")]
+            {
+                self.num_threads(num_threads)
+            }
+
+            #[cfg(not(feature = "multithreading"))]
+            {
+                let _ = num_threads;
+                self
+            }
+        }
+    }
+
+    /// A packet located by walking the packet headers of a stream
+    struct PacketPos {
+        packet_type: u8,
+        /// Offset of the packet header
+        offset: usize,
+        /// Offset of the payload, following the packet header (and the timestamp of frame packets in variable framerate streams)
+        payload: usize,
+        len: usize,
+    }
+
+    /// Walk the packet headers following the stream header
+    fn find_packets(encoded: &[u8]) -> Vec<PacketPos> {
+        let decoder = new_decoder(Cursor::new(encoded), 1).unwrap();
+        let has_timestamps = decoder.is_variable_framerate();
+
+        let mut packets = Vec::new();
+        let mut offset = decoder.header_len() as usize;
+
+        while offset < encoded.len() {
+            let packet_type = encoded[offset];
+            let len = u32::from_le_bytes(encoded[(offset + 1)..(offset + 5)].try_into().unwrap()) as usize;
+            let is_frame = packet_type == crate::common::PFV_PACKET_IFRAME || packet_type == crate::common::PFV_PACKET_PFRAME;
+            let payload = offset + if has_timestamps && is_frame { 13 } else { 5 };
+
+            packets.push(PacketPos { packet_type, offset, payload, len });
+            offset = payload + len;
+        }
+
+        packets
+    }
+
+    /// Offset of the slice table of a frame packet, which follows the 16 byte symbol frequency table & the qtable index of each plane
+    fn slice_table_offset(packet: &PacketPos) -> usize {
+        packet.payload + 19
+    }
+
+    fn decode_all(encoded: &[u8], num_threads: usize) -> Vec<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+        let mut decoder = new_decoder(Cursor::new(encoded), num_threads).unwrap();
+        let mut frames = Vec::new();
+
+        while decoder.advance_frame(&mut |frame| {
+            frames.push((frame.plane_y.pixels.clone(), frame.plane_u.pixels.clone(), frame.plane_v.pixels.clone()));
+        }).unwrap() {}
+
+        frames
+    }
+
+    fn gen_frame(width: usize, height: usize, t: usize) -> VideoFrame {
+        let mut frame = VideoFrame::new(width, height);
+
+        // scrolling diagonal gradient with a few hard edges so that both smooth areas and detail get coded
+        for y in 0..height {
+            for x in 0..width {
+                let px = ((x + (t * 3)) * 4 + y * 2) as u8;
+                frame.plane_y.pixels[x + (y * width)] = if (x / 12 + y / 12) % 3 == 0 { px / 2 } else { px };
+            }
+        }
+
+        for y in 0..(height / 2) {
+            for x in 0..(width / 2) {
+                frame.plane_u.pixels[x + (y * width / 2)] = (96 + x + t) as u8;
+                frame.plane_v.pixels[x + (y * width / 2)] = (160 - y) as u8;
+            }
+        }
+
+        frame
+    }
+
     fn load_frame<Q: AsRef<Path>>(path: Q) -> VideoFrame {
         let src_img = ImageReader::open(path).unwrap().decode().unwrap().into_rgb8();
         
@@ -0,0 +1,29 @@
+//! Container metadata & user data.
+//!
+//! Streams may carry a set of key/value metadata entries in their header, plus timestamped user data packets
+//! (subtitle cues, gameplay events, chapter markers, etc) interleaved with video frames.
+//! Keys are free-form UTF-8 strings - the constants below are conventional names for common entries.
+
+use alloc::vec::Vec;
+
+/// Title of the video
+pub const KEY_TITLE: &str = "title";
+
+/// Where the video came from (original file name, URL, etc)
+pub const KEY_SOURCE: &str = "source";
+
+/// Name & version of the software which encoded the video
+pub const KEY_ENCODER: &str = "encoder";
+
+/// Colorimetry of the source material (for example "bt709" or "bt601")
+pub const KEY_COLORIMETRY: &str = "colorimetry";
+
+/// A user data packet read from a stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserData {
+    /// Timestamp in timebase ticks
+    pub pts_ticks: u64,
+    /// Timestamp in seconds
+    pub pts: f64,
+    pub data: Vec<u8>,
+}
@@ -0,0 +1,295 @@
+//! Objective quality metrics (PSNR, SSIM, MS-SSIM) between two frames, computed separately for each plane.
+//!
+//! SSIM uses the usual 11x11 Gaussian window (sigma = 1.5). Planes smaller than the window are compared as a whole.
+//! MS-SSIM uses up to five scales, stopping early (and renormalizing the scale weights) once a plane gets smaller than the window.
+
+use crate::{frame::VideoFrame, plane::VideoPlane};
+
+const SSIM_WINDOW: usize = 11;
+const SSIM_SIGMA: f64 = 1.5;
+const SSIM_C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// Weight of each scale in MS-SSIM, from full resolution down
+const MS_SSIM_WEIGHTS: [f64;5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+/// A metric computed for each plane of a frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlaneMetrics {
+    pub y: f64,
+    pub u: f64,
+    pub v: f64,
+}
+
+impl PlaneMetrics {
+    /// Average over all three planes, weighted by their pixel count (so luma counts 4x as much as each chroma plane)
+    pub fn average(self: &PlaneMetrics) -> f64 {
+        (self.y * 4.0 + self.u + self.v) / 6.0
+    }
+}
+
+/// PSNR, SSIM, and MS-SSIM of a frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameMetrics {
+    pub psnr: PlaneMetrics,
+    pub ssim: PlaneMetrics,
+    pub ms_ssim: PlaneMetrics,
+}
+
+/// Peak signal-to-noise ratio in dB of each plane. Identical planes have an infinite PSNR.
+/// Panics if the frames have different dimensions
+pub fn psnr(reference: &VideoFrame, distorted: &VideoFrame) -> PlaneMetrics {
+    assert_same_size(reference, distorted);
+    compare_planes(reference, distorted, region_psnr)
+}
+
+/// Structural similarity of each plane, from -1 to 1 (identical). Panics if the frames have different dimensions
+pub fn ssim(reference: &VideoFrame, distorted: &VideoFrame) -> PlaneMetrics {
+    assert_same_size(reference, distorted);
+    compare_planes(reference, distorted, region_ssim)
+}
+
+/// Multi-scale structural similarity of each plane, from 0 to 1 (identical). Panics if the frames have different dimensions
+pub fn ms_ssim(reference: &VideoFrame, distorted: &VideoFrame) -> PlaneMetrics {
+    assert_same_size(reference, distorted);
+    compare_planes(reference, distorted, region_ms_ssim)
+}
+
+/// PSNR, SSIM, and MS-SSIM of each plane. Panics if the frames have different dimensions
+pub fn compare(reference: &VideoFrame, distorted: &VideoFrame) -> FrameMetrics {
+    assert_same_size(reference, distorted);
+    compare_cropped(reference, distorted)
+}
+
+/// Like compare, but the distorted frame's planes may be larger than the reference (such as the encoder's padded reconstruction), in which case only the top left area is compared
+pub(crate) fn compare_cropped(reference: &VideoFrame, distorted: &VideoFrame) -> FrameMetrics {
+    FrameMetrics {
+        psnr: compare_planes(reference, distorted, region_psnr),
+        ssim: compare_planes(reference, distorted, region_ssim),
+        ms_ssim: compare_planes(reference, distorted, region_ms_ssim),
+    }
+}
+
+/// PSNR in dB of a single plane. Panics if the planes have different dimensions
+pub fn plane_psnr(reference: &VideoPlane, distorted: &VideoPlane) -> f64 {
+    assert!(reference.width == distorted.width && reference.height == distorted.height);
+    region_psnr(reference, distorted)
+}
+
+/// SSIM of a single plane. Panics if the planes have different dimensions
+pub fn plane_ssim(reference: &VideoPlane, distorted: &VideoPlane) -> f64 {
+    assert!(reference.width == distorted.width && reference.height == distorted.height);
+    region_ssim(reference, distorted)
+}
+
+/// MS-SSIM of a single plane. Panics if the planes have different dimensions
+pub fn plane_ms_ssim(reference: &VideoPlane, distorted: &VideoPlane) -> f64 {
+    assert!(reference.width == distorted.width && reference.height == distorted.height);
+    region_ms_ssim(reference, distorted)
+}
+
+fn assert_same_size(reference: &VideoFrame, distorted: &VideoFrame) {
+    assert!(reference.plane_y.width == distorted.plane_y.width && reference.plane_y.height == distorted.plane_y.height);
+    assert!(reference.plane_u.width == distorted.plane_u.width && reference.plane_u.height == distorted.plane_u.height);
+    assert!(reference.plane_v.width == distorted.plane_v.width && reference.plane_v.height == distorted.plane_v.height);
+}
+
+fn compare_planes<F: Fn(&VideoPlane, &VideoPlane) -> f64>(reference: &VideoFrame, distorted: &VideoFrame, metric: F) -> PlaneMetrics {
+    PlaneMetrics {
+        y: metric(&reference.plane_y, &distorted.plane_y),
+        u: metric(&reference.plane_u, &distorted.plane_u),
+        v: metric(&reference.plane_v, &distorted.plane_v),
+    }
+}
+
+/// Pixels of a plane as floating point values
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<f64>,
+}
+
+impl Image {
+    /// Copy the reference plane's area out of a plane (which may be larger than the reference)
+    fn from_plane(plane: &VideoPlane, width: usize, height: usize) -> Image {
+        debug_assert!(plane.width >= width && plane.height >= height);
+
+        let mut pixels = Vec::with_capacity(width * height);
+
+        for row in 0..height {
+            let offset = row * plane.width;
+            pixels.extend(plane.pixels[offset..(offset + width)].iter().map(|x| *x as f64));
+        }
+
+        Image { width, height, pixels }
+    }
+
+    /// Halve the resolution, averaging each 2x2 block
+    fn downsample(self: &Image) -> Image {
+        let width = self.width / 2;
+        let height = self.height / 2;
+        let mut pixels = Vec::with_capacity(width * height);
+
+        for y in 0..height {
+            for x in 0..width {
+                let idx = (x * 2) + (y * 2 * self.width);
+                pixels.push((self.pixels[idx] + self.pixels[idx + 1] + self.pixels[idx + self.width] + self.pixels[idx + self.width + 1]) * 0.25);
+            }
+        }
+
+        Image { width, height, pixels }
+    }
+
+    /// Filter with a separable kernel, keeping only the area where the kernel fits entirely
+    fn filter(self: &Image, kernel: &[f64]) -> Image {
+        let width = self.width - kernel.len() + 1;
+        let height = self.height - kernel.len() + 1;
+
+        let mut rows = vec![0.0;width * self.height];
+
+        for y in 0..self.height {
+            for x in 0..width {
+                let src = &self.pixels[(x + (y * self.width))..];
+                rows[x + (y * width)] = kernel.iter().enumerate().map(|(i, k)| src[i] * k).sum();
+            }
+        }
+
+        let mut pixels = vec![0.0;width * height];
+
+        for y in 0..height {
+            for x in 0..width {
+                pixels[x + (y * width)] = kernel.iter().enumerate().map(|(i, k)| rows[x + ((y + i) * width)] * k).sum();
+            }
+        }
+
+        Image { width, height, pixels }
+    }
+
+    fn map2<F: Fn(f64, f64) -> f64>(self: &Image, other: &Image, f: F) -> Image {
+        Image { width: self.width, height: self.height, pixels: self.pixels.iter().zip(&other.pixels).map(|(a, b)| f(*a, *b)).collect() }
+    }
+}
+
+fn region_psnr(reference: &VideoPlane, distorted: &VideoPlane) -> f64 {
+    let mut sum = 0.0;
+
+    for row in 0..reference.height {
+        let a = &reference.pixels[(row * reference.width)..((row + 1) * reference.width)];
+        let b = &distorted.pixels[(row * distorted.width)..((row * distorted.width) + reference.width)];
+
+        sum += a.iter().zip(b).map(|(x, y)| (*x as f64 - *y as f64) * (*x as f64 - *y as f64)).sum::<f64>();
+    }
+
+    let mse = sum / (reference.width * reference.height) as f64;
+
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+}
+
+fn region_ssim(reference: &VideoPlane, distorted: &VideoPlane) -> f64 {
+    let a = Image::from_plane(reference, reference.width, reference.height);
+    let b = Image::from_plane(distorted, reference.width, reference.height);
+
+    ssim_components(&a, &b).0
+}
+
+fn region_ms_ssim(reference: &VideoPlane, distorted: &VideoPlane) -> f64 {
+    let mut a = Image::from_plane(reference, reference.width, reference.height);
+    let mut b = Image::from_plane(distorted, reference.width, reference.height);
+
+    // use as many scales as the plane size allows
+    let mut num_scales = 1;
+
+    while num_scales < MS_SSIM_WEIGHTS.len() && (a.width >> num_scales) >= SSIM_WINDOW && (a.height >> num_scales) >= SSIM_WINDOW {
+        num_scales += 1;
+    }
+
+    let weight_sum: f64 = MS_SSIM_WEIGHTS[..num_scales].iter().sum();
+    let mut result = 1.0;
+
+    for (scale, scale_weight) in MS_SSIM_WEIGHTS[..num_scales].iter().enumerate() {
+        let (ssim, cs) = ssim_components(&a, &b);
+        let weight = scale_weight / weight_sum;
+
+        // only the coarsest scale includes the luminance term
+        let value = if scale == num_scales - 1 { ssim } else { cs };
+        result *= value.max(0.0).powf(weight);
+
+        if scale < num_scales - 1 {
+            a = a.downsample();
+            b = b.downsample();
+        }
+    }
+
+    result
+}
+
+/// Mean SSIM and mean contrast-structure term of two images of the same size
+fn ssim_components(a: &Image, b: &Image) -> (f64, f64) {
+    if a.width == 0 || a.height == 0 {
+        return (1.0, 1.0);
+    }
+
+    // too small for the window: compare the whole image at once
+    if a.width < SSIM_WINDOW || a.height < SSIM_WINDOW {
+        return global_ssim(a, b);
+    }
+
+    let kernel = gaussian_kernel();
+
+    let mu_a = a.filter(&kernel);
+    let mu_b = b.filter(&kernel);
+    let sq_a = a.map2(a, |x, _| x * x).filter(&kernel);
+    let sq_b = b.map2(b, |x, _| x * x).filter(&kernel);
+    let prod = a.map2(b, |x, y| x * y).filter(&kernel);
+
+    let mut ssim_sum = 0.0;
+    let mut cs_sum = 0.0;
+
+    for i in 0..mu_a.pixels.len() {
+        let (ssim, cs) = ssim_terms(mu_a.pixels[i], mu_b.pixels[i], sq_a.pixels[i], sq_b.pixels[i], prod.pixels[i]);
+        ssim_sum += ssim;
+        cs_sum += cs;
+    }
+
+    let count = mu_a.pixels.len() as f64;
+    (ssim_sum / count, cs_sum / count)
+}
+
+fn global_ssim(a: &Image, b: &Image) -> (f64, f64) {
+    let count = a.pixels.len() as f64;
+
+    let mu_a = a.pixels.iter().sum::<f64>() / count;
+    let mu_b = b.pixels.iter().sum::<f64>() / count;
+    let sq_a = a.pixels.iter().map(|x| x * x).sum::<f64>() / count;
+    let sq_b = b.pixels.iter().map(|x| x * x).sum::<f64>() / count;
+    let prod = a.pixels.iter().zip(&b.pixels).map(|(x, y)| x * y).sum::<f64>() / count;
+
+    ssim_terms(mu_a, mu_b, sq_a, sq_b, prod)
+}
+
+/// SSIM and contrast-structure term from local means & second moments
+fn ssim_terms(mu_a: f64, mu_b: f64, sq_a: f64, sq_b: f64, prod: f64) -> (f64, f64) {
+    let var_a = sq_a - (mu_a * mu_a);
+    let var_b = sq_b - (mu_b * mu_b);
+    let covar = prod - (mu_a * mu_b);
+
+    let cs = ((2.0 * covar) + SSIM_C2) / (var_a + var_b + SSIM_C2);
+    let luminance = ((2.0 * mu_a * mu_b) + SSIM_C1) / ((mu_a * mu_a) + (mu_b * mu_b) + SSIM_C1);
+
+    (luminance * cs, cs)
+}
+
+fn gaussian_kernel() -> Vec<f64> {
+    let center = (SSIM_WINDOW / 2) as f64;
+    let kernel: Vec<f64> = (0..SSIM_WINDOW).map(|i| {
+        let d = i as f64 - center;
+        (-(d * d) / (2.0 * SSIM_SIGMA * SSIM_SIGMA)).exp()
+    }).collect();
+
+    let sum: f64 = kernel.iter().sum();
+    kernel.iter().map(|x| x / sum).collect()
+}
@@ -1,3 +1,8 @@
+use alloc::{vec, vec::Vec};
+
+use crate::simd;
+
+#[derive(Clone)]
 pub struct VideoPlane {
     pub width: usize,
     pub height: usize,
@@ -18,6 +23,16 @@ impl VideoPlane {
     }
 
     pub fn blit(self: &mut VideoPlane, src: &VideoPlane, dx: usize, dy: usize, sx: usize, sy: usize, sw: usize, sh: usize) {
+        if sh > 0 {
+            let src_offset = (sy * src.width) + sx;
+            let dst_offset = (dy * self.width) + dx;
+            let dst_stride = self.width;
+
+            if simd::copy_rows(&mut self.pixels[dst_offset..], dst_stride, &src.pixels[src_offset..], src.width, sw, sh) {
+                return;
+            }
+        }
+
         for row in 0..sh {
             let src_row = row + sy;
             let dest_row = row + dy;
@@ -34,4 +49,44 @@ impl VideoPlane {
 
         new_slice
     }
-}
\ No newline at end of file
+
+    /// Copy the top-left width x height region of this plane into a caller-provided buffer with the given stride
+    pub fn copy_into(self: &VideoPlane, target: &mut [u8], stride: usize, width: usize, height: usize) {
+        assert!(width <= self.width && height <= self.height);
+        assert_target_size(target, stride, width, height);
+
+        if height > 0 && simd::copy_rows(target, stride, &self.pixels, self.width, width, height) {
+            return;
+        }
+
+        for row in 0..height {
+            let src_offset = row * self.width;
+            let dst_offset = row * stride;
+
+            target[dst_offset..(dst_offset + width)].copy_from_slice(&self.pixels[src_offset..(src_offset + width)]);
+        }
+    }
+
+    /// Interleave the top-left width x height region of this plane and another plane (as in NV12's UV plane) into a caller-provided buffer with the given stride
+    pub fn interleave_into(self: &VideoPlane, other: &VideoPlane, target: &mut [u8], stride: usize, width: usize, height: usize) {
+        assert!(width <= self.width && height <= self.height);
+        assert!(width <= other.width && height <= other.height);
+        assert_target_size(target, stride, width * 2, height);
+
+        for row in 0..height {
+            let a = &self.pixels[(row * self.width)..(row * self.width + width)];
+            let b = &other.pixels[(row * other.width)..(row * other.width + width)];
+            let dst_offset = row * stride;
+
+            for ((px, a), b) in target[dst_offset..(dst_offset + (width * 2))].chunks_exact_mut(2).zip(a).zip(b) {
+                px[0] = *a;
+                px[1] = *b;
+            }
+        }
+    }
+}
+
+fn assert_target_size(target: &[u8], stride: usize, width: usize, height: usize) {
+    assert!(stride >= width, "target stride is smaller than row width");
+    assert!(height == 0 || target.len() >= (stride * (height - 1)) + width, "target buffer is too small");
+}
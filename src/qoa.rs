@@ -0,0 +1,231 @@
+//! Minimal QOA ("Quite OK Audio", https://qoaformat.org/) frame encoder & decoder.
+//! Audio packets store bare QOA frames (no file header), each frame carrying its own LMS state so that it decodes independently.
+
+use alloc::vec::Vec;
+
+/// Maximum number of samples per channel in a single frame
+pub const QOA_FRAME_LEN: usize = 5120;
+
+/// Number of samples per channel packed into each 64-bit slice
+const QOA_SLICE_LEN: usize = 20;
+
+const QOA_LMS_LEN: usize = 4;
+
+#[cfg(feature = "std")]
+const QOA_QUANT_TAB: [i32;17] = [
+    7, 7, 7, 5, 5, 3, 3, 1, // -8..-1
+    0,                      //  0
+    0, 2, 2, 4, 4, 6, 6, 6  //  1.. 8
+];
+
+const QOA_SCALEFACTOR_TAB: [i32;16] = [
+    1, 7, 21, 45, 84, 138, 211, 304, 421, 562, 731, 928, 1157, 1419, 1715, 2048
+];
+
+/// Dequantized values in quarter steps (0.75, -0.75, 2.5, -2.5, 4.5, -4.5, 7, -7), so that dequantizing is integer math
+const QOA_DEQUANT_TAB: [i32;8] = [3, -3, 10, -10, 18, -18, 28, -28];
+
+#[derive(Clone, Copy)]
+pub struct QoaLms {
+    history: [i32;QOA_LMS_LEN],
+    weights: [i32;QOA_LMS_LEN],
+}
+
+impl QoaLms {
+    pub fn new() -> QoaLms {
+        QoaLms { history: [0;QOA_LMS_LEN], weights: [0, 0, -(1 << 13), 1 << 14] }
+    }
+
+    fn predict(self: &QoaLms) -> i32 {
+        let mut prediction = 0_i64;
+        for i in 0..QOA_LMS_LEN {
+            prediction += self.weights[i] as i64 * self.history[i] as i64;
+        }
+
+        (prediction >> 13) as i32
+    }
+
+    fn update(self: &mut QoaLms, sample: i32, residual: i32) {
+        let delta = residual >> 4;
+        for i in 0..QOA_LMS_LEN {
+            self.weights[i] += if self.history[i] < 0 { -delta } else { delta };
+        }
+
+        self.history.copy_within(1.., 0);
+        self.history[QOA_LMS_LEN - 1] = sample;
+    }
+}
+
+fn dequantize(scalefactor: usize, quantized: usize) -> i32 {
+    // round half away from zero, as the reference implementation does
+    let v = QOA_SCALEFACTOR_TAB[scalefactor] * QOA_DEQUANT_TAB[quantized];
+    (v + (2 * v.signum())) / 4
+}
+
+#[cfg(feature = "std")]
+fn div(v: i32, scalefactor: usize) -> i32 {
+    let reciprocal = ((1 << 16) + QOA_SCALEFACTOR_TAB[scalefactor] - 1) / QOA_SCALEFACTOR_TAB[scalefactor];
+    let n = ((v as i64 * reciprocal as i64) + (1 << 15)) >> 16;
+    (n + (v.signum() as i64 - n.signum())).clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
+fn clamp_s16(v: i32) -> i32 {
+    v.clamp(i16::MIN as i32, i16::MAX as i32)
+}
+
+/// Size in bytes of a frame holding the given number of samples per channel
+pub fn frame_size(channels: usize, samples: usize) -> usize {
+    let num_slices = samples.div_ceil(QOA_SLICE_LEN);
+    8 + (QOA_LMS_LEN * 4 * channels) + (8 * num_slices * channels)
+}
+
+/// Encode up to QOA_FRAME_LEN interleaved samples per channel as a single frame, updating the LMS state of each channel
+#[cfg(feature = "std")]
+pub fn encode_frame(samples: &[i16], channels: usize, samplerate: u32, lms: &mut [QoaLms], out: &mut Vec<u8>) {
+    let frame_len = samples.len() / channels;
+    debug_assert!(frame_len <= QOA_FRAME_LEN && lms.len() == channels);
+
+    // frame header
+    let header = ((channels as u64) << 56) | ((samplerate as u64 & 0xFFFFFF) << 32) | ((frame_len as u64) << 16) | frame_size(channels, frame_len) as u64;
+    out.extend_from_slice(&header.to_be_bytes());
+
+    // LMS state of each channel. Weights are stored as 16 bits, so truncate our own state the same way the decoder will see it
+    for state in lms.iter_mut() {
+        for i in 0..QOA_LMS_LEN {
+            state.weights[i] = state.weights[i] as i16 as i32;
+        }
+
+        let mut history = 0_u64;
+        let mut weights = 0_u64;
+
+        for i in 0..QOA_LMS_LEN {
+            history = (history << 16) | (state.history[i] as u16 as u64);
+            weights = (weights << 16) | (state.weights[i] as u16 as u64);
+        }
+
+        out.extend_from_slice(&history.to_be_bytes());
+        out.extend_from_slice(&weights.to_be_bytes());
+    }
+
+    // slices, interleaved by channel
+    for slice_start in (0..frame_len).step_by(QOA_SLICE_LEN) {
+        let slice_len = QOA_SLICE_LEN.min(frame_len - slice_start);
+
+        for c in 0..channels {
+            // try every scalefactor & keep whichever one reconstructs the slice with the least error
+            let mut best_error = u64::MAX;
+            let mut best_slice = 0;
+            let mut best_lms = lms[c];
+
+            for scalefactor in 0..16 {
+                let mut state = lms[c];
+                let mut slice = scalefactor as u64;
+                let mut error = 0_u64;
+
+                for si in slice_start..(slice_start + slice_len) {
+                    let sample = samples[(si * channels) + c] as i32;
+                    let predicted = state.predict();
+
+                    let residual = sample - predicted;
+                    let scaled = div(residual, scalefactor).clamp(-8, 8);
+                    let quantized = QOA_QUANT_TAB[(scaled + 8) as usize];
+                    let dequantized = dequantize(scalefactor, quantized as usize);
+                    let reconstructed = clamp_s16(predicted + dequantized);
+
+                    let e = (sample - reconstructed) as i64;
+                    error += (e * e) as u64;
+
+                    // penalize large LMS weights, which would otherwise be able to grow until they no longer fit in the frame header
+                    let weights_penalty = ((state.weights.iter().map(|x| (*x as i64) * (*x as i64)).sum::<i64>()) >> 18) - 0x8ff;
+                    if weights_penalty > 0 {
+                        error += (weights_penalty * weights_penalty) as u64;
+                    }
+
+                    if error > best_error {
+                        break;
+                    }
+
+                    state.update(reconstructed, dequantized);
+                    slice = (slice << 3) | quantized as u64;
+                }
+
+                if error < best_error {
+                    best_error = error;
+                    best_slice = slice;
+                    best_lms = state;
+                }
+            }
+
+            lms[c] = best_lms;
+
+            // short final slices are padded with zeroes
+            best_slice <<= (QOA_SLICE_LEN - slice_len) * 3;
+            out.extend_from_slice(&best_slice.to_be_bytes());
+        }
+    }
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..(offset + 8))?;
+    Some(u64::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]))
+}
+
+/// Decode a single frame, appending its interleaved samples to the output buffer.
+/// Returns None if the frame is malformed or doesn't match the expected channel count
+pub fn decode_frame(data: &[u8], channels: usize, out: &mut Vec<i16>) -> Option<()> {
+    let header = read_u64(data, 0)?;
+
+    let frame_channels = (header >> 56) as usize;
+    let frame_len = ((header >> 16) & 0xFFFF) as usize;
+    let size = (header & 0xFFFF) as usize;
+
+    if frame_channels != channels || frame_len > QOA_FRAME_LEN || size != frame_size(channels, frame_len) || size > data.len() {
+        return None;
+    }
+
+    let mut lms = [QoaLms::new();256];
+
+    for (c, state) in lms[..channels].iter_mut().enumerate() {
+        let mut history = read_u64(data, 8 + (c * 16))?;
+        let mut weights = read_u64(data, 16 + (c * 16))?;
+
+        for i in 0..QOA_LMS_LEN {
+            state.history[i] = (history >> 48) as i16 as i32;
+            state.weights[i] = (weights >> 48) as i16 as i32;
+            history <<= 16;
+            weights <<= 16;
+        }
+    }
+
+    let out_start = out.len();
+    out.resize(out_start + (frame_len * channels), 0);
+    let samples = &mut out[out_start..];
+
+    let mut offset = 8 + (QOA_LMS_LEN * 4 * channels);
+
+    for slice_start in (0..frame_len).step_by(QOA_SLICE_LEN) {
+        let slice_len = QOA_SLICE_LEN.min(frame_len - slice_start);
+
+        for (c, state) in lms[..channels].iter_mut().enumerate() {
+            let mut slice = read_u64(data, offset)?;
+            offset += 8;
+
+            let scalefactor = (slice >> 60) as usize;
+            slice <<= 4;
+
+            for si in slice_start..(slice_start + slice_len) {
+                let predicted = state.predict();
+                let quantized = (slice >> 61) as usize;
+                let dequantized = dequantize(scalefactor, quantized);
+                let reconstructed = clamp_s16(predicted + dequantized);
+
+                samples[(si * channels) + c] = reconstructed as i16;
+                slice <<= 3;
+
+                state.update(reconstructed, dequantized);
+            }
+        }
+    }
+
+    Some(())
+}
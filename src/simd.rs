@@ -0,0 +1,681 @@
+//! Vectorized implementations of the hot DCT & pixel loops.
+//!
+//! Every routine here must produce bit-identical results to the scalar code it replaces - the scalar
+//! DCT divides with Rust's `/` operator (rounding toward zero), so the vector code does the same instead of
+//! using a plain arithmetic shift. Each entry point returns `false` if no vector implementation is
+//! available on the current CPU, in which case the caller falls back to the portable scalar path.
+
+/// A vector of i32 lanes which the DCT butterflies can be expressed in
+pub trait Lanes: Copy {
+    fn add(self, b: Self) -> Self;
+    fn sub(self, b: Self) -> Self;
+
+    /// Divide each lane by 2, rounding toward zero
+    fn div2(self) -> Self;
+
+    /// Divide each lane by 4, rounding toward zero
+    fn div4(self) -> Self;
+
+    /// Divide each lane by 16, rounding toward zero
+    fn div16(self) -> Self;
+}
+
+/// Lane-parallel version of `DctMatrix8x8::fdct`
+#[inline(always)]
+pub fn fdct_lanes<L: Lanes>(v: &mut [L;8]) {
+    let [i0, i1, i2, i3, i4, i5, i6, i7] = *v;
+
+    // stage 1
+    let a0 = i0.add(i7);
+    let a1 = i1.add(i6);
+    let a2 = i2.add(i5);
+    let a3 = i3.add(i4);
+    let a4 = i0.sub(i7);
+    let a5 = i1.sub(i6);
+    let a6 = i2.sub(i5);
+    let a7 = i3.sub(i4);
+
+    // even stage 2
+    let b0 = a0.add(a3);
+    let b1 = a1.add(a2);
+    let b2 = a0.sub(a3);
+    let b3 = a1.sub(a2);
+
+    // even stage 3
+    let c0 = b0.add(b1);
+    let c1 = b0.sub(b1);
+    let c2 = b2.add(b2.div4()).add(b3.div2());
+    let c3 = b2.div2().sub(b3).sub(b3.div4());
+
+    // odd stage 2
+    let b4 = a7.div4().add(a4).add(a4.div4()).sub(a4.div16());
+    let b7 = a4.div4().sub(a7).sub(a7.div4()).add(a7.div16());
+    let b5 = a5.add(a6).sub(a6.div4()).sub(a6.div16());
+    let b6 = a6.sub(a5).add(a5.div4()).add(a5.div16());
+
+    // odd stage 3
+    let c4 = b4.add(b5);
+    let c5 = b4.sub(b5);
+    let c6 = b6.add(b7);
+    let c7 = b6.sub(b7);
+
+    // odd stage 4
+    let d4 = c4;
+    let d5 = c5.add(c7);
+    let d6 = c5.sub(c7);
+    let d7 = c6;
+
+    *v = [c0, d4, c2, d6, c1, d5, c3, d7];
+}
+
+/// Lane-parallel version of `DctMatrix8x8::idct`
+#[inline(always)]
+pub fn idct_lanes<L: Lanes>(v: &mut [L;8]) {
+    let [c0, d4, c2, d6, c1, d5, c3, d7] = *v;
+
+    // odd stage 4
+    let c4 = d4;
+    let c5 = d5.add(d6);
+    let c7 = d5.sub(d6);
+    let c6 = d7;
+
+    // odd stage 3
+    let b4 = c4.add(c5);
+    let b5 = c4.sub(c5);
+    let b6 = c6.add(c7);
+    let b7 = c6.sub(c7);
+
+    // even stage 3
+    let b0 = c0.add(c1);
+    let b1 = c0.sub(c1);
+    let b2 = c2.add(c2.div4()).add(c3.div2());
+    let b3 = c2.div2().sub(c3).sub(c3.div4());
+
+    // odd stage 2
+    let a4 = b7.div4().add(b4).add(b4.div4()).sub(b4.div16());
+    let a7 = b4.div4().sub(b7).sub(b7.div4()).add(b7.div16());
+    let a5 = b5.sub(b6).add(b6.div4()).add(b6.div16());
+    let a6 = b6.add(b5).sub(b5.div4()).sub(b5.div16());
+
+    // even stage 2
+    let a0 = b0.add(b2);
+    let a1 = b1.add(b3);
+    let a2 = b1.sub(b3);
+    let a3 = b0.sub(b2);
+
+    // stage 1
+    *v = [a0.add(a4), a1.add(a5), a2.add(a6), a3.add(a7), a3.sub(a7), a2.sub(a6), a1.sub(a5), a0.sub(a4)];
+}
+
+/// Runtime CPU feature detection needs std - without it, only features enabled at compile time (with -C target-feature) are used
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+macro_rules! has_x86_feature {
+    ($feature:tt) => {{
+        #[cfg(feature = "std")]
+        {
+            is_x86_feature_detected!($feature)
+        }
+
+        #[cfg(not(feature = "std"))]
+        {
+            cfg!(target_feature = $feature)
+        }
+    }};
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) mod x86 {
+    #[cfg(target_arch = "x86")]
+    use core::arch::x86::*;
+
+    #[cfg(target_arch = "x86_64")]
+    use core::arch::x86_64::*;
+
+    use super::{Lanes, fdct_lanes, idct_lanes};
+
+    #[derive(Clone, Copy)]
+    pub struct Sse2(__m128i);
+
+    impl Lanes for Sse2 {
+        #[inline(always)]
+        fn add(self, b: Self) -> Self {
+            unsafe { Sse2(_mm_add_epi32(self.0, b.0)) }
+        }
+
+        #[inline(always)]
+        fn sub(self, b: Self) -> Self {
+            unsafe { Sse2(_mm_sub_epi32(self.0, b.0)) }
+        }
+
+        #[inline(always)]
+        fn div2(self) -> Self {
+            unsafe { Sse2(_mm_srai_epi32(_mm_add_epi32(self.0, _mm_srli_epi32(self.0, 31)), 1)) }
+        }
+
+        #[inline(always)]
+        fn div4(self) -> Self {
+            unsafe { Sse2(_mm_srai_epi32(_mm_add_epi32(self.0, _mm_srli_epi32(_mm_srai_epi32(self.0, 31), 30)), 2)) }
+        }
+
+        #[inline(always)]
+        fn div16(self) -> Self {
+            unsafe { Sse2(_mm_srai_epi32(_mm_add_epi32(self.0, _mm_srli_epi32(_mm_srai_epi32(self.0, 31), 28)), 4)) }
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    pub struct Avx2(__m256i);
+
+    impl Lanes for Avx2 {
+        #[inline(always)]
+        fn add(self, b: Self) -> Self {
+            unsafe { Avx2(_mm256_add_epi32(self.0, b.0)) }
+        }
+
+        #[inline(always)]
+        fn sub(self, b: Self) -> Self {
+            unsafe { Avx2(_mm256_sub_epi32(self.0, b.0)) }
+        }
+
+        #[inline(always)]
+        fn div2(self) -> Self {
+            unsafe { Avx2(_mm256_srai_epi32(_mm256_add_epi32(self.0, _mm256_srli_epi32(self.0, 31)), 1)) }
+        }
+
+        #[inline(always)]
+        fn div4(self) -> Self {
+            unsafe { Avx2(_mm256_srai_epi32(_mm256_add_epi32(self.0, _mm256_srli_epi32(_mm256_srai_epi32(self.0, 31), 30)), 2)) }
+        }
+
+        #[inline(always)]
+        fn div16(self) -> Self {
+            unsafe { Avx2(_mm256_srai_epi32(_mm256_add_epi32(self.0, _mm256_srli_epi32(_mm256_srai_epi32(self.0, 31), 28)), 4)) }
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn transpose4x4(r: [__m128i;4]) -> [__m128i;4] {
+        let t0 = _mm_unpacklo_epi32(r[0], r[1]);
+        let t1 = _mm_unpackhi_epi32(r[0], r[1]);
+        let t2 = _mm_unpacklo_epi32(r[2], r[3]);
+        let t3 = _mm_unpackhi_epi32(r[2], r[3]);
+
+        [_mm_unpacklo_epi64(t0, t2), _mm_unpackhi_epi64(t0, t2), _mm_unpacklo_epi64(t1, t3), _mm_unpackhi_epi64(t1, t3)]
+    }
+
+    /// Transpose an 8x8 matrix stored as 8 rows of (left half, right half)
+    #[inline(always)]
+    unsafe fn transpose8x8_sse2(lo: &mut [Sse2;8], hi: &mut [Sse2;8]) {
+        let a = transpose4x4([lo[0].0, lo[1].0, lo[2].0, lo[3].0]);
+        let b = transpose4x4([hi[0].0, hi[1].0, hi[2].0, hi[3].0]);
+        let c = transpose4x4([lo[4].0, lo[5].0, lo[6].0, lo[7].0]);
+        let d = transpose4x4([hi[4].0, hi[5].0, hi[6].0, hi[7].0]);
+
+        for i in 0..4 {
+            lo[i] = Sse2(a[i]);
+            hi[i] = Sse2(c[i]);
+            lo[i + 4] = Sse2(b[i]);
+            hi[i + 4] = Sse2(d[i]);
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn load_sse2(m: &[i32;64]) -> ([Sse2;8], [Sse2;8]) {
+        let ptr = m.as_ptr() as *const __m128i;
+        let mut lo = [Sse2(_mm_setzero_si128());8];
+        let mut hi = [Sse2(_mm_setzero_si128());8];
+
+        for row in 0..8 {
+            lo[row] = Sse2(_mm_loadu_si128(ptr.add(row * 2)));
+            hi[row] = Sse2(_mm_loadu_si128(ptr.add(row * 2 + 1)));
+        }
+
+        (lo, hi)
+    }
+
+    #[inline(always)]
+    unsafe fn store_sse2(m: &mut [i32;64], lo: &[Sse2;8], hi: &[Sse2;8]) {
+        let ptr = m.as_mut_ptr() as *mut __m128i;
+
+        for row in 0..8 {
+            _mm_storeu_si128(ptr.add(row * 2), lo[row].0);
+            _mm_storeu_si128(ptr.add(row * 2 + 1), hi[row].0);
+        }
+    }
+
+    /// Apply a 1D transform to each column (or each row, if `rows` is set) of a row-order 8x8 matrix
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn transform_sse2(m: &mut [i32;64], rows: bool, inverse: bool) {
+        let (mut lo, mut hi) = load_sse2(m);
+
+        if rows {
+            transpose8x8_sse2(&mut lo, &mut hi);
+        }
+
+        if inverse {
+            idct_lanes(&mut lo);
+            idct_lanes(&mut hi);
+        } else {
+            fdct_lanes(&mut lo);
+            fdct_lanes(&mut hi);
+        }
+
+        if rows {
+            transpose8x8_sse2(&mut lo, &mut hi);
+        }
+
+        store_sse2(m, &lo, &hi);
+    }
+
+    #[inline(always)]
+    unsafe fn transpose8x8_avx2(r: &mut [Avx2;8]) {
+        let t0 = _mm256_unpacklo_epi32(r[0].0, r[1].0);
+        let t1 = _mm256_unpackhi_epi32(r[0].0, r[1].0);
+        let t2 = _mm256_unpacklo_epi32(r[2].0, r[3].0);
+        let t3 = _mm256_unpackhi_epi32(r[2].0, r[3].0);
+        let t4 = _mm256_unpacklo_epi32(r[4].0, r[5].0);
+        let t5 = _mm256_unpackhi_epi32(r[4].0, r[5].0);
+        let t6 = _mm256_unpacklo_epi32(r[6].0, r[7].0);
+        let t7 = _mm256_unpackhi_epi32(r[6].0, r[7].0);
+
+        let u0 = _mm256_unpacklo_epi64(t0, t2);
+        let u1 = _mm256_unpackhi_epi64(t0, t2);
+        let u2 = _mm256_unpacklo_epi64(t1, t3);
+        let u3 = _mm256_unpackhi_epi64(t1, t3);
+        let u4 = _mm256_unpacklo_epi64(t4, t6);
+        let u5 = _mm256_unpackhi_epi64(t4, t6);
+        let u6 = _mm256_unpacklo_epi64(t5, t7);
+        let u7 = _mm256_unpackhi_epi64(t5, t7);
+
+        r[0] = Avx2(_mm256_permute2x128_si256(u0, u4, 0x20));
+        r[1] = Avx2(_mm256_permute2x128_si256(u1, u5, 0x20));
+        r[2] = Avx2(_mm256_permute2x128_si256(u2, u6, 0x20));
+        r[3] = Avx2(_mm256_permute2x128_si256(u3, u7, 0x20));
+        r[4] = Avx2(_mm256_permute2x128_si256(u0, u4, 0x31));
+        r[5] = Avx2(_mm256_permute2x128_si256(u1, u5, 0x31));
+        r[6] = Avx2(_mm256_permute2x128_si256(u2, u6, 0x31));
+        r[7] = Avx2(_mm256_permute2x128_si256(u3, u7, 0x31));
+    }
+
+    /// Apply a 1D transform to each column (or each row, if `rows` is set) of a row-order 8x8 matrix
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn transform_avx2(m: &mut [i32;64], rows: bool, inverse: bool) {
+        let ptr = m.as_mut_ptr() as *mut __m256i;
+        let mut r = [Avx2(_mm256_setzero_si256());8];
+
+        for (row, v) in r.iter_mut().enumerate() {
+            *v = Avx2(_mm256_loadu_si256(ptr.add(row)));
+        }
+
+        if rows {
+            transpose8x8_avx2(&mut r);
+        }
+
+        if inverse {
+            idct_lanes(&mut r);
+        } else {
+            fdct_lanes(&mut r);
+        }
+
+        if rows {
+            transpose8x8_avx2(&mut r);
+        }
+
+        for (row, v) in r.iter().enumerate() {
+            _mm256_storeu_si256(ptr.add(row), v.0);
+        }
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn apply_residuals_sse2(pixels: &mut [u8;256], from: &[u8;256]) {
+        let bias = _mm_set1_epi16(128);
+        let zero = _mm_setzero_si128();
+
+        for i in 0..16 {
+            let d = _mm_loadu_si128(pixels.as_ptr().add(i * 16) as *const __m128i);
+            let p = _mm_loadu_si128(from.as_ptr().add(i * 16) as *const __m128i);
+
+            // widen to i16: p + (d - 128) * 2
+            let d_lo = _mm_sub_epi16(_mm_unpacklo_epi8(d, zero), bias);
+            let d_hi = _mm_sub_epi16(_mm_unpackhi_epi8(d, zero), bias);
+            let p_lo = _mm_unpacklo_epi8(p, zero);
+            let p_hi = _mm_unpackhi_epi8(p, zero);
+
+            let r_lo = _mm_add_epi16(p_lo, _mm_add_epi16(d_lo, d_lo));
+            let r_hi = _mm_add_epi16(p_hi, _mm_add_epi16(d_hi, d_hi));
+
+            // saturating pack performs the clamp to 0..255
+            _mm_storeu_si128(pixels.as_mut_ptr().add(i * 16) as *mut __m128i, _mm_packus_epi16(r_lo, r_hi));
+        }
+    }
+
+    /// Sum of squared differences between two equally sized pixel buffers, checking against `ref_err` after every 16 pixels
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn calc_error_sse2(a: &[u8], b: &[u8], ref_err: u64) -> u64 {
+        debug_assert!(a.len() == b.len());
+
+        let zero = _mm_setzero_si128();
+        let chunks = a.len() / 16;
+        let mut sum: u64 = 0;
+
+        for i in 0..chunks {
+            let va = _mm_loadu_si128(a.as_ptr().add(i * 16) as *const __m128i);
+            let vb = _mm_loadu_si128(b.as_ptr().add(i * 16) as *const __m128i);
+
+            // |a - b| via saturating subtraction in both directions, then widen & square-accumulate with madd
+            let diff = _mm_or_si128(_mm_subs_epu8(va, vb), _mm_subs_epu8(vb, va));
+            let lo = _mm_unpacklo_epi8(diff, zero);
+            let hi = _mm_unpackhi_epi8(diff, zero);
+            let sq = _mm_add_epi32(_mm_madd_epi16(lo, lo), _mm_madd_epi16(hi, hi));
+
+            let mut lanes = [0u32;4];
+            _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, sq);
+            sum += (lanes[0] + lanes[1] + lanes[2] + lanes[3]) as u64;
+
+            if sum >= ref_err {
+                return sum;
+            }
+        }
+
+        for i in (chunks * 16)..a.len() {
+            let diff = a[i] as i32 - b[i] as i32;
+            sum += (diff * diff) as u64;
+
+            if sum >= ref_err {
+                return sum;
+            }
+        }
+
+        sum
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn copy_rows_sse2(dst: &mut [u8], dst_stride: usize, src: &[u8], src_stride: usize, width: usize, rows: usize) {
+        for row in 0..rows {
+            let src_row = &src[(row * src_stride)..(row * src_stride + width)];
+            let dst_row = &mut dst[(row * dst_stride)..(row * dst_stride + width)];
+
+            let mut x = 0;
+            while x + 16 <= width {
+                let v = _mm_loadu_si128(src_row.as_ptr().add(x) as *const __m128i);
+                _mm_storeu_si128(dst_row.as_mut_ptr().add(x) as *mut __m128i, v);
+                x += 16;
+            }
+
+            dst_row[x..].copy_from_slice(&src_row[x..]);
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use core::arch::aarch64::*;
+
+    use super::{Lanes, fdct_lanes, idct_lanes};
+
+    #[derive(Clone, Copy)]
+    pub struct Neon(int32x4_t);
+
+    impl Lanes for Neon {
+        #[inline(always)]
+        fn add(self, b: Self) -> Self {
+            unsafe { Neon(vaddq_s32(self.0, b.0)) }
+        }
+
+        #[inline(always)]
+        fn sub(self, b: Self) -> Self {
+            unsafe { Neon(vsubq_s32(self.0, b.0)) }
+        }
+
+        #[inline(always)]
+        fn div2(self) -> Self {
+            unsafe { Neon(vshrq_n_s32(vaddq_s32(self.0, vreinterpretq_s32_u32(vshrq_n_u32(vreinterpretq_u32_s32(self.0), 31))), 1)) }
+        }
+
+        #[inline(always)]
+        fn div4(self) -> Self {
+            unsafe { Neon(vshrq_n_s32(vaddq_s32(self.0, vreinterpretq_s32_u32(vshrq_n_u32(vreinterpretq_u32_s32(vshrq_n_s32(self.0, 31)), 30))), 2)) }
+        }
+
+        #[inline(always)]
+        fn div16(self) -> Self {
+            unsafe { Neon(vshrq_n_s32(vaddq_s32(self.0, vreinterpretq_s32_u32(vshrq_n_u32(vreinterpretq_u32_s32(vshrq_n_s32(self.0, 31)), 28))), 4)) }
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn transpose4x4(r: [int32x4_t;4]) -> [int32x4_t;4] {
+        let t0 = vtrnq_s32(r[0], r[1]);
+        let t1 = vtrnq_s32(r[2], r[3]);
+
+        [vcombine_s32(vget_low_s32(t0.0), vget_low_s32(t1.0)),
+        vcombine_s32(vget_low_s32(t0.1), vget_low_s32(t1.1)),
+        vcombine_s32(vget_high_s32(t0.0), vget_high_s32(t1.0)),
+        vcombine_s32(vget_high_s32(t0.1), vget_high_s32(t1.1))]
+    }
+
+    #[inline(always)]
+    unsafe fn transpose8x8(lo: &mut [Neon;8], hi: &mut [Neon;8]) {
+        let a = transpose4x4([lo[0].0, lo[1].0, lo[2].0, lo[3].0]);
+        let b = transpose4x4([hi[0].0, hi[1].0, hi[2].0, hi[3].0]);
+        let c = transpose4x4([lo[4].0, lo[5].0, lo[6].0, lo[7].0]);
+        let d = transpose4x4([hi[4].0, hi[5].0, hi[6].0, hi[7].0]);
+
+        for i in 0..4 {
+            lo[i] = Neon(a[i]);
+            hi[i] = Neon(c[i]);
+            lo[i + 4] = Neon(b[i]);
+            hi[i + 4] = Neon(d[i]);
+        }
+    }
+
+    /// Apply a 1D transform to each column (or each row, if `rows` is set) of a row-order 8x8 matrix
+    pub unsafe fn transform_neon(m: &mut [i32;64], rows: bool, inverse: bool) {
+        let ptr = m.as_mut_ptr();
+        let mut lo = [Neon(vdupq_n_s32(0));8];
+        let mut hi = [Neon(vdupq_n_s32(0));8];
+
+        for row in 0..8 {
+            lo[row] = Neon(vld1q_s32(ptr.add(row * 8)));
+            hi[row] = Neon(vld1q_s32(ptr.add(row * 8 + 4)));
+        }
+
+        if rows {
+            transpose8x8(&mut lo, &mut hi);
+        }
+
+        if inverse {
+            idct_lanes(&mut lo);
+            idct_lanes(&mut hi);
+        } else {
+            fdct_lanes(&mut lo);
+            fdct_lanes(&mut hi);
+        }
+
+        if rows {
+            transpose8x8(&mut lo, &mut hi);
+        }
+
+        for row in 0..8 {
+            vst1q_s32(ptr.add(row * 8), lo[row].0);
+            vst1q_s32(ptr.add(row * 8 + 4), hi[row].0);
+        }
+    }
+
+    pub unsafe fn apply_residuals_neon(pixels: &mut [u8;256], from: &[u8;256]) {
+        let bias = vdupq_n_s16(128);
+
+        for i in 0..16 {
+            let d = vld1q_u8(pixels.as_ptr().add(i * 16));
+            let p = vld1q_u8(from.as_ptr().add(i * 16));
+
+            // widen to i16: p + (d - 128) * 2
+            let d_lo = vsubq_s16(vreinterpretq_s16_u16(vmovl_u8(vget_low_u8(d))), bias);
+            let d_hi = vsubq_s16(vreinterpretq_s16_u16(vmovl_u8(vget_high_u8(d))), bias);
+            let p_lo = vreinterpretq_s16_u16(vmovl_u8(vget_low_u8(p)));
+            let p_hi = vreinterpretq_s16_u16(vmovl_u8(vget_high_u8(p)));
+
+            let r_lo = vaddq_s16(p_lo, vshlq_n_s16(d_lo, 1));
+            let r_hi = vaddq_s16(p_hi, vshlq_n_s16(d_hi, 1));
+
+            // saturating narrow performs the clamp to 0..255
+            vst1q_u8(pixels.as_mut_ptr().add(i * 16), vcombine_u8(vqmovun_s16(r_lo), vqmovun_s16(r_hi)));
+        }
+    }
+
+    /// Sum of squared differences between two equally sized pixel buffers, checking against `ref_err` after every 16 pixels
+    pub unsafe fn calc_error_neon(a: &[u8], b: &[u8], ref_err: u64) -> u64 {
+        debug_assert!(a.len() == b.len());
+
+        let chunks = a.len() / 16;
+        let mut sum: u64 = 0;
+
+        for i in 0..chunks {
+            let va = vld1q_u8(a.as_ptr().add(i * 16));
+            let vb = vld1q_u8(b.as_ptr().add(i * 16));
+
+            let diff = vabdq_u8(va, vb);
+            let lo = vmull_u8(vget_low_u8(diff), vget_low_u8(diff));
+            let hi = vmull_u8(vget_high_u8(diff), vget_high_u8(diff));
+            sum += (vaddlvq_u16(lo) + vaddlvq_u16(hi)) as u64;
+
+            if sum >= ref_err {
+                return sum;
+            }
+        }
+
+        for i in (chunks * 16)..a.len() {
+            let diff = a[i] as i32 - b[i] as i32;
+            sum += (diff * diff) as u64;
+
+            if sum >= ref_err {
+                return sum;
+            }
+        }
+
+        sum
+    }
+
+    pub unsafe fn copy_rows_neon(dst: &mut [u8], dst_stride: usize, src: &[u8], src_stride: usize, width: usize, rows: usize) {
+        for row in 0..rows {
+            let src_row = &src[(row * src_stride)..(row * src_stride + width)];
+            let dst_row = &mut dst[(row * dst_stride)..(row * dst_stride + width)];
+
+            let mut x = 0;
+            while x + 16 <= width {
+                vst1q_u8(dst_row.as_mut_ptr().add(x), vld1q_u8(src_row.as_ptr().add(x)));
+                x += 16;
+            }
+
+            dst_row[x..].copy_from_slice(&src_row[x..]);
+        }
+    }
+}
+
+/// Apply a forward or inverse 1D DCT to each column (or each row, if `rows` is set) of a row-order 8x8 matrix
+#[inline]
+// without std, clippy sees both feature checks as the same cfg! expansion
+#[cfg_attr(not(feature = "std"), allow(clippy::ifs_same_cond))]
+pub fn transform(m: &mut [i32;64], rows: bool, inverse: bool) -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if has_x86_feature!("avx2") {
+            unsafe { x86::transform_avx2(m, rows, inverse) };
+            true
+        } else if has_x86_feature!("sse2") {
+            unsafe { x86::transform_sse2(m, rows, inverse) };
+            true
+        } else {
+            false
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        unsafe { neon::transform_neon(m, rows, inverse) };
+        true
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        let _ = (m, rows, inverse);
+        false
+    }
+}
+
+/// Apply decoded residuals (stored in `pixels`) on top of the reference block `from`
+#[inline]
+pub fn apply_residuals(pixels: &mut [u8;256], from: &[u8;256]) -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if has_x86_feature!("sse2") {
+            unsafe { x86::apply_residuals_sse2(pixels, from) };
+            true
+        } else {
+            false
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        unsafe { neon::apply_residuals_neon(pixels, from) };
+        true
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        let _ = (pixels, from);
+        false
+    }
+}
+
+/// Sum of squared differences between two pixel buffers. Like the scalar version, this may return early
+/// with a partial sum as soon as the sum reaches `ref_err` - any result below `ref_err` is exact
+#[inline]
+pub fn calc_error(a: &[u8], b: &[u8], ref_err: u64) -> Option<u64> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if has_x86_feature!("sse2") {
+            Some(unsafe { x86::calc_error_sse2(a, b, ref_err) })
+        } else {
+            None
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        Some(unsafe { neon::calc_error_neon(a, b, ref_err) })
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        let _ = (a, b, ref_err);
+        None
+    }
+}
+
+/// Copy a `width` x `rows` rectangle between two pixel buffers with the given row strides
+#[inline]
+pub fn copy_rows(dst: &mut [u8], dst_stride: usize, src: &[u8], src_stride: usize, width: usize, rows: usize) -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if has_x86_feature!("sse2") {
+            unsafe { x86::copy_rows_sse2(dst, dst_stride, src, src_stride, width, rows) };
+            true
+        } else {
+            false
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        unsafe { neon::copy_rows_neon(dst, dst_stride, src, src_stride, width, rows) };
+        true
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        let _ = (dst, dst_stride, src, src_stride, width, rows);
+        false
+    }
+}
@@ -0,0 +1,174 @@
+use core::ops::Range;
+
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use bitstream_io::{BitWriter, BitWrite};
+
+use crate::bits::BitReader;
+use crate::huffman::{HuffmanTree, HuffmanError};
+#[cfg(feature = "std")]
+use crate::rle::RLESequence;
+
+/// Describes a contiguous run of macroblocks which is entropy-coded as an independent bitstream.
+/// Block offsets index into the combined list of Y, U, and V plane macroblocks (in that order)
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug)]
+pub struct Slice {
+    pub block_offset: usize,
+    pub num_blocks: usize,
+}
+
+/// Compute the slice layout of a frame. Each row of macroblocks in each plane is coded as its own slice
+#[cfg(feature = "std")]
+pub fn slice_layout(blocks_wide: usize, blocks_high: usize, chroma_blocks_wide: usize, chroma_blocks_high: usize) -> Vec<Slice> {
+    let mut slices = Vec::with_capacity(blocks_high + (chroma_blocks_high * 2));
+    let mut block_offset = 0;
+
+    for group in slice_groups(blocks_wide, blocks_high, chroma_blocks_wide, chroma_blocks_high) {
+        for _ in 0..group.num_slices {
+            slices.push(Slice { block_offset, num_blocks: group.blocks_per_slice });
+            block_offset += group.blocks_per_slice;
+        }
+    }
+
+    slices
+}
+
+/// A run of consecutive slices which all contain the same number of blocks
+#[derive(Clone, Copy, Debug)]
+pub struct SliceGroup {
+    pub num_slices: usize,
+    pub blocks_per_slice: usize,
+}
+
+/// Compute the slice layout of a frame as groups of equally-sized slices (luma rows, then chroma rows)
+pub fn slice_groups(blocks_wide: usize, blocks_high: usize, chroma_blocks_wide: usize, chroma_blocks_high: usize) -> [SliceGroup;2] {
+    [
+        SliceGroup { num_slices: blocks_high, blocks_per_slice: blocks_wide },
+        SliceGroup { num_slices: chroma_blocks_high * 2, blocks_per_slice: chroma_blocks_wide },
+    ]
+}
+
+/// Serialize a sequence of RLE-encoded coefficients using the given huffman tree
+#[cfg(feature = "std")]
+pub fn write_rle<W: BitWrite>(sequence: &[RLESequence], tree: &HuffmanTree, bitwriter: &mut W) -> Result<(), std::io::Error> {
+    for sq in sequence {
+        let num_zeroes = tree.get_code(sq.num_zeroes);
+        let num_bits = tree.get_code(sq.coeff_size);
+
+        debug_assert!(num_zeroes.len > 0 && num_bits.len > 0);
+
+        bitwriter.write(num_zeroes.len, num_zeroes.val)?;
+        bitwriter.write(num_bits.len, num_bits.val)?;
+
+        if sq.coeff_size > 0 {
+            bitwriter.write_signed(sq.coeff_size as u32, sq.coeff)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Number of bits write_rle produces for a sequence of RLE-encoded coefficients
+#[cfg(feature = "std")]
+pub fn rle_bits(sequence: &[RLESequence], tree: &HuffmanTree) -> u64 {
+    sequence.iter().map(|sq| {
+        (tree.get_code(sq.num_zeroes).len + tree.get_code(sq.coeff_size).len + sq.coeff_size as u32) as u64
+    }).sum()
+}
+
+/// Write a slice table (slice count + byte offset of each slice) followed by the slice bitstreams themselves
+#[cfg(feature = "std")]
+pub fn write_slices<W: std::io::Write>(slices: &[Vec<u8>], writer: &mut BitWriter<W, bitstream_io::LittleEndian>) -> Result<(), std::io::Error> {
+    writer.write(16, slices.len() as u16)?;
+
+    let mut offset = 0;
+    for s in slices {
+        writer.write(32, offset as u32)?;
+        offset += s.len();
+    }
+
+    for s in slices {
+        writer.write_bytes(s)?;
+    }
+
+    Ok(())
+}
+
+/// Read a slice table from the given packet payload, appending the byte range (relative to the start of the payload) of each slice's bitstream
+pub fn read_slices(payload: &[u8], num_slices: usize, ranges: &mut Vec<Range<usize>>) -> bool {
+    ranges.clear();
+
+    if payload.len() < 2 {
+        return false;
+    }
+
+    let count = u16::from_le_bytes([payload[0], payload[1]]) as usize;
+    let table_end = 2 + (count * 4);
+
+    if count != num_slices || payload.len() < table_end {
+        return false;
+    }
+
+    for i in 0..count {
+        let o = 2 + (i * 4);
+        let start = u32::from_le_bytes([payload[o], payload[o + 1], payload[o + 2], payload[o + 3]]) as usize;
+
+        let end = if i + 1 < count {
+            let o = o + 4;
+            u32::from_le_bytes([payload[o], payload[o + 1], payload[o + 2], payload[o + 3]]) as usize
+        } else {
+            payload.len() - table_end
+        };
+
+        if start > end || end > payload.len() - table_end {
+            return false;
+        }
+
+        ranges.push((table_end + start)..(table_end + end));
+    }
+
+    true
+}
+
+/// Decode a run of RLE-encoded coefficients from the bitstream, exactly filling the output buffer
+pub fn read_rle(tree: &HuffmanTree, bitreader: &mut BitReader, bitstream_length: u64, out: &mut [i16]) -> Result<(), HuffmanError> {
+    let mut out_idx = 0;
+
+    while out_idx < out.len() {
+        let num_zeroes = tree.read(bitreader, bitstream_length)? as usize;
+
+        out_idx += num_zeroes;
+
+        let num_bits = tree.read(bitreader, bitstream_length)?;
+
+        // an empty run without a value never advances (a table with a single symbol 0 produces these without consuming any bits)
+        if num_zeroes == 0 && num_bits == 0 {
+            return Err(HuffmanError::DecodeError);
+        }
+
+        // if num_bits is 0, then this is only a run of 0s with no value
+        if num_bits > 0 {
+            if out_idx >= out.len() {
+                return Err(HuffmanError::DecodeError);
+            }
+
+            let coeff = match bitreader.read_signed(num_bits as u32) {
+                Ok(v) => v as i16,
+                Err(e) => {
+                    return Err(HuffmanError::IOError(e));
+                }
+            };
+            out[out_idx] = coeff;
+
+            out_idx += 1;
+        }
+    }
+
+    if out_idx > out.len() {
+        return Err(HuffmanError::DecodeError);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,253 @@
+//! Push-based decoding.
+//!
+//! StreamDecoder is fed arbitrary chunks of a stream as they arrive (from a socket, an async runtime, etc) and hands out frames
+//! once every packet up to & including the next frame has been received. It never blocks waiting for more data.
+
+#[cfg(feature = "multithreading")]
+use std::sync::Arc;
+
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{io::{self, Source}, common::{PFV_PACKET_EOF, PFV_PACKET_IFRAME, PFV_PACKET_PFRAME}, dec::{Decoder, DecodeError, DecodedFrame}};
+
+/// Bytes which have been fed to a StreamDecoder but not consumed yet
+pub struct FeedBuffer {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl FeedBuffer {
+    fn new() -> FeedBuffer {
+        FeedBuffer { data: Vec::new(), pos: 0 }
+    }
+
+    fn push(self: &mut FeedBuffer, bytes: &[u8]) {
+        // discard consumed bytes before appending, so the buffer only grows with the amount of unconsumed data
+        if self.pos > 0 {
+            self.data.drain(..self.pos);
+            self.pos = 0;
+        }
+
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn clear(self: &mut FeedBuffer) {
+        self.data.clear();
+        self.pos = 0;
+    }
+
+    fn remaining(self: &FeedBuffer) -> &[u8] {
+        &self.data[self.pos..]
+    }
+}
+
+impl Source for FeedBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let len = buf.len().min(self.data.len() - self.pos);
+
+        buf[..len].copy_from_slice(&self.data[self.pos..(self.pos + len)]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+pub struct StreamDecoder {
+    decoder: Option<Decoder<FeedBuffer>>,
+    header: Vec<u8>,
+    finished: bool,
+    #[cfg(feature = "multithreading")]
+    threadpool: Arc<rayon::ThreadPool>,
+}
+
+#[cfg(not(feature = "multithreading"))]
+impl Default for StreamDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamDecoder {
+    pub fn new(#[cfg(feature = "multithreading")] num_threads: usize) -> StreamDecoder {
+        #[cfg(feature = "multithreading")]
+        {
+            StreamDecoder::new_with_thread_pool(Arc::new(rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap()))
+        }
+
+        #[cfg(not(feature = "multithreading"))]
+        {
+            StreamDecoder { decoder: None, header: Vec::new(), finished: false }
+        }
+    }
+
+    /// Create a new stream decoder which runs on an existing thread pool (see Decoder::new_with_thread_pool)
+    #[cfg(feature = "multithreading")]
+    pub fn new_with_thread_pool(threadpool: Arc<rayon::ThreadPool>) -> StreamDecoder {
+        StreamDecoder { decoder: None, header: Vec::new(), finished: false, threadpool }
+    }
+
+    /// Append the next chunk of the stream. Chunks may be split anywhere, including in the middle of the header or a packet
+    pub fn feed(self: &mut StreamDecoder, bytes: &[u8]) {
+        match &mut self.decoder {
+            Some(decoder) => {
+                decoder.source_mut().push(bytes);
+            }
+            None => {
+                self.header.extend_from_slice(bytes);
+            }
+        }
+    }
+
+    /// The underlying decoder, once the stream header has been received. Use it to query dimensions, timebase, metadata, and tracks,
+    /// or to take user data & subtitles which have been read so far
+    pub fn decoder(self: &StreamDecoder) -> Option<&Decoder<FeedBuffer>> {
+        self.decoder.as_ref()
+    }
+
+    /// Mutable access to the underlying decoder (for example to select tracks), once the stream header has been received
+    pub fn decoder_mut(self: &mut StreamDecoder) -> Option<&mut Decoder<FeedBuffer>> {
+        self.decoder.as_mut()
+    }
+
+    /// Length in bytes of the stream header, once it has been received
+    pub fn header_len(self: &StreamDecoder) -> Option<u64> {
+        self.decoder.as_ref().map(|x| x.header_len())
+    }
+
+    /// Rewind to the first frame (for example to loop a replay). Any data which has been fed but not decoded yet is discarded,
+    /// and the caller should continue feeding the stream starting right after the header (see header_len).
+    /// If the header hasn't been received yet, the decoder starts over from scratch
+    pub fn reset(self: &mut StreamDecoder) {
+        match &mut self.decoder {
+            Some(decoder) => {
+                decoder.source_mut().clear();
+                decoder.rewind();
+            }
+            None => {
+                self.header.clear();
+            }
+        }
+
+        self.finished = false;
+    }
+
+    /// Returns true once the end of the stream has been decoded
+    pub fn is_finished(self: &StreamDecoder) -> bool {
+        self.finished
+    }
+
+    /// Decode the next frame if all of its data has arrived, or return None if more data needs to be fed first (or the stream has ended - see is_finished)
+    pub fn next_frame(self: &mut StreamDecoder) -> Result<Option<DecodedFrame<'_>>, DecodeError> {
+        self.next_frame_with_audio(&mut |_| {})
+    }
+
+    /// Like next_frame, but also hands audio of the selected audio track preceding the frame to the onaudio callback
+    pub fn next_frame_with_audio<FA: FnMut(&[i16])>(self: &mut StreamDecoder, onaudio: &mut FA) -> Result<Option<DecodedFrame<'_>>, DecodeError> {
+        if self.finished || !self.try_read_header()? {
+            return Ok(None);
+        }
+
+        let decoder = self.decoder.as_mut().unwrap();
+        let has_timestamps = decoder.is_variable_framerate();
+
+        let end_of_stream = match StreamDecoder::next_frame_available(decoder.source_mut().remaining(), has_timestamps) {
+            Some(v) => v,
+            None => {
+                return Ok(None);
+            }
+        };
+
+        // every packet up to the next frame has arrived, so the decoder won't run out of data
+        match decoder.peek_frame_pts() {
+            Ok(_) => {}
+            Err(e) => {
+                return Err(DecodeError::IOError(e));
+            }
+        };
+
+        // frames are decoded as soon as they arrive rather than against a playback clock, so all audio read so far goes along with the frame
+        decoder.deliver_audio(f64::INFINITY, onaudio);
+
+        if end_of_stream {
+            self.finished = true;
+        }
+
+        decoder.next_frame()
+    }
+
+    /// Returns true if the next call to next_frame would decode a frame or reach the end of the stream, or false if more data is needed
+    #[cfg(feature = "async")]
+    pub(crate) fn has_next_frame(self: &mut StreamDecoder) -> Result<bool, DecodeError> {
+        if self.finished {
+            return Ok(true);
+        }
+
+        if !self.try_read_header()? {
+            return Ok(false);
+        }
+
+        let decoder = self.decoder.as_mut().unwrap();
+        let has_timestamps = decoder.is_variable_framerate();
+
+        Ok(StreamDecoder::next_frame_available(decoder.source_mut().remaining(), has_timestamps).is_some())
+    }
+
+    /// Try to parse the stream header from the bytes fed so far. Returns false if more data is needed
+    pub(crate) fn try_read_header(self: &mut StreamDecoder) -> Result<bool, DecodeError> {
+        if self.decoder.is_some() {
+            return Ok(true);
+        }
+
+        let mut source = FeedBuffer::new();
+        source.push(&self.header);
+
+        #[cfg(feature = "multithreading")]
+        let result = Decoder::new_with_thread_pool(source, self.threadpool.clone());
+
+        #[cfg(not(feature = "multithreading"))]
+        let result = Decoder::new(source);
+
+        match result {
+            Ok(v) => {
+                self.decoder = Some(v);
+                self.header = Vec::new();
+                Ok(true)
+            }
+            Err(DecodeError::IOError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Ok(false)
+            }
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Walk the buffered packets to see whether they contain the whole of the next frame (or the end of the stream) along with every packet before it.
+    /// Returns Some(true) for the end of the stream, Some(false) for a frame, or None if more data is needed
+    fn next_frame_available(mut data: &[u8], has_timestamps: bool) -> Option<bool> {
+        loop {
+            if data.len() < 5 {
+                return None;
+            }
+
+            let packet_type = data[0];
+            let packet_len = LittleEndian::read_u32(&data[1..5]) as usize;
+            let is_frame = packet_type == PFV_PACKET_IFRAME || packet_type == PFV_PACKET_PFRAME;
+
+            let header_len = if has_timestamps && is_frame { 13 } else { 5 };
+
+            if data.len() < header_len + packet_len {
+                return None;
+            }
+
+            if packet_type == PFV_PACKET_EOF {
+                return Some(true);
+            }
+
+            if is_frame {
+                return Some(false);
+            }
+
+            data = &data[(header_len + packet_len)..];
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Track model.
+//!
+//! Every stream has one video track (always track 0), and may declare any number of additional audio or subtitle tracks
+//! (for example one audio track per language). Audio & subtitle packets carry the ID of the track they belong to,
+//! and the decoder only hands out packets from the tracks the caller has selected.
+
+use alloc::string::String;
+
+use crate::audio::AudioFormat;
+
+/// ID of a track - its index in the stream's track list
+pub type TrackId = u8;
+
+/// ID of the video track, which every stream has
+pub const VIDEO_TRACK: TrackId = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackKind {
+    Video,
+    Audio(AudioFormat),
+    /// Timed UTF-8 text cues
+    Subtitle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    pub id: TrackId,
+    pub kind: TrackKind,
+    /// Language tag (for example "en" or "pt-BR"), or empty if unspecified
+    pub language: String,
+}
+
+/// A subtitle cue read from a stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subtitle {
+    pub track: TrackId,
+    /// Start time in seconds
+    pub pts: f64,
+    /// How long the cue stays on screen, in seconds
+    pub duration: f64,
+    pub text: String,
+}
+
+impl TrackKind {
+    #[cfg(feature = "std")]
+    pub(crate) fn to_id(self: &TrackKind) -> u8 {
+        match self {
+            TrackKind::Video => 0,
+            TrackKind::Audio(_) => 1,
+            TrackKind::Subtitle => 2,
+        }
+    }
+}
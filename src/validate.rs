@@ -0,0 +1,457 @@
+//! Stream validation.
+//!
+//! validate walks every packet of a stream & checks it far more strictly than the decoder, which tolerates (and conceals) damage so that playback can continue.
+//! Frame packets are fully parsed without being reconstructed: qtable indices, slice tables, huffman tables, the coefficients of every block, and the motion vector
+//! of every P-frame block are all checked. Problems which a decoder copes with but which point at a broken encoder or muxer are reported as warnings.
+
+use std::{io::Read, ops::Range, fmt};
+
+use crate::{bits::BitReader, analyze::{Plane, block_positions}, audio::AudioCodec, common::{PFV_PACKET_EOF, PFV_PACKET_IFRAME, PFV_PACKET_PFRAME, PFV_PACKET_USER_DATA, PFV_PACKET_AUDIO, PFV_PACKET_SUBTITLE, PFV_PACKET_INDEX},
+    dec::{Decoder, DecodeError}, huffman::{HuffmanTree, HuffmanError}, qoa::decode_frame, track::TrackKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The stream plays, but wasn't written the way a conforming encoder would write it
+    Warning,
+    /// The stream is malformed. Decoders will reject or conceal the affected data
+    Error,
+}
+
+/// A single problem found in a stream
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub severity: Severity,
+    /// Byte offset (from the start of the stream) of the packet the problem was found in. None for problems with the header or the stream as a whole
+    pub offset: Option<u64>,
+    /// Index of the frame the problem was found in
+    pub frame: Option<u64>,
+    pub message: String,
+}
+
+/// Result of validating a stream
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Number of packets read (including the EOF packet)
+    pub packets: u64,
+    /// Number of frames (including drop frames)
+    pub frames: u64,
+    pub issues: Vec<Issue>,
+}
+
+/// Error found while parsing a slice
+enum SliceError {
+    /// The frame's huffman table can't code any coefficients
+    Table,
+    Other(String),
+}
+
+impl Report {
+    /// True if no errors were found (warnings are allowed)
+    pub fn is_valid(self: &Report) -> bool {
+        !self.issues.iter().any(|x| x.severity == Severity::Error)
+    }
+
+    pub fn errors(self: &Report) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().filter(|x| x.severity == Severity::Error)
+    }
+
+    pub fn warnings(self: &Report) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().filter(|x| x.severity == Severity::Warning)
+    }
+
+    fn error(self: &mut Report, offset: Option<u64>, frame: Option<u64>, message: String) {
+        self.issues.push(Issue { severity: Severity::Error, offset, frame, message });
+    }
+
+    fn warning(self: &mut Report, offset: Option<u64>, frame: Option<u64>, message: String) {
+        self.issues.push(Issue { severity: Severity::Warning, offset, frame, message });
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning")?,
+            Severity::Error => write!(f, "error")?,
+        }
+
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+
+        if let Some(frame) = self.frame {
+            write!(f, " (frame {})", frame)?;
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for issue in &self.issues {
+            writeln!(f, "{}", issue)?;
+        }
+
+        write!(f, "{} packets, {} frames: {} errors, {} warnings", self.packets, self.frames, self.errors().count(), self.warnings().count())
+    }
+}
+
+/// Check every packet of a stream, returning a report of all problems found
+pub fn validate<TReader: Read>(reader: TReader) -> Report {
+    let mut report = Report::default();
+
+    #[cfg(feature = "multithreading")]
+    let decoder = Decoder::new(reader, 1);
+
+    #[cfg(not(feature = "multithreading"))]
+    let decoder = Decoder::new(reader);
+
+    let mut decoder = match decoder {
+        Ok(v) => v,
+        Err(e) => {
+            let message = match e {
+                DecodeError::FormatError => "invalid header".to_string(),
+                DecodeError::VersionError => "unsupported codec version".to_string(),
+                DecodeError::InvalidTrack => "invalid track".to_string(),
+                DecodeError::IOError(e) => format!("failed to read header: {}", e),
+            };
+
+            report.error(None, None, message);
+            return report;
+        }
+    };
+
+    if decoder.width() == 0 || decoder.height() == 0 {
+        report.error(None, None, format!("invalid frame size {}x{}", decoder.width(), decoder.height()));
+    }
+
+    for (i, qtable) in decoder.qtables().iter().enumerate() {
+        if qtable.contains(&0) {
+            report.warning(None, None, format!("qtable {} contains zero entries", i));
+        }
+    }
+
+    let mut iframes = Vec::new();
+    let mut index_packet = None;
+    let mut seen_iframe = false;
+    let mut last_pts = None;
+    let mut eof = false;
+    let mut slice_ranges = Vec::new();
+
+    loop {
+        let packet = match decoder.read_raw_packet() {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                break;
+            }
+            Err(e) => {
+                report.error(None, None, format!("stream is truncated: {}", e));
+                break;
+            }
+        };
+
+        if eof {
+            report.warning(Some(packet.offset), None, "stream continues past the EOF packet".to_string());
+            break;
+        }
+
+        report.packets += 1;
+
+        let offset = Some(packet.offset);
+        let payload = decoder.last_packet();
+
+        match packet.packet_type {
+            PFV_PACKET_EOF => {
+                eof = true;
+            }
+            PFV_PACKET_IFRAME | PFV_PACKET_PFRAME => {
+                let index = report.frames;
+                let frame = Some(index);
+                report.frames += 1;
+
+                if let Some(pts) = packet.pts {
+                    if last_pts.is_some_and(|x| pts <= x) {
+                        report.warning(offset, frame, format!("timestamp {} doesn't follow the previous frame's timestamp", pts));
+                    }
+
+                    last_pts = Some(pts);
+                }
+
+                // drop frame
+                if packet.packet_type == PFV_PACKET_IFRAME && payload.is_empty() {
+                    continue;
+                }
+
+                if packet.packet_type == PFV_PACKET_IFRAME {
+                    seen_iframe = true;
+                    iframes.push((packet.offset, index));
+                } else if !seen_iframe {
+                    report.warning(offset, frame, "P-frame precedes the first I-frame".to_string());
+                }
+
+                if payload.len() < 19 {
+                    report.error(offset, frame, format!("frame packet is too short ({} bytes)", payload.len()));
+                    continue;
+                }
+
+                let num_qtables = decoder.qtables().len();
+
+                for (plane, qtable) in ["Y", "U", "V"].iter().zip(&payload[16..19]) {
+                    if *qtable as usize >= num_qtables {
+                        report.error(offset, frame, format!("{} plane uses qtable {}, but the header only has {} qtables", plane, qtable, num_qtables));
+                    }
+                }
+
+                if decoder.last_packet_slices(&mut slice_ranges).is_err() {
+                    report.error(offset, frame, "invalid slice table".to_string());
+                    continue;
+                }
+
+                validate_frame(&decoder, packet.packet_type == PFV_PACKET_IFRAME, &slice_ranges, &mut report, packet.offset, index);
+            }
+            PFV_PACKET_USER_DATA => {
+                if payload.len() < 8 {
+                    report.error(offset, None, format!("user data packet is too short ({} bytes)", payload.len()));
+                }
+            }
+            PFV_PACKET_AUDIO => {
+                // track ID + position of first sample frame
+                let header_len = 9;
+
+                if payload.len() < header_len {
+                    report.error(offset, None, format!("audio packet is too short ({} bytes)", payload.len()));
+                    continue;
+                }
+
+                let track = payload[0];
+
+                let format = match decoder.tracks().get(track as usize).map(|x| x.kind) {
+                    Some(TrackKind::Audio(v)) => v,
+                    _ => {
+                        report.error(offset, None, format!("audio packet belongs to track {}, which is not an audio track", track));
+                        continue;
+                    }
+                };
+
+                let data = &payload[header_len..];
+                let channels = format.channels as usize;
+
+                let valid = match format.codec {
+                    AudioCodec::Pcm => data.len() % (channels * 2) == 0,
+                    AudioCodec::Qoa => decode_frame(data, channels, &mut Vec::new()).is_some(),
+                };
+
+                if !valid {
+                    report.error(offset, None, format!("audio packet of track {} can't be decoded", track));
+                }
+            }
+            PFV_PACKET_SUBTITLE => {
+                if payload.len() < 17 {
+                    report.error(offset, None, format!("subtitle packet is too short ({} bytes)", payload.len()));
+                    continue;
+                }
+
+                let track = payload[0];
+
+                if decoder.tracks().get(track as usize).map(|x| x.kind) != Some(TrackKind::Subtitle) {
+                    report.error(offset, None, format!("subtitle packet belongs to track {}, which is not a subtitle track", track));
+                }
+
+                if std::str::from_utf8(&payload[17..]).is_err() {
+                    report.error(offset, None, "subtitle text is not valid UTF-8".to_string());
+                }
+            }
+            PFV_PACKET_INDEX => {
+                if payload.len() % 24 != 0 {
+                    report.error(offset, None, format!("index packet length {} is not a multiple of 24", payload.len()));
+                    continue;
+                }
+
+                // (frame, pts, offset) of each I-frame
+                let entries: Vec<_> = payload.chunks_exact(24).map(|x| {
+                    let frame = u64::from_le_bytes(x[0..8].try_into().unwrap());
+                    let offset = u64::from_le_bytes(x[16..24].try_into().unwrap());
+                    (offset, frame)
+                }).collect();
+
+                index_packet = Some((packet.offset, entries));
+            }
+            packet_type => {
+                report.warning(offset, None, format!("unknown packet type {}", packet_type));
+            }
+        }
+    }
+
+    if !eof {
+        report.warning(None, None, "stream ends without an EOF packet".to_string());
+    }
+
+    if let Some(frame_count) = decoder.frame_count() {
+        if frame_count != report.frames {
+            report.error(None, None, format!("header records {} frames, but the stream contains {}", frame_count, report.frames));
+        }
+    }
+
+    match (decoder.index_offset(), &index_packet) {
+        (Some(expected), Some((offset, _))) if expected != *offset => {
+            report.error(None, None, format!("header records the index at offset {}, but it is at offset {}", expected, offset));
+        }
+        (Some(expected), None) => {
+            report.error(None, None, format!("header records the index at offset {}, but the stream has no index packet", expected));
+        }
+        _ => {}
+    }
+
+    if let Some((offset, entries)) = index_packet {
+        if entries != iframes {
+            report.error(Some(offset), None, format!("index lists {} I-frames which don't match the {} I-frames in the stream", entries.len(), iframes.len()));
+        }
+    }
+
+    report
+}
+
+/// Parse every slice of a frame packet, checking the coefficients & motion vectors of each block
+fn validate_frame<TReader: Read>(decoder: &Decoder<TReader>, is_iframe: bool, slice_ranges: &[Range<usize>], report: &mut Report, offset: u64, frame: u64) {
+    let payload = decoder.last_packet();
+
+    let mut table = [0;16];
+    table.copy_from_slice(&payload[0..16]);
+
+    // with fewer than two symbols, codes are zero bits long & coefficients can't be coded
+    let num_symbols = table.iter().filter(|x| **x > 0).count();
+    let tree = if num_symbols >= 2 { Some(HuffmanTree::from_table(&table)) } else { None };
+
+    let plane_blocks = decoder.plane_blocks();
+    let positions = block_positions(plane_blocks);
+    let mut positions_remaining = &positions[..];
+    let mut table_reported = false;
+
+    for (slice, (num_blocks, range)) in decoder.slice_sizes().into_iter().zip(slice_ranges).enumerate() {
+        let (slice_positions, next_positions) = positions_remaining.split_at(num_blocks);
+        positions_remaining = next_positions;
+
+        let data = &payload[range.clone()];
+
+        let result = if is_iframe {
+            validate_islice(tree.as_ref(), data, num_blocks)
+        } else {
+            validate_pslice(tree.as_ref(), data, slice_positions, plane_blocks)
+        };
+
+        match result {
+            Ok(trailing_bits) => {
+                if trailing_bits >= 8 {
+                    report.warning(Some(offset), Some(frame), format!("slice {} has {} bytes of trailing data", slice, trailing_bits / 8));
+                }
+            }
+            Err(SliceError::Table) => {
+                if !table_reported {
+                    report.error(Some(offset), Some(frame), format!("huffman table has {} symbols, which can't code any coefficients", num_symbols));
+                    table_reported = true;
+                }
+            }
+            Err(SliceError::Other(message)) => {
+                report.error(Some(offset), Some(frame), format!("slice {}: {}", slice, message));
+            }
+        }
+    }
+}
+
+/// Parse an I-frame slice, returning the number of unused bits at its end
+fn validate_islice(tree: Option<&HuffmanTree>, data: &[u8], num_blocks: usize) -> Result<u64, SliceError> {
+    let mut bitreader = BitReader::new(data);
+    let bitstream_length = data.len() as u64 * 8;
+
+    if tree.is_none() && num_blocks > 0 {
+        return Err(SliceError::Table);
+    }
+
+    for block in 0..num_blocks {
+        validate_block(tree, &mut bitreader, bitstream_length).map_err(|e| SliceError::Other(format!("block {}: {}", block, e)))?;
+    }
+
+    let position = bitreader.position_in_bits();
+    Ok(bitstream_length - position)
+}
+
+/// Parse a P-frame slice, returning the number of unused bits at its end
+fn validate_pslice(tree: Option<&HuffmanTree>, data: &[u8], positions: &[(Plane, usize, usize)], plane_blocks: [(usize, usize);3]) -> Result<u64, SliceError> {
+    let mut bitreader = BitReader::new(data);
+    let bitstream_length = data.len() as u64 * 8;
+    let mut has_coeff = Vec::with_capacity(positions.len());
+
+    for (block, (plane, x, y)) in positions.iter().enumerate() {
+        let header_err = |e: std::io::Error| SliceError::Other(format!("block {} header: {}", block, e));
+
+        let has_mvec = bitreader.read_bit().map_err(header_err)?;
+        has_coeff.push(bitreader.read_bit().map_err(header_err)?);
+
+        if has_mvec {
+            let mx = bitreader.read_signed(7).map_err(header_err)?;
+            let my = bitreader.read_signed(7).map_err(header_err)?;
+
+            let (blocks_wide, blocks_high) = match plane {
+                Plane::Y => plane_blocks[0],
+                Plane::U => plane_blocks[1],
+                Plane::V => plane_blocks[2],
+            };
+
+            let sx = *x as i32 + mx;
+            let sy = *y as i32 + my;
+
+            if sx < 0 || sy < 0 || sx > (blocks_wide as i32 - 1) * 16 || sy > (blocks_high as i32 - 1) * 16 {
+                return Err(SliceError::Other(format!("block {} motion vector ({}, {}) points outside the plane", block, mx, my)));
+            }
+        }
+    }
+
+    for (block, _) in has_coeff.iter().enumerate().filter(|(_, x)| **x) {
+        validate_block(tree, &mut bitreader, bitstream_length).map_err(|e| match tree {
+            Some(_) => SliceError::Other(format!("block {}: {}", block, e)),
+            None => SliceError::Table,
+        })?;
+    }
+
+    let position = bitreader.position_in_bits();
+    Ok(bitstream_length - position)
+}
+
+/// Read the RLE coefficients of one block, which must fill the block exactly
+fn validate_block(tree: Option<&HuffmanTree>, bitreader: &mut BitReader, bitstream_length: u64) -> Result<(), String> {
+    let tree = match tree {
+        Some(v) => v,
+        None => {
+            return Err("no huffman codes".to_string());
+        }
+    };
+
+    let symbol_err = |e: HuffmanError| match e {
+        HuffmanError::DecodeError => "invalid huffman code".to_string(),
+        HuffmanError::IOError(_) => "bitstream ends in the middle of the block".to_string(),
+    };
+
+    let mut num_coeff = 0;
+
+    while num_coeff < 256 {
+        num_coeff += tree.read(bitreader, bitstream_length).map_err(symbol_err)? as usize;
+
+        let num_bits = tree.read(bitreader, bitstream_length).map_err(symbol_err)?;
+
+        if num_bits > 0 {
+            if num_coeff >= 256 {
+                return Err(format!("coefficient {} is past the end of the block", num_coeff));
+            }
+
+            bitreader.read_signed(num_bits as u32).map_err(|_| "bitstream ends in the middle of the block".to_string())?;
+            num_coeff += 1;
+        }
+    }
+
+    if num_coeff != 256 {
+        return Err(format!("run of zeroes overruns the block ({} coefficients)", num_coeff));
+    }
+
+    Ok(())
+}